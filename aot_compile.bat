@@ -1 +1 @@
-cargo run && nasm -f win64 out.asm -o out.obj && gcc out.obj -o out.exe
\ No newline at end of file
+cargo run -- compile out.bin -o out.asm --target win64 && nasm -f win64 out.asm -o out.obj && gcc out.obj ryde_runtime.c -o out.exe
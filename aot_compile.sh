@@ -0,0 +1,3 @@
+#!/bin/sh
+set -e
+cargo run -- compile "${1:-out.bin}" -o out.asm --target sysv64 && nasm -f elf64 out.asm -o out.o && cc out.o ryde_runtime.c -o out -lm
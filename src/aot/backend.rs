@@ -0,0 +1,157 @@
+//! Code generation interface shared by the assembly and C backends.
+//!
+//! [`crate::aot::emit_instruction`] decides how each bytecode instruction is
+//! computed, in terms of runtime calls, jumps and native integer operations.
+//! A backend only decides how those are written out. Operands are strings in
+//! the backend's own syntax, as returned by [`Backend::location`] and friends.
+//! Whatever a backend cannot write is returned as an [`AotErrorKind`], which
+//! `emit_instruction` ties to the instruction.
+use crate::{
+    aot::types::ValueType, error::aot::AotErrorKind, function::Capture, instruction::Instruction,
+    value::VmValue,
+};
+
+/// Function called by `CALLF` or `CALLR`
+#[derive(Clone, Copy, Debug)]
+pub enum Callee {
+    /// Index into the function table
+    Function(usize),
+    /// Register holding a closure
+    Closure(usize),
+}
+
+pub trait Backend {
+    /// Operand naming a bytecode register, holding a boxed value
+    fn location(&self, vreg: usize) -> String;
+
+    /// Operand naming the unboxed integer in a bytecode register known to
+    /// hold an Int
+    fn int_location(&self, vreg: usize) -> String;
+
+    /// Operand holding a boxed immediate value
+    fn immediate(&mut self, value: &VmValue) -> Result<String, AotErrorKind>;
+
+    /// Operand holding a value of the constant pool
+    fn constant(&mut self, index: usize) -> Result<String, AotErrorKind>;
+
+    fn constant_value(&self, index: usize) -> Option<&VmValue>;
+
+    fn write_loadv(&mut self, vreg: usize, src: String) -> String;
+
+    /// Prints `value` from the instruction at `index`
+    fn write_print(&mut self, index: usize, value: String) -> Result<String, AotErrorKind> {
+        self.write_runtime_call(index, "ry_print", vec![value], None)
+    }
+
+    /// Calls a runtime routine with boxed arguments from the instruction at
+    /// `index`, storing the value it returns in `target`
+    fn write_runtime_call(
+        &mut self,
+        index: usize,
+        routine: &'static str,
+        arguments: Vec<String>,
+        target: Option<usize>,
+    ) -> Result<String, AotErrorKind>;
+
+    /// Computes `operation` (`add`, `sub`, `imul`, `neg`, `and`, `or`, `xor`
+    /// or `not`) on operands from [`Backend::int_location`] or integer
+    /// literals, boxing the result into `vreg`. Overflow saturates like the
+    /// VM's `as i32`.
+    fn write_int_arithmetic(
+        &mut self,
+        operation: &str,
+        vreg: usize,
+        operands: Vec<String>,
+    ) -> String;
+
+    fn write_jump(&mut self, address: usize) -> String;
+
+    /// Compares two registers like `VmValue`'s `PartialOrd`, or `PartialEq`
+    /// for `e` and `ne`, and jumps with `condition` (`l`, `le`, `g`, `ge`,
+    /// `e` or `ne`)
+    fn write_compare_jump(
+        &mut self,
+        index: usize,
+        condition: &str,
+        a: usize,
+        b: usize,
+        address: usize,
+    ) -> String;
+
+    /// Compares two registers known to hold Ints and jumps with `condition`
+    fn write_int_compare_jump(
+        &mut self,
+        condition: &str,
+        a: usize,
+        b: usize,
+        address: usize,
+    ) -> String;
+
+    /// Jumps when a register of type `kind` is truthy, or falsy if `truthy`
+    /// is false
+    fn write_truth_jump(
+        &mut self,
+        index: usize,
+        source: usize,
+        kind: ValueType,
+        truthy: bool,
+        address: usize,
+    ) -> String;
+
+    /// Calls a subroutine from the instruction at `index`
+    fn write_call(&mut self, index: usize, address: usize) -> String;
+
+    /// Returns from a subroutine or function without values
+    fn write_return(&mut self) -> String;
+
+    fn write_exit(&mut self) -> String;
+
+    /// Annotates what was just written for an instruction
+    fn write_comment(&mut self, written: &str, instruction: &Instruction);
+
+    /// Calls a function with a register window of its own. Backends keeping
+    /// registers in machine registers have no such windows.
+    fn write_function_call(
+        &mut self,
+        _index: usize,
+        _callee: Callee,
+        _args: usize,
+        _arg_count: usize,
+        _target: usize,
+        _result_count: usize,
+    ) -> Result<String, AotErrorKind> {
+        Err(AotErrorKind::UnsupportedOpcode)
+    }
+
+    /// Returns `count` registers from `source` on to the caller
+    fn write_return_values(
+        &mut self,
+        _source: usize,
+        _count: usize,
+    ) -> Result<String, AotErrorKind> {
+        Err(AotErrorKind::UnsupportedOpcode)
+    }
+
+    /// Creates a closure of `function` over `captures`
+    fn write_closure(
+        &mut self,
+        _index: usize,
+        _target: usize,
+        _function: usize,
+        _captures: &[Capture],
+    ) -> Result<String, AotErrorKind> {
+        Err(AotErrorKind::UnsupportedOpcode)
+    }
+
+    /// Calls a host function named by the boxed string `name`
+    fn write_native_call(
+        &mut self,
+        _index: usize,
+        _name: String,
+        _args: usize,
+        _arg_count: usize,
+        _target: usize,
+    ) -> Result<String, AotErrorKind> {
+        Err(AotErrorKind::UnsupportedOpcode)
+    }
+}
@@ -0,0 +1,531 @@
+//! Backend translating a program into portable C.
+//!
+//! The generated `main` keeps the bytecode registers in the register stack of
+//! the runtime library, NaN-boxed as in [`crate::aot::runtime`], so function
+//! calls get register windows of their own like in the VM. Instructions are
+//! labelled statements connected by `goto`. Returns resolve the address to
+//! continue at through a `switch` over every address a call returns to.
+//!
+//! The output includes [`runtime::HEADER_SOURCE`] as `ryde_runtime.h` and is
+//! built with the runtime library:
+//!     cc out.c ryde_runtime.c -o out -lm
+use std::collections::BTreeSet;
+
+use crate::{
+    aot::{
+        backend::{Backend, Callee},
+        emit_instruction, runtime,
+        types::{self, ValueType},
+    },
+    error::aot::{AotError, AotErrorKind},
+    function::Capture,
+    instruction::Instruction,
+    serde::Program,
+    value::VmValue,
+};
+
+const TAB: &str = "    ";
+const COMMENT_COLUMN: usize = 40;
+
+pub struct CState {
+    constant_pool: Vec<VmValue>,
+    instruction_count: usize,
+    function_count: usize,
+    /// Instructions jumped or returned to, which keep their label
+    labels: BTreeSet<usize>,
+    /// Addresses `ry_dispatch` can continue at
+    dispatch: BTreeSet<usize>,
+    /// Whether any instruction continues through `ry_dispatch`
+    dispatched: bool,
+    /// String immediates, created when the program starts
+    pub strings: Vec<String>,
+    pub source: String,
+}
+
+/// Compiles `program` to C, running its top-level code with
+/// `register_count` registers like `Vm::new`. Fails on the first instruction
+/// the backend cannot translate.
+pub fn compile(program: &Program, register_count: usize) -> Result<String, AotError> {
+    let types = types::infer(program);
+    let mut state = CState::new(program);
+
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        state.write_instruction_label(index);
+        emit_instruction(&mut state, &types, index, instruction)?;
+    }
+    if program.instructions.last() != Some(&Instruction::HALT) {
+        // falling off the end finishes the program, as in the VM
+        state.write_instruction_label(program.instructions.len());
+        let exit = state.write_exit();
+        state.write_statement(&exit);
+    }
+    if state.dispatched {
+        state.write_dispatch();
+    }
+
+    Ok(state.finish(program, register_count))
+}
+
+impl CState {
+    pub fn new(program: &Program) -> CState {
+        let count = program.instructions.len();
+        let mut dispatch: BTreeSet<usize> = program
+            .functions
+            .iter()
+            .map(|function| function.address)
+            .filter(|address| *address < count)
+            .collect();
+        for (index, instruction) in program.instructions.iter().enumerate() {
+            if let Instruction::CALL(_) | Instruction::CALLF { .. } | Instruction::CALLR { .. } =
+                instruction
+            {
+                dispatch.insert(index + 1);
+            }
+        }
+
+        CState {
+            constant_pool: program.constant_pool.clone(),
+            instruction_count: count,
+            function_count: program.functions.len(),
+            labels: BTreeSet::new(),
+            dispatch,
+            dispatched: false,
+            strings: Vec::new(),
+            source: String::new(),
+        }
+    }
+
+    /// Label of the code for the instruction at `index`
+    pub fn label(index: usize) -> String {
+        format!("L{}", index)
+    }
+
+    /// Labels every instruction, as jumps to it may come later. Unused
+    /// labels are removed by [`CState::finish`].
+    pub fn write_instruction_label(&mut self, index: usize) {
+        self.source.push_str(&format!("{}:\n", Self::label(index)));
+    }
+
+    /// Jumps to the code for `address`, failing like the VM when there is none
+    fn jump(&mut self, address: usize) -> String {
+        if address < self.instruction_count {
+            self.labels.insert(address);
+            format!("goto {};", Self::label(address))
+        } else {
+            "ry_fail(\"Program counter out of bounds\");".to_string()
+        }
+    }
+
+    /// Continues at the address the runtime returned
+    fn dispatch(&mut self, address: String) -> String {
+        self.dispatched = true;
+        format!("ry_address = {};\n{}goto ry_dispatch;", address, TAB)
+    }
+
+    fn write_dispatch(&mut self) {
+        let mut text = String::from("\nry_dispatch:\n");
+        text.push_str(&format!("{}switch (ry_address) {{\n", TAB));
+        self.labels.extend(self.dispatch.iter().copied());
+        for address in self.dispatch.iter() {
+            text.push_str(&format!(
+                "{0}case {1}:\n{0}{0}goto {2};\n",
+                TAB,
+                address,
+                Self::label(*address)
+            ));
+        }
+        text.push_str(&format!(
+            "{0}default:\n{0}{0}ry_fail(\"Program counter out of bounds\");\n{0}}}\n",
+            TAB
+        ));
+        self.source.push_str(&text);
+    }
+
+    /// Writes a statement without a comment
+    fn write_statement(&mut self, statement: &str) {
+        self.source.push_str(&format!("{}{}\n", TAB, statement));
+    }
+
+    /// Wraps the body in `main`, after the function table and strings
+    fn finish(self, program: &Program, register_count: usize) -> String {
+        let mut text = String::from("/* Generated by the ryde AOT compiler */\n");
+        text.push_str("#include \"ryde_runtime.h\"\n\n");
+
+        let functions = if program.functions.is_empty() {
+            "NULL".to_string()
+        } else {
+            text.push_str("static const RyFunction ry_functions[] = {\n");
+            for function in program.functions.iter() {
+                text.push_str(&format!(
+                    "{}{{{}, {}, {}, {}}},\n",
+                    TAB,
+                    string_literal(&function.name),
+                    function.address,
+                    function.arity,
+                    function.register_count
+                ));
+            }
+            text.push_str("};\n");
+            "ry_functions".to_string()
+        };
+        if !self.strings.is_empty() {
+            text.push_str(&format!(
+                "static ry_value ry_strings[{}];\n",
+                self.strings.len()
+            ));
+        }
+        if text.ends_with(";\n") {
+            text.push('\n');
+        }
+
+        text.push_str("int main(void) {\n");
+        if self.dispatched {
+            text.push_str(&format!("{}uint64_t ry_address;\n", TAB));
+        }
+        text.push_str(&format!(
+            "{}ry_start({}, {}, {}, {});\n",
+            TAB,
+            register_count,
+            functions,
+            program.functions.len(),
+            self.instruction_count
+        ));
+        for (index, string) in self.strings.iter().enumerate() {
+            text.push_str(&format!(
+                "{}ry_strings[{}] = ry_string({}, {});\n",
+                TAB,
+                index,
+                string_literal(string),
+                string.len()
+            ));
+        }
+        text.push('\n');
+        for line in self.source.lines() {
+            let unused = line
+                .strip_prefix('L')
+                .and_then(|label| label.strip_suffix(':'))
+                .and_then(|label| label.parse().ok())
+                .is_some_and(|index| !self.labels.contains(&index));
+            if !unused {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        text.push_str("}\n");
+        text
+    }
+}
+
+impl Backend for CState {
+    fn location(&self, vreg: usize) -> String {
+        format!("R({})", vreg)
+    }
+
+    fn int_location(&self, vreg: usize) -> String {
+        format!("ry_int(R({}))", vreg)
+    }
+
+    fn immediate(&mut self, value: &VmValue) -> Result<String, AotErrorKind> {
+        if let Some(bits) = runtime::box_value(value) {
+            return Ok(format!("0x{:x}ULL", bits));
+        }
+        match value {
+            VmValue::String(string) => {
+                let index = match self.strings.iter().position(|s| s == string) {
+                    Some(index) => index,
+                    None => {
+                        self.strings.push(string.clone());
+                        self.strings.len() - 1
+                    }
+                };
+                Ok(format!("ry_strings[{}]", index))
+            }
+            _ => Err(AotErrorKind::UnsupportedImmediate(value.clone())),
+        }
+    }
+
+    fn constant(&mut self, index: usize) -> Result<String, AotErrorKind> {
+        match self.constant_pool.get(index).cloned() {
+            Some(value) => self.immediate(&value),
+            None => Ok(format!("ry_constant_out_of_bounds({})", index)),
+        }
+    }
+
+    fn constant_value(&self, index: usize) -> Option<&VmValue> {
+        self.constant_pool.get(index)
+    }
+
+    fn write_loadv(&mut self, vreg: usize, src: String) -> String {
+        format!("R({}) = {};", vreg, src)
+    }
+
+    fn write_runtime_call(
+        &mut self,
+        _index: usize,
+        routine: &'static str,
+        arguments: Vec<String>,
+        target: Option<usize>,
+    ) -> Result<String, AotErrorKind> {
+        let call = format!("{}({})", routine, arguments.join(", "));
+        Ok(match target {
+            Some(target) => format!("R({}) = {};", target, call),
+            None => format!("{};", call),
+        })
+    }
+
+    /// Computes in 64 bits, where any sum or product of two i32s fits, before
+    /// saturating like the VM
+    fn write_int_arithmetic(
+        &mut self,
+        operation: &str,
+        vreg: usize,
+        operands: Vec<String>,
+    ) -> String {
+        let value = match operation {
+            "and" => format!("{} & {}", operands[0], operands[1]),
+            "or" => format!("{} | {}", operands[0], operands[1]),
+            "xor" => format!("{} ^ {}", operands[0], operands[1]),
+            "not" => format!("~{}", operands[0]),
+            "add" => format!(
+                "ry_saturate_int((int64_t){} + {})",
+                operands[0], operands[1]
+            ),
+            "sub" => format!(
+                "ry_saturate_int((int64_t){} - {})",
+                operands[0], operands[1]
+            ),
+            "imul" => format!(
+                "ry_saturate_int((int64_t){} * {})",
+                operands[0], operands[1]
+            ),
+            "neg" => format!("ry_saturate_int(-(int64_t){})", operands[0]),
+            _ => panic!("unknown integer operation: {}", operation),
+        };
+        format!("R({}) = ry_box_int({});", vreg, value)
+    }
+
+    fn write_jump(&mut self, address: usize) -> String {
+        self.jump(address)
+    }
+
+    fn write_compare_jump(
+        &mut self,
+        _index: usize,
+        condition: &str,
+        a: usize,
+        b: usize,
+        address: usize,
+    ) -> String {
+        let (a, b) = (self.location(a), self.location(b));
+        // ry_compare returns -1, 0, 1, or 2 for unordered operands
+        let test = match condition {
+            "l" => format!("ry_compare({}, {}) == -1", a, b),
+            "le" => format!("ry_compare({}, {}) <= 0", a, b),
+            "g" => format!("ry_compare({}, {}) == 1", a, b),
+            "ge" => format!("(uint64_t)ry_compare({}, {}) <= 1", a, b),
+            "e" => format!("ry_equal({}, {})", a, b),
+            "ne" => format!("!ry_equal({}, {})", a, b),
+            _ => panic!("unknown condition: {}", condition),
+        };
+        format!("if ({}) {}", test, self.jump(address))
+    }
+
+    fn write_int_compare_jump(
+        &mut self,
+        condition: &str,
+        a: usize,
+        b: usize,
+        address: usize,
+    ) -> String {
+        let operator = match condition {
+            "l" => "<",
+            "le" => "<=",
+            "g" => ">",
+            "ge" => ">=",
+            "e" => "==",
+            "ne" => "!=",
+            _ => panic!("unknown condition: {}", condition),
+        };
+        let jump = self.jump(address);
+        format!(
+            "if ({} {} {}) {}",
+            self.int_location(a),
+            operator,
+            self.int_location(b),
+            jump
+        )
+    }
+
+    /// Numbers are always truthy, but their register is still checked like
+    /// the VM's read of it
+    fn write_truth_jump(
+        &mut self,
+        _index: usize,
+        source: usize,
+        kind: ValueType,
+        truthy: bool,
+        address: usize,
+    ) -> String {
+        let source = self.location(source);
+        match kind {
+            ValueType::Int | ValueType::Float if truthy => {
+                format!("(void){};\n{}{}", source, TAB, self.jump(address))
+            }
+            ValueType::Int | ValueType::Float => format!("(void){};", source),
+            _ => {
+                let negation = if truthy { "" } else { "!" };
+                format!(
+                    "if ({}ry_truthy({})) {}",
+                    negation,
+                    source,
+                    self.jump(address)
+                )
+            }
+        }
+    }
+
+    fn write_call(&mut self, index: usize, address: usize) -> String {
+        if address >= self.instruction_count {
+            return self.jump(address);
+        }
+        format!(
+            "ry_call_subroutine({});\n{}{}",
+            index + 1,
+            TAB,
+            self.jump(address)
+        )
+    }
+
+    fn write_return(&mut self) -> String {
+        self.dispatch("ry_return(0, 0)".to_string())
+    }
+
+    fn write_exit(&mut self) -> String {
+        "return 0;".to_string()
+    }
+
+    fn write_comment(&mut self, written: &str, instruction: &Instruction) {
+        if written.is_empty() {
+            return;
+        }
+        // lines after the first are indented already
+        let width = match written.rsplit_once('\n') {
+            Some((_, last_line)) => last_line.len(),
+            None => TAB.len() + written.len(),
+        };
+        let padding = COMMENT_COLUMN.saturating_sub(width + 1);
+        self.source.push_str(&format!(
+            "{}{}{} // {:?}\n",
+            TAB,
+            written,
+            " ".repeat(padding),
+            instruction
+        ));
+    }
+
+    fn write_function_call(
+        &mut self,
+        index: usize,
+        callee: Callee,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+        result_count: usize,
+    ) -> Result<String, AotErrorKind> {
+        let (routine, callee) = match callee {
+            Callee::Function(function) => ("ry_call_function", function.to_string()),
+            Callee::Closure(register) => ("ry_call_closure", self.location(register)),
+        };
+        Ok(self.dispatch(format!(
+            "{}({}, {}, {}, {}, {}, {})",
+            routine,
+            index + 1,
+            callee,
+            args,
+            arg_count,
+            target,
+            result_count
+        )))
+    }
+
+    fn write_return_values(&mut self, source: usize, count: usize) -> Result<String, AotErrorKind> {
+        Ok(self.dispatch(format!("ry_return({}, {})", source, count)))
+    }
+
+    fn write_closure(
+        &mut self,
+        _index: usize,
+        target: usize,
+        function: usize,
+        captures: &[Capture],
+    ) -> Result<String, AotErrorKind> {
+        if function >= self.function_count {
+            return Ok(format!(
+                "ry_fail(\"Invalid function index: {}\");",
+                function
+            ));
+        }
+        if captures.is_empty() {
+            return Ok(format!(
+                "R({}) = ry_new_closure({}, 0, NULL);",
+                target, function
+            ));
+        }
+        let captures = captures
+            .iter()
+            .map(|capture| {
+                Ok(match capture {
+                    Capture::Variable(name) => format!(
+                        "ry_capture_variable({})",
+                        self.immediate(&VmValue::String(name.clone()))?
+                    ),
+                    Capture::Register(register) => {
+                        format!("ry_capture_value({})", self.location(*register))
+                    }
+                    Capture::Upvalue(upvalue) => format!("ry_capture_upvalue({})", upvalue),
+                })
+            })
+            .collect::<Result<Vec<_>, AotErrorKind>>()?;
+        Ok(format!(
+            "{{\n{0}{0}RyCell *ry_captures[] = {{{1}}};\n{0}{0}R({2}) = ry_new_closure({3}, {4}, ry_captures);\n{0}}}",
+            TAB,
+            captures.join(", "),
+            target,
+            function,
+            captures.len()
+        ))
+    }
+
+    fn write_native_call(
+        &mut self,
+        _index: usize,
+        name: String,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+    ) -> Result<String, AotErrorKind> {
+        Ok(format!(
+            "R({}) = ry_call_native({}, {}, {});",
+            target, name, args, arg_count
+        ))
+    }
+}
+
+/// C string literal of `string`, with octal escapes for anything but
+/// printable ASCII, so UTF-8 and NUL bytes survive any source encoding
+fn string_literal(string: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in string.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                literal.push('\\');
+                literal.push(byte as char);
+            }
+            // `?` would start a trigraph
+            b' '..=b'~' if byte != b'?' => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
@@ -0,0 +1,165 @@
+//! Control flow and register usage of bytecode, shared by the AOT passes
+use crate::{function::Capture, instruction::Instruction, opcode::Operand, serde::Program};
+
+/// Instructions that may execute right after the one at `index`.
+///
+/// `CALL` subroutines share the caller's registers, so a `RETURN` is treated
+/// as flowing to the instruction after every `CALL` in the program.
+pub fn successors(program: &Program, index: usize) -> Vec<usize> {
+    use Instruction::*;
+    let next = index + 1;
+    let in_bounds = |address: &usize| *address < program.instructions.len();
+
+    let successors = match &program.instructions[index] {
+        JMP(address) => vec![*address],
+        JZ { address, .. }
+        | JNZ { address, .. }
+        | JLT { address, .. }
+        | JLTE { address, .. }
+        | JGT { address, .. }
+        | JGTE { address, .. }
+        | JEQ { address, .. }
+        | JNEQ { address, .. } => vec![*address, next],
+        CALL(address) | PUSH_HANDLER { address, .. } => vec![*address, next],
+        RETURN | RETURNV { .. } => return_addresses(program),
+        HALT | THROW(_) => Vec::new(),
+        _ => vec![next],
+    };
+    successors.into_iter().filter(in_bounds).collect()
+}
+
+/// Addresses a subroutine `RETURN` can jump back to
+pub fn return_addresses(program: &Program) -> Vec<usize> {
+    program
+        .instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, instruction)| matches!(instruction, Instruction::CALL(_)))
+        .map(|(index, _)| index + 1)
+        .collect()
+}
+
+/// Registers written by an instruction, and registers it reads
+pub struct RegisterUse {
+    pub defs: Vec<usize>,
+    pub uses: Vec<usize>,
+}
+
+pub fn register_use(instruction: &Instruction) -> RegisterUse {
+    use Instruction::*;
+    let range = |start: usize, count: usize| (start..start + count).collect::<Vec<_>>();
+
+    match instruction {
+        CALLF {
+            args,
+            arg_count,
+            target,
+            result_count,
+            ..
+        } => RegisterUse {
+            defs: range(*target, *result_count),
+            uses: range(*args, *arg_count),
+        },
+        CALLR {
+            callee,
+            args,
+            arg_count,
+            target,
+            result_count,
+        } => RegisterUse {
+            defs: range(*target, *result_count),
+            uses: std::iter::once(*callee)
+                .chain(range(*args, *arg_count))
+                .collect(),
+        },
+        CALL_NATIVE {
+            args,
+            arg_count,
+            target,
+            ..
+        }
+        | CALL_NATIVEC {
+            args,
+            arg_count,
+            target,
+            ..
+        } => RegisterUse {
+            defs: vec![*target],
+            uses: range(*args, *arg_count),
+        },
+        RETURNV { source, count } => RegisterUse {
+            defs: Vec::new(),
+            uses: range(*source, *count),
+        },
+        _ => {
+            let mut defs = Vec::new();
+            let mut uses = Vec::new();
+            for (i, operand) in instruction.operands().into_iter().enumerate() {
+                match operand {
+                    Operand::Register(register) | Operand::OptionalRegister(Some(register))
+                        if i == 0 && defines_first_register(instruction) =>
+                    {
+                        defs.push(register)
+                    }
+                    Operand::Register(register) => uses.push(register),
+                    Operand::Captures(captures) => {
+                        uses.extend(captures.iter().filter_map(|capture| match capture {
+                            Capture::Register(register) => Some(*register),
+                            _ => None,
+                        }))
+                    }
+                    _ => {}
+                }
+            }
+            if may_keep_target(instruction) {
+                uses.extend(defs.iter().copied());
+            }
+            RegisterUse { defs, uses }
+        }
+    }
+}
+
+/// Whether the first register operand is the instruction's destination,
+/// rather than a value it reads or an array it mutates in place
+fn defines_first_register(instruction: &Instruction) -> bool {
+    use Instruction::*;
+    !matches!(
+        instruction,
+        PRINT(_)
+            | THROW(_)
+            | JZ { .. }
+            | JNZ { .. }
+            | JLT { .. }
+            | JLTE { .. }
+            | JGT { .. }
+            | JGTE { .. }
+            | JEQ { .. }
+            | JNEQ { .. }
+            | STORE { .. }
+            | DECLARE { .. }
+            | STORE_GLOBAL { .. }
+            | SETUPVAL { .. }
+            | STORE_INDEX { .. }
+            | STORE_INDEXN { .. }
+            | STORE_INDEXK { .. }
+            | STORE_INDEXC { .. }
+            | DELETE_INDEX { .. }
+            | DELETE_INDEXN { .. }
+            | DELETE_INDEXK { .. }
+            | DELETE_INDEXC { .. }
+            | ARRAY_PUSH { .. }
+            | ARRAY_PUSHK { .. }
+            | ARRAY_PUSHC { .. }
+    )
+}
+
+/// Whether the instruction leaves its target unchanged for some operands, as
+/// ADD does for anything but numbers and strings and LEN for anything but
+/// arrays and strings, so the target's old value may still be read later
+fn may_keep_target(instruction: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        instruction,
+        ADD { .. } | ADDK { .. } | ADDC { .. } | LEN { .. }
+    )
+}
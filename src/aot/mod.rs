@@ -1,70 +1,472 @@
+pub mod backend;
+pub mod c;
+pub mod flow;
+pub mod regalloc;
+pub mod runtime;
 pub mod state;
+pub mod types;
 
 use crate::{
-    aot::state::{AotState, AotTarget},
+    aot::{
+        backend::{Backend, Callee},
+        state::{AotState, AotTarget},
+        types::{TypeInfo, ValueType},
+    },
+    error::aot::{AotError, AotErrorKind},
     instruction::Instruction,
+    opcode::Operand,
     serde::Program,
     value::VmValue,
 };
 
-pub fn compile(program: &Program) -> String {
-    let mut state = AotState::new(AotTarget::Win64);
+/// Compiles `program` to assembly for `target`, failing on the first
+/// instruction the backend cannot translate
+pub fn compile(program: &Program, target: AotTarget) -> Result<String, AotError> {
+    let allocation = regalloc::allocate(program, target);
+    let types = types::infer(program);
+    let mut state = AotState::new(target, allocation, program.constant_pool.clone());
     state.write_header();
 
-    for instruction in program.instructions.iter() {
-        emit_instruction(&mut state, &instruction);
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        state.write_instruction_label(index);
+        emit_instruction(&mut state, &types, index, instruction)?;
     }
+    if program.instructions.last() != Some(&Instruction::HALT) {
+        // falling off the end, which a truthy jump on the last instruction may skip to
+        state.write_instruction_label(program.instructions.len());
+        state.write_exit();
+    }
+    runtime::write_routines(&mut state);
+
+    Ok(state.assembly)
+}
 
-    state.assembly
+/// Writes the code of one instruction through any backend
+fn emit_instruction<B: Backend>(
+    state: &mut B,
+    types: &TypeInfo,
+    index: usize,
+    instruction: &Instruction,
+) -> Result<(), AotError> {
+    let written = write_instruction(state, types, index, instruction)
+        .map_err(|kind| AotError::new(index, instruction.opcode(), kind))?;
+    state.write_comment(&written, instruction);
+    Ok(())
 }
 
-const COMMENT_WIDTH: usize = 20;
+/// Code of one instruction, or why the backend cannot write it
+fn write_instruction<B: Backend>(
+    state: &mut B,
+    types: &TypeInfo,
+    index: usize,
+    instruction: &Instruction,
+) -> Result<String, AotErrorKind> {
+    if let Some(operation) = int_operation(instruction)
+        && let Some((target, operands)) = int_operands(state, types, index, instruction)
+    {
+        return Ok(state.write_int_arithmetic(operation, target, operands));
+    }
+    if let Some(routine) = arithmetic_routine(instruction) {
+        return write_arithmetic(state, index, routine, instruction);
+    }
+    if let Some(routine) = value_routine(instruction) {
+        let (target, arguments) = target_and_arguments(state, instruction)?;
+        return state.write_runtime_call(index, routine, arguments, Some(target));
+    }
 
-fn emit_instruction(state: &mut AotState, instruction: &Instruction) -> () {
     let written = match instruction {
-        Instruction::LOADV { target, value } => load_immediate(state, target, value),
-        Instruction::ADD { target: _, a, b } => state.write_add(*a, *b),
-        Instruction::PRINT(target) => state.write_print(*target),
+        Instruction::LOADV { target, value } => {
+            let value = state.immediate(value)?;
+            state.write_loadv(*target, value)
+        }
+        Instruction::LOADC {
+            target,
+            constant_index,
+        } => {
+            let value = state.constant(*constant_index)?;
+            state.write_loadv(*target, value)
+        }
+        Instruction::PRINT(target) => {
+            let value = state.location(*target);
+            state.write_print(index, value)?
+        }
         Instruction::PRINTK(value) => {
-            state.write(format!("; {:?}", instruction));
-            state.write_line();
-
-            let vreg = state.alloc_new_vreg();
-            load_immediate(state, &vreg, value);
-            state.write_line();
-            state.write_print(vreg)
+            let value = state.immediate(value)?;
+            state.write_print(index, value)?
+        }
+        Instruction::PRINTC(constant) => {
+            let value = state.constant(*constant)?;
+            state.write_print(index, value)?
+        }
+        Instruction::NEW_ARRAY(target) => {
+            state.write_runtime_call(index, "ry_new_array", Vec::new(), Some(*target))?
+        }
+        Instruction::NEW_OBJECT(target) => {
+            state.write_runtime_call(index, "ry_new_object", Vec::new(), Some(*target))?
+        }
+        Instruction::INDEX { .. }
+        | Instruction::INDEXN { .. }
+        | Instruction::INDEXK { .. }
+        | Instruction::INDEXC { .. } => {
+            let (target, arguments) = target_and_arguments(state, instruction)?;
+            state.write_runtime_call(index, "ry_index", arguments, Some(target))?
+        }
+        Instruction::STORE_INDEX { .. }
+        | Instruction::STORE_INDEXN { .. }
+        | Instruction::STORE_INDEXK { .. }
+        | Instruction::STORE_INDEXC { .. } => {
+            let mut arguments = arguments(state, instruction)?;
+            // the source comes first in the instruction and last in the call
+            arguments.rotate_left(1);
+            state.write_runtime_call(index, "ry_store_index", arguments, None)?
+        }
+        // deleting stores null, as in the VM
+        Instruction::DELETE_INDEX { .. }
+        | Instruction::DELETE_INDEXN { .. }
+        | Instruction::DELETE_INDEXK { .. }
+        | Instruction::DELETE_INDEXC { .. } => {
+            let mut arguments = arguments(state, instruction)?;
+            arguments.push(state.immediate(&VmValue::Null)?);
+            state.write_runtime_call(index, "ry_store_index", arguments, None)?
+        }
+        Instruction::ARRAY_PUSH { .. }
+        | Instruction::ARRAY_PUSHK { .. }
+        | Instruction::ARRAY_PUSHC { .. } => {
+            let arguments = arguments(state, instruction)?;
+            state.write_runtime_call(index, "ry_array_push", arguments, None)?
+        }
+        Instruction::LEN { target, source } => {
+            let arguments = vec![state.location(*source), state.location(*target)];
+            state.write_runtime_call(index, "ry_len", arguments, Some(*target))?
+        }
+        Instruction::JMP(address) => state.write_jump(*address),
+        Instruction::JZ { source, address } => {
+            let kind = types.register_type(index, *source);
+            state.write_truth_jump(index, *source, kind, false, *address)
+        }
+        Instruction::JNZ { source, address } => {
+            let kind = types.register_type(index, *source);
+            state.write_truth_jump(index, *source, kind, true, *address)
+        }
+        Instruction::JLT { a, b, address } => {
+            write_compare_jump(state, types, index, "l", *a, *b, *address)
+        }
+        Instruction::JLTE { a, b, address } => {
+            write_compare_jump(state, types, index, "le", *a, *b, *address)
+        }
+        Instruction::JGT { a, b, address } => {
+            write_compare_jump(state, types, index, "g", *a, *b, *address)
+        }
+        Instruction::JGTE { a, b, address } => {
+            write_compare_jump(state, types, index, "ge", *a, *b, *address)
+        }
+        Instruction::JEQ { a, b, address } => {
+            write_compare_jump(state, types, index, "e", *a, *b, *address)
+        }
+        Instruction::JNEQ { a, b, address } => {
+            write_compare_jump(state, types, index, "ne", *a, *b, *address)
+        }
+        Instruction::STORE { source, name } => {
+            let arguments = vec![state.immediate(&name_value(name))?, state.location(*source)];
+            state.write_runtime_call(index, "ry_store", arguments, None)?
+        }
+        Instruction::STOREK { name, value } => {
+            let arguments = vec![state.immediate(&name_value(name))?, state.immediate(value)?];
+            state.write_runtime_call(index, "ry_store", arguments, None)?
+        }
+        Instruction::STOREC { name, constant } => {
+            let arguments = vec![
+                state.immediate(&name_value(name))?,
+                state.constant(*constant)?,
+            ];
+            state.write_runtime_call(index, "ry_store", arguments, None)?
+        }
+        Instruction::LOAD { target, name } => {
+            let arguments = vec![state.immediate(&name_value(name))?];
+            state.write_runtime_call(index, "ry_load", arguments, Some(*target))?
         }
-        Instruction::HALT => "".to_string(),
-        _ => panic!("unsupported instruction: {}", instruction),
+        Instruction::DECLARE { source, name } => {
+            let arguments = vec![state.immediate(&name_value(name))?, state.location(*source)];
+            state.write_runtime_call(index, "ry_declare", arguments, None)?
+        }
+        Instruction::STORE_GLOBAL { source, name } => {
+            let arguments = vec![state.immediate(&name_value(name))?, state.location(*source)];
+            state.write_runtime_call(index, "ry_store_global", arguments, None)?
+        }
+        Instruction::LOAD_GLOBAL { target, name } => {
+            let arguments = vec![state.immediate(&name_value(name))?];
+            state.write_runtime_call(index, "ry_load_global", arguments, Some(*target))?
+        }
+        Instruction::PUSH_SCOPE => {
+            state.write_runtime_call(index, "ry_push_scope", Vec::new(), None)?
+        }
+        Instruction::POP_SCOPE => {
+            state.write_runtime_call(index, "ry_pop_scope", Vec::new(), None)?
+        }
+        Instruction::INC {
+            target,
+            name,
+            returns_old,
+        }
+        | Instruction::DEC {
+            target,
+            name,
+            returns_old,
+        } => {
+            let amount = if matches!(instruction, Instruction::INC { .. }) {
+                1
+            } else {
+                -1
+            };
+            let arguments = vec![
+                state.immediate(&name_value(name))?,
+                state.immediate(&VmValue::Int(amount))?,
+                state.immediate(&VmValue::Boolean(*returns_old))?,
+            ];
+            state.write_runtime_call(index, "ry_increment", arguments, *target)?
+        }
+        Instruction::GETUPVAL { target, upvalue } => {
+            let arguments = vec![state.immediate(&VmValue::Int(*upvalue as i32))?];
+            state.write_runtime_call(index, "ry_get_upvalue", arguments, Some(*target))?
+        }
+        Instruction::SETUPVAL { source, upvalue } => {
+            let arguments = vec![
+                state.immediate(&VmValue::Int(*upvalue as i32))?,
+                state.location(*source),
+            ];
+            state.write_runtime_call(index, "ry_set_upvalue", arguments, None)?
+        }
+        Instruction::CALL(address) => state.write_call(index, *address),
+        Instruction::CALLF {
+            function,
+            args,
+            arg_count,
+            target,
+            result_count,
+        } => state.write_function_call(
+            index,
+            Callee::Function(*function),
+            *args,
+            *arg_count,
+            *target,
+            *result_count,
+        )?,
+        Instruction::CALLR {
+            callee,
+            args,
+            arg_count,
+            target,
+            result_count,
+        } => state.write_function_call(
+            index,
+            Callee::Closure(*callee),
+            *args,
+            *arg_count,
+            *target,
+            *result_count,
+        )?,
+        Instruction::CLOSURE {
+            target,
+            function,
+            captures,
+        } => state.write_closure(index, *target, *function, captures)?,
+        Instruction::CALL_NATIVE {
+            name,
+            args,
+            arg_count,
+            target,
+        } => {
+            let name = state.immediate(&name_value(name))?;
+            state.write_native_call(index, name, *args, *arg_count, *target)?
+        }
+        Instruction::CALL_NATIVEC {
+            name_constant,
+            args,
+            arg_count,
+            target,
+        } => {
+            let name = state.constant(*name_constant)?;
+            state.write_native_call(index, name, *args, *arg_count, *target)?
+        }
+        Instruction::RETURN => state.write_return(),
+        Instruction::RETURNV { source, count } => state.write_return_values(*source, *count)?,
+        Instruction::HALT => state.write_exit(),
+        // exception handlers and coroutines are among the rest: compiled code
+        // has no handler stack to unwind to, nor a call stack per coroutine
+        // to switch between
+        _ => return Err(AotErrorKind::UnsupportedOpcode),
     };
+    Ok(written)
+}
 
-    if written.len() == 0 || written.contains("\n") {
-        return;
-    }
+/// Runtime routine computing an arithmetic or bitwise instruction
+fn arithmetic_routine(instruction: &Instruction) -> Option<&'static str> {
+    use Instruction::*;
+    let routine = match instruction {
+        ADD { .. } | ADDK { .. } | ADDC { .. } => "ry_add",
+        SUB { .. } | SUBK { .. } | SUBC { .. } => "ry_sub",
+        MUL { .. } | MULK { .. } | MULC { .. } => "ry_mul",
+        DIV { .. } | DIVK { .. } | DIVC { .. } => "ry_div",
+        IDIV { .. } | IDIVC { .. } => "ry_idiv",
+        IDIVK { .. } => "ry_idivk",
+        MOD { .. } | MODK { .. } | MODC { .. } => "ry_mod",
+        POW { .. } | POWK { .. } | POWC { .. } => "ry_pow",
+        BXOR { .. } | BXORK { .. } | BXORC { .. } => "ry_bxor",
+        BAND { .. } | BANDK { .. } | BANDC { .. } => "ry_band",
+        BOR { .. } | BORK { .. } | BORC { .. } => "ry_bor",
+        BLSH { .. } | BLSHK { .. } | BLSHC { .. } => "ry_blsh",
+        BRSH { .. } | BRSHK { .. } | BRSHC { .. } => "ry_brsh",
+        BARSH { .. } | BARSHK { .. } | BARSHC { .. } => "ry_barsh",
+        BNOT { .. } | BNOTK { .. } | BNOTC { .. } => "ry_bnot",
+        NEGATE { .. } | NEGATEK { .. } | NEGATEC { .. } => "ry_negate",
+        _ => return None,
+    };
+    Some(routine)
+}
 
-    let padding = COMMENT_WIDTH.saturating_sub(written.len());
-    state.write(format!("{}; {:?}", " ".repeat(padding), instruction));
-    state.write_line();
+/// Runtime routine computing a logical, comparison or null-coalescing
+/// instruction from its operands
+fn value_routine(instruction: &Instruction) -> Option<&'static str> {
+    use Instruction::*;
+    let routine = match instruction {
+        AND { .. } | ANDK { .. } | ANDC { .. } => "ry_and",
+        OR { .. } | ORK { .. } | ORC { .. } => "ry_or",
+        NOT { .. } | NOTK { .. } | NOTC { .. } => "ry_not",
+        NULL_COALESCE { .. } | NULL_COALESCEK { .. } | NULL_COALESCEC { .. } => "ry_null_coalesce",
+        EQ { .. } => "ry_eq",
+        NEQ { .. } => "ry_neq",
+        LT { .. } => "ry_lt",
+        LTE { .. } => "ry_lte",
+        GT { .. } => "ry_gt",
+        GTE { .. } => "ry_gte",
+        _ => return None,
+    };
+    Some(routine)
+}
+
+/// Variable names are passed to the runtime as string values
+fn name_value(name: &str) -> VmValue {
+    VmValue::String(name.to_string())
+}
+
+/// Machine instruction computing an arithmetic or bitwise instruction on
+/// Ints, for the opcodes worth doing without the runtime
+fn int_operation(instruction: &Instruction) -> Option<&'static str> {
+    use Instruction::*;
+    let operation = match instruction {
+        ADD { .. } | ADDK { .. } | ADDC { .. } => "add",
+        SUB { .. } | SUBK { .. } | SUBC { .. } => "sub",
+        MUL { .. } | MULK { .. } | MULC { .. } => "imul",
+        BXOR { .. } | BXORK { .. } | BXORC { .. } => "xor",
+        BAND { .. } | BANDK { .. } | BANDC { .. } => "and",
+        BOR { .. } | BORK { .. } | BORC { .. } => "or",
+        BNOT { .. } | BNOTK { .. } | BNOTC { .. } => "not",
+        NEGATE { .. } | NEGATEK { .. } | NEGATEC { .. } => "neg",
+        _ => return None,
+    };
+    Some(operation)
 }
 
-fn load_immediate(state: &mut AotState, target: &usize, value: &VmValue) -> String {
-    state.write_loadv(*target, get_immediate(value))
+/// Target register of an instruction and its other operands as 32-bit
+/// operands, when they are all known to be Ints
+fn int_operands<B: Backend>(
+    state: &B,
+    types: &TypeInfo,
+    index: usize,
+    instruction: &Instruction,
+) -> Option<(usize, Vec<String>)> {
+    let mut operands = instruction.operands().into_iter();
+    let Some(Operand::Register(target)) = operands.next() else {
+        return None;
+    };
+    let operands = operands
+        .map(|operand| {
+            if types.operand_type(index, &operand) != ValueType::Int {
+                return None;
+            }
+            let value = match operand {
+                Operand::Register(register) => return Some(state.int_location(register)),
+                Operand::Value(value) => value,
+                Operand::Constant(constant) => state.constant_value(constant)?.clone(),
+                Operand::Number(number) => VmValue::Int(number as i32),
+                _ => return None,
+            };
+            match value {
+                VmValue::Int(int) => Some(int.to_string()),
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((target, operands))
 }
 
-fn get_immediate(value: &VmValue) -> String {
-    if let VmValue::Int(value) = value {
-        value.to_string()
+/// Compares registers natively when both hold Ints, or through the runtime
+fn write_compare_jump<B: Backend>(
+    state: &mut B,
+    types: &TypeInfo,
+    index: usize,
+    condition: &str,
+    a: usize,
+    b: usize,
+    address: usize,
+) -> String {
+    let int = |register| types.register_type(index, register) == ValueType::Int;
+    if int(a) && int(b) {
+        state.write_int_compare_jump(condition, a, b, address)
     } else {
-        panic!("only immediates are currently supported for VmValues");
+        state.write_compare_jump(index, condition, a, b, address)
     }
 }
 
-fn vreg_to_reg(vreg: usize) -> String {
-    match vreg {
-        0 => "rax",
-        1 => "rcx",
-        2 => "rdx",
-        _ => panic!("inconvertible vreg: {}", vreg),
+/// Passes the operands after the target to `routine`
+fn write_arithmetic<B: Backend>(
+    state: &mut B,
+    index: usize,
+    routine: &'static str,
+    instruction: &Instruction,
+) -> Result<String, AotErrorKind> {
+    let (target, mut arguments) = target_and_arguments(state, instruction)?;
+    if routine == "ry_add" {
+        // ADD leaves the target as it was when it cannot concatenate either
+        arguments.push(state.location(target));
+    }
+    state.write_runtime_call(index, routine, arguments, Some(target))
+}
+
+/// Target register of an instruction, and its other operands as backend operands
+fn target_and_arguments<B: Backend>(
+    state: &mut B,
+    instruction: &Instruction,
+) -> Result<(usize, Vec<String>), AotErrorKind> {
+    let mut operands = instruction.operands().into_iter();
+    let Some(Operand::Register(target)) = operands.next() else {
+        panic!("instruction without a target register: {}", instruction);
+    };
+    let arguments = operands
+        .map(|operand| operand_location(state, operand))
+        .collect::<Result<_, _>>()?;
+    Ok((target, arguments))
+}
+
+/// Every operand of an instruction as a backend operand
+fn arguments<B: Backend>(
+    state: &mut B,
+    instruction: &Instruction,
+) -> Result<Vec<String>, AotErrorKind> {
+    instruction
+        .operands()
+        .into_iter()
+        .map(|operand| operand_location(state, operand))
+        .collect()
+}
+
+/// Register location, or boxed immediate of a value, constant or index operand
+fn operand_location<B: Backend>(state: &mut B, operand: Operand) -> Result<String, AotErrorKind> {
+    match operand {
+        Operand::Register(register) => Ok(state.location(register)),
+        Operand::Value(value) => state.immediate(&value),
+        Operand::Constant(index) => state.constant(index),
+        Operand::Number(number) => state.immediate(&VmValue::Int(number as i32)),
+        other => panic!("unexpected operand: {:?}", other),
     }
-    .to_string()
 }
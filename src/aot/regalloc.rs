@@ -0,0 +1,186 @@
+//! Linear-scan register allocation of bytecode registers to machine registers.
+//!
+//! Live intervals come from a backward liveness analysis over the bytecode
+//! control flow graph, so values live around a loop stay live for the whole
+//! loop. Intervals are then allocated in order of their start (Poletto and
+//! Sarkar): when every machine register is taken, the interval ending last is
+//! spilled to a stack slot.
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    aot::{
+        flow::{register_use, successors},
+        state::AotTarget,
+    },
+    serde::Program,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Location {
+    Register(&'static str),
+    /// Index of a spill slot in the stack frame
+    Stack(usize),
+}
+
+/// Instructions from `start` to `end` inclusive during which `register` holds a value
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LiveInterval {
+    pub register: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+pub struct Allocation {
+    /// Sorted by start
+    pub intervals: Vec<LiveInterval>,
+    locations: HashMap<usize, Location>,
+    pub spill_slots: usize,
+    /// Callee-saved registers handed out, which the prologue has to preserve
+    pub callee_saved: Vec<&'static str>,
+}
+
+impl Allocation {
+    pub fn location(&self, register: usize) -> Option<Location> {
+        self.locations.get(&register).copied()
+    }
+
+    /// Machine registers holding values that are still needed after the
+    /// instruction at `index`, and so must survive a call made by it
+    pub fn live_across(&self, index: usize) -> Vec<&'static str> {
+        let mut registers: Vec<&'static str> = self
+            .intervals
+            .iter()
+            .filter(|interval| interval.start <= index && interval.end > index)
+            .filter_map(|interval| match self.locations[&interval.register] {
+                Location::Register(name) => Some(name),
+                Location::Stack(_) => None,
+            })
+            .collect();
+        registers.sort();
+        registers.dedup();
+        registers
+    }
+}
+
+pub fn allocate(program: &Program, target: AotTarget) -> Allocation {
+    let intervals = live_intervals(program);
+    let pool: Vec<&'static str> = target
+        .callee_saved_registers()
+        .iter()
+        .chain(target.caller_saved_registers())
+        .copied()
+        .collect();
+
+    let mut locations = HashMap::new();
+    let mut spill_slots = 0;
+    let mut free = pool.clone();
+    // Intervals currently holding a machine register, sorted by end
+    let mut active: Vec<(LiveInterval, &'static str)> = Vec::new();
+
+    for interval in intervals.iter() {
+        active.retain(|(other, register)| {
+            if other.end < interval.start {
+                free.push(register);
+                false
+            } else {
+                true
+            }
+        });
+        free.sort_by_key(|register| pool.iter().position(|r| r == register));
+
+        let register = if free.is_empty() {
+            let (last, register) = *active.last().unwrap();
+            if last.end > interval.end {
+                active.pop();
+                locations.insert(last.register, Location::Stack(spill_slots));
+                spill_slots += 1;
+                Some(register)
+            } else {
+                None
+            }
+        } else {
+            Some(free.remove(0))
+        };
+
+        match register {
+            Some(register) => {
+                locations.insert(interval.register, Location::Register(register));
+                let position = active
+                    .iter()
+                    .position(|(other, _)| other.end > interval.end)
+                    .unwrap_or(active.len());
+                active.insert(position, (*interval, register));
+            }
+            None => {
+                locations.insert(interval.register, Location::Stack(spill_slots));
+                spill_slots += 1;
+            }
+        }
+    }
+
+    let used: HashSet<Location> = locations.values().copied().collect();
+    let callee_saved = target
+        .callee_saved_registers()
+        .iter()
+        .copied()
+        .filter(|register| used.contains(&Location::Register(register)))
+        .collect();
+
+    Allocation {
+        intervals,
+        locations,
+        spill_slots,
+        callee_saved,
+    }
+}
+
+/// Live interval of every register the program mentions, sorted by start
+pub fn live_intervals(program: &Program) -> Vec<LiveInterval> {
+    let count = program.instructions.len();
+    let usage: Vec<_> = program.instructions.iter().map(register_use).collect();
+    let successors: Vec<_> = (0..count).map(|index| successors(program, index)).collect();
+
+    let mut live_in: Vec<HashSet<usize>> = vec![HashSet::new(); count];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..count).rev() {
+            let mut live: HashSet<usize> = successors[index]
+                .iter()
+                .flat_map(|successor| live_in[*successor].iter().copied())
+                .collect();
+            for def in usage[index].defs.iter() {
+                live.remove(def);
+            }
+            live.extend(usage[index].uses.iter().copied());
+
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut ranges: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut extend = |register: usize, index: usize| {
+        let range = ranges.entry(register).or_insert((index, index));
+        range.0 = range.0.min(index);
+        range.1 = range.1.max(index);
+    };
+    for index in 0..count {
+        for register in live_in[index].iter().chain(usage[index].defs.iter()) {
+            extend(*register, index);
+        }
+    }
+
+    let mut intervals: Vec<LiveInterval> = ranges
+        .into_iter()
+        .map(|(register, (start, end))| LiveInterval {
+            register,
+            start,
+            end,
+        })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.register));
+    intervals
+}
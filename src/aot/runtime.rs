@@ -0,0 +1,576 @@
+//! Support routines appended to the generated assembly.
+//!
+//! Every bytecode register holds one NaN-boxed 64-bit value: doubles are
+//! stored as their own bits, and the other types live in the payload of a
+//! quiet NaN, tagged by the top 16 bits. Ints keep their `i32` in the low 32
+//! bits. Only the default NaN produced by the hardware, `0xfff8...`, and the
+//! canonical NaN of constants, `0x7ff8...`, ever occur as floats, so neither
+//! collides with a tag.
+//!
+//! Routines named by call sites follow the C calling convention of the
+//! target, taking boxed values and returning one in `rax`, so a call only
+//! has to preserve the caller-saved registers that are still live. Internal
+//! routines pass values in `rax`, `r11` and `xmm` registers instead.
+//!
+//! Numeric work is done by the routines here. Strings, arrays, objects,
+//! variables and printing are left to a runtime library in C,
+//! [`LIBRARY_SOURCE`], which has to be linked with the assembled output. The
+//! library also implements every routine here for the C backend.
+use std::collections::BTreeSet;
+
+use crate::{
+    aot::state::{AotState, AotTarget},
+    value::VmValue,
+};
+
+pub const INT_TAG: u64 = 0xfff9 << 48;
+pub const BOOL_TAG: u64 = 0xfffa << 48;
+pub const NULL: u64 = 0xfffb << 48;
+/// Tags of pointers to the heap objects of the runtime library
+pub const STRING_TAG: u64 = 0xfffc << 48;
+pub const ARRAY_TAG: u64 = 0xfffd << 48;
+pub const OBJECT_TAG: u64 = 0xfffe << 48;
+pub const CLOSURE_TAG: u64 = 0xffff << 48;
+/// Bits every NaN constant is boxed as
+pub const CANONICAL_NAN: u64 = 0x7ff8 << 48;
+
+/// Source of the runtime library, to be compiled and linked with the
+/// generated assembly
+pub const LIBRARY_SOURCE: &str = include_str!("ryde_runtime.c");
+
+/// Header of the runtime library, which the library and generated C source
+/// include as `ryde_runtime.h`
+pub const HEADER_SOURCE: &str = include_str!("ryde_runtime.h");
+
+/// Functions of the runtime library, called with the C calling convention
+const LIBRARY: &[&str] = &[
+    "ry_fail",
+    "ry_print",
+    "ry_compare",
+    "ry_equal",
+    "ry_concat",
+    "ry_new_array",
+    "ry_new_object",
+    "ry_array_push",
+    "ry_index",
+    "ry_store_index",
+    "ry_len",
+    "ry_and",
+    "ry_or",
+    "ry_not",
+    "ry_null_coalesce",
+    "ry_eq",
+    "ry_neq",
+    "ry_lt",
+    "ry_lte",
+    "ry_gt",
+    "ry_gte",
+    "ry_store",
+    "ry_load",
+    "ry_declare",
+    "ry_store_global",
+    "ry_load_global",
+    "ry_push_scope",
+    "ry_pop_scope",
+    "ry_increment",
+    "ry_get_upvalue",
+    "ry_set_upvalue",
+];
+
+/// Boxes a value that fits in a word, or `None` for heap values
+pub fn box_value(value: &VmValue) -> Option<u64> {
+    match value {
+        VmValue::Int(int) => Some(INT_TAG | *int as u32 as u64),
+        VmValue::Float(float) if float.is_nan() => Some(CANONICAL_NAN),
+        VmValue::Float(float) => Some(float.to_bits()),
+        VmValue::Boolean(boolean) => Some(BOOL_TAG | *boolean as u64),
+        VmValue::Null => Some(NULL),
+        _ => None,
+    }
+}
+
+pub struct Routine {
+    pub name: &'static str,
+    /// Routines it calls or jumps to
+    requires: &'static [&'static str],
+    /// C library functions it calls, written `{name}` in the body
+    externs: &'static [&'static str],
+    /// Labelled `db` lines for the data section
+    data: &'static [&'static str],
+    /// NASM source. `{arg0}`..`{arg3}` are the integer argument registers.
+    /// Lines mentioning `{shadow}` reserve shadow space and are only kept on
+    /// Win64.
+    body: &'static str,
+}
+
+/// Binary arithmetic of `float_binop`: int-int results without a fractional
+/// part become Ints, anything else stays a Float
+macro_rules! float_binop {
+    ($name:literal, $operation:literal) => {
+        Routine {
+            name: $name,
+            requires: &["ry_numbers", "ry_box_number"],
+            externs: &[],
+            data: &[],
+            body: concat!(
+                "  mov rax, {arg0}\n  mov r11, {arg1}\n  call ry_numbers\n  ",
+                $operation,
+                " xmm0, xmm1\n  jmp ry_box_number\n"
+            ),
+        }
+    };
+}
+
+/// Binary bitwise operation on two Ints
+macro_rules! int_binop {
+    ($name:literal, $operation:literal) => {
+        Routine {
+            name: $name,
+            requires: &["ry_ints", "ry_tag_int"],
+            externs: &[],
+            data: &[],
+            body: concat!(
+                "  mov rax, {arg0}\n  mov r11, {arg1}\n  call ry_ints\n",
+                $operation,
+                "  jmp ry_tag_int\n"
+            ),
+        }
+    };
+}
+
+pub const ROUTINES: &[Routine] = &[
+    Routine {
+        name: "ry_type_error",
+        requires: &[],
+        externs: &["ry_fail"],
+        data: &["ry_type_mismatch db \"expected numbers\", 0"],
+        // Reached from any depth of internal calls, so it realigns the stack
+        body: "  and rsp, -16
+  sub rsp, 32                 ; {shadow}
+  lea {arg0}, [rel ry_type_mismatch]
+  call {ry_fail}
+",
+    },
+    Routine {
+        name: "ry_numbers",
+        requires: &["ry_type_error"],
+        externs: &[],
+        data: &[],
+        // Unboxes the numbers in rax and r11 into xmm0 and xmm1. r11 is
+        // left as 1 when both were Ints and 0 otherwise.
+        body: "  xor edx, edx
+  mov rcx, rax
+  shr rcx, 48
+  cmp ecx, 0xfff9
+  ja ry_type_error
+  jb .a_float
+  cvtsi2sd xmm0, eax
+  inc edx
+  jmp .b
+.a_float:
+  movq xmm0, rax
+.b:
+  mov rcx, r11
+  shr rcx, 48
+  cmp ecx, 0xfff9
+  ja ry_type_error
+  jb .b_float
+  cvtsi2sd xmm1, r11d
+  inc edx
+  jmp .done
+.b_float:
+  movq xmm1, r11
+.done:
+  xor r11d, r11d
+  cmp edx, 2
+  sete r11b
+  ret
+",
+    },
+    Routine {
+        name: "ry_ints",
+        requires: &["ry_type_error"],
+        externs: &[],
+        data: &[],
+        // Checks that rax and r11 both hold Ints
+        body: "  mov rcx, rax
+  shr rcx, 48
+  cmp ecx, 0xfff9
+  jne ry_type_error
+  mov rcx, r11
+  shr rcx, 48
+  cmp ecx, 0xfff9
+  jne ry_type_error
+  ret
+",
+    },
+    Routine {
+        name: "ry_tag_int",
+        requires: &[],
+        externs: &[],
+        data: &[],
+        // Boxes the i32 in eax
+        body: "  mov eax, eax
+  mov rcx, 0xfff9000000000000
+  or rax, rcx
+  ret
+",
+    },
+    Routine {
+        name: "ry_to_i32",
+        requires: &[],
+        externs: &[],
+        data: &[],
+        // Converts xmm0 to an i32 in eax like `as i32`: saturating, with NaN as 0
+        body: "  ucomisd xmm0, xmm0
+  jp .nan
+  cvttsd2si rax, xmm0
+  mov rcx, 0x8000000000000000
+  cmp rax, rcx
+  jne .clamp
+  xorpd xmm1, xmm1
+  ucomisd xmm0, xmm1
+  jb .clamp
+  not rax
+.clamp:
+  mov rcx, 0x7fffffff
+  cmp rax, rcx
+  cmovg rax, rcx
+  mov rcx, -0x80000000
+  cmp rax, rcx
+  cmovl rax, rcx
+  ret
+.nan:
+  xor eax, eax
+  ret
+",
+    },
+    Routine {
+        name: "ry_box_int",
+        requires: &["ry_to_i32", "ry_tag_int"],
+        externs: &[],
+        data: &[],
+        // Boxes xmm0 as an Int
+        body: "  call ry_to_i32
+  jmp ry_tag_int
+",
+    },
+    Routine {
+        name: "ry_box_number",
+        requires: &["ry_box_int"],
+        externs: &[],
+        data: &[],
+        // Boxes xmm0 as an Int if r11 is set and it has no fractional part,
+        // and as a Float otherwise. Infinities and NaN have a fractional part
+        // of NaN, and every double beyond 2^63 is integral.
+        body: "  test r11, r11
+  jz .float
+  movq rax, xmm0
+  btr rax, 63
+  mov rcx, 0x7ff0000000000000
+  cmp rax, rcx
+  jae .float
+  cvttsd2si rax, xmm0
+  mov rcx, 0x8000000000000000
+  cmp rax, rcx
+  je ry_box_int
+  cvtsi2sd xmm1, rax
+  ucomisd xmm0, xmm1
+  je ry_box_int
+.float:
+  movq rax, xmm0
+  ret
+",
+    },
+    Routine {
+        name: "ry_add",
+        requires: &["ry_numbers", "ry_box_number"],
+        externs: &["ry_concat"],
+        data: &[],
+        // Anything but two numbers is left to `ry_concat`, which takes the
+        // previous value of the target as its third argument
+        body: "  mov rax, {arg0}
+  mov r11, {arg1}
+  mov r10, rax
+  shr r10, 48
+  cmp r10d, 0xfff9
+  ja {ry_concat}
+  mov r10, r11
+  shr r10, 48
+  cmp r10d, 0xfff9
+  ja {ry_concat}
+  call ry_numbers
+  addsd xmm0, xmm1
+  jmp ry_box_number
+",
+    },
+    float_binop!("ry_sub", "subsd"),
+    float_binop!("ry_mul", "mulsd"),
+    float_binop!("ry_div", "divsd"),
+    Routine {
+        name: "ry_idiv",
+        requires: &["ry_numbers", "ry_box_int"],
+        externs: &["floor"],
+        data: &[],
+        // `float_binop` always makes the result of IDIV and IDIVC an Int
+        body: "  mov rax, {arg0}
+  mov r11, {arg1}
+  call ry_numbers
+  divsd xmm0, xmm1
+  push r11
+  sub rsp, 32                 ; {shadow}
+  call {floor}
+  add rsp, 32                 ; {shadow}
+  pop r11
+  jmp ry_box_int
+",
+    },
+    Routine {
+        name: "ry_idivk",
+        requires: &["ry_numbers", "ry_box_number"],
+        externs: &["floor"],
+        data: &[],
+        // `float_binop` singles out IDIV and IDIVC, so IDIVK boxes its
+        // result like any other operation
+        body: "  mov rax, {arg0}
+  mov r11, {arg1}
+  call ry_numbers
+  divsd xmm0, xmm1
+  push r11
+  sub rsp, 32                 ; {shadow}
+  call {floor}
+  add rsp, 32                 ; {shadow}
+  pop r11
+  jmp ry_box_number
+",
+    },
+    Routine {
+        name: "ry_mod",
+        requires: &["ry_numbers", "ry_box_number"],
+        externs: &["fmod"],
+        data: &[],
+        // `%` on f64 is the C remainder
+        body: "  mov rax, {arg0}
+  mov r11, {arg1}
+  call ry_numbers
+  push r11
+  sub rsp, 32                 ; {shadow}
+  call {fmod}
+  add rsp, 32                 ; {shadow}
+  pop r11
+  jmp ry_box_number
+",
+    },
+    Routine {
+        name: "ry_pow",
+        requires: &["ry_numbers", "ry_to_i32", "ry_box_number"],
+        externs: &["pow"],
+        data: &[],
+        // Integral exponents are raised by squaring like Rust's `powi`, so
+        // rounding matches the VM; the rest go to the C `pow`.
+        body: "  mov rax, {arg0}
+  mov r11, {arg1}
+  call ry_numbers
+  movq rax, xmm1
+  btr rax, 63
+  mov rcx, 0x7ff0000000000000
+  cmp rax, rcx
+  jae .powf
+  cvttsd2si rax, xmm1
+  mov rcx, 0x8000000000000000
+  cmp rax, rcx
+  je .powi
+  cvtsi2sd xmm2, rax
+  ucomisd xmm1, xmm2
+  jne .powf
+.powi:
+  movapd xmm3, xmm0
+  movapd xmm0, xmm1
+  call ry_to_i32
+  mov edx, eax
+  test eax, eax
+  jns .square
+  neg edx
+.square:
+  mov rcx, 0x3ff0000000000000
+  movq xmm0, rcx
+.loop:
+  test edx, 1
+  jz .next
+  mulsd xmm0, xmm3
+.next:
+  shr edx, 1
+  jz .sign
+  mulsd xmm3, xmm3
+  jmp .loop
+.sign:
+  test eax, eax
+  jns .box
+  movq xmm1, rcx
+  divsd xmm1, xmm0
+  movapd xmm0, xmm1
+.box:
+  jmp ry_box_number
+.powf:
+  push r11
+  sub rsp, 32                 ; {shadow}
+  call {pow}
+  add rsp, 32                 ; {shadow}
+  pop r11
+  jmp ry_box_number
+",
+    },
+    int_binop!("ry_bxor", "  xor eax, r11d\n"),
+    int_binop!("ry_band", "  and eax, r11d\n"),
+    int_binop!("ry_bor", "  or eax, r11d\n"),
+    // Shift counts are masked to 5 bits, as in Rust release builds
+    int_binop!("ry_blsh", "  mov ecx, r11d\n  shl eax, cl\n"),
+    int_binop!("ry_brsh", "  mov ecx, r11d\n  shr eax, cl\n"),
+    int_binop!("ry_barsh", "  mov ecx, r11d\n  sar eax, cl\n"),
+    Routine {
+        name: "ry_bnot",
+        requires: &["ry_ints", "ry_tag_int"],
+        externs: &[],
+        data: &[],
+        body: "  mov rax, {arg0}
+  mov r11, rax
+  call ry_ints
+  not eax
+  jmp ry_tag_int
+",
+    },
+    Routine {
+        name: "ry_negate",
+        requires: &["ry_type_error", "ry_tag_int"],
+        externs: &[],
+        data: &[],
+        // Ints are negated through f64 by the VM, so i32::MIN saturates
+        body: "  mov rax, {arg0}
+  mov rcx, rax
+  shr rcx, 48
+  cmp ecx, 0xfff9
+  ja ry_type_error
+  jb .float
+  neg eax
+  mov ecx, 0x7fffffff
+  cmovo eax, ecx
+  jmp ry_tag_int
+.float:
+  btc rax, 63
+  ret
+",
+    },
+];
+
+fn routine(name: &str) -> &'static Routine {
+    ROUTINES
+        .iter()
+        .find(|routine| routine.name == name)
+        .unwrap_or_else(|| panic!("unknown runtime routine: {}", name))
+}
+
+/// Whether `name` is defined by the C runtime library rather than appended
+/// to the assembly
+pub fn is_library_function(name: &str) -> bool {
+    LIBRARY.contains(&name)
+}
+
+/// Appends the routines called by the program and those they depend on,
+/// along with its string constants
+pub fn write_routines(state: &mut AotState) {
+    let mut needed: BTreeSet<&'static str> = BTreeSet::new();
+    let mut externs: BTreeSet<&'static str> = BTreeSet::new();
+    let mut pending: Vec<&'static str> = state.routines.iter().copied().collect();
+    while let Some(name) = pending.pop() {
+        if is_library_function(name) {
+            externs.insert(name);
+        } else if needed.insert(name) {
+            pending.extend(routine(name).requires);
+        }
+    }
+    // Keep the table's order, so output does not depend on hashing
+    let routines: Vec<&Routine> = ROUTINES
+        .iter()
+        .filter(|routine| needed.contains(routine.name))
+        .collect();
+    externs.extend(
+        routines
+            .iter()
+            .flat_map(|routine| routine.externs.iter().copied()),
+    );
+    let mut data: Vec<String> = routines
+        .iter()
+        .flat_map(|routine| routine.data.iter().map(|line| line.to_string()))
+        .collect();
+    for (i, string) in state.strings.iter().enumerate() {
+        data.extend(string_constant(i, string));
+    }
+    if externs.is_empty() && data.is_empty() && routines.is_empty() {
+        return;
+    }
+
+    let target = state.target();
+    let mut text = String::from("\n");
+    for name in externs.iter() {
+        text.push_str(&format!("extern {}\n", name));
+    }
+    if !data.is_empty() {
+        text.push_str("section .data\n");
+        for line in data {
+            text.push_str(&line);
+            text.push('\n');
+        }
+    }
+    if !routines.is_empty() {
+        text.push_str("section .text\n");
+    }
+    for routine in routines {
+        text.push_str(&format!("{}:\n", routine.name));
+        text.push_str(&instantiate(routine, target, state));
+    }
+    state.write(text);
+}
+
+/// Operand holding the boxed string constant at `index` of [`AotState::strings`]
+pub fn string_operand(index: usize) -> String {
+    format!("qword [rel ry_constant_{}]", index)
+}
+
+/// A static string laid out like the runtime's `RyString`, and its boxed pointer
+fn string_constant(index: usize, string: &str) -> Vec<String> {
+    let bytes: Vec<String> = string
+        .bytes()
+        .chain(std::iter::once(0))
+        .map(|byte| byte.to_string())
+        .collect();
+    vec![
+        "align 8".to_string(),
+        format!("ry_string_{} dq {}", index, string.len()),
+        format!("ry_string_{}_bytes db {}", index, bytes.join(", ")),
+        format!(
+            "ry_constant_{0} dq ry_string_{0} + 0x{1:x}",
+            index, STRING_TAG
+        ),
+    ]
+}
+
+fn instantiate(routine: &Routine, target: AotTarget, state: &AotState) -> String {
+    let mut body: String = routine
+        .body
+        .lines()
+        .filter(|line| target.is_win64() || !line.contains("{shadow}"))
+        .map(|line| {
+            format!(
+                "{}\n",
+                line.replace("; {shadow}", "; shadow space").trim_end()
+            )
+        })
+        .collect();
+    for (i, register) in target.argument_registers().iter().enumerate() {
+        body = body.replace(&format!("{{arg{}}}", i), register);
+    }
+    for name in routine.externs {
+        body = body.replace(&format!("{{{}}}", name), &state.extern_call(name));
+    }
+    body
+}
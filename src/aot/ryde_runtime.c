@@ -0,0 +1,1335 @@
+/*
+ * Runtime library linked into AOT-compiled ryde programs.
+ *
+ * Values are NaN-boxed 64-bit words, as described in src/aot/runtime.rs.
+ * Strings, arrays, objects and closures live on the heap and are never
+ * freed: compiled programs are short-lived, like the VM runs they stand in
+ * for.
+ *
+ * Build it next to the generated assembly or C source, with ryde_runtime.h
+ * in the same directory:
+ *     cc out.o ryde_runtime.c -o out -lm
+ *     cc out.c ryde_runtime.c -o out -lm
+ */
+#include <inttypes.h>
+#include <math.h>
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "ryde_runtime.h"
+
+#define RY_CANONICAL_NAN 0x7ff8000000000000ULL
+
+typedef struct {
+    uint64_t length;
+    /* NUL-terminated for printing, though strings may contain NUL */
+    char bytes[];
+} RyString;
+
+typedef struct {
+    uint64_t length;
+    uint64_t capacity;
+    ry_value *items;
+} RyArray;
+
+typedef struct {
+    ry_value key;
+    ry_value value;
+} RyEntry;
+
+/* Entries are kept in insertion order, with an open-addressing index of
+ * entry positions plus one (0 marks a free slot) */
+typedef struct {
+    uint64_t length;
+    uint64_t capacity;
+    RyEntry *entries;
+    uint64_t index_size;
+    uint64_t *index;
+} RyObject;
+
+/* Function value over the variables it captured. Every CLOSURE creates a
+ * new one, so closures are equal only to themselves, like the VM's
+ * `Rc::ptr_eq` on upvalues. */
+typedef struct {
+    uint64_t function;
+    uint64_t upvalue_count;
+    RyCell *upvalues[];
+} RyClosure;
+
+typedef struct {
+    char *bytes;
+    size_t length;
+    size_t capacity;
+} RyBuffer;
+
+void ry_fail(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "VM error: %s\n", message);
+    exit(1);
+}
+
+static void ry_failf(const char *format, ...) {
+    char message[512];
+    va_list arguments;
+    va_start(arguments, format);
+    vsnprintf(message, sizeof message, format, arguments);
+    va_end(arguments);
+    ry_fail(message);
+}
+
+static void *ry_alloc(size_t size) {
+    void *memory = malloc(size);
+    if (memory == NULL) {
+        ry_fail("out of memory");
+    }
+    return memory;
+}
+
+static int ry_is_number(ry_value value) {
+    return RY_TAG(value) <= RY_INT;
+}
+
+static double ry_number(ry_value value) {
+    if (RY_TAG(value) == RY_INT) {
+        return (double)ry_int(value);
+    }
+    double number;
+    memcpy(&number, &value, sizeof number);
+    return number;
+}
+
+static void *ry_pointer(ry_value value) {
+    return (void *)(uintptr_t)RY_PAYLOAD(value);
+}
+
+static ry_value ry_box_pointer(int tag, const void *pointer) {
+    return RY_BOX(tag, (uint64_t)(uintptr_t)pointer);
+}
+
+static ry_value ry_new_string(const char *bytes, uint64_t length) {
+    RyString *string = ry_alloc(sizeof(RyString) + length + 1);
+    string->length = length;
+    memcpy(string->bytes, bytes, length);
+    string->bytes[length] = '\0';
+    return ry_box_pointer(RY_STRING, string);
+}
+
+static int ry_string_equal(RyString *a, RyString *b) {
+    return a->length == b->length && memcmp(a->bytes, b->bytes, a->length) == 0;
+}
+
+/* Buffer */
+
+static void ry_write(RyBuffer *buffer, const char *bytes, size_t length) {
+    if (buffer->length + length > buffer->capacity) {
+        size_t capacity = buffer->capacity ? buffer->capacity : 64;
+        while (capacity < buffer->length + length) {
+            capacity *= 2;
+        }
+        char *grown = realloc(buffer->bytes, capacity);
+        if (grown == NULL) {
+            ry_fail("out of memory");
+        }
+        buffer->bytes = grown;
+        buffer->capacity = capacity;
+    }
+    memcpy(buffer->bytes + buffer->length, bytes, length);
+    buffer->length += length;
+}
+
+static void ry_write_str(RyBuffer *buffer, const char *text) {
+    ry_write(buffer, text, strlen(text));
+}
+
+static void ry_write_tab(RyBuffer *buffer, size_t indent) {
+    for (size_t i = 0; i < indent; i++) {
+        ry_write_str(buffer, "  ");
+    }
+}
+
+/* Shortest decimal digits that read back as the positive, finite `value`,
+ * and the exponent of the first one */
+static long ry_shortest_digits(double value, char digits[20], size_t *count) {
+    char scientific[32];
+    for (int precision = 0; precision < 17; precision++) {
+        snprintf(scientific, sizeof scientific, "%.*e", precision, value);
+        if (strtod(scientific, NULL) == value) {
+            break;
+        }
+    }
+
+    /* scientific is "d.ddde[+-]x": collect the digits and the exponent */
+    *count = 0;
+    char *cursor = scientific;
+    for (; *cursor != 'e'; cursor++) {
+        if (*cursor != '.') {
+            digits[(*count)++] = *cursor;
+        }
+    }
+    return strtol(cursor + 1, NULL, 10);
+}
+
+/* Writes a double like Rust's `Display`: the shortest digits that read back
+ * as the same double, without an exponent. `Debug` also keeps a fractional
+ * part, and switches to an exponent for very large and small values. */
+static void ry_write_float(RyBuffer *buffer, double value, int debug) {
+    if (isnan(value)) {
+        ry_write_str(buffer, "NaN");
+        return;
+    }
+    if (isinf(value)) {
+        ry_write_str(buffer, value < 0 ? "-inf" : "inf");
+        return;
+    }
+    if (signbit(value)) {
+        ry_write_str(buffer, "-");
+        value = -value;
+    }
+    if (value == 0) {
+        ry_write_str(buffer, debug ? "0.0" : "0");
+        return;
+    }
+
+    char digits[20];
+    size_t count;
+    long exponent = ry_shortest_digits(value, digits, &count);
+
+    if (debug && (value >= 1e16 || value < 1e-4)) {
+        char text[8];
+        ry_write(buffer, digits, 1);
+        if (count > 1) {
+            ry_write_str(buffer, ".");
+            ry_write(buffer, digits + 1, count - 1);
+        }
+        snprintf(text, sizeof text, "e%ld", exponent);
+        ry_write_str(buffer, text);
+    } else if (exponent < 0) {
+        ry_write_str(buffer, "0.");
+        for (long i = 0; i < -exponent - 1; i++) {
+            ry_write_str(buffer, "0");
+        }
+        ry_write(buffer, digits, count);
+    } else if ((size_t)exponent + 1 >= count) {
+        ry_write(buffer, digits, count);
+        for (size_t i = count; i < (size_t)exponent + 1; i++) {
+            ry_write_str(buffer, "0");
+        }
+        if (debug) {
+            ry_write_str(buffer, ".0");
+        }
+    } else {
+        ry_write(buffer, digits, exponent + 1);
+        ry_write_str(buffer, ".");
+        ry_write(buffer, digits + exponent + 1, count - exponent - 1);
+    }
+}
+
+/* Mirrors `VmValue::inspect`, including its trailing spaces */
+static void ry_inspect(RyBuffer *buffer, ry_value value, size_t indent) {
+    char text[32];
+    switch (RY_TAG(value)) {
+    case RY_INT:
+        snprintf(text, sizeof text, "%" PRId32, ry_int(value));
+        ry_write_str(buffer, text);
+        break;
+    case RY_BOOL:
+        ry_write_str(buffer, RY_PAYLOAD(value) ? "true" : "false");
+        break;
+    case RY_NULL:
+        ry_write_str(buffer, "null");
+        break;
+    case RY_STRING: {
+        RyString *string = ry_pointer(value);
+        ry_write_str(buffer, "\"");
+        ry_write(buffer, string->bytes, string->length);
+        ry_write_str(buffer, "\"");
+        break;
+    }
+    case RY_ARRAY: {
+        RyArray *array = ry_pointer(value);
+        int is_long = array->length >= 3;
+        ry_write_str(buffer, "[");
+        if (is_long) {
+            ry_write_str(buffer, "\n");
+            ry_write_tab(buffer, indent);
+        }
+        for (uint64_t i = 0; i < array->length; i++) {
+            ry_inspect(buffer, array->items[i], is_long ? indent + 1 : 1);
+            if (i < array->length - 1) {
+                ry_write_str(buffer, ", ");
+                if (is_long) {
+                    ry_write_str(buffer, "\n");
+                    ry_write_tab(buffer, indent);
+                }
+            }
+        }
+        if (is_long) {
+            ry_write_str(buffer, "\n");
+            ry_write_tab(buffer, indent - 1);
+        }
+        ry_write_str(buffer, "]");
+        break;
+    }
+    case RY_OBJECT: {
+        RyObject *object = ry_pointer(value);
+        int is_long = object->length >= 3;
+        ry_write_str(buffer, "{");
+        if (is_long) {
+            ry_write_str(buffer, "\n");
+            ry_write_tab(buffer, indent);
+        } else if (object->length > 0) {
+            ry_write_str(buffer, " ");
+        }
+        for (uint64_t i = 0; i < object->length; i++) {
+            ry_write_str(buffer, "[");
+            ry_inspect(buffer, object->entries[i].key, 1);
+            ry_write_str(buffer, "]: ");
+            ry_inspect(buffer, object->entries[i].value, is_long ? indent + 1 : 1);
+            if (i < object->length - 1) {
+                ry_write_str(buffer, ", ");
+                if (is_long) {
+                    ry_write_str(buffer, "\n");
+                    ry_write_tab(buffer, indent);
+                }
+            }
+        }
+        if (is_long) {
+            ry_write_str(buffer, "\n");
+            ry_write_tab(buffer, indent - 1);
+        } else if (object->length > 0) {
+            ry_write_str(buffer, " ");
+        }
+        ry_write_str(buffer, "}");
+        break;
+    }
+    case RY_CLOSURE:
+        snprintf(text, sizeof text, "<function #%" PRIu64 ">",
+                 ((RyClosure *)ry_pointer(value))->function);
+        ry_write_str(buffer, text);
+        break;
+    default:
+        ry_write_float(buffer, ry_number(value), 0);
+        break;
+    }
+}
+
+/* Strings are printed without quotes at the top level, like `Vm::print_value` */
+void ry_print(ry_value value) {
+    RyBuffer buffer = {0};
+    if (RY_TAG(value) == RY_STRING) {
+        RyString *string = ry_pointer(value);
+        ry_write(&buffer, string->bytes, string->length);
+    } else {
+        ry_inspect(&buffer, value, 1);
+    }
+    ry_write_str(&buffer, "\n");
+    fwrite(buffer.bytes, 1, buffer.length, stdout);
+    free(buffer.bytes);
+}
+
+/* Writes `value` like its derived `Debug`, as error messages of the VM do */
+static void ry_debug(RyBuffer *buffer, ry_value value) {
+    char text[32];
+    switch (RY_TAG(value)) {
+    case RY_INT:
+        snprintf(text, sizeof text, "Int(%" PRId32 ")", ry_int(value));
+        ry_write_str(buffer, text);
+        break;
+    case RY_BOOL:
+        ry_write_str(buffer, RY_PAYLOAD(value) ? "Boolean(true)" : "Boolean(false)");
+        break;
+    case RY_NULL:
+        ry_write_str(buffer, "Null");
+        break;
+    case RY_STRING: {
+        RyString *string = ry_pointer(value);
+        ry_write_str(buffer, "String(\"");
+        for (uint64_t i = 0; i < string->length; i++) {
+            unsigned char byte = (unsigned char)string->bytes[i];
+            switch (byte) {
+            case '"':
+                ry_write_str(buffer, "\\\"");
+                break;
+            case '\\':
+                ry_write_str(buffer, "\\\\");
+                break;
+            case '\n':
+                ry_write_str(buffer, "\\n");
+                break;
+            case '\r':
+                ry_write_str(buffer, "\\r");
+                break;
+            case '\t':
+                ry_write_str(buffer, "\\t");
+                break;
+            case '\0':
+                ry_write_str(buffer, "\\0");
+                break;
+            default:
+                if (byte < 0x20 || byte == 0x7f) {
+                    snprintf(text, sizeof text, "\\u{%x}", byte);
+                    ry_write_str(buffer, text);
+                } else {
+                    ry_write(buffer, (const char *)&byte, 1);
+                }
+            }
+        }
+        ry_write_str(buffer, "\")");
+        break;
+    }
+    case RY_ARRAY: {
+        RyArray *array = ry_pointer(value);
+        ry_write_str(buffer, "DynamicArray(DynamicArray(RefCell { value: [");
+        for (uint64_t i = 0; i < array->length; i++) {
+            ry_write_str(buffer, i > 0 ? ", " : "");
+            ry_debug(buffer, array->items[i]);
+        }
+        ry_write_str(buffer, "] }))");
+        break;
+    }
+    case RY_OBJECT: {
+        RyObject *object = ry_pointer(value);
+        ry_write_str(buffer, "Object(Object(RefCell { value: {");
+        for (uint64_t i = 0; i < object->length; i++) {
+            ry_write_str(buffer, i > 0 ? ", " : "");
+            ry_debug(buffer, object->entries[i].key);
+            ry_write_str(buffer, ": ");
+            ry_debug(buffer, object->entries[i].value);
+        }
+        ry_write_str(buffer, "} }))");
+        break;
+    }
+    case RY_CLOSURE: {
+        RyClosure *closure = ry_pointer(value);
+        snprintf(text, sizeof text, "%" PRIu64, closure->function);
+        ry_write_str(buffer, "Closure(Closure { function: ");
+        ry_write_str(buffer, text);
+        ry_write_str(buffer, ", upvalues: [");
+        for (uint64_t i = 0; i < closure->upvalue_count; i++) {
+            ry_write_str(buffer, i > 0 ? ", RefCell { value: " : "RefCell { value: ");
+            ry_debug(buffer, closure->upvalues[i]->value);
+            ry_write_str(buffer, " }");
+        }
+        ry_write_str(buffer, "] })");
+        break;
+    }
+    default:
+        ry_write_str(buffer, "Float(");
+        ry_write_float(buffer, ry_number(value), 1);
+        ry_write_str(buffer, ")");
+        break;
+    }
+}
+
+/* Fails with a message quoting `value`, written with `Debug` or `Display` */
+static void ry_fail_value(const char *prefix, ry_value value, int debug) {
+    RyBuffer buffer = {0};
+    ry_write_str(&buffer, prefix);
+    ry_write_str(&buffer, "'");
+    if (debug) {
+        ry_debug(&buffer, value);
+    } else {
+        ry_inspect(&buffer, value, 1);
+    }
+    /* NUL-terminated for ry_fail */
+    ry_write(&buffer, "'", 2);
+    ry_fail(buffer.bytes);
+}
+
+/* Fails like `VmError::OperandTypeMismatch` */
+static void ry_type_mismatch(const char *expected, ry_value actual) {
+    char prefix[64];
+    snprintf(prefix, sizeof prefix, "Expected type '%s', got ", expected);
+    ry_fail_value(prefix, actual, 1);
+}
+
+/* Comparison */
+
+/* -1, 0 or 1 like `VmValue::partial_cmp`, or 2 when there is no ordering */
+int64_t ry_compare(ry_value a, ry_value b) {
+    if (RY_TAG(a) == RY_INT && RY_TAG(b) == RY_INT) {
+        return (ry_int(a) > ry_int(b)) - (ry_int(a) < ry_int(b));
+    }
+    if (ry_is_number(a) && ry_is_number(b)) {
+        double x = ry_number(a);
+        double y = ry_number(b);
+        if (x < y) {
+            return -1;
+        }
+        if (x > y) {
+            return 1;
+        }
+        return x == y ? 0 : 2;
+    }
+    if (RY_TAG(a) == RY_STRING && RY_TAG(b) == RY_STRING) {
+        RyString *x = ry_pointer(a);
+        RyString *y = ry_pointer(b);
+        size_t shorter = x->length < y->length ? x->length : y->length;
+        int order = memcmp(x->bytes, y->bytes, shorter);
+        if (order == 0) {
+            return (x->length > y->length) - (x->length < y->length);
+        }
+        return order < 0 ? -1 : 1;
+    }
+    return 2;
+}
+
+/* `VmValue::eq`: values of other types are equal when their kinds match, so
+ * any two arrays or two objects compare equal, but closures only to
+ * themselves */
+int64_t ry_equal(ry_value a, ry_value b) {
+    if (ry_is_number(a) && ry_is_number(b)) {
+        return ry_compare(a, b) == 0;
+    }
+    if (RY_TAG(a) != RY_TAG(b)) {
+        return 0;
+    }
+    switch (RY_TAG(a)) {
+    case RY_BOOL:
+        return a == b;
+    case RY_STRING:
+        return ry_string_equal(ry_pointer(a), ry_pointer(b));
+    case RY_CLOSURE:
+        return a == b;
+    default:
+        return 1;
+    }
+}
+
+/* Arithmetic on non-numbers: ADD concatenates two strings and otherwise
+ * leaves its target as it was */
+ry_value ry_concat(ry_value a, ry_value b, ry_value previous) {
+    if (RY_TAG(a) != RY_STRING || RY_TAG(b) != RY_STRING) {
+        return previous;
+    }
+    RyString *x = ry_pointer(a);
+    RyString *y = ry_pointer(b);
+    ry_value result = ry_new_string(x->bytes, x->length + y->length);
+    RyString *string = ry_pointer(result);
+    memcpy(string->bytes + x->length, y->bytes, y->length);
+    return result;
+}
+
+/* Arrays */
+
+ry_value ry_new_array(void) {
+    RyArray *array = ry_alloc(sizeof(RyArray));
+    array->length = 0;
+    array->capacity = 0;
+    array->items = NULL;
+    return ry_box_pointer(RY_ARRAY, array);
+}
+
+static void ry_array_reserve(RyArray *array, uint64_t length) {
+    if (length <= array->capacity) {
+        return;
+    }
+    uint64_t capacity = array->capacity ? array->capacity : 4;
+    while (capacity < length) {
+        capacity *= 2;
+    }
+    ry_value *items = realloc(array->items, capacity * sizeof(ry_value));
+    if (items == NULL) {
+        ry_fail("out of memory");
+    }
+    array->items = items;
+    array->capacity = capacity;
+}
+
+void ry_array_push(ry_value target, ry_value value) {
+    if (RY_TAG(target) != RY_ARRAY) {
+        ry_fail_value("Expected type 'Array', got ", target, 0);
+    }
+    RyArray *array = ry_pointer(target);
+    ry_array_reserve(array, array->length + 1);
+    array->items[array->length++] = value;
+}
+
+/* Objects */
+
+static uint64_t ry_hash(ry_value key) {
+    uint64_t hash = 0xcbf29ce484222325ULL;
+    if (RY_TAG(key) == RY_STRING) {
+        RyString *string = ry_pointer(key);
+        for (uint64_t i = 0; i < string->length; i++) {
+            hash = (hash ^ (unsigned char)string->bytes[i]) * 0x100000001b3ULL;
+        }
+        return hash;
+    }
+    for (int i = 0; i < 8; i++) {
+        hash = (hash ^ ((key >> (8 * i)) & 0xff)) * 0x100000001b3ULL;
+    }
+    return hash;
+}
+
+/* Keys match like in the VM's `HashMap`: by value for strings, and by bits
+ * for everything else, so Int 1 and Float 1 are different keys */
+static int ry_same_key(ry_value a, ry_value b) {
+    if (a == b) {
+        return 1;
+    }
+    return RY_TAG(a) == RY_STRING && RY_TAG(b) == RY_STRING &&
+           ry_string_equal(ry_pointer(a), ry_pointer(b));
+}
+
+ry_value ry_new_object(void) {
+    RyObject *object = ry_alloc(sizeof(RyObject));
+    memset(object, 0, sizeof(RyObject));
+    return ry_box_pointer(RY_OBJECT, object);
+}
+
+/* Slot of the index holding `key`, or the free slot it would go in */
+static uint64_t ry_object_slot(RyObject *object, ry_value key) {
+    uint64_t mask = object->index_size - 1;
+    uint64_t slot = ry_hash(key) & mask;
+    while (object->index[slot] != 0 &&
+           !ry_same_key(object->entries[object->index[slot] - 1].key, key)) {
+        slot = (slot + 1) & mask;
+    }
+    return slot;
+}
+
+static void ry_object_grow(RyObject *object) {
+    uint64_t size = object->index_size ? object->index_size * 2 : 8;
+    free(object->index);
+    object->index = ry_alloc(size * sizeof(uint64_t));
+    memset(object->index, 0, size * sizeof(uint64_t));
+    object->index_size = size;
+    for (uint64_t i = 0; i < object->length; i++) {
+        object->index[ry_object_slot(object, object->entries[i].key)] = i + 1;
+    }
+
+    object->capacity = size / 2;
+    RyEntry *entries = realloc(object->entries, object->capacity * sizeof(RyEntry));
+    if (entries == NULL) {
+        ry_fail("out of memory");
+    }
+    object->entries = entries;
+}
+
+static ry_value ry_object_get(RyObject *object, ry_value key) {
+    if (object->length == 0) {
+        return RY_NULL_VALUE;
+    }
+    uint64_t position = object->index[ry_object_slot(object, key)];
+    return position ? object->entries[position - 1].value : RY_NULL_VALUE;
+}
+
+static void ry_object_set(RyObject *object, ry_value key, ry_value value) {
+    if (object->length + 1 > object->capacity) {
+        ry_object_grow(object);
+    }
+    uint64_t slot = ry_object_slot(object, key);
+    if (object->index[slot] != 0) {
+        object->entries[object->index[slot] - 1].value = value;
+        return;
+    }
+    object->entries[object->length] = (RyEntry){key, value};
+    object->index[slot] = ++object->length;
+}
+
+/* Indexing */
+
+/* The character at `index` of a UTF-8 string, like `str::chars().nth` */
+static ry_value ry_string_char(RyString *string, uint64_t index) {
+    uint64_t seen = 0;
+    for (uint64_t start = 0; start < string->length; seen++) {
+        uint64_t end = start + 1;
+        while (end < string->length && (string->bytes[end] & 0xc0) == 0x80) {
+            end++;
+        }
+        if (seen == index) {
+            return ry_new_string(string->bytes + start, end - start);
+        }
+        start = end;
+    }
+    return RY_NULL_VALUE;
+}
+
+ry_value ry_index(ry_value object, ry_value index) {
+    switch (RY_TAG(object)) {
+    case RY_ARRAY: {
+        if (RY_TAG(index) != RY_INT) {
+            ry_fail_value("Invalid index type, got ", index, 1);
+        }
+        RyArray *array = ry_pointer(object);
+        /* negative indices wrap to huge ones through `as usize` */
+        uint64_t i = (uint64_t)(int64_t)ry_int(index);
+        return i < array->length ? array->items[i] : RY_NULL_VALUE;
+    }
+    case RY_OBJECT:
+        return ry_object_get(ry_pointer(object), index);
+    case RY_STRING:
+        if (RY_TAG(index) != RY_INT) {
+            ry_fail_value("Invalid index type, got ", index, 1);
+        }
+        return ry_string_char(ry_pointer(object), (uint64_t)(int64_t)ry_int(index));
+    default:
+        return RY_NULL_VALUE;
+    }
+}
+
+/* Writing past the end of an array pads it with nulls */
+void ry_store_index(ry_value object, ry_value index, ry_value value) {
+    switch (RY_TAG(object)) {
+    case RY_ARRAY: {
+        /* negative indices are rejected like other non-index values */
+        if (RY_TAG(index) != RY_INT || ry_int(index) < 0) {
+            ry_fail_value("Invalid index type, got ", index, 1);
+        }
+        RyArray *array = ry_pointer(object);
+        uint64_t i = (uint64_t)ry_int(index);
+        if (i >= array->length) {
+            ry_array_reserve(array, i + 1);
+            while (array->length <= i) {
+                array->items[array->length++] = RY_NULL_VALUE;
+            }
+        }
+        array->items[i] = value;
+        break;
+    }
+    case RY_OBJECT:
+        ry_object_set(ry_pointer(object), index, value);
+        break;
+    default:
+        break;
+    }
+}
+
+/* Byte length of a string or element count of an array; LEN leaves its
+ * target as it was for anything else */
+ry_value ry_len(ry_value value, ry_value previous) {
+    switch (RY_TAG(value)) {
+    case RY_ARRAY:
+        return ry_box_int((int32_t)((RyArray *)ry_pointer(value))->length);
+    case RY_STRING:
+        return ry_box_int((int32_t)((RyString *)ry_pointer(value))->length);
+    default:
+        return previous;
+    }
+}
+
+/* Numbers */
+
+/* Converts like Rust's `as i32`: saturating, with NaN as 0 */
+static int32_t ry_to_i32(double value) {
+    if (isnan(value)) {
+        return 0;
+    }
+    if (value >= 2147483647.0) {
+        return INT32_MAX;
+    }
+    return value <= -2147483648.0 ? INT32_MIN : (int32_t)value;
+}
+
+static ry_value ry_box_float(double value) {
+    if (isnan(value)) {
+        return RY_CANONICAL_NAN;
+    }
+    ry_value bits;
+    memcpy(&bits, &value, sizeof bits);
+    return bits;
+}
+
+/* Results of two Ints without a fractional part become Ints, like in
+ * `float_binop`. Infinities and NaN have a fractional part of NaN. */
+static ry_value ry_box_number(double value, int both_int) {
+    if (both_int && isfinite(value) && value == trunc(value)) {
+        return ry_box_int(ry_to_i32(value));
+    }
+    return ry_box_float(value);
+}
+
+/* Unboxes two numbers, telling whether both were Ints */
+static int ry_numbers(ry_value a, ry_value b, double *x, double *y) {
+    if (!ry_is_number(a)) {
+        ry_type_mismatch("number", a);
+    }
+    if (!ry_is_number(b)) {
+        ry_type_mismatch("number", b);
+    }
+    *x = ry_number(a);
+    *y = ry_number(b);
+    return RY_TAG(a) == RY_INT && RY_TAG(b) == RY_INT;
+}
+
+/* Anything but two numbers is left to `ry_concat` */
+ry_value ry_add(ry_value a, ry_value b, ry_value previous) {
+    if (!ry_is_number(a) || !ry_is_number(b)) {
+        return ry_concat(a, b, previous);
+    }
+    double x, y;
+    int both_int = ry_numbers(a, b, &x, &y);
+    return ry_box_number(x + y, both_int);
+}
+
+ry_value ry_sub(ry_value a, ry_value b) {
+    double x, y;
+    int both_int = ry_numbers(a, b, &x, &y);
+    return ry_box_number(x - y, both_int);
+}
+
+ry_value ry_mul(ry_value a, ry_value b) {
+    double x, y;
+    int both_int = ry_numbers(a, b, &x, &y);
+    return ry_box_number(x * y, both_int);
+}
+
+ry_value ry_div(ry_value a, ry_value b) {
+    double x, y;
+    int both_int = ry_numbers(a, b, &x, &y);
+    return ry_box_number(x / y, both_int);
+}
+
+/* `float_binop` always makes the result of IDIV and IDIVC an Int */
+ry_value ry_idiv(ry_value a, ry_value b) {
+    double x, y;
+    ry_numbers(a, b, &x, &y);
+    return ry_box_int(ry_to_i32(floor(x / y)));
+}
+
+/* `float_binop` singles out IDIV and IDIVC, so IDIVK boxes its result like
+ * any other operation */
+ry_value ry_idivk(ry_value a, ry_value b) {
+    double x, y;
+    int both_int = ry_numbers(a, b, &x, &y);
+    return ry_box_number(floor(x / y), both_int);
+}
+
+/* `%` on f64 is the C remainder */
+ry_value ry_mod(ry_value a, ry_value b) {
+    double x, y;
+    int both_int = ry_numbers(a, b, &x, &y);
+    return ry_box_number(fmod(x, y), both_int);
+}
+
+/* Rust's `powi`, raising by squaring like compiler-rt's `__powidf2` so
+ * rounding matches the VM */
+static double ry_powi(double base, int32_t exponent) {
+    int reciprocal = exponent < 0;
+    double result = 1;
+    for (;;) {
+        if (exponent & 1) {
+            result *= base;
+        }
+        exponent /= 2;
+        if (exponent == 0) {
+            break;
+        }
+        base *= base;
+    }
+    return reciprocal ? 1 / result : result;
+}
+
+ry_value ry_pow(ry_value a, ry_value b) {
+    double x, y;
+    int both_int = ry_numbers(a, b, &x, &y);
+    double result = isfinite(y) && y == trunc(y) ? ry_powi(x, ry_to_i32(y)) : pow(x, y);
+    return ry_box_number(result, both_int);
+}
+
+static void ry_ints(ry_value a, ry_value b) {
+    if (RY_TAG(a) != RY_INT) {
+        ry_type_mismatch("number", a);
+    }
+    if (RY_TAG(b) != RY_INT) {
+        ry_type_mismatch("number", b);
+    }
+}
+
+ry_value ry_bxor(ry_value a, ry_value b) {
+    ry_ints(a, b);
+    return ry_box_int(ry_int(a) ^ ry_int(b));
+}
+
+ry_value ry_band(ry_value a, ry_value b) {
+    ry_ints(a, b);
+    return ry_box_int(ry_int(a) & ry_int(b));
+}
+
+ry_value ry_bor(ry_value a, ry_value b) {
+    ry_ints(a, b);
+    return ry_box_int(ry_int(a) | ry_int(b));
+}
+
+/* Shift counts are masked to 5 bits, as in Rust release builds */
+ry_value ry_blsh(ry_value a, ry_value b) {
+    ry_ints(a, b);
+    return ry_box_int((int32_t)((uint32_t)ry_int(a) << (ry_int(b) & 31)));
+}
+
+ry_value ry_brsh(ry_value a, ry_value b) {
+    ry_ints(a, b);
+    return ry_box_int((int32_t)((uint32_t)ry_int(a) >> (ry_int(b) & 31)));
+}
+
+ry_value ry_barsh(ry_value a, ry_value b) {
+    ry_ints(a, b);
+    return ry_box_int(ry_int(a) >> (ry_int(b) & 31));
+}
+
+ry_value ry_bnot(ry_value value) {
+    if (RY_TAG(value) != RY_INT) {
+        ry_type_mismatch("number", value);
+    }
+    return ry_box_int(~ry_int(value));
+}
+
+/* Ints are negated through f64 by the VM, so i32::MIN saturates */
+ry_value ry_negate(ry_value value) {
+    if (RY_TAG(value) == RY_INT) {
+        return ry_box_int(ry_to_i32(-(double)ry_int(value)));
+    }
+    if (!ry_is_number(value)) {
+        ry_type_mismatch("number", value);
+    }
+    return value ^ 0x8000000000000000ULL;
+}
+
+/* Logic */
+
+static ry_value ry_box_bool(int value) {
+    return value ? RY_TRUE : RY_FALSE;
+}
+
+ry_value ry_and(ry_value a, ry_value b) {
+    return ry_box_bool(ry_truthy(a) && ry_truthy(b));
+}
+
+ry_value ry_or(ry_value a, ry_value b) {
+    return ry_box_bool(ry_truthy(a) || ry_truthy(b));
+}
+
+ry_value ry_not(ry_value value) {
+    return ry_box_bool(!ry_truthy(value));
+}
+
+ry_value ry_null_coalesce(ry_value a, ry_value b) {
+    return a == RY_NULL_VALUE ? b : a;
+}
+
+ry_value ry_eq(ry_value a, ry_value b) {
+    return ry_box_bool(ry_equal(a, b));
+}
+
+ry_value ry_neq(ry_value a, ry_value b) {
+    return ry_box_bool(!ry_equal(a, b));
+}
+
+ry_value ry_lt(ry_value a, ry_value b) {
+    return ry_box_bool(ry_compare(a, b) == -1);
+}
+
+ry_value ry_lte(ry_value a, ry_value b) {
+    int64_t order = ry_compare(a, b);
+    return ry_box_bool(order == -1 || order == 0);
+}
+
+ry_value ry_gt(ry_value a, ry_value b) {
+    return ry_box_bool(ry_compare(a, b) == 1);
+}
+
+ry_value ry_gte(ry_value a, ry_value b) {
+    int64_t order = ry_compare(a, b);
+    return ry_box_bool(order == 0 || order == 1);
+}
+
+/* Program */
+
+/* Frame of a call, like the VM's `Frame` */
+typedef struct {
+    uint64_t return_address;
+    /* Index into the function table, or -1 for a plain subroutine */
+    int64_t function;
+    uint64_t register_base;
+    uint64_t register_count;
+    uint64_t result_target;
+    uint64_t result_count;
+    /* Closure providing the upvalues, if any */
+    RyClosure *closure;
+} RyFrame;
+
+typedef struct {
+    ry_value name;
+    RyCell *cell;
+} RyVariable;
+
+typedef struct {
+    /* Depth of the call stack that opened it, 0 for top-level code */
+    uint64_t owner;
+    uint64_t length;
+    uint64_t capacity;
+    RyVariable *variables;
+} RyScope;
+
+ry_value *ry_window;
+uint64_t ry_window_size;
+
+static const RyFunction *ry_function_table;
+static uint64_t ry_function_count;
+static uint64_t ry_instruction_count;
+static uint64_t ry_top_register_count;
+
+/* Register stack, each function call owning a window at its top */
+static ry_value *ry_stack;
+static uint64_t ry_stack_length;
+static uint64_t ry_stack_capacity;
+static uint64_t ry_window_base;
+
+static RyFrame *ry_frames;
+static uint64_t ry_frame_count;
+static uint64_t ry_frame_capacity;
+
+/* Local scopes of every frame, innermost last */
+static RyScope *ry_scopes;
+static uint64_t ry_scope_count;
+static uint64_t ry_scope_capacity;
+static RyScope ry_globals;
+
+/* Grows `*items` to hold `length` items of `size` bytes */
+static void ry_reserve(void **items, uint64_t *capacity, uint64_t length, size_t size) {
+    if (length <= *capacity) {
+        return;
+    }
+    uint64_t grown = *capacity ? *capacity : 8;
+    while (grown < length) {
+        grown *= 2;
+    }
+    void *reallocated = realloc(*items, grown * size);
+    if (reallocated == NULL) {
+        ry_fail("out of memory");
+    }
+    *items = reallocated;
+    *capacity = grown;
+}
+
+static void ry_set_window(uint64_t base, uint64_t size) {
+    ry_window_base = base;
+    ry_window = ry_stack + base;
+    ry_window_size = size;
+}
+
+void ry_register_out_of_bounds(uint64_t index) {
+    ry_failf("Invalid register index: %" PRIu64, index);
+}
+
+void ry_start(uint64_t register_count, const RyFunction *functions, uint64_t function_count,
+              uint64_t instruction_count) {
+    ry_function_table = functions;
+    ry_function_count = function_count;
+    ry_instruction_count = instruction_count;
+    ry_top_register_count = register_count;
+    ry_reserve((void **)&ry_stack, &ry_stack_capacity, register_count, sizeof(ry_value));
+    for (uint64_t i = 0; i < register_count; i++) {
+        ry_stack[i] = RY_NULL_VALUE;
+    }
+    ry_stack_length = register_count;
+    ry_set_window(0, register_count);
+}
+
+ry_value ry_string(const char *bytes, uint64_t length) {
+    return ry_new_string(bytes, length);
+}
+
+ry_value ry_constant_out_of_bounds(uint64_t index) {
+    ry_failf("Invalid constant index: %" PRIu64, index);
+    return RY_NULL_VALUE;
+}
+
+/* Variables */
+
+static RyCell *ry_new_cell(ry_value value) {
+    RyCell *cell = ry_alloc(sizeof(RyCell));
+    cell->value = value;
+    return cell;
+}
+
+static RyVariable *ry_scope_find(RyScope *scope, ry_value name) {
+    for (uint64_t i = 0; i < scope->length; i++) {
+        if (ry_string_equal(ry_pointer(scope->variables[i].name), ry_pointer(name))) {
+            return &scope->variables[i];
+        }
+    }
+    return NULL;
+}
+
+/* Binds `name` to a new cell, replacing any binding of the same scope */
+static void ry_scope_bind(RyScope *scope, ry_value name, ry_value value) {
+    RyVariable *variable = ry_scope_find(scope, name);
+    if (variable == NULL) {
+        ry_reserve((void **)&scope->variables, &scope->capacity, scope->length + 1,
+                   sizeof(RyVariable));
+        variable = &scope->variables[scope->length++];
+        variable->name = name;
+    }
+    variable->cell = ry_new_cell(value);
+}
+
+/* Depth of the nearest function frame: functions cannot see the locals of
+ * their callers, so scopes opened below it are hidden. 0 when there is none. */
+static uint64_t ry_visible_floor(void) {
+    for (uint64_t depth = ry_frame_count; depth > 0; depth--) {
+        if (ry_frames[depth - 1].function >= 0) {
+            return depth;
+        }
+    }
+    return 0;
+}
+
+static RyCell *ry_lookup(ry_value name) {
+    uint64_t floor = ry_visible_floor();
+    for (uint64_t i = ry_scope_count; i > 0 && ry_scopes[i - 1].owner >= floor; i--) {
+        RyVariable *variable = ry_scope_find(&ry_scopes[i - 1], name);
+        if (variable != NULL) {
+            return variable->cell;
+        }
+    }
+    RyVariable *variable = ry_scope_find(&ry_globals, name);
+    return variable ? variable->cell : NULL;
+}
+
+/* Fails like `VmError::VariableNotFound`, naming the scopes searched */
+static void ry_variable_not_found(ry_value name) {
+    RyString *string = ry_pointer(name);
+    uint64_t floor = ry_visible_floor();
+    uint64_t depth = 0;
+    for (uint64_t i = ry_scope_count; i > 0 && ry_scopes[i - 1].owner >= floor; i--) {
+        depth++;
+    }
+    if (floor > 0) {
+        const char *function = ry_function_table[ry_frames[floor - 1].function].name;
+        ry_failf("Variable '%s' not found in local scope of function '%s' (depth %" PRIu64
+                 ") or global scope",
+                 string->bytes, function, depth);
+    } else if (depth > 0) {
+        ry_failf("Variable '%s' not found in local scope (depth %" PRIu64 ") or global scope",
+                 string->bytes, depth);
+    } else {
+        ry_failf("Variable '%s' not found in global scope", string->bytes);
+    }
+}
+
+static RyCell *ry_variable(ry_value name) {
+    RyCell *cell = ry_lookup(name);
+    if (cell == NULL) {
+        ry_variable_not_found(name);
+    }
+    return cell;
+}
+
+/* Binds `name` in the innermost visible scope, or globally when no scope is
+ * open, like `Vm::declare_variable` */
+void ry_declare(ry_value name, ry_value value) {
+    uint64_t floor = ry_visible_floor();
+    if (ry_scope_count > 0 && ry_scopes[ry_scope_count - 1].owner >= floor) {
+        ry_scope_bind(&ry_scopes[ry_scope_count - 1], name, value);
+    } else {
+        ry_scope_bind(&ry_globals, name, value);
+    }
+}
+
+void ry_store(ry_value name, ry_value value) {
+    RyCell *cell = ry_lookup(name);
+    if (cell != NULL) {
+        cell->value = value;
+    } else {
+        ry_declare(name, value);
+    }
+}
+
+ry_value ry_load(ry_value name) {
+    return ry_variable(name)->value;
+}
+
+void ry_store_global(ry_value name, ry_value value) {
+    RyVariable *variable = ry_scope_find(&ry_globals, name);
+    if (variable != NULL) {
+        variable->cell->value = value;
+    } else {
+        ry_scope_bind(&ry_globals, name, value);
+    }
+}
+
+ry_value ry_load_global(ry_value name) {
+    RyVariable *variable = ry_scope_find(&ry_globals, name);
+    if (variable == NULL) {
+        ry_failf("Variable '%s' not found in global scope",
+                 ((RyString *)ry_pointer(name))->bytes);
+    }
+    return variable->cell->value;
+}
+
+void ry_push_scope(void) {
+    ry_reserve((void **)&ry_scopes, &ry_scope_capacity, ry_scope_count + 1, sizeof(RyScope));
+    ry_scopes[ry_scope_count++] = (RyScope){ry_frame_count, 0, 0, NULL};
+}
+
+/* Only closes scopes of the current frame */
+void ry_pop_scope(void) {
+    if (ry_scope_count == 0 || ry_scopes[ry_scope_count - 1].owner != ry_frame_count) {
+        ry_fail("Scope stack is empty, cannot pop scope");
+    }
+    ry_scope_count--;
+}
+
+/* INC and DEC, which return the old value when `returns_old` is true */
+ry_value ry_increment(ry_value name, ry_value amount, ry_value returns_old) {
+    RyCell *cell = ry_variable(name);
+    if (RY_TAG(cell->value) != RY_INT) {
+        ry_type_mismatch("number", cell->value);
+    }
+    ry_value old = cell->value;
+    cell->value = ry_box_int((int32_t)((uint32_t)ry_int(old) + (uint32_t)ry_int(amount)));
+    return ry_truthy(returns_old) ? old : cell->value;
+}
+
+/* Closures */
+
+static RyCell *ry_upvalue(uint64_t index) {
+    RyClosure *closure = ry_frame_count > 0 ? ry_frames[ry_frame_count - 1].closure : NULL;
+    if (closure == NULL || index >= closure->upvalue_count) {
+        ry_failf("Invalid upvalue index: %" PRIu64, index);
+    }
+    return closure->upvalues[index];
+}
+
+ry_value ry_get_upvalue(ry_value index) {
+    return ry_upvalue((uint64_t)ry_int(index))->value;
+}
+
+void ry_set_upvalue(ry_value index, ry_value value) {
+    ry_upvalue((uint64_t)ry_int(index))->value = value;
+}
+
+RyCell *ry_capture_variable(ry_value name) {
+    return ry_variable(name);
+}
+
+RyCell *ry_capture_value(ry_value value) {
+    return ry_new_cell(value);
+}
+
+RyCell *ry_capture_upvalue(uint64_t index) {
+    return ry_upvalue(index);
+}
+
+ry_value ry_new_closure(uint64_t function, uint64_t count, RyCell **captures) {
+    RyClosure *closure = ry_alloc(sizeof(RyClosure) + count * sizeof(RyCell *));
+    closure->function = function;
+    closure->upvalue_count = count;
+    for (uint64_t i = 0; i < count; i++) {
+        closure->upvalues[i] = captures[i];
+    }
+    return ry_box_pointer(RY_CLOSURE, closure);
+}
+
+/* Calls */
+
+static void ry_push_frame(RyFrame frame) {
+    ry_reserve((void **)&ry_frames, &ry_frame_capacity, ry_frame_count + 1, sizeof(RyFrame));
+    ry_frames[ry_frame_count++] = frame;
+}
+
+/* Subroutines share the register window and upvalues of their caller */
+void ry_call_subroutine(uint64_t return_address) {
+    RyClosure *closure = ry_frame_count > 0 ? ry_frames[ry_frame_count - 1].closure : NULL;
+    ry_push_frame((RyFrame){return_address, -1, ry_window_base, ry_window_size, 0, 0, closure});
+}
+
+static uint64_t ry_enter(uint64_t return_address, uint64_t function, RyClosure *closure,
+                         uint64_t args, uint64_t arg_count, uint64_t target,
+                         uint64_t result_count) {
+    if (function >= ry_function_count) {
+        ry_failf("Invalid function index: %" PRIu64, function);
+    }
+    const RyFunction *proto = &ry_function_table[function];
+    if (arg_count != proto->arity) {
+        ry_failf("Function '%s' expects %" PRIu64 " argument(s), got %" PRIu64, proto->name,
+                 proto->arity, arg_count);
+    }
+    if (proto->address >= ry_instruction_count) {
+        ry_fail("Program counter out of bounds");
+    }
+    if (arg_count > proto->register_count) {
+        ry_register_out_of_bounds(arg_count - 1);
+    }
+    for (uint64_t i = 0; i < arg_count; i++) {
+        ry_register(args + i);
+    }
+
+    uint64_t base = ry_stack_length;
+    ry_reserve((void **)&ry_stack, &ry_stack_capacity, base + proto->register_count,
+               sizeof(ry_value));
+    /* the arguments are read from the caller's window, which may have moved */
+    for (uint64_t i = 0; i < proto->register_count; i++) {
+        ry_stack[base + i] = i < arg_count ? ry_stack[ry_window_base + args + i] : RY_NULL_VALUE;
+    }
+    ry_stack_length = base + proto->register_count;
+
+    ry_push_frame((RyFrame){return_address, (int64_t)function, base, proto->register_count,
+                            target, result_count, closure});
+    ry_push_scope();
+    ry_set_window(base, proto->register_count);
+    return proto->address;
+}
+
+uint64_t ry_call_function(uint64_t return_address, uint64_t function, uint64_t args,
+                          uint64_t arg_count, uint64_t target, uint64_t result_count) {
+    return ry_enter(return_address, function, NULL, args, arg_count, target, result_count);
+}
+
+uint64_t ry_call_closure(uint64_t return_address, ry_value callee, uint64_t args,
+                         uint64_t arg_count, uint64_t target, uint64_t result_count) {
+    if (RY_TAG(callee) != RY_CLOSURE) {
+        ry_type_mismatch("function", callee);
+    }
+    RyClosure *closure = ry_pointer(callee);
+    return ry_enter(return_address, closure->function, closure, args, arg_count, target,
+                    result_count);
+}
+
+/* Returns registers `source..source + count` to the caller of a function;
+ * subroutines ignore them */
+uint64_t ry_return(uint64_t source, uint64_t count) {
+    for (uint64_t i = 0; i < count; i++) {
+        ry_register(source + i);
+    }
+    if (ry_frame_count == 0) {
+        ry_fail("Call stack is empty, cannot return");
+    }
+    RyFrame frame = ry_frames[--ry_frame_count];
+    while (ry_scope_count > 0 && ry_scopes[ry_scope_count - 1].owner > ry_frame_count) {
+        ry_scope_count--;
+    }
+    if (frame.function < 0) {
+        return frame.return_address;
+    }
+
+    /* values are left in place above the caller's window until copied */
+    const ry_value *values = ry_stack + frame.register_base + source;
+    ry_stack_length = frame.register_base;
+    if (ry_frame_count > 0) {
+        RyFrame *caller = &ry_frames[ry_frame_count - 1];
+        ry_set_window(caller->register_base, caller->register_count);
+    } else {
+        ry_set_window(0, ry_top_register_count);
+    }
+    for (uint64_t i = 0; i < frame.result_count; i++) {
+        R(frame.result_target + i) = i < count ? values[i] : RY_NULL_VALUE;
+    }
+    return frame.return_address;
+}
+
+/* Compiled programs have no host functions registered */
+ry_value ry_call_native(ry_value name, uint64_t args, uint64_t arg_count) {
+    (void)args;
+    (void)arg_count;
+    if (RY_TAG(name) != RY_STRING) {
+        ry_type_mismatch("string", name);
+    }
+    ry_failf("Native function '%s' is not registered", ((RyString *)ry_pointer(name))->bytes);
+    return RY_NULL_VALUE;
+}
@@ -0,0 +1,161 @@
+/*
+ * Interface of the runtime library, included by the library itself and by
+ * the C source generated by the ryde AOT compiler.
+ *
+ * Values are NaN-boxed 64-bit words, as described in src/aot/runtime.rs.
+ * Generated programs keep their bytecode registers in the register stack of
+ * the runtime: `R(i)` is register `i` of the running function's window.
+ */
+#ifndef RYDE_RUNTIME_H
+#define RYDE_RUNTIME_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef uint64_t ry_value;
+
+#define RY_TAG(value) ((value) >> 48)
+#define RY_PAYLOAD(value) ((value) & 0xffffffffffffULL)
+#define RY_BOX(tag, payload) (((uint64_t)(tag) << 48) | (payload))
+
+enum {
+    RY_INT = 0xfff9,
+    RY_BOOL = 0xfffa,
+    RY_NULL = 0xfffb,
+    RY_STRING = 0xfffc,
+    RY_ARRAY = 0xfffd,
+    RY_OBJECT = 0xfffe,
+    RY_CLOSURE = 0xffff,
+};
+
+#define RY_NULL_VALUE RY_BOX(RY_NULL, 0)
+#define RY_FALSE RY_BOX(RY_BOOL, 0)
+#define RY_TRUE RY_BOX(RY_BOOL, 1)
+
+/* Entry of a program's function table, like `FunctionProto` */
+typedef struct {
+    const char *name;
+    uint64_t address;
+    uint64_t arity;
+    uint64_t register_count;
+} RyFunction;
+
+/* Variable, shared with the closures capturing it */
+typedef struct {
+    ry_value value;
+} RyCell;
+
+/* Register window of the running function */
+extern ry_value *ry_window;
+extern uint64_t ry_window_size;
+
+void ry_fail(const char *message);
+void ry_register_out_of_bounds(uint64_t index);
+
+/* Sets up the top-level register window and the program's function table */
+void ry_start(uint64_t register_count, const RyFunction *functions, uint64_t function_count,
+              uint64_t instruction_count);
+ry_value ry_string(const char *bytes, uint64_t length);
+ry_value ry_constant_out_of_bounds(uint64_t index);
+
+void ry_print(ry_value value);
+
+/* Arithmetic, following the VM's `float_binop` and `int_binop` */
+ry_value ry_add(ry_value a, ry_value b, ry_value previous);
+ry_value ry_sub(ry_value a, ry_value b);
+ry_value ry_mul(ry_value a, ry_value b);
+ry_value ry_div(ry_value a, ry_value b);
+ry_value ry_idiv(ry_value a, ry_value b);
+ry_value ry_idivk(ry_value a, ry_value b);
+ry_value ry_mod(ry_value a, ry_value b);
+ry_value ry_pow(ry_value a, ry_value b);
+ry_value ry_bxor(ry_value a, ry_value b);
+ry_value ry_band(ry_value a, ry_value b);
+ry_value ry_bor(ry_value a, ry_value b);
+ry_value ry_blsh(ry_value a, ry_value b);
+ry_value ry_brsh(ry_value a, ry_value b);
+ry_value ry_barsh(ry_value a, ry_value b);
+ry_value ry_bnot(ry_value value);
+ry_value ry_negate(ry_value value);
+ry_value ry_concat(ry_value a, ry_value b, ry_value previous);
+
+/* Logic and comparison */
+ry_value ry_and(ry_value a, ry_value b);
+ry_value ry_or(ry_value a, ry_value b);
+ry_value ry_not(ry_value value);
+ry_value ry_null_coalesce(ry_value a, ry_value b);
+int64_t ry_compare(ry_value a, ry_value b);
+int64_t ry_equal(ry_value a, ry_value b);
+ry_value ry_eq(ry_value a, ry_value b);
+ry_value ry_neq(ry_value a, ry_value b);
+ry_value ry_lt(ry_value a, ry_value b);
+ry_value ry_lte(ry_value a, ry_value b);
+ry_value ry_gt(ry_value a, ry_value b);
+ry_value ry_gte(ry_value a, ry_value b);
+
+/* Arrays and objects */
+ry_value ry_new_array(void);
+void ry_array_push(ry_value target, ry_value value);
+ry_value ry_new_object(void);
+ry_value ry_index(ry_value object, ry_value index);
+void ry_store_index(ry_value object, ry_value index, ry_value value);
+ry_value ry_len(ry_value value, ry_value previous);
+
+/* Variables, named by boxed strings */
+void ry_store(ry_value name, ry_value value);
+ry_value ry_load(ry_value name);
+void ry_declare(ry_value name, ry_value value);
+void ry_store_global(ry_value name, ry_value value);
+ry_value ry_load_global(ry_value name);
+void ry_push_scope(void);
+void ry_pop_scope(void);
+ry_value ry_increment(ry_value name, ry_value amount, ry_value returns_old);
+
+/* Closures */
+ry_value ry_get_upvalue(ry_value index);
+void ry_set_upvalue(ry_value index, ry_value value);
+RyCell *ry_capture_variable(ry_value name);
+RyCell *ry_capture_value(ry_value value);
+RyCell *ry_capture_upvalue(uint64_t index);
+ry_value ry_new_closure(uint64_t function, uint64_t count, RyCell **captures);
+
+/* Calls. Functions and returns give the address to continue at. */
+void ry_call_subroutine(uint64_t return_address);
+uint64_t ry_call_function(uint64_t return_address, uint64_t function, uint64_t args,
+                          uint64_t arg_count, uint64_t target, uint64_t result_count);
+uint64_t ry_call_closure(uint64_t return_address, ry_value callee, uint64_t args,
+                         uint64_t arg_count, uint64_t target, uint64_t result_count);
+uint64_t ry_return(uint64_t source, uint64_t count);
+ry_value ry_call_native(ry_value name, uint64_t args, uint64_t arg_count);
+
+static inline int32_t ry_int(ry_value value) {
+    return (int32_t)(uint32_t)value;
+}
+
+static inline ry_value ry_box_int(int32_t value) {
+    return RY_BOX(RY_INT, (uint32_t)value);
+}
+
+/* Clamps a sum or product of two Ints like the VM's `as i32` */
+static inline int32_t ry_saturate_int(int64_t value) {
+    if (value > INT32_MAX) {
+        return INT32_MAX;
+    }
+    return value < INT32_MIN ? INT32_MIN : (int32_t)value;
+}
+
+/* Only `false` and `null` are falsy */
+static inline int ry_truthy(ry_value value) {
+    return value != RY_FALSE && value != RY_NULL_VALUE;
+}
+
+static inline ry_value *ry_register(uint64_t index) {
+    if (index >= ry_window_size) {
+        ry_register_out_of_bounds(index);
+    }
+    return &ry_window[index];
+}
+
+#define R(index) (*ry_register(index))
+
+#endif
@@ -1,107 +1,210 @@
-use crate::aot::vreg_to_reg;
+use std::collections::BTreeSet;
+
+use crate::{
+    aot::{
+        backend::Backend,
+        regalloc::{Allocation, Location},
+        runtime,
+        types::ValueType,
+    },
+    error::aot::AotErrorKind,
+    instruction::Instruction,
+    value::VmValue,
+};
 
 const TAB: &str = "  ";
+const COMMENT_WIDTH: usize = 20;
+/// Never allocated, so it is free for staging operands and receiving results
+const SCRATCH: &str = "rax";
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum AotTarget {
+    /// Windows x64 calling convention, assembled with `nasm -f win64`
     Win64,
+    /// Linux x86-64 System V calling convention, assembled with `nasm -f elf64`
+    SysV64,
 }
 
 impl AotTarget {
+    /// The target matching the platform this compiler was built for
+    pub fn host() -> AotTarget {
+        if cfg!(windows) {
+            AotTarget::Win64
+        } else {
+            AotTarget::SysV64
+        }
+    }
+
     pub fn is_win64(&self) -> bool {
         self == &AotTarget::Win64
     }
+
+    pub fn is_sysv64(&self) -> bool {
+        self == &AotTarget::SysV64
+    }
+
+    /// Output format to pass to `nasm -f`
+    pub fn nasm_format(&self) -> &'static str {
+        match self {
+            AotTarget::Win64 => "win64",
+            AotTarget::SysV64 => "elf64",
+        }
+    }
+
+    /// Registers a called function must preserve, handed out first by the allocator
+    pub fn callee_saved_registers(&self) -> &'static [&'static str] {
+        match self {
+            AotTarget::Win64 => &["rbx", "rsi", "rdi", "r12", "r13", "r14", "r15"],
+            AotTarget::SysV64 => &["rbx", "r12", "r13", "r14", "r15"],
+        }
+    }
+
+    /// Registers clobbered by calls. `rax` and `r11` are kept out of
+    /// allocation as scratch registers.
+    pub fn caller_saved_registers(&self) -> &'static [&'static str] {
+        match self {
+            AotTarget::Win64 => &["rcx", "rdx", "r8", "r9", "r10"],
+            AotTarget::SysV64 => &["rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10"],
+        }
+    }
+
+    /// Registers holding the first four integer arguments of a call
+    pub fn argument_registers(&self) -> [&'static str; 4] {
+        match self {
+            AotTarget::Win64 => ["rcx", "rdx", "r8", "r9"],
+            AotTarget::SysV64 => ["rdi", "rsi", "rdx", "rcx"],
+        }
+    }
 }
 
 pub struct AotState {
     target: AotTarget,
+    allocation: Allocation,
     indent: usize,
-    closest_free_vreg: usize,
-    pub print_used: bool,
+    constant_pool: Vec<VmValue>,
+    /// Runtime routines called so far, see [`crate::aot::runtime`]
+    pub routines: BTreeSet<&'static str>,
+    /// String immediates, emitted as static data
+    pub strings: Vec<String>,
     pub assembly: String,
 }
 
 impl AotState {
-    pub fn new(target: AotTarget) -> AotState {
+    pub fn new(target: AotTarget, allocation: Allocation, constant_pool: Vec<VmValue>) -> AotState {
         AotState {
             target,
+            allocation,
             indent: 0,
-            closest_free_vreg: 0,
-            print_used: false,
+            constant_pool,
+            routines: BTreeSet::new(),
+            strings: Vec::new(),
             assembly: String::new(),
         }
     }
 
-    pub fn alloc_vreg(&mut self, vreg: usize) -> usize {
-        self.closest_free_vreg = vreg + 1;
-        vreg
+    pub fn target(&self) -> AotTarget {
+        self.target
     }
 
-    pub fn alloc_new_vreg(&mut self) -> usize {
-        let allocated = self.closest_free_vreg;
-        self.closest_free_vreg += 1;
-        allocated
+    /// Stack slots sit below the saved rbp and callee-saved registers
+    fn slot(&self, slot: usize) -> String {
+        let offset = 8 * (self.allocation.callee_saved.len() + 1 + slot);
+        format!("qword [rbp - {}]", offset)
     }
 
-    pub fn alloc_reg(&mut self, vreg: usize) -> String {
-        vreg_to_reg(self.alloc_vreg(vreg))
+    /// Slot preserving a caller-saved register across calls, after the spill slots
+    fn save_slot(&self, reg: &str) -> String {
+        let index = self
+            .target
+            .caller_saved_registers()
+            .iter()
+            .position(|r| *r == reg)
+            .unwrap();
+        self.slot(self.allocation.spill_slots + index)
     }
 
-    pub fn free_vreg(&mut self, vreg: usize) -> () {
-        if vreg <= self.closest_free_vreg {
-            self.closest_free_vreg = vreg;
-        }
+    /// Bytes reserved below the pushed registers, keeping rsp 16-byte aligned
+    fn frame_size(&self) -> usize {
+        let slots = self.allocation.spill_slots + self.target.caller_saved_registers().len();
+        let pushed = self.allocation.callee_saved.len();
+        8 * (slots + (slots + pushed) % 2)
     }
 
-    pub fn write_loadv(&mut self, vreg: usize, src: String) -> String {
-        let reg = self.alloc_reg(vreg);
-        self.write_instruction("mov", vec![reg, src])
+    /// Loads a 32-bit operand or immediate into a 64-bit register
+    fn write_sign_extend(&mut self, register: &str, operand: &str) -> String {
+        if operand.parse::<i32>().is_ok() {
+            self.write_instruction("mov", vec![register.to_string(), operand.to_string()])
+        } else {
+            self.write_instruction("movsxd", vec![register.to_string(), operand.to_string()])
+        }
     }
 
-    pub fn write_add(&mut self, vreg0: usize, vreg1: usize) -> String {
-        let reg0 = vreg_to_reg(vreg0);
-        let reg1 = vreg_to_reg(vreg1);
-        self.write_instruction("add", vec![reg0, reg1])
+    /// Label of the native code for the instruction at `index`
+    pub fn label(index: usize) -> String {
+        format!(".L{}", index)
     }
 
-    pub fn write_print(&mut self, vreg: usize) -> String {
-        self.free_vreg(vreg);
-        self.slice(|s| {
-            let reg = vreg_to_reg(vreg);
-
-            if s.target.is_win64() {
-                // shadow space for win64 calling convention
-                s.write_instruction("sub", vec!["rsp".to_string(), "40".to_string()]);
-                s.write_line();
-            }
-            s.write_instruction("lea", vec!["rcx".to_string(), "[rel fmt]".to_string()]);
-            s.write_line();
-            s.write_instruction("mov", vec!["rdx".to_string(), reg]);
-            s.write_line();
-            s.write_instruction("call", vec!["printf".to_string()]);
-            s.write_line();
+    pub fn write_instruction_label(&mut self, index: usize) {
+        self.write(format!("{}:", Self::label(index)));
+        self.write_line();
+    }
 
-            if s.target.is_win64() {
-                // restore stack
-                s.write_instruction("add", vec!["rsp".to_string(), "40".to_string()]);
-                s.write_line();
-            }
-        })
+    /// Operand for calling an external function. ELF executables are linked
+    /// as position independent by default, so calls go through the PLT.
+    pub fn extern_call(&self, name: &str) -> String {
+        if self.target.is_sysv64() {
+            format!("{} wrt ..plt", name)
+        } else {
+            name.to_string()
+        }
     }
 
     pub fn write_header(&mut self) -> () {
         self.write_borrowed("global main");
         self.write_line();
-        self.write_extern("printf");
         self.write_line();
 
-        self.section("data");
-        self.write_borrowed("fmt db \"%d\", 10, 0");
+        if self.target.is_sysv64() {
+            // mark the stack as non-executable for the linker
+            self.write_borrowed("section .note.GNU-stack noalloc noexec nowrite progbits");
+            self.write_line();
+        }
+        self.section("text");
+        self.write_label("main");
+
+        // keeps rsp 16-byte aligned at calls
+        self.write_instruction("push", vec!["rbp".to_string()]);
         self.write_line();
+        self.write_instruction("mov", vec!["rbp".to_string(), "rsp".to_string()]);
         self.write_line();
+        for reg in self.allocation.callee_saved.clone() {
+            self.write_instruction("push", vec![reg.to_string()]);
+            self.write_line();
+        }
+        let frame_size = self.frame_size();
+        if frame_size > 0 {
+            self.write_instruction("sub", vec!["rsp".to_string(), frame_size.to_string()]);
+            self.write_line();
+        }
 
-        self.section("text");
-        self.write_label("main");
+        // registers start out null, as in the VM
+        let mut locations: Vec<String> = self
+            .allocation
+            .intervals
+            .iter()
+            .map(|interval| self.location(interval.register))
+            .collect();
+        locations.sort();
+        locations.dedup();
+        if !locations.is_empty() {
+            let null = format!("0x{:x}", runtime::NULL);
+            self.write_instruction("mov", vec![SCRATCH.to_string(), null]);
+            self.write_line();
+        }
+        for location in locations {
+            self.write_instruction("mov", vec![location, SCRATCH.to_string()]);
+            self.write_line();
+        }
     }
 
     fn section(&mut self, name: &str) -> () {
@@ -110,12 +213,6 @@ impl AotState {
         self.write_line();
     }
 
-    fn write_extern(&mut self, name: &str) -> () {
-        self.write_borrowed("extern ");
-        self.write_borrowed(name);
-        self.write_line();
-    }
-
     fn write_label(&mut self, name: &str) {
         self.write_borrowed(name);
         self.write_borrowed(":");
@@ -174,4 +271,393 @@ impl AotState {
         let end = self.assembly.len();
         self.assembly[start..end].to_string()
     }
+
+    /// Calls a runtime routine with at most three arguments, as checked by
+    /// [`Backend::write_runtime_call`]
+    fn write_routine_call(
+        &mut self,
+        index: usize,
+        routine: &'static str,
+        arguments: Vec<String>,
+        target: Option<usize>,
+    ) -> String {
+        let dest = target.map(|target| self.location(target));
+        let saved: Vec<&'static str> = self
+            .allocation
+            .live_across(index)
+            .into_iter()
+            .filter(|reg| self.target.caller_saved_registers().contains(reg))
+            // the result overwrites the target anyway
+            .filter(|reg| dest.as_deref() != Some(*reg))
+            .collect();
+        self.routines.insert(routine);
+
+        self.slice(|s| {
+            // the routine may clobber caller-saved registers
+            for reg in saved.iter() {
+                s.write_instruction("mov", vec![s.save_slot(reg), reg.to_string()]);
+                s.write_line();
+            }
+            // the first two arguments are staged in the scratch registers, as
+            // they may live in each other's argument registers. A third one is
+            // moved before the first two argument registers are overwritten.
+            let registers = s.target.argument_registers();
+            let staging = [SCRATCH, "r11"];
+            if arguments.len() > 1 {
+                for (argument, scratch) in arguments.iter().zip(staging) {
+                    s.write_instruction("mov", vec![scratch.to_string(), argument.clone()]);
+                    s.write_line();
+                }
+            }
+            if let Some(argument) = arguments.get(2) {
+                s.write_instruction("mov", vec![registers[2].to_string(), argument.clone()]);
+                s.write_line();
+            }
+            for (i, argument) in arguments.iter().take(2).enumerate() {
+                let source = if arguments.len() > 1 {
+                    staging[i].to_string()
+                } else {
+                    argument.clone()
+                };
+                s.write_instruction("mov", vec![registers[i].to_string(), source]);
+                s.write_line();
+            }
+            if s.target.is_win64() {
+                // shadow space for win64 calling convention
+                s.write_instruction("sub", vec!["rsp".to_string(), "32".to_string()]);
+                s.write_line();
+            }
+            let callee = if runtime::is_library_function(routine) {
+                s.extern_call(routine)
+            } else {
+                routine.to_string()
+            };
+            s.write_instruction("call", vec![callee]);
+            s.write_line();
+            if s.target.is_win64() {
+                // restore stack
+                s.write_instruction("add", vec!["rsp".to_string(), "32".to_string()]);
+                s.write_line();
+            }
+            for reg in saved.iter() {
+                s.write_instruction("mov", vec![reg.to_string(), s.save_slot(reg)]);
+                s.write_line();
+            }
+            if let Some(dest) = dest {
+                s.write_instruction("mov", vec![dest, SCRATCH.to_string()]);
+            }
+        })
+    }
+}
+
+impl Backend for AotState {
+    /// Operand naming the machine location of a bytecode register
+    fn location(&self, vreg: usize) -> String {
+        match self.allocation.location(vreg) {
+            Some(Location::Register(reg)) => reg.to_string(),
+            Some(Location::Stack(slot)) => self.slot(slot),
+            None => panic!("unallocated vreg: {}", vreg),
+        }
+    }
+
+    /// Operand holding a boxed immediate value
+    fn immediate(&mut self, value: &VmValue) -> Result<String, AotErrorKind> {
+        if let Some(bits) = runtime::box_value(value) {
+            return Ok(format!("0x{:x}", bits));
+        }
+        match value {
+            VmValue::String(string) => {
+                let index = match self.strings.iter().position(|s| s == string) {
+                    Some(index) => index,
+                    None => {
+                        self.strings.push(string.clone());
+                        self.strings.len() - 1
+                    }
+                };
+                Ok(runtime::string_operand(index))
+            }
+            _ => Err(AotErrorKind::UnsupportedImmediate(value.clone())),
+        }
+    }
+
+    /// Operand holding a value of the constant pool
+    fn constant(&mut self, index: usize) -> Result<String, AotErrorKind> {
+        let value = self
+            .constant_pool
+            .get(index)
+            .cloned()
+            .ok_or(AotErrorKind::ConstantOutOfBounds(index))?;
+        self.immediate(&value)
+    }
+
+    fn constant_value(&self, index: usize) -> Option<&VmValue> {
+        self.constant_pool.get(index)
+    }
+
+    /// Operand naming the unboxed integer in a bytecode register, the low 32
+    /// bits of its boxed value
+    fn int_location(&self, vreg: usize) -> String {
+        match self.allocation.location(vreg) {
+            Some(Location::Register(reg)) => dword_register(reg),
+            Some(Location::Stack(slot)) => self.slot(slot).replacen("qword", "dword", 1),
+            None => panic!("unallocated vreg: {}", vreg),
+        }
+    }
+
+    fn write_loadv(&mut self, vreg: usize, src: String) -> String {
+        let dest = self.location(vreg);
+        self.slice(|s| {
+            if is_register(&dest) {
+                s.write_instruction("mov", vec![dest, src]);
+            } else {
+                // 64-bit immediates can only be moved into registers
+                s.write_instruction("mov", vec![SCRATCH.to_string(), src]);
+                s.write_line();
+                s.write_instruction("mov", vec![dest, SCRATCH.to_string()]);
+            }
+        })
+    }
+
+    /// Calls a runtime routine with up to three boxed arguments from the
+    /// instruction at `index`, storing the value it returns in `target`
+    fn write_runtime_call(
+        &mut self,
+        index: usize,
+        routine: &'static str,
+        arguments: Vec<String>,
+        target: Option<usize>,
+    ) -> Result<String, AotErrorKind> {
+        if arguments.len() > 3 {
+            return Err(AotErrorKind::TooManyArguments {
+                routine,
+                count: arguments.len(),
+            });
+        }
+        Ok(self.write_routine_call(index, routine, arguments, target))
+    }
+
+    /// Computes an integer operation with machine instructions, for operands
+    /// known to be Ints given as 32-bit operands or immediates. `add`, `sub`,
+    /// `imul` and `neg` saturate like the VM's `as i32` conversion; the
+    /// bitwise `and`, `or`, `xor` and `not` cannot overflow.
+    fn write_int_arithmetic(
+        &mut self,
+        operation: &str,
+        vreg: usize,
+        operands: Vec<String>,
+    ) -> String {
+        let dest = self.location(vreg);
+        self.slice(|s| {
+            let scratch = SCRATCH.to_string();
+            match operation {
+                "and" | "or" | "xor" | "not" => {
+                    s.write_instruction("mov", vec!["eax".to_string(), operands[0].clone()]);
+                    s.write_line();
+                    let mut arguments = vec!["eax".to_string()];
+                    arguments.extend(operands.get(1).cloned());
+                    s.write_instruction(operation, arguments);
+                    s.write_line();
+                }
+                "add" | "sub" | "imul" | "neg" => {
+                    // 64 bits hold any sum or product of two i32s
+                    for (operand, register) in operands.iter().zip([SCRATCH, "r11"]) {
+                        s.write_sign_extend(register, operand);
+                        s.write_line();
+                    }
+                    let mut arguments = vec![scratch.clone()];
+                    if operands.len() > 1 {
+                        arguments.push("r11".to_string());
+                    }
+                    s.write_instruction(operation, arguments);
+                    s.write_line();
+                    for (bound, condition) in [(i32::MAX, "cmovg"), (i32::MIN, "cmovl")] {
+                        s.write_instruction("mov", vec!["r11".to_string(), bound.to_string()]);
+                        s.write_line();
+                        s.write_instruction("cmp", vec![scratch.clone(), "r11".to_string()]);
+                        s.write_line();
+                        s.write_instruction(condition, vec![scratch.clone(), "r11".to_string()]);
+                        s.write_line();
+                    }
+                    // clears the upper half for the tag
+                    s.write_instruction("mov", vec!["eax".to_string(), "eax".to_string()]);
+                    s.write_line();
+                }
+                _ => panic!("unknown integer operation: {}", operation),
+            }
+            s.write_instruction(
+                "mov",
+                vec!["r11".to_string(), format!("0x{:x}", runtime::INT_TAG)],
+            );
+            s.write_line();
+            s.write_instruction("or", vec![scratch.clone(), "r11".to_string()]);
+            s.write_line();
+            s.write_instruction("mov", vec![dest, scratch]);
+        })
+    }
+
+    fn write_jump(&mut self, address: usize) -> String {
+        self.write_instruction("jmp", vec![Self::label(address)])
+    }
+
+    /// Compares two registers like `VmValue`'s `PartialOrd`, or `PartialEq`
+    /// for `e` and `ne`, and jumps with `condition` (`l`, `le`, `g`, `ge`, `e`
+    /// or `ne`)
+    fn write_compare_jump(
+        &mut self,
+        index: usize,
+        condition: &str,
+        a: usize,
+        b: usize,
+        address: usize,
+    ) -> String {
+        let a = self.location(a);
+        let b = self.location(b);
+        // ry_compare returns -1, 0, 1, or 2 for unordered operands, and
+        // ry_equal returns 1 for equal ones
+        let (routine, value, jump) = match condition {
+            "l" => ("ry_compare", "-1", "je"),
+            "le" => ("ry_compare", "0", "jle"),
+            "g" => ("ry_compare", "1", "je"),
+            "ge" => ("ry_compare", "1", "jbe"),
+            "e" => ("ry_equal", "1", "je"),
+            "ne" => ("ry_equal", "1", "jne"),
+            _ => panic!("unknown condition: {}", condition),
+        };
+        self.slice(|s| {
+            s.write_routine_call(index, routine, vec![a, b], None);
+            s.write_instruction("cmp", vec!["eax".to_string(), value.to_string()]);
+            s.write_line();
+            s.write_instruction(jump, vec![Self::label(address)]);
+        })
+    }
+
+    /// Compares two registers known to hold Ints and jumps with `condition`
+    fn write_int_compare_jump(
+        &mut self,
+        condition: &str,
+        a: usize,
+        b: usize,
+        address: usize,
+    ) -> String {
+        let a = self.int_location(a);
+        let b = self.int_location(b);
+        self.slice(|s| {
+            s.write_instruction("mov", vec!["eax".to_string(), a]);
+            s.write_line();
+            s.write_instruction("cmp", vec!["eax".to_string(), b]);
+            s.write_line();
+            s.write_instruction(&format!("j{}", condition), vec![Self::label(address)]);
+        })
+    }
+
+    /// Jumps when a register is truthy, or falsy if `truthy` is false. Only
+    /// `false` and `null` are falsy, so numbers need no check at all and
+    /// Bools only one.
+    fn write_truth_jump(
+        &mut self,
+        index: usize,
+        source: usize,
+        kind: ValueType,
+        truthy: bool,
+        address: usize,
+    ) -> String {
+        let source = self.location(source);
+        match kind {
+            ValueType::Int | ValueType::Float if truthy => return self.write_jump(address),
+            ValueType::Int | ValueType::Float => return String::new(),
+            ValueType::Bool => {
+                return self.slice(|s| {
+                    let falsy = format!("0x{:x}", runtime::BOOL_TAG);
+                    s.write_instruction("mov", vec![SCRATCH.to_string(), falsy]);
+                    s.write_line();
+                    s.write_instruction("cmp", vec![source, SCRATCH.to_string()]);
+                    s.write_line();
+                    let jump = if truthy { "jne" } else { "je" };
+                    s.write_instruction(jump, vec![Self::label(address)]);
+                });
+            }
+            ValueType::Unknown => {}
+        }
+        // a truthy jump skips to the next instruction on a falsy value
+        let falsy_target = if truthy { index + 1 } else { address };
+        self.slice(|s| {
+            for (i, falsy) in [runtime::BOOL_TAG, runtime::NULL].into_iter().enumerate() {
+                if i > 0 {
+                    s.write_line();
+                }
+                s.write_instruction("mov", vec![SCRATCH.to_string(), format!("0x{:x}", falsy)]);
+                s.write_line();
+                s.write_instruction("cmp", vec![source.clone(), SCRATCH.to_string()]);
+                s.write_line();
+                if truthy && i == 1 {
+                    s.write_instruction("jne", vec![Self::label(address)]);
+                } else {
+                    s.write_instruction("je", vec![Self::label(falsy_target)]);
+                }
+            }
+        })
+    }
+
+    /// Calls a subroutine. Subroutines share the caller's registers and frame;
+    /// the padding keeps rsp 16-byte aligned inside them.
+    fn write_call(&mut self, _index: usize, address: usize) -> String {
+        self.slice(|s| {
+            s.write_instruction("sub", vec!["rsp".to_string(), "8".to_string()]);
+            s.write_line();
+            s.write_instruction("call", vec![Self::label(address)]);
+            s.write_line();
+            s.write_instruction("add", vec!["rsp".to_string(), "8".to_string()]);
+        })
+    }
+
+    fn write_return(&mut self) -> String {
+        self.write_unit_instruction("ret")
+    }
+
+    /// Returns 0 from `main`
+    fn write_exit(&mut self) -> String {
+        self.slice(|s| {
+            s.write_instruction("xor", vec!["eax".to_string(), "eax".to_string()]);
+            s.write_line();
+            let pushed = 8 * s.allocation.callee_saved.len();
+            s.write_instruction(
+                "lea",
+                vec!["rsp".to_string(), format!("[rbp - {}]", pushed)],
+            );
+            s.write_line();
+            for reg in s.allocation.callee_saved.clone().iter().rev() {
+                s.write_instruction("pop", vec![reg.to_string()]);
+                s.write_line();
+            }
+            s.write_instruction("pop", vec!["rbp".to_string()]);
+            s.write_line();
+            s.write_unit_instruction("ret");
+            s.write_line();
+        })
+    }
+
+    fn write_comment(&mut self, written: &str, instruction: &Instruction) {
+        // sequences ending in a line break carry no comment
+        let last_line = written.rsplit('\n').next().unwrap_or_default().trim();
+        if last_line.is_empty() {
+            return;
+        }
+
+        let padding = COMMENT_WIDTH.saturating_sub(last_line.len());
+        self.write(format!("{}; {:?}", " ".repeat(padding), instruction));
+        self.write_line();
+    }
+}
+
+fn is_register(operand: &str) -> bool {
+    !operand.contains('[')
+}
+
+/// Lower 32 bits of a 64-bit general purpose register
+fn dword_register(register: &str) -> String {
+    match register.strip_prefix('r') {
+        Some(number) if number.parse::<u8>().is_ok() => format!("{}d", register),
+        Some(name) => format!("e{}", name),
+        None => panic!("not a 64-bit register: {}", register),
+    }
 }
@@ -0,0 +1,232 @@
+//! Forward type inference of bytecode registers.
+//!
+//! Every opcode is dynamically typed, but most registers only ever hold one
+//! kind of value. A forward dataflow pass over the control flow graph tracks
+//! which registers are known to hold an Int, Float or Bool before each
+//! instruction, so the code generator can skip the runtime for them.
+use std::collections::HashMap;
+
+use crate::{
+    aot::flow::{register_use, successors},
+    instruction::Instruction,
+    opcode::Operand,
+    serde::Program,
+    value::VmValue,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ValueType {
+    Int,
+    Float,
+    Bool,
+    Unknown,
+}
+
+impl ValueType {
+    pub fn of(value: &VmValue) -> ValueType {
+        match value {
+            VmValue::Int(_) => ValueType::Int,
+            VmValue::Float(_) => ValueType::Float,
+            VmValue::Boolean(_) => ValueType::Bool,
+            _ => ValueType::Unknown,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self, ValueType::Int | ValueType::Float)
+    }
+}
+
+/// Registers of known type, any other register may hold anything
+type Types = HashMap<usize, ValueType>;
+
+pub struct TypeInfo {
+    /// Known types before each instruction, `None` for unreachable ones
+    before: Vec<Option<Types>>,
+    constant_pool: Vec<VmValue>,
+}
+
+impl TypeInfo {
+    /// Type of `register` right before the instruction at `index` runs
+    pub fn register_type(&self, index: usize, register: usize) -> ValueType {
+        self.before[index]
+            .as_ref()
+            .and_then(|types| types.get(&register).copied())
+            .unwrap_or(ValueType::Unknown)
+    }
+
+    /// Type of a constant, Unknown for missing ones, which fail at run time
+    fn constant_type(&self, constant: usize) -> ValueType {
+        self.constant_pool
+            .get(constant)
+            .map_or(ValueType::Unknown, ValueType::of)
+    }
+
+    /// Type of an instruction operand when the instruction at `index` runs
+    pub fn operand_type(&self, index: usize, operand: &Operand) -> ValueType {
+        match operand {
+            Operand::Register(register) => self.register_type(index, *register),
+            Operand::Value(value) => ValueType::of(value),
+            Operand::Constant(constant) => self.constant_type(*constant),
+            Operand::Number(_) => ValueType::Int,
+            _ => ValueType::Unknown,
+        }
+    }
+}
+
+pub fn infer(program: &Program) -> TypeInfo {
+    let count = program.instructions.len();
+    let mut info = TypeInfo {
+        before: vec![None; count],
+        constant_pool: program.constant_pool.clone(),
+    };
+    if count == 0 {
+        return info;
+    }
+
+    // registers start out null, and functions start with their arguments
+    let mut worklist = vec![0];
+    worklist.extend(
+        program
+            .functions
+            .iter()
+            .map(|function| function.address)
+            .filter(|address| *address < count),
+    );
+    for entry in worklist.iter() {
+        info.before[*entry] = Some(Types::new());
+    }
+    while let Some(index) = worklist.pop() {
+        let Some(types) = info.before[index].clone() else {
+            continue;
+        };
+        let after = transfer(&info, index, &program.instructions[index], types);
+
+        for successor in successors(program, index) {
+            let merged = match &info.before[successor] {
+                Some(existing) => meet(existing, &after),
+                None => after.clone(),
+            };
+            if info.before[successor].as_ref() != Some(&merged) {
+                info.before[successor] = Some(merged);
+                worklist.push(successor);
+            }
+        }
+    }
+    info
+}
+
+/// Types known on both paths into an instruction
+fn meet(a: &Types, b: &Types) -> Types {
+    a.iter()
+        .filter(|(register, kind)| b.get(register) == Some(kind))
+        .map(|(register, kind)| (*register, *kind))
+        .collect()
+}
+
+fn transfer(info: &TypeInfo, index: usize, instruction: &Instruction, mut types: Types) -> Types {
+    let result = result_type(info, index, instruction);
+    for register in register_use(instruction).defs {
+        types.remove(&register);
+    }
+    if let Some((target, kind)) = result
+        && kind != ValueType::Unknown
+    {
+        types.insert(target, kind);
+    }
+    types
+}
+
+/// Target and type of the value an instruction produces, following the
+/// VM's `float_binop` and `int_binop`
+fn result_type(
+    info: &TypeInfo,
+    index: usize,
+    instruction: &Instruction,
+) -> Option<(usize, ValueType)> {
+    use Instruction::*;
+    let mut operands = instruction.operands().into_iter();
+    let Some(Operand::Register(target)) = operands.next() else {
+        return None;
+    };
+    let types: Vec<ValueType> = operands
+        .map(|operand| info.operand_type(index, &operand))
+        .collect();
+    let both_numbers = types.iter().all(ValueType::is_number);
+    let any_float = types.contains(&ValueType::Float);
+
+    let result = match instruction {
+        LOADV { value, .. } => ValueType::of(value),
+        LOADC { constant_index, .. } => info.constant_type(*constant_index),
+        // results of integers are whole, so they stay integers
+        ADD { .. }
+        | ADDK { .. }
+        | ADDC { .. }
+        | SUB { .. }
+        | SUBK { .. }
+        | SUBC { .. }
+        | MUL { .. }
+        | MULK { .. }
+        | MULC { .. } => match (both_numbers, any_float) {
+            (true, true) => ValueType::Float,
+            (true, false) => ValueType::Int,
+            _ => ValueType::Unknown,
+        },
+        // integers divide into fractions, or NaN and infinities
+        DIV { .. }
+        | DIVK { .. }
+        | DIVC { .. }
+        | IDIVK { .. }
+        | MOD { .. }
+        | MODK { .. }
+        | MODC { .. }
+        | POW { .. }
+        | POWK { .. }
+        | POWC { .. }
+            if both_numbers && any_float =>
+        {
+            ValueType::Float
+        }
+        IDIV { .. } | IDIVC { .. } => ValueType::Int,
+        // integer only opcodes fail on anything else
+        BXOR { .. }
+        | BXORK { .. }
+        | BXORC { .. }
+        | BAND { .. }
+        | BANDK { .. }
+        | BANDC { .. }
+        | BOR { .. }
+        | BORK { .. }
+        | BORC { .. }
+        | BLSH { .. }
+        | BLSHK { .. }
+        | BLSHC { .. }
+        | BRSH { .. }
+        | BRSHK { .. }
+        | BRSHC { .. }
+        | BARSH { .. }
+        | BARSHK { .. }
+        | BARSHC { .. }
+        | BNOT { .. }
+        | BNOTK { .. }
+        | BNOTC { .. } => ValueType::Int,
+        NEGATE { .. } | NEGATEK { .. } | NEGATEC { .. } if both_numbers => types[0],
+        AND { .. }
+        | ANDK { .. }
+        | ANDC { .. }
+        | OR { .. }
+        | ORK { .. }
+        | ORC { .. }
+        | NOT { .. }
+        | NOTK { .. }
+        | NOTC { .. }
+        | EQ { .. }
+        | NEQ { .. }
+        | LT { .. }
+        | LTE { .. }
+        | GT { .. }
+        | GTE { .. } => ValueType::Bool,
+        _ => ValueType::Unknown,
+    };
+    Some((target, result))
+}
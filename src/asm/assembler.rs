@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+
+use crate::{
+    array::DynamicArray,
+    asm::lexer::{Token, TokenKind, tokenize},
+    debug_info::DebugInfo,
+    error::asm::AsmError,
+    function::{Capture, FunctionProto},
+    instruction::Instruction,
+    object::Object,
+    opcode::{Opcode, Operand, OperandKind},
+    serde::Program,
+    value::VmValue,
+};
+
+/// Operand whose value is only known once every label and function has been seen
+enum PendingOperand {
+    Ready(Operand),
+    Label(String, Token),
+    Function(String, Token),
+}
+
+struct PendingInstruction {
+    opcode: Opcode,
+    operands: Vec<PendingOperand>,
+    token: Token,
+}
+
+struct PendingFunction {
+    name: String,
+    arity: usize,
+    register_count: usize,
+    label: PendingOperand,
+}
+
+pub struct Assembler {
+    tokens: Vec<Token>,
+    position: usize,
+    labels: HashMap<String, usize>,
+    instructions: Vec<PendingInstruction>,
+    constant_pool: Vec<VmValue>,
+    functions: Vec<PendingFunction>,
+    debug_info: Option<DebugInfo>,
+}
+
+impl Assembler {
+    pub fn new(source: &str) -> Result<Self, AsmError> {
+        Ok(Self {
+            tokens: tokenize(source)?,
+            position: 0,
+            labels: HashMap::new(),
+            instructions: Vec::new(),
+            constant_pool: Vec::new(),
+            functions: Vec::new(),
+            debug_info: None,
+        })
+    }
+
+    pub fn assemble(mut self) -> Result<Program, AsmError> {
+        loop {
+            while self.peek().kind == TokenKind::Newline {
+                self.next();
+            }
+
+            let token = self.next();
+            match &token.kind {
+                TokenKind::Eof => break,
+                TokenKind::Symbol('.') => self.directive()?,
+                TokenKind::Identifier(name) if self.peek().kind == TokenKind::Symbol(':') => {
+                    self.next();
+                    if self.labels.contains_key(name) {
+                        return Err(error_at(&token, format!("duplicate label '{}'", name)));
+                    }
+                    self.labels.insert(name.clone(), self.instructions.len());
+                    continue;
+                }
+                TokenKind::Identifier(name) => {
+                    let opcode = Opcode::from_name(name)
+                        .ok_or_else(|| error_at(&token, format!("unknown opcode '{}'", name)))?;
+                    let operands = self.operands(opcode)?;
+                    self.instructions.push(PendingInstruction {
+                        opcode,
+                        operands,
+                        token,
+                    });
+                }
+                _ => return Err(error_at(&token, "expected instruction, label or directive")),
+            }
+            self.end_of_line()?;
+        }
+
+        self.finish()
+    }
+
+    fn finish(self) -> Result<Program, AsmError> {
+        // A name shared by several functions maps to `None` and can only be
+        // referred to by index
+        let mut function_indices: HashMap<&str, Option<usize>> = HashMap::new();
+        for (i, function) in self.functions.iter().enumerate() {
+            function_indices
+                .entry(function.name.as_str())
+                .and_modify(|index| *index = None)
+                .or_insert(Some(i));
+        }
+        let resolve = |operand: &PendingOperand| -> Result<Operand, AsmError> {
+            match operand {
+                PendingOperand::Ready(operand) => Ok(operand.clone()),
+                PendingOperand::Label(name, token) => self
+                    .labels
+                    .get(name)
+                    .map(|address| Operand::Address(*address))
+                    .ok_or_else(|| error_at(token, format!("undefined label '{}'", name))),
+                PendingOperand::Function(name, token) => {
+                    match function_indices.get(name.as_str()) {
+                        Some(Some(index)) => Ok(Operand::Function(*index)),
+                        Some(None) => {
+                            Err(error_at(token, format!("ambiguous function '{}'", name)))
+                        }
+                        None => Err(error_at(token, format!("undefined function '{}'", name))),
+                    }
+                }
+            }
+        };
+
+        let mut instructions = Vec::new();
+        for pending in self.instructions.iter() {
+            let operands = pending
+                .operands
+                .iter()
+                .map(resolve)
+                .collect::<Result<Vec<_>, _>>()?;
+            let instruction = Instruction::from_operands(pending.opcode, operands)
+                .ok_or_else(|| error_at(&pending.token, "invalid operands"))?;
+            instructions.push(instruction);
+        }
+
+        let mut functions = Vec::new();
+        for function in self.functions.iter() {
+            let Operand::Address(address) = resolve(&function.label)? else {
+                unreachable!("function labels always resolve to addresses");
+            };
+            functions.push(FunctionProto {
+                name: function.name.clone(),
+                address,
+                arity: function.arity,
+                register_count: function.register_count,
+            });
+        }
+
+        Ok(Program::new(instructions, self.constant_pool)
+            .with_functions(functions)
+            .with_debug_info(self.debug_info))
+    }
+
+    /// `.const <value>`, `.function <name> <arity> <registers> <label>` or
+    /// `.loc <file> <line> <column>`
+    fn directive(&mut self) -> Result<(), AsmError> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Identifier(name) if name == "const" => {
+                let value = self.value()?;
+                self.constant_pool.push(value);
+            }
+            TokenKind::Identifier(name) if name == "function" => {
+                let name = self.name()?;
+                let arity = self.unsigned()?;
+                let register_count = self.unsigned()?;
+                let label = self.address()?;
+                self.functions.push(PendingFunction {
+                    name,
+                    arity,
+                    register_count,
+                    label,
+                });
+            }
+            TokenKind::Identifier(name) if name == "loc" => {
+                let file = self.name()?;
+                let line = self.unsigned()?;
+                let column = self.unsigned()?;
+                let address = self.instructions.len();
+                self.debug_info
+                    .get_or_insert_default()
+                    .add(address, &file, line, column);
+            }
+            _ => {
+                return Err(error_at(&token, "expected '.const', '.function' or '.loc'"));
+            }
+        }
+        Ok(())
+    }
+
+    fn operands(&mut self, opcode: Opcode) -> Result<Vec<PendingOperand>, AsmError> {
+        let mut operands = Vec::new();
+        for (i, kind) in opcode.operand_kinds().iter().enumerate() {
+            if *kind == OperandKind::Captures && self.at_end_of_line() {
+                operands.push(PendingOperand::Ready(Operand::Captures(Vec::new())));
+                break;
+            }
+            if i > 0 {
+                self.expect_symbol(',')?;
+            }
+            operands.push(self.operand(*kind)?);
+        }
+        Ok(operands)
+    }
+
+    fn operand(&mut self, kind: OperandKind) -> Result<PendingOperand, AsmError> {
+        let operand = match kind {
+            OperandKind::Register => Operand::Register(self.register()?),
+            OperandKind::OptionalRegister => {
+                if self.peek().kind == TokenKind::Identifier("_".to_string()) {
+                    self.next();
+                    Operand::OptionalRegister(None)
+                } else {
+                    Operand::OptionalRegister(Some(self.register()?))
+                }
+            }
+            OperandKind::Address => return self.address(),
+            OperandKind::Constant => {
+                self.expect_symbol('#')?;
+                Operand::Constant(self.unsigned()?)
+            }
+            OperandKind::Function => {
+                if let TokenKind::Identifier(name) = &self.peek().kind {
+                    let name = name.clone();
+                    return Ok(PendingOperand::Function(name, self.next()));
+                }
+                Operand::Function(self.unsigned()?)
+            }
+            OperandKind::Upvalue => {
+                self.expect_symbol('^')?;
+                Operand::Upvalue(self.unsigned()?)
+            }
+            OperandKind::Number => Operand::Number(self.unsigned()?),
+            OperandKind::Boolean => match self.value()? {
+                VmValue::Boolean(value) => Operand::Boolean(value),
+                _ => return Err(self.error_before("expected 'true' or 'false'")),
+            },
+            OperandKind::Name => Operand::Name(self.name()?),
+            OperandKind::Value => Operand::Value(self.value()?),
+            OperandKind::Captures => {
+                let mut captures = vec![self.capture()?];
+                while self.peek().kind == TokenKind::Symbol(',') {
+                    self.next();
+                    captures.push(self.capture()?);
+                }
+                Operand::Captures(captures)
+            }
+        };
+        Ok(PendingOperand::Ready(operand))
+    }
+
+    /// `r<n>` register, `^<n>` upvalue, or a variable name
+    fn capture(&mut self) -> Result<Capture, AsmError> {
+        if self.peek().kind == TokenKind::Symbol('^') {
+            self.next();
+            return Ok(Capture::Upvalue(self.unsigned()?));
+        }
+        if let TokenKind::Identifier(name) = &self.peek().kind
+            && let Some(register) = parse_register(name)
+        {
+            self.next();
+            return Ok(Capture::Register(register));
+        }
+        Ok(Capture::Variable(self.name()?))
+    }
+
+    fn address(&mut self) -> Result<PendingOperand, AsmError> {
+        if let TokenKind::Identifier(name) = &self.peek().kind {
+            let name = name.clone();
+            return Ok(PendingOperand::Label(name, self.next()));
+        }
+        Ok(PendingOperand::Ready(Operand::Address(self.unsigned()?)))
+    }
+
+    fn register(&mut self) -> Result<usize, AsmError> {
+        let token = self.next();
+        if let TokenKind::Identifier(name) = &token.kind
+            && let Some(register) = parse_register(name)
+        {
+            return Ok(register);
+        }
+        Err(error_at(&token, "expected register"))
+    }
+
+    fn name(&mut self) -> Result<String, AsmError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Identifier(name) | TokenKind::String(name) => Ok(name),
+            _ => Err(error_at(&token, "expected name")),
+        }
+    }
+
+    fn unsigned(&mut self) -> Result<usize, AsmError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Integer(n) if n >= 0 => Ok(n as usize),
+            _ => Err(error_at(&token, "expected non-negative integer")),
+        }
+    }
+
+    fn value(&mut self) -> Result<VmValue, AsmError> {
+        let token = self.next();
+        let value = match &token.kind {
+            TokenKind::Symbol('-') => self.value_negated(&token)?,
+            TokenKind::Integer(n) => VmValue::Int(
+                i32::try_from(*n)
+                    .map_err(|_| error_at(&token, format!("integer {} is out of range", n)))?,
+            ),
+            TokenKind::Float(n) => VmValue::Float(*n),
+            TokenKind::String(s) => VmValue::String(s.clone()),
+            TokenKind::Identifier(name) => match name.as_str() {
+                "true" => VmValue::Boolean(true),
+                "false" => VmValue::Boolean(false),
+                "null" => VmValue::Null,
+                "inf" => VmValue::Float(f64::INFINITY),
+                "NaN" => VmValue::Float(f64::NAN),
+                _ => return Err(error_at(&token, format!("unknown literal '{}'", name))),
+            },
+            TokenKind::Symbol('[') => {
+                let array = DynamicArray::new();
+                self.skip_newlines();
+                while self.peek().kind != TokenKind::Symbol(']') {
+                    array.0.borrow_mut().push(self.value()?);
+                    self.list_separator(']')?;
+                }
+                self.next();
+                VmValue::DynamicArray(array)
+            }
+            TokenKind::Symbol('{') => {
+                let mut object = Object::new();
+                self.skip_newlines();
+                while self.peek().kind != TokenKind::Symbol('}') {
+                    let key = self.value()?;
+                    self.expect_symbol(':')?;
+                    let value = self.value()?;
+                    object.new_index(key, value);
+                    self.list_separator('}')?;
+                }
+                self.next();
+                VmValue::Object(object)
+            }
+            _ => return Err(error_at(&token, "expected value")),
+        };
+        Ok(value)
+    }
+
+    /// Parses the number following a `-`. Integers are negated before the
+    /// range check so that `i32::MIN` can be written.
+    fn value_negated(&mut self, minus: &Token) -> Result<VmValue, AsmError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Integer(n) => i32::try_from(-n)
+                .map(VmValue::Int)
+                .map_err(|_| error_at(&token, format!("integer -{} is out of range", n))),
+            TokenKind::Float(n) => Ok(VmValue::Float(-n)),
+            TokenKind::Identifier(ref name) if name == "inf" => {
+                Ok(VmValue::Float(f64::NEG_INFINITY))
+            }
+            _ => Err(error_at(minus, "expected number after '-'")),
+        }
+    }
+
+    /// Consumes the `,` between list elements, unless the list ends with `close`
+    fn list_separator(&mut self, close: char) -> Result<(), AsmError> {
+        self.skip_newlines();
+        if self.peek().kind != TokenKind::Symbol(close) {
+            self.expect_symbol(',')?;
+            self.skip_newlines();
+        }
+        Ok(())
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), AsmError> {
+        let token = self.next();
+        if token.kind == TokenKind::Symbol(symbol) {
+            Ok(())
+        } else {
+            Err(error_at(&token, format!("expected '{}'", symbol)))
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), AsmError> {
+        if self.at_end_of_line() {
+            Ok(())
+        } else {
+            Err(error_at(self.peek(), "expected end of line"))
+        }
+    }
+
+    fn at_end_of_line(&self) -> bool {
+        matches!(self.peek().kind, TokenKind::Newline | TokenKind::Eof)
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek().kind == TokenKind::Newline {
+            self.next();
+        }
+    }
+
+    fn error_before(&self, message: &str) -> AsmError {
+        error_at(&self.tokens[self.position.saturating_sub(1)], message)
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if token.kind != TokenKind::Eof {
+            self.position += 1;
+        }
+        token
+    }
+}
+
+fn error_at(token: &Token, message: impl Into<String>) -> AsmError {
+    AsmError::new(token.line, token.column, message)
+}
+
+fn parse_register(name: &str) -> Option<usize> {
+    let digits = name.strip_prefix('r')?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
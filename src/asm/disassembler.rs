@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    debug_info::LineEntry,
+    function::Capture,
+    instruction::Instruction,
+    opcode::{Opcode, Operand},
+    serde::Program,
+    value::VmValue,
+};
+
+const COMMENT_COLUMN: usize = 40;
+const KEYWORDS: [&str; 6] = ["_", "true", "false", "null", "inf", "NaN"];
+
+/// Renders `program` as `.ryasm` source that assembles back into an equal program
+pub fn disassemble(program: &Program) -> String {
+    let disassembler = Disassembler::new(program);
+    let mut out = String::new();
+
+    for (i, constant) in program.constant_pool.iter().enumerate() {
+        let line = format!(".const {}", format_value(constant));
+        push_commented(&mut out, &line, &format!("#{}", i));
+    }
+    if !program.constant_pool.is_empty() {
+        out.push('\n');
+    }
+
+    for function in program.functions.iter() {
+        out.push_str(&format!(
+            ".function {} {} {} {}\n",
+            format_name(&function.name),
+            function.arity,
+            function.register_count,
+            disassembler.format_address(function.address)
+        ));
+    }
+    if !program.functions.is_empty() {
+        out.push('\n');
+    }
+
+    let debug_info = program.debug_info.as_ref();
+    let mut entries = debug_info
+        .map(|debug_info| debug_info.entries.iter())
+        .unwrap_or_default()
+        .peekable();
+    let loc = |entry: &LineEntry| {
+        format!(
+            "    .loc {} {} {}\n",
+            format_name(&debug_info.unwrap().files[entry.file]),
+            entry.line,
+            entry.column
+        )
+    };
+    for (i, instruction) in program.instructions.iter().enumerate() {
+        if let Some(label) = disassembler.labels.get(&i) {
+            out.push_str(&format!("{}:\n", label));
+        }
+        while let Some(entry) = entries.next_if(|entry| entry.instruction <= i) {
+            out.push_str(&loc(entry));
+        }
+        let line = format!("    {}", disassembler.format_instruction(instruction));
+        push_commented(&mut out, &line, &format!("{:04}", i));
+    }
+    if let Some(label) = disassembler.labels.get(&program.instructions.len()) {
+        out.push_str(&format!("{}:\n", label));
+    }
+    for entry in entries {
+        out.push_str(&loc(entry));
+    }
+
+    out
+}
+
+/// Each instruction of `program` in `.ryasm` syntax, jump targets named by
+/// the labels `disassemble` gives them
+pub fn instruction_lines(program: &Program) -> Vec<String> {
+    let disassembler = Disassembler::new(program);
+    program
+        .instructions
+        .iter()
+        .map(|instruction| disassembler.format_instruction(instruction))
+        .collect()
+}
+
+struct Disassembler<'a> {
+    labels: HashMap<usize, String>,
+    /// Function table entries that can be referred to by name
+    function_names: HashMap<usize, &'a str>,
+}
+
+impl<'a> Disassembler<'a> {
+    fn new(program: &'a Program) -> Self {
+        let mut seen = HashSet::new();
+        let duplicates: HashSet<&str> = program
+            .functions
+            .iter()
+            .map(|function| function.name.as_str())
+            .filter(|name| !seen.insert(*name))
+            .collect();
+        let function_names: HashMap<usize, &str> = program
+            .functions
+            .iter()
+            .enumerate()
+            .filter(|(_, function)| {
+                is_identifier(&function.name) && !duplicates.contains(function.name.as_str())
+            })
+            .map(|(i, function)| (i, function.name.as_str()))
+            .collect();
+
+        let mut labels = HashMap::new();
+        for (i, function) in program.functions.iter().enumerate() {
+            if let Some(name) = function_names.get(&i)
+                && !is_synthesized_label(name)
+            {
+                labels.entry(function.address).or_insert(name.to_string());
+            }
+        }
+        let targets = program
+            .instructions
+            .iter()
+            .flat_map(|instruction| instruction.operands())
+            .filter_map(|operand| match operand {
+                Operand::Address(address) => Some(address),
+                _ => None,
+            })
+            .chain(program.functions.iter().map(|function| function.address));
+        for address in targets {
+            labels
+                .entry(address)
+                .or_insert_with(|| format!("L{}", address));
+        }
+        labels.retain(|address, _| *address <= program.instructions.len());
+
+        Self {
+            labels,
+            function_names,
+        }
+    }
+
+    fn format_instruction(&self, instruction: &Instruction) -> String {
+        let opcode: Opcode = instruction.opcode();
+        let operands: Vec<String> = instruction
+            .operands()
+            .iter()
+            .map(|operand| self.format_operand(operand))
+            .filter(|operand| !operand.is_empty())
+            .collect();
+
+        if operands.is_empty() {
+            opcode.name().to_string()
+        } else {
+            format!("{} {}", opcode.name(), operands.join(", "))
+        }
+    }
+
+    fn format_operand(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Register(register) => format!("r{}", register),
+            Operand::OptionalRegister(Some(register)) => format!("r{}", register),
+            Operand::OptionalRegister(None) => "_".to_string(),
+            Operand::Address(address) => self.format_address(*address),
+            Operand::Constant(index) => format!("#{}", index),
+            Operand::Function(index) => self
+                .function_names
+                .get(index)
+                .map_or(index.to_string(), |name| name.to_string()),
+            Operand::Upvalue(index) => format!("^{}", index),
+            Operand::Number(n) => n.to_string(),
+            Operand::Boolean(value) => value.to_string(),
+            Operand::Name(name) => format_name(name),
+            Operand::Value(value) => format_value(value),
+            Operand::Captures(captures) => captures
+                .iter()
+                .map(|capture| match capture {
+                    Capture::Variable(name) => format_name(name),
+                    Capture::Register(register) => format!("r{}", register),
+                    Capture::Upvalue(index) => format!("^{}", index),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+
+    fn format_address(&self, address: usize) -> String {
+        self.labels
+            .get(&address)
+            .cloned()
+            .unwrap_or_else(|| address.to_string())
+    }
+}
+
+fn push_commented(out: &mut String, line: &str, comment: &str) {
+    let padding = COMMENT_COLUMN.saturating_sub(line.len()).max(1);
+    out.push_str(line);
+    out.push_str(&" ".repeat(padding));
+    out.push_str("; ");
+    out.push_str(comment);
+    out.push('\n');
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+fn is_synthesized_label(name: &str) -> bool {
+    name.strip_prefix('L')
+        .is_some_and(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn is_register_name(name: &str) -> bool {
+    name.strip_prefix('r')
+        .is_some_and(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Names that could be mistaken for another operand are written as strings
+fn format_name(name: &str) -> String {
+    if is_identifier(name) && !is_register_name(name) {
+        name.to_string()
+    } else {
+        format_string(name)
+    }
+}
+
+fn format_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats a value as an assembler literal. Floats always keep a fractional
+/// part or exponent so they read back as floats.
+pub fn format_value(value: &VmValue) -> String {
+    match value {
+        VmValue::Float(v) => format!("{:?}", v),
+        VmValue::Int(v) => v.to_string(),
+        VmValue::String(s) => format_string(s),
+        VmValue::Boolean(v) => v.to_string(),
+        VmValue::DynamicArray(array) => {
+            let elements: Vec<String> = array.0.borrow().iter().map(format_value).collect();
+            format!("[{}]", elements.join(", "))
+        }
+        VmValue::Object(object) => {
+            let mut entries: Vec<String> = object
+                .0
+                .borrow()
+                .iter()
+                .map(|(key, value)| format!("{}: {}", format_value(key), format_value(value)))
+                .collect();
+            entries.sort();
+            if entries.is_empty() {
+                "{}".to_string()
+            } else {
+                format!("{{ {} }}", entries.join(", "))
+            }
+        }
+        VmValue::Closure(_) | VmValue::Coroutine(_) => value.to_string(),
+        VmValue::Null => "null".to_string(),
+    }
+}
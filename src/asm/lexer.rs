@@ -0,0 +1,179 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::error::asm::AsmError;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum TokenKind {
+    Identifier(String),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    /// One of `, : . # ^ - [ ] { }`
+    Symbol(char),
+    Newline,
+    Eof,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+const SYMBOLS: &str = ",:.#^-[]{}";
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+    tokens: Vec<Token>,
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Token>, AsmError> {
+    let mut lexer = Lexer {
+        chars: source.chars().peekable(),
+        line: 1,
+        column: 1,
+        tokens: Vec::new(),
+    };
+    lexer.run()?;
+    Ok(lexer.tokens)
+}
+
+impl Lexer<'_> {
+    fn run(&mut self) -> Result<(), AsmError> {
+        while let Some(&c) = self.chars.peek() {
+            let (line, column) = (self.line, self.column);
+            let kind = match c {
+                '\n' => {
+                    self.advance();
+                    TokenKind::Newline
+                }
+                ';' => {
+                    while self.chars.peek().is_some_and(|&c| c != '\n') {
+                        self.advance();
+                    }
+                    continue;
+                }
+                c if c.is_whitespace() => {
+                    self.advance();
+                    continue;
+                }
+                '"' => TokenKind::String(self.string()?),
+                c if c.is_ascii_digit() => self.number()?,
+                c if c.is_alphabetic() || c == '_' => {
+                    let mut identifier = String::new();
+                    while let Some(&c) = self.chars.peek() {
+                        if !(c.is_alphanumeric() || c == '_') {
+                            break;
+                        }
+                        identifier.push(c);
+                        self.advance();
+                    }
+                    TokenKind::Identifier(identifier)
+                }
+                c if SYMBOLS.contains(c) => {
+                    self.advance();
+                    TokenKind::Symbol(c)
+                }
+                c => return Err(self.error(format!("unexpected character '{}'", c))),
+            };
+            self.tokens.push(Token { kind, line, column });
+        }
+
+        self.tokens.push(Token {
+            kind: TokenKind::Eof,
+            line: self.line,
+            column: self.column,
+        });
+        Ok(())
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: String) -> AsmError {
+        AsmError::new(self.line, self.column, message)
+    }
+
+    fn string(&mut self) -> Result<String, AsmError> {
+        self.advance(); // opening quote
+        let mut s = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let escaped = match self.advance() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some(c) => return Err(self.error(format!("unknown escape '\\{}'", c))),
+                        None => return Err(self.error("unterminated string".to_string())),
+                    };
+                    s.push(escaped);
+                }
+                Some('\n') | None => return Err(self.error("unterminated string".to_string())),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<TokenKind, AsmError> {
+        let mut text = String::new();
+        let mut is_float = false;
+        self.digits(&mut text);
+
+        if self.chars.peek() == Some(&'.') {
+            is_float = true;
+            text.push('.');
+            self.advance();
+            self.digits(&mut text);
+        }
+        if let Some(&c) = self.chars.peek()
+            && (c == 'e' || c == 'E')
+        {
+            is_float = true;
+            text.push('e');
+            self.advance();
+            if let Some(&sign) = self.chars.peek()
+                && (sign == '+' || sign == '-')
+            {
+                text.push(sign);
+                self.advance();
+            }
+            self.digits(&mut text);
+        }
+
+        if is_float {
+            text.parse()
+                .map(TokenKind::Float)
+                .map_err(|_| self.error(format!("invalid float literal '{}'", text)))
+        } else {
+            text.parse()
+                .map(TokenKind::Integer)
+                .map_err(|_| self.error(format!("integer literal '{}' is too large", text)))
+        }
+    }
+
+    fn digits(&mut self, text: &mut String) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            text.push(c);
+            self.advance();
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Textual assembly language for Ryde bytecode (`.ryasm`).
+//!
+//! Each line holds a label (`name:`), a directive or an instruction, and `;`
+//! starts a comment. Instructions are written as the opcode followed by
+//! comma-separated operands in the field order of the `Instruction` variant:
+//!
+//! - registers: `r0`, or `_` where a register is optional
+//! - addresses: a label name or an instruction index
+//! - constant pool indices: `#0`
+//! - functions: a name declared with `.function`, or a function table index
+//! - upvalues: `^0`
+//! - values: `42`, `-1.5`, `"text"`, `true`, `null`, `[1, 2]`, `{"key": 1}`
+//! - variable names: an identifier or a string literal
+//! - closure captures: any number of registers, upvalues and variable names
+//!
+//! Directives:
+//!
+//! - `.const <value>` appends a value to the constant pool
+//! - `.function <name> <arity> <registers> <label>` adds a function table
+//!   entry. The name may be a string literal; a name shared by several
+//!   functions can only be referred to by index.
+//! - `.loc <file> <line> <column>` gives the source position of the next
+//!   instruction and those after it, recorded in the program's debug info.
+//!   The file is an identifier or a string literal.
+//!
+//! `disassemble` produces this syntax from a `Program`, and assembling its
+//! output yields an equal program.
+pub mod assembler;
+pub mod disassembler;
+pub mod lexer;
+
+use crate::{asm::assembler::Assembler, error::asm::AsmError, serde::Program};
+
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    Assembler::new(source)?.assemble()
+}
+
+pub fn disassemble(program: &Program) -> String {
+    disassembler::disassemble(program)
+}
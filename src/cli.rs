@@ -0,0 +1,311 @@
+//! Command line of the `ryde` binary.
+//!
+//! ```text
+//! ryde run <file> [--registers N]
+//! ryde compile <file> [-o <out>] [--target sysv64|win64|c] [--registers N]
+//! ryde disasm <file>
+//! ryde debug <file> [--registers N]
+//! ryde asm <src> [-o <bin>]
+//! ryde verify <file> [--registers N]
+//! ```
+//!
+//! Programs are read as serialized bytecode, or assembled first when the file
+//! name ends in `.ryasm`. `compile` writes the runtime library next to its
+//! output, to be built along with it as in `aot_compile.sh`. `debug` reads
+//! commands for [`debugger::repl`] from stdin.
+use std::{fs, io, path::Path};
+
+use crate::{
+    aot::{self, runtime, state::AotTarget},
+    asm,
+    debugger::{self, Debugger},
+    error::cli::CliError,
+    serde::{Program, deserializer, serializer},
+    verifier,
+    vm::Vm,
+};
+
+/// Registers of the top-level window when `--registers` is not given
+pub const DEFAULT_REGISTERS: usize = 16;
+
+pub const USAGE: &str = "\
+Usage:
+  ryde run <file> [--registers N]
+  ryde compile <file> [-o <out>] [--target sysv64|win64|c] [--registers N]
+  ryde disasm <file>
+  ryde debug <file> [--registers N]
+  ryde asm <src> [-o <bin>]
+  ryde verify <file> [--registers N]";
+
+/// Backend used by `compile`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Target {
+    Asm(AotTarget),
+    C,
+}
+
+impl Target {
+    fn parse(name: &str) -> Result<Target, CliError> {
+        match name {
+            "sysv64" => Ok(Target::Asm(AotTarget::SysV64)),
+            "win64" => Ok(Target::Asm(AotTarget::Win64)),
+            "c" => Ok(Target::C),
+            _ => Err(CliError::Usage(format!(
+                "Unknown target '{}', expected sysv64, win64 or c",
+                name
+            ))),
+        }
+    }
+
+    fn default_output(&self) -> &'static str {
+        match self {
+            Target::Asm(_) => "out.asm",
+            Target::C => "out.c",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run {
+        file: String,
+        registers: usize,
+    },
+    Compile {
+        file: String,
+        output: String,
+        target: Target,
+        registers: usize,
+    },
+    Disasm {
+        file: String,
+    },
+    Debug {
+        file: String,
+        registers: usize,
+    },
+    Asm {
+        source: String,
+        output: String,
+    },
+    Verify {
+        file: String,
+        registers: usize,
+    },
+}
+
+/// Options of a subcommand, in any order around its single positional argument
+#[derive(Default)]
+struct Options {
+    positional: Option<String>,
+    output: Option<String>,
+    target: Option<String>,
+    registers: Option<usize>,
+}
+
+impl Options {
+    fn parse(subcommand: &str, args: &[String], allowed: &[&str]) -> Result<Options, CliError> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = arg.as_str();
+            if !flag.starts_with('-') {
+                if options.positional.is_some() {
+                    return Err(CliError::Usage(format!(
+                        "Unexpected argument '{}' for '{}'",
+                        arg, subcommand
+                    )));
+                }
+                options.positional = Some(arg.clone());
+                continue;
+            }
+            if !allowed.contains(&flag) {
+                return Err(CliError::Usage(format!(
+                    "Unknown option '{}' for '{}'",
+                    arg, subcommand
+                )));
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| CliError::Usage(format!("Missing value for '{}'", arg)))?
+                .clone();
+            match flag {
+                "-o" => options.output = Some(value),
+                "--target" => options.target = Some(value),
+                "--registers" => {
+                    let registers = value.parse().map_err(|_| {
+                        CliError::Usage(format!("Invalid register count '{}'", value))
+                    })?;
+                    options.registers = Some(registers);
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(options)
+    }
+
+    fn file(&mut self, subcommand: &str) -> Result<String, CliError> {
+        self.positional
+            .take()
+            .ok_or_else(|| CliError::Usage(format!("Missing file for '{}'", subcommand)))
+    }
+
+    fn registers(&self) -> usize {
+        self.registers.unwrap_or(DEFAULT_REGISTERS)
+    }
+}
+
+/// Parses the arguments following the program name
+pub fn parse(args: &[String]) -> Result<Command, CliError> {
+    let Some((subcommand, args)) = args.split_first() else {
+        return Err(CliError::Usage(USAGE.to_string()));
+    };
+    let subcommand = subcommand.as_str();
+    let command = match subcommand {
+        "run" => {
+            let mut options = Options::parse(subcommand, args, &["--registers"])?;
+            Command::Run {
+                file: options.file(subcommand)?,
+                registers: options.registers(),
+            }
+        }
+        "compile" => {
+            let mut options = Options::parse(subcommand, args, &["-o", "--target", "--registers"])?;
+            let target = match options.target.as_deref() {
+                Some(name) => Target::parse(name)?,
+                None => Target::Asm(AotTarget::host()),
+            };
+            Command::Compile {
+                file: options.file(subcommand)?,
+                output: options
+                    .output
+                    .take()
+                    .unwrap_or_else(|| target.default_output().to_string()),
+                target,
+                registers: options.registers(),
+            }
+        }
+        "disasm" => Command::Disasm {
+            file: Options::parse(subcommand, args, &[])?.file(subcommand)?,
+        },
+        "debug" => {
+            let mut options = Options::parse(subcommand, args, &["--registers"])?;
+            Command::Debug {
+                file: options.file(subcommand)?,
+                registers: options.registers(),
+            }
+        }
+        "asm" => {
+            let mut options = Options::parse(subcommand, args, &["-o"])?;
+            Command::Asm {
+                source: options.file(subcommand)?,
+                output: options.output.take().unwrap_or("out.bin".to_string()),
+            }
+        }
+        "verify" => {
+            let mut options = Options::parse(subcommand, args, &["--registers"])?;
+            Command::Verify {
+                file: options.file(subcommand)?,
+                registers: options.registers(),
+            }
+        }
+        _ => {
+            return Err(CliError::Usage(format!(
+                "Unknown command '{}'\n\n{}",
+                subcommand, USAGE
+            )));
+        }
+    };
+    Ok(command)
+}
+
+pub fn execute(command: Command) -> Result<(), CliError> {
+    match command {
+        Command::Run { file, registers } => {
+            let program = load_program(&file)?;
+            let mut vm = Vm::new(&program, registers);
+            vm.run_traced().map_err(CliError::Vm)
+        }
+        Command::Compile {
+            file,
+            output,
+            target,
+            registers,
+        } => {
+            let program = load_program(&file)?;
+            let source = match target {
+                Target::Asm(target) => aot::compile(&program, target),
+                Target::C => aot::c::compile(&program, registers),
+            }
+            .map_err(CliError::Aot)?;
+            write_file(&output, source.as_bytes())?;
+            // the runtime library is built along with the output
+            let directory = Path::new(&output).parent().unwrap_or(Path::new(""));
+            for (name, source) in [
+                ("ryde_runtime.c", runtime::LIBRARY_SOURCE),
+                ("ryde_runtime.h", runtime::HEADER_SOURCE),
+            ] {
+                write_file(&directory.join(name).to_string_lossy(), source.as_bytes())?;
+            }
+            Ok(())
+        }
+        Command::Disasm { file } => {
+            print!("{}", asm::disassemble(&load_program(&file)?));
+            Ok(())
+        }
+        Command::Debug { file, registers } => {
+            let program = load_program(&file)?;
+            let mut vm = Vm::new(&program, registers);
+            let mut debugger = Debugger::new(&mut vm);
+            debugger::repl(&mut debugger, io::stdin().lock(), &mut io::stdout()).map_err(|error| {
+                CliError::Io {
+                    path: "<stdin>".to_string(),
+                    error,
+                }
+            })
+        }
+        Command::Asm { source, output } => {
+            let program = assemble_file(&source)?;
+            let binary = serializer::serialize(&program).map_err(CliError::Program)?;
+            write_file(&output, &binary)
+        }
+        Command::Verify { file, registers } => {
+            let diagnostics = verifier::verify(&load_program(&file)?, registers);
+            if diagnostics.is_empty() {
+                Ok(())
+            } else {
+                Err(CliError::Verification(diagnostics))
+            }
+        }
+    }
+}
+
+/// Reads a serialized program, or assembles a `.ryasm` file
+pub fn load_program(path: &str) -> Result<Program, CliError> {
+    if path.ends_with(".ryasm") {
+        return assemble_file(path);
+    }
+    let binary = fs::read(path).map_err(|error| CliError::Io {
+        path: path.to_string(),
+        error,
+    })?;
+    deserializer::deserialize(binary).map_err(CliError::Program)
+}
+
+fn assemble_file(path: &str) -> Result<Program, CliError> {
+    let source = fs::read_to_string(path).map_err(|error| CliError::Io {
+        path: path.to_string(),
+        error,
+    })?;
+    asm::assemble(&source).map_err(|error| CliError::Asm {
+        path: path.to_string(),
+        error,
+    })
+}
+
+fn write_file(path: &str, contents: &[u8]) -> Result<(), CliError> {
+    fs::write(path, contents).map_err(|error| CliError::Io {
+        path: path.to_string(),
+        error,
+    })
+}
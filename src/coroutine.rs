@@ -0,0 +1,84 @@
+use std::{
+    cell::RefCell,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use bincode::{
+    Decode, Encode,
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    impl_borrow_decode,
+};
+
+use crate::{function::Closure, vm::Thread};
+
+/// Coroutine value: a closure running on a register file and call stack of
+/// its own, suspended at each `YIELD` until it is resumed again.
+///
+/// Coroutines are runtime values only; they cannot be stored in a program.
+#[derive(Debug, Clone)]
+pub struct Coroutine(pub(crate) Rc<RefCell<CoroutineState>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    /// Not started yet, or stopped at a `YIELD`
+    Suspended,
+    /// Running, or resuming another coroutine
+    Running,
+    /// Returned, or ended by an uncaught exception
+    Dead,
+}
+
+#[derive(Debug)]
+pub(crate) struct CoroutineState {
+    pub(crate) status: CoroutineStatus,
+    pub(crate) closure: Closure,
+    /// Saved while the coroutine is suspended. It has no frames until the
+    /// first resume calls the closure.
+    pub(crate) thread: Thread,
+    /// Register of the pending `YIELD` receiving the next resumed value
+    pub(crate) yield_target: Option<usize>,
+}
+
+impl Coroutine {
+    pub fn new(closure: Closure) -> Self {
+        Self(Rc::new(RefCell::new(CoroutineState {
+            status: CoroutineStatus::Suspended,
+            closure,
+            thread: Thread::default(),
+            yield_target: None,
+        })))
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.0.borrow().status
+    }
+}
+
+impl PartialEq for Coroutine {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Hash for Coroutine {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state);
+    }
+}
+
+impl Encode for Coroutine {
+    fn encode<E: Encoder>(&self, _encoder: &mut E) -> Result<(), EncodeError> {
+        Err(EncodeError::Other("coroutines cannot be encoded"))
+    }
+}
+
+impl<Context> Decode<Context> for Coroutine {
+    fn decode<D: Decoder<Context = Context>>(_decoder: &mut D) -> Result<Self, DecodeError> {
+        Err(DecodeError::Other("coroutines cannot be decoded"))
+    }
+}
+
+impl_borrow_decode!(Coroutine);
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use bincode::{Decode, Encode};
+
+/// Maps instruction indices to positions in the source a program was
+/// compiled from.
+///
+/// Like a DWARF line table, each entry gives the location of its instruction
+/// and of every following instruction up to the next entry.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    /// Sorted by instruction, at most one entry per instruction
+    pub entries: Vec<LineEntry>,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct LineEntry {
+    pub instruction: usize,
+    /// Index into `DebugInfo::files`
+    pub file: usize,
+    /// 1-based line and column
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Resolved source position of an instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the index of `file`, adding it if it is new
+    pub fn file(&mut self, file: &str) -> usize {
+        match self.files.iter().position(|name| name == file) {
+            Some(index) => index,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Locates `instruction` and the instructions after it. Entries must be
+    /// added in instruction order; a later entry for the same instruction
+    /// replaces the earlier one.
+    pub fn add(&mut self, instruction: usize, file: &str, line: usize, column: usize) {
+        let entry = LineEntry {
+            instruction,
+            file: self.file(file),
+            line,
+            column,
+        };
+        match self.entries.last_mut() {
+            Some(last) if last.instruction == instruction => *last = entry,
+            Some(last) if last.instruction > instruction => {
+                panic!("debug info entries must be added in instruction order")
+            }
+            _ => self.entries.push(entry),
+        }
+    }
+
+    /// Source position of the instruction at `index`, if any entry covers it
+    pub fn location(&self, index: usize) -> Option<SourceLocation> {
+        let position = self
+            .entries
+            .partition_point(|entry| entry.instruction <= index);
+        let entry = self.entries.get(position.checked_sub(1)?)?;
+        Some(SourceLocation {
+            file: self.files.get(entry.file)?.clone(),
+            line: entry.line,
+            column: entry.column,
+        })
+    }
+}
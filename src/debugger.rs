@@ -0,0 +1,391 @@
+//! Step debugger over a [`Vm`].
+//!
+//! A [`Debugger`] executes the VM one instruction at a time, stopping at
+//! breakpoints set on instruction indices and when a watched register or
+//! variable changes. Between stops the VM can be inspected through its own
+//! API: [`Vm::window`], [`Vm::variables`], [`Vm::call_stack`] and so on.
+//!
+//! [`repl`] drives a debugger from text commands, as `ryde debug` does.
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{self, BufRead, Write},
+};
+
+use crate::{asm::disassembler, error::vm::VmError, vm::Vm};
+
+/// Instructions shown around `pc` by the `list` command
+const LIST_CONTEXT: usize = 3;
+
+/// Something whose value the debugger stops on when it changes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watch {
+    /// A slot of the register stack. Registers are resolved to slots in the
+    /// window active when the watchpoint is added, so a watched register of
+    /// a function keeps its meaning while that function calls others.
+    Slot(usize),
+    /// A variable, looked up from whichever instruction runs
+    Variable(String),
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watch::Slot(slot) => write!(f, "register slot {}", slot),
+            Watch::Variable(name) => write!(f, "variable '{}'", name),
+        }
+    }
+}
+
+/// Why the debugger stopped
+#[derive(Debug)]
+pub enum Stop {
+    /// A single step finished
+    Step,
+    /// `pc` reached a breakpoint, whose instruction has not run yet
+    Breakpoint(usize),
+    /// A watched value changed. Values are displayed, or `None` when the
+    /// register or variable does not exist.
+    Watchpoint {
+        watch: Watch,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    /// The program ran past its last instruction
+    Finished,
+    /// The instruction at `pc` failed. `pc` is left on it.
+    Error(VmError),
+}
+
+pub struct Debugger<'v, 'a> {
+    pub vm: &'v mut Vm<'a>,
+    breakpoints: BTreeSet<usize>,
+    /// Watched values with their last displayed value
+    watchpoints: Vec<(Watch, Option<String>)>,
+}
+
+impl<'v, 'a> Debugger<'v, 'a> {
+    pub fn new(vm: &'v mut Vm<'a>) -> Self {
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Returns false if there already was a breakpoint at `index`
+    pub fn add_breakpoint(&mut self, index: usize) -> bool {
+        self.breakpoints.insert(index)
+    }
+
+    /// Returns false if there was no breakpoint at `index`
+    pub fn remove_breakpoint(&mut self, index: usize) -> bool {
+        self.breakpoints.remove(&index)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Watches a register of the current window
+    pub fn watch_register(&mut self, register: usize) -> Result<(), VmError> {
+        let slot = self.vm.register_slot(register)?;
+        self.watch(Watch::Slot(slot));
+        Ok(())
+    }
+
+    pub fn watch(&mut self, watch: Watch) {
+        let value = self.value(&watch);
+        self.watchpoints.push((watch, value));
+    }
+
+    /// Removes the watchpoint at `index` in [`Debugger::watchpoints`]
+    pub fn unwatch(&mut self, index: usize) -> Option<Watch> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index).0)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &Watch> + '_ {
+        self.watchpoints.iter().map(|(watch, _)| watch)
+    }
+
+    /// Executes one instruction
+    pub fn step(&mut self) -> Stop {
+        if self.vm.is_finished() {
+            return Stop::Finished;
+        }
+        let pc = self.vm.pc;
+        if let Err(error) = self.vm.step() {
+            self.vm.pc = pc;
+            return Stop::Error(error);
+        }
+        if let Some(stop) = self.check_watchpoints() {
+            return stop;
+        }
+        if self.vm.is_finished() {
+            Stop::Finished
+        } else {
+            Stop::Step
+        }
+    }
+
+    /// Runs until a breakpoint, a watchpoint, an error or the end of the
+    /// program. The instruction at `pc` always runs, so continuing from a
+    /// breakpoint does not stop at it again.
+    pub fn resume(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Step if self.breakpoints.contains(&self.vm.pc) => {
+                    return Stop::Breakpoint(self.vm.pc);
+                }
+                Stop::Step => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    fn value(&self, watch: &Watch) -> Option<String> {
+        match watch {
+            Watch::Slot(slot) => self
+                .vm
+                .registers
+                .get(*slot)
+                .map(|register| register.borrow().to_string()),
+            Watch::Variable(name) => self.vm.variable(name).ok().map(|value| value.to_string()),
+        }
+    }
+
+    /// Updates the watched values, reporting the first that changed
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        let mut stop = None;
+        for index in 0..self.watchpoints.len() {
+            let new = self.value(&self.watchpoints[index].0);
+            let (watch, old) = &mut self.watchpoints[index];
+            if *old != new {
+                let old = std::mem::replace(old, new.clone());
+                stop.get_or_insert(Stop::Watchpoint {
+                    watch: watch.clone(),
+                    old,
+                    new,
+                });
+            }
+        }
+        stop
+    }
+}
+
+const HELP: &str = "\
+Commands:
+  s, step [n]          execute n instructions (default 1)
+  c, continue          run to the next breakpoint or watchpoint
+  b, break <index>     set a breakpoint at an instruction index
+  d, delete <index>    remove the breakpoint at an instruction index
+  w, watch r<n>|<name> stop when a register or variable changes
+  unwatch <n>          remove the n-th watchpoint
+  info                 list breakpoints and watchpoints
+  r, registers         show the registers of the current window
+  p, print r<n>|<name> show a register or variable
+  v, vars              show the visible variables
+  bt, backtrace        show the call stack
+  l, list              show the instructions around pc
+  h, help              show this help
+  q, quit              stop debugging";
+
+/// Reads debugger commands from `input` until `quit` or the end of input,
+/// writing responses to `output`
+pub fn repl<R: BufRead, W: Write>(
+    debugger: &mut Debugger,
+    input: R,
+    output: &mut W,
+) -> io::Result<()> {
+    let lines = disassembler::instruction_lines(debugger.vm.program);
+    write_location(debugger, &lines, output)?;
+    write!(output, "(ryde) ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, arguments)) = words.split_first() else {
+            write!(output, "(ryde) ")?;
+            output.flush()?;
+            continue;
+        };
+        match command {
+            "q" | "quit" => return Ok(()),
+            "s" | "step" => {
+                let count = match arguments.first() {
+                    Some(count) => match count.parse() {
+                        Ok(count) => count,
+                        Err(_) => {
+                            writeln!(output, "Invalid step count '{}'", count)?;
+                            0
+                        }
+                    },
+                    None => 1,
+                };
+                for i in 0..count {
+                    match debugger.step() {
+                        Stop::Step if i + 1 < count => {}
+                        stop => {
+                            write_stop(debugger, &lines, stop, output)?;
+                            break;
+                        }
+                    }
+                }
+            }
+            "c" | "continue" => {
+                let stop = debugger.resume();
+                write_stop(debugger, &lines, stop, output)?;
+            }
+            "b" | "break" | "d" | "delete" => match parse_index(arguments) {
+                Some(index) if command.starts_with('b') => {
+                    if index >= lines.len() {
+                        writeln!(output, "No instruction at {}", index)?;
+                    } else if debugger.add_breakpoint(index) {
+                        writeln!(output, "Breakpoint at {:04}", index)?;
+                    } else {
+                        writeln!(output, "Breakpoint at {:04} already set", index)?;
+                    }
+                }
+                Some(index) => {
+                    if debugger.remove_breakpoint(index) {
+                        writeln!(output, "Removed breakpoint at {:04}", index)?;
+                    } else {
+                        writeln!(output, "No breakpoint at {:04}", index)?;
+                    }
+                }
+                None => writeln!(output, "Expected an instruction index")?,
+            },
+            "w" | "watch" => match arguments.first() {
+                Some(target) => match parse_register(target) {
+                    Some(register) => match debugger.watch_register(register) {
+                        Ok(()) => writeln!(output, "Watching r{}", register)?,
+                        Err(error) => writeln!(output, "{}", error)?,
+                    },
+                    None => {
+                        debugger.watch(Watch::Variable(target.to_string()));
+                        writeln!(output, "Watching variable '{}'", target)?;
+                    }
+                },
+                None => writeln!(output, "Expected a register or variable")?,
+            },
+            "unwatch" => match parse_index(arguments).and_then(|index| debugger.unwatch(index)) {
+                Some(watch) => writeln!(output, "Removed watchpoint on {}", watch)?,
+                None => writeln!(output, "No such watchpoint")?,
+            },
+            "info" => {
+                for index in debugger.breakpoints() {
+                    writeln!(output, "breakpoint {:04}", index)?;
+                }
+                for (index, watch) in debugger.watchpoints().enumerate() {
+                    writeln!(output, "watchpoint {}: {}", index, watch)?;
+                }
+            }
+            "r" | "registers" => {
+                for (index, value) in debugger.vm.window().iter().enumerate() {
+                    writeln!(output, "r{} = {}", index, value)?;
+                }
+            }
+            "p" | "print" => match arguments.first() {
+                Some(target) => {
+                    let value = match parse_register(target) {
+                        Some(register) => debugger.vm.register(register),
+                        None => debugger.vm.variable(target),
+                    };
+                    match value {
+                        Ok(value) => writeln!(output, "{} = {}", target, value)?,
+                        Err(error) => writeln!(output, "{}", error)?,
+                    }
+                }
+                None => writeln!(output, "Expected a register or variable")?,
+            },
+            "v" | "vars" => {
+                for (name, value) in debugger.vm.variables() {
+                    writeln!(output, "{} = {}", name, value)?;
+                }
+            }
+            "bt" | "backtrace" => write_backtrace(debugger.vm, output)?,
+            "l" | "list" => {
+                let pc = debugger.vm.pc;
+                let start = pc.saturating_sub(LIST_CONTEXT);
+                let end = (pc + LIST_CONTEXT + 1).min(lines.len());
+                for (index, line) in lines.iter().enumerate().take(end).skip(start) {
+                    let marker = if index == pc { "=>" } else { "  " };
+                    writeln!(output, "{} {:04}: {}", marker, index, line)?;
+                }
+            }
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            _ => writeln!(output, "Unknown command '{}', try 'help'", command)?,
+        }
+        write!(output, "(ryde) ")?;
+        output.flush()?;
+    }
+    writeln!(output)
+}
+
+fn write_stop<W: Write>(
+    debugger: &Debugger,
+    lines: &[String],
+    stop: Stop,
+    output: &mut W,
+) -> io::Result<()> {
+    match stop {
+        Stop::Step => {}
+        Stop::Breakpoint(index) => writeln!(output, "Breakpoint at {:04}", index)?,
+        Stop::Watchpoint { watch, old, new } => {
+            let old = old.as_deref().unwrap_or("<none>");
+            let new = new.as_deref().unwrap_or("<none>");
+            writeln!(output, "Watchpoint on {}: {} -> {}", watch, old, new)?;
+        }
+        Stop::Finished => return writeln!(output, "Program finished"),
+        Stop::Error(error) => writeln!(output, "VM error: {}", error)?,
+    }
+    write_location(debugger, lines, output)
+}
+
+fn write_location<W: Write>(
+    debugger: &Debugger,
+    lines: &[String],
+    output: &mut W,
+) -> io::Result<()> {
+    match lines.get(debugger.vm.pc) {
+        Some(line) => writeln!(output, "=> {:04}: {}", debugger.vm.pc, line),
+        None => writeln!(output, "Program finished"),
+    }
+}
+
+/// Frames from the innermost out, ending with the top level
+fn write_backtrace<W: Write>(vm: &Vm, output: &mut W) -> io::Result<()> {
+    let mut pc = vm.pc;
+    for (depth, frame) in vm.call_stack.iter().rev().enumerate() {
+        let (base, count) = frame.window();
+        write!(
+            output,
+            "#{} {:04} in {}, registers {}..{}",
+            depth,
+            pc,
+            vm.frame_name(frame).unwrap_or("<subroutine>"),
+            base,
+            base + count
+        )?;
+        write_source_location(vm, pc, output)?;
+        // callers are shown at their call instruction
+        pc = frame.return_address().saturating_sub(1);
+    }
+    write!(output, "#{} {:04} in <top level>", vm.call_stack.len(), pc)?;
+    write_source_location(vm, pc, output)
+}
+
+fn write_source_location<W: Write>(vm: &Vm, pc: usize, output: &mut W) -> io::Result<()> {
+    match vm.location(pc) {
+        Some(location) => writeln!(output, " at {}", location),
+        None => writeln!(output),
+    }
+}
+
+fn parse_index(arguments: &[&str]) -> Option<usize> {
+    arguments.first()?.parse().ok()
+}
+
+fn parse_register(target: &str) -> Option<usize> {
+    target.strip_prefix('r')?.parse().ok()
+}
@@ -0,0 +1,65 @@
+use std::{error::Error, fmt};
+
+use crate::{opcode::Opcode, value::VmValue};
+
+/// Instruction the AOT compiler cannot translate, tied to its index in the program
+#[derive(Debug, PartialEq, Clone)]
+pub struct AotError {
+    pub pc: usize,
+    pub opcode: Opcode,
+    pub kind: AotErrorKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AotErrorKind {
+    /// The backend has no translation for the opcode
+    UnsupportedOpcode,
+    /// Arrays and objects cannot be written into the compiled code, as
+    /// operands or from the constant pool
+    UnsupportedImmediate(VmValue),
+    ConstantOutOfBounds(usize),
+    /// A runtime routine was given more arguments than the backend passes
+    TooManyArguments {
+        routine: &'static str,
+        count: usize,
+    },
+}
+
+impl AotError {
+    pub fn new(pc: usize, opcode: Opcode, kind: AotErrorKind) -> Self {
+        Self { pc, opcode, kind }
+    }
+}
+
+impl fmt::Display for AotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cannot compile {} at pc {}: {}",
+            self.opcode, self.pc, self.kind
+        )
+    }
+}
+
+impl fmt::Display for AotErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AotErrorKind::UnsupportedOpcode => {
+                write!(f, "the opcode is not supported by this backend")
+            }
+            AotErrorKind::UnsupportedImmediate(value) => {
+                write!(f, "{} cannot be compiled as an immediate value", value)
+            }
+            AotErrorKind::ConstantOutOfBounds(index) => {
+                write!(f, "Invalid constant index: {}", index)
+            }
+            AotErrorKind::TooManyArguments { routine, count } => write!(
+                f,
+                "{} argument(s) are too many for runtime routine {}",
+                count, routine
+            ),
+        }
+    }
+}
+
+impl Error for AotError {}
@@ -0,0 +1,69 @@
+use std::{error::Error, fmt, io};
+
+use crate::{
+    error::{aot::AotError, asm::AsmError, verifier::Diagnostic, vm::VmTrace},
+    serde::ProgramError,
+};
+
+/// Error ending a `ryde` command
+#[derive(Debug)]
+pub enum CliError {
+    /// The command line could not be parsed
+    Usage(String),
+    Io {
+        path: String,
+        error: io::Error,
+    },
+    Program(ProgramError),
+    Asm {
+        path: String,
+        error: AsmError,
+    },
+    Vm(Box<VmTrace>),
+    Verification(Vec<Diagnostic>),
+    /// The program uses an instruction the compile target cannot translate
+    Aot(AotError),
+}
+
+impl CliError {
+    /// Process exit code: 2 for usage errors, 1 for anything else
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Usage(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}", message),
+            CliError::Io { path, error } => write!(f, "{}: {}", path, error),
+            CliError::Program(error) => write!(f, "Program error: {}", error),
+            CliError::Asm { path, error } => write!(f, "{}:{}", path, error),
+            CliError::Vm(error) => write!(f, "VM error: {}", error),
+            CliError::Verification(diagnostics) => {
+                write!(f, "Program failed verification:")?;
+                for diagnostic in diagnostics.iter() {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+            CliError::Aot(error) => write!(f, "Compile error: {}", error),
+        }
+    }
+}
+
+impl Error for CliError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CliError::Io { error, .. } => Some(error),
+            CliError::Program(error) => Some(error),
+            CliError::Asm { error, .. } => Some(error),
+            CliError::Vm(error) => Some(error),
+            CliError::Aot(error) => Some(error),
+            _ => None,
+        }
+    }
+}
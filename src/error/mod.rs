@@ -1 +1,5 @@
+pub mod aot;
+pub mod asm;
+pub mod cli;
+pub mod verifier;
 pub mod vm;
@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// Problem found by the verifier, tied to the index of the offending instruction
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub index: usize,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DiagnosticKind {
+    /// `register` is not below the size of the register window it runs in
+    RegisterOutOfBounds {
+        register: usize,
+        register_count: usize,
+    },
+    /// The `count` registers from `start` used by a call or `RETURNV` do not
+    /// all fit in the register window
+    RegisterRangeOutOfBounds {
+        start: usize,
+        count: usize,
+        register_count: usize,
+    },
+    /// A jump or call target, or the entry point of a called function, is
+    /// not the address of an instruction
+    AddressOutOfBounds(usize),
+    ConstantOutOfBounds(usize),
+    FunctionOutOfBounds(usize),
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// `RETURN` or `RETURNV` is reachable from the top level without a call
+    ReturnOutsideCall,
+}
+
+impl Diagnostic {
+    pub fn new(index: usize, kind: DiagnosticKind) -> Self {
+        Self { index, kind }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}: {}", self.index, self.kind)
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::RegisterOutOfBounds {
+                register,
+                register_count,
+            } => write!(
+                f,
+                "Register r{} is out of bounds for a window of {} register(s)",
+                register, register_count
+            ),
+            DiagnosticKind::RegisterRangeOutOfBounds {
+                start,
+                count,
+                register_count,
+            } => write!(
+                f,
+                "{} register(s) from r{} do not fit in a window of {} register(s)",
+                count, start, register_count
+            ),
+            DiagnosticKind::AddressOutOfBounds(address) => {
+                write!(f, "Address {} is out of bounds", address)
+            }
+            DiagnosticKind::ConstantOutOfBounds(index) => {
+                write!(f, "Invalid constant index: {}", index)
+            }
+            DiagnosticKind::FunctionOutOfBounds(index) => {
+                write!(f, "Invalid function index: {}", index)
+            }
+            DiagnosticKind::ArityMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Function '{}' expects {} argument(s), got {}",
+                name, expected, actual
+            ),
+            DiagnosticKind::ReturnOutsideCall => {
+                write!(f, "Return is reachable outside of a call")
+            }
+        }
+    }
+}
@@ -1,17 +1,71 @@
 use std::{error::Error, fmt};
 
-use crate::value::VmValue;
+use crate::{
+    debug_info::SourceLocation, error::verifier::Diagnostic, object::Object, opcode::Opcode,
+    value::VmValue,
+};
 
 pub fn invalid_index_err(index: VmValue) -> VmError {
     VmError::InvalidIndexType(format!("{:?}", index))
 }
 
+/// The scope chain that was searched when a variable could not be resolved
+#[derive(Debug, PartialEq)]
+pub enum VariableScope {
+    Global,
+    /// `depth` local scopes were searched before falling back to the globals.
+    /// `function` names the enclosing function, if any.
+    Local {
+        function: Option<String>,
+        depth: usize,
+    },
+}
+
+impl fmt::Display for VariableScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariableScope::Global => write!(f, "global scope"),
+            VariableScope::Local {
+                function: Some(function),
+                depth,
+            } => write!(
+                f,
+                "local scope of function '{}' (depth {}) or global scope",
+                function, depth
+            ),
+            VariableScope::Local {
+                function: None,
+                depth,
+            } => write!(f, "local scope (depth {}) or global scope", depth),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum VmError {
     RegisterOutOfBounds(usize),
-    VariableNotFound(String),
+    ConstantOutOfBounds(usize),
+    UpvalueOutOfBounds(usize),
+    VariableNotFound {
+        name: String,
+        scope: VariableScope,
+    },
+    ScopeStackEmpty,
     ProgramCounterOutOfBounds,
+    FunctionOutOfBounds(usize),
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
     CallStackEmpty,
+    NativeFunctionNotFound(String),
+    NativeFunctionError {
+        name: String,
+        error: Box<VmError>,
+    },
+    IoError(std::io::Error),
+    VerificationFailed(Vec<Diagnostic>),
     AttemptToIndex(String),
     InvalidIndexType(String),
     OperandTypeMismatch {
@@ -24,17 +78,100 @@ pub enum VmError {
         a_actual: String,
         b_actual: String,
     },
+    /// A `THROW` that no handler caught
+    Thrown(VmValue),
+    HandlerStackEmpty,
+    /// The instruction budget given to `Vm::run_with_limits` ran out
+    FuelExhausted,
+    /// The call stack would have grown past the given number of frames
+    CallDepthExceeded(usize),
+    /// An array or object would have grown past the given size
+    CollectionTooLarge(usize),
+    /// A string would have grown past the given length
+    StringTooLong(usize),
+    Cancelled,
+    /// `RESUME` of a coroutine that has returned or failed
+    CoroutineDead,
+    /// `RESUME` of a coroutine that is already running
+    CoroutineRunning,
+}
+
+impl VmError {
+    /// Value received by an exception handler for this error, or `None` if
+    /// the error means the bytecode itself is malformed and cannot be caught.
+    ///
+    /// Thrown values are passed through as they are, even from native
+    /// functions; other errors become an object with the error's `kind` and
+    /// `message`.
+    pub fn exception_value(&self) -> Option<VmValue> {
+        let kind = match self {
+            VmError::Thrown(value) => return Some(value.clone()),
+            VmError::NativeFunctionError { error, .. }
+                if matches!(error.as_ref(), VmError::Thrown(_)) =>
+            {
+                return error.exception_value();
+            }
+            VmError::VariableNotFound { .. } => "VariableNotFound",
+            VmError::ArityMismatch { .. } => "ArityMismatch",
+            VmError::NativeFunctionNotFound(_) => "NativeFunctionNotFound",
+            VmError::NativeFunctionError { .. } => "NativeFunctionError",
+            VmError::AttemptToIndex(_) => "AttemptToIndex",
+            VmError::InvalidIndexType(_) => "InvalidIndexType",
+            VmError::OperandTypeMismatch { .. } => "OperandTypeMismatch",
+            VmError::BinaryTypeMismatch { .. } => "BinaryTypeMismatch",
+            VmError::CoroutineDead => "CoroutineDead",
+            VmError::CoroutineRunning => "CoroutineRunning",
+            _ => return None,
+        };
+        let mut object = Object::new();
+        object.new_index(
+            VmValue::String("kind".to_string()),
+            VmValue::String(kind.to_string()),
+        );
+        object.new_index(
+            VmValue::String("message".to_string()),
+            VmValue::String(self.to_string()),
+        );
+        Some(VmValue::Object(object))
+    }
 }
 
 impl fmt::Display for VmError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             VmError::RegisterOutOfBounds(index) => write!(f, "Invalid register index: {}", index),
-            VmError::VariableNotFound(name) => {
-                write!(f, "Variable '{}' not found in local scope", name)
+            VmError::ConstantOutOfBounds(index) => write!(f, "Invalid constant index: {}", index),
+            VmError::UpvalueOutOfBounds(index) => write!(f, "Invalid upvalue index: {}", index),
+            VmError::VariableNotFound { name, scope } => {
+                write!(f, "Variable '{}' not found in {}", name, scope)
             }
+            VmError::ScopeStackEmpty => write!(f, "Scope stack is empty, cannot pop scope"),
             VmError::ProgramCounterOutOfBounds => write!(f, "Program counter out of bounds"),
+            VmError::FunctionOutOfBounds(index) => write!(f, "Invalid function index: {}", index),
+            VmError::ArityMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Function '{}' expects {} argument(s), got {}",
+                name, expected, actual
+            ),
             VmError::CallStackEmpty => write!(f, "Call stack is empty, cannot return"),
+            VmError::NativeFunctionNotFound(name) => {
+                write!(f, "Native function '{}' is not registered", name)
+            }
+            VmError::NativeFunctionError { name, error } => {
+                write!(f, "Error in native function '{}': {}", name, error)
+            }
+            VmError::IoError(error) => write!(f, "I/O error: {}", error),
+            VmError::VerificationFailed(diagnostics) => {
+                write!(f, "Program failed verification:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
             VmError::AttemptToIndex(actual) => write!(f, "Attempt to index '{}'", actual),
             VmError::InvalidIndexType(actual) => write!(f, "Invalid index type, got '{}'", actual),
             VmError::OperandTypeMismatch { expected, actual } => {
@@ -52,8 +189,101 @@ impl fmt::Display for VmError {
                     expected, opcode_name, a_actual, opcode_name, b_actual
                 )
             }
+            VmError::Thrown(value) => write!(f, "Uncaught exception: {}", value),
+            VmError::HandlerStackEmpty => {
+                write!(f, "Handler stack is empty, cannot pop handler")
+            }
+            VmError::FuelExhausted => write!(f, "Instruction budget exhausted"),
+            VmError::CallDepthExceeded(limit) => {
+                write!(f, "Call depth limit of {} frame(s) exceeded", limit)
+            }
+            VmError::CollectionTooLarge(limit) => {
+                write!(f, "Collection size limit of {} element(s) exceeded", limit)
+            }
+            VmError::StringTooLong(limit) => {
+                write!(f, "String length limit of {} byte(s) exceeded", limit)
+            }
+            VmError::Cancelled => write!(f, "Execution was cancelled"),
+            VmError::CoroutineDead => write!(f, "Cannot resume a dead coroutine"),
+            VmError::CoroutineRunning => {
+                write!(f, "Cannot resume a coroutine that is already running")
+            }
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::NativeFunctionError { error, .. } => Some(error.as_ref()),
+            VmError::IoError(error) => Some(error),
+            _ => None,
         }
     }
 }
 
-impl Error for VmError {}
+/// A runtime error together with where it happened and the calls that led
+/// there
+#[derive(Debug)]
+pub struct VmTrace {
+    pub error: VmError,
+    /// Index of the instruction that failed
+    pub pc: usize,
+    pub opcode: Opcode,
+    /// Source position of the failing instruction, if the program has debug info
+    pub location: Option<SourceLocation>,
+    /// The call stack at the time of the error, innermost first
+    pub frames: Vec<TraceFrame>,
+}
+
+/// One unwound call frame
+#[derive(Debug, PartialEq)]
+pub struct TraceFrame {
+    /// Name of the called function, or `None` for a plain subroutine
+    pub function: Option<String>,
+    /// Index of the instruction that made the call
+    pub call_site: usize,
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for VmTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        // each frame ran the code above its call site
+        let mut pc = self.pc;
+        let mut opcode = Some(self.opcode);
+        let mut location = self.location.as_ref();
+        for frame in &self.frames {
+            let function = frame.function.as_deref().unwrap_or("<subroutine>");
+            write_trace_line(f, pc, opcode, function, location)?;
+            pc = frame.call_site;
+            opcode = None;
+            location = frame.location.as_ref();
+        }
+        write_trace_line(f, pc, opcode, "<top level>", location)
+    }
+}
+
+fn write_trace_line(
+    f: &mut fmt::Formatter<'_>,
+    pc: usize,
+    opcode: Option<Opcode>,
+    function: &str,
+    location: Option<&SourceLocation>,
+) -> fmt::Result {
+    write!(f, "\n  at {:04}", pc)?;
+    if let Some(opcode) = opcode {
+        write!(f, " {}", opcode)?;
+    }
+    write!(f, " in {}", function)?;
+    if let Some(location) = location {
+        write!(f, " ({})", location)?;
+    }
+    Ok(())
+}
+
+impl Error for VmTrace {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
@@ -0,0 +1,74 @@
+use std::{hash::Hash, rc::Rc};
+
+use bincode::{Decode, Encode};
+
+use crate::value::{SharedValue, VmValue};
+
+/// Entry in a program's function table
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct FunctionProto {
+    pub name: String,
+    /// Address of the first instruction of the function body
+    pub address: usize,
+    /// Number of arguments, copied into registers `0..arity` of the callee
+    pub arity: usize,
+    /// Size of the register window allocated for each call
+    pub register_count: usize,
+}
+
+impl FunctionProto {
+    pub fn new(name: &str, address: usize, arity: usize, register_count: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            address,
+            arity,
+            register_count,
+        }
+    }
+}
+
+/// Function value: an entry of the function table together with the
+/// variables it captured. Upvalues are shared cells, so closures capturing
+/// the same variable observe each other's writes.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Closure {
+    pub function: usize,
+    pub upvalues: Rc<Vec<SharedValue>>,
+}
+
+impl Closure {
+    pub fn new(function: usize, upvalues: Vec<SharedValue>) -> Self {
+        Self {
+            function,
+            upvalues: Rc::new(upvalues),
+        }
+    }
+
+    pub fn new_vm_value(function: usize, upvalues: Vec<SharedValue>) -> VmValue {
+        VmValue::Closure(Self::new(function, upvalues))
+    }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        self.function == other.function && Rc::ptr_eq(&self.upvalues, &other.upvalues)
+    }
+}
+
+impl Hash for Closure {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.function.hash(state);
+        Rc::as_ptr(&self.upvalues).hash(state);
+    }
+}
+
+/// How `CLOSURE` obtains each upvalue of the new closure
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub enum Capture {
+    /// Captures variable `name` by reference
+    Variable(String),
+    /// Captures the current value of a register
+    Register(usize),
+    /// Shares an upvalue of the enclosing closure
+    Upvalue(usize),
+}
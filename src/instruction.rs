@@ -2,15 +2,16 @@ use std::fmt;
 
 use bincode::{Decode, Encode};
 
-use crate::value::VmValue;
+use crate::{function::Capture, value::VmValue};
 
+#[allow(non_camel_case_types)]
 #[derive(Encode, Decode, PartialEq, Debug, Clone)]
 pub enum Instruction {
-    // /// Load a constant value from the constant pool into a register
-    // LOADC {
-    //     target: usize,
-    //     constant_index: usize,
-    // },
+    /// Load a constant value from the constant pool into a register
+    LOADC {
+        target: usize,
+        constant_index: usize,
+    },
     /// Load a constant value directly into a register
     LOADV { target: usize, value: VmValue },
 
@@ -22,6 +23,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a + b
+    ADDC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a - b
     SUB { target: usize, a: usize, b: usize },
     /// target = a - b
@@ -30,6 +37,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a - b
+    SUBC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a * b
     MUL { target: usize, a: usize, b: usize },
     /// target = a * b
@@ -38,6 +51,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a * b
+    MULC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a / b
     DIV { target: usize, a: usize, b: usize },
     /// target = a / b
@@ -46,6 +65,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a / b
+    DIVC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a // b
     IDIV { target: usize, a: usize, b: usize },
     /// target = a // b
@@ -54,6 +79,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a // b
+    IDIVC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a ^ b
     POW { target: usize, a: usize, b: usize },
     /// target = a ^ b
@@ -62,6 +93,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a ^ b
+    POWC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a % b
     MOD { target: usize, a: usize, b: usize },
     /// target = a % b
@@ -70,6 +107,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a % b
+    MODC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a ~ b
     BXOR { target: usize, a: usize, b: usize },
     /// target = a ~ b
@@ -78,6 +121,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a ~ b
+    BXORC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a & b
     BAND { target: usize, a: usize, b: usize },
     /// target = a & b
@@ -86,6 +135,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a & b
+    BANDC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a | b
     BOR { target: usize, a: usize, b: usize },
     /// target = a | b
@@ -94,6 +149,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a | b
+    BORC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a << b
     BLSH { target: usize, a: usize, b: usize },
     /// target = a <<b
@@ -102,6 +163,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a <<b
+    BLSHC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a >>> b
     BRSH { target: usize, a: usize, b: usize },
     /// target = a >>> b
@@ -110,6 +177,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a >>> b
+    BRSHC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a >> b
     BARSH { target: usize, a: usize, b: usize },
     /// target = a >> b
@@ -118,6 +191,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a >> b
+    BARSHC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = ~operand
     BNOT { target: usize, operand: usize },
     /// target = ~operand
@@ -125,6 +204,11 @@ pub enum Instruction {
         target: usize,
         operand_value: VmValue,
     },
+    /// target = ~operand
+    BNOTC {
+        target: usize,
+        operand_constant: usize,
+    },
     /// target = -operand
     NEGATE { target: usize, operand: usize },
     /// target = -operand
@@ -132,6 +216,11 @@ pub enum Instruction {
         target: usize,
         operand_value: VmValue,
     },
+    /// target = -operand
+    NEGATEC {
+        target: usize,
+        operand_constant: usize,
+    },
 
     /// target = a && b
     AND { target: usize, a: usize, b: usize },
@@ -141,6 +230,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a && b
+    ANDC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a || b
     OR { target: usize, a: usize, b: usize },
     /// target = a || b
@@ -149,6 +244,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a || b
+    ORC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a ?? b
     NULL_COALESCE { target: usize, a: usize, b: usize },
     /// target = a ?? b
@@ -157,6 +258,12 @@ pub enum Instruction {
         a_value: VmValue,
         b: usize,
     },
+    /// target = a ?? b
+    NULL_COALESCEC {
+        target: usize,
+        a_constant: usize,
+        b: usize,
+    },
     /// target = a == b
     EQ { target: usize, a: usize, b: usize },
     /// target = a != b
@@ -176,6 +283,11 @@ pub enum Instruction {
         target: usize,
         operand_value: VmValue,
     },
+    /// target = !operand
+    NOTC {
+        target: usize,
+        operand_constant: usize,
+    },
     /// Increment variable `name` by 1, and store the result in `target` if specified
     INC {
         target: Option<usize>,
@@ -206,6 +318,12 @@ pub enum Instruction {
         object: usize,
         index: VmValue,
     },
+    /// target = object[index] -- constant pool index
+    INDEXC {
+        target: usize,
+        object: usize,
+        index_constant: usize,
+    },
     /// object[index] = source -- register index
     STORE_INDEX {
         source: usize,
@@ -224,6 +342,12 @@ pub enum Instruction {
         object: usize,
         index: VmValue,
     },
+    /// object[index] = source -- constant pool index
+    STORE_INDEXC {
+        source: usize,
+        object: usize,
+        index_constant: usize,
+    },
     /// object[index] = null -- register index
     DELETE_INDEX {
         object: usize,
@@ -236,6 +360,11 @@ pub enum Instruction {
     },
     /// object[index] = null
     DELETE_INDEXK { object: usize, index: VmValue },
+    /// object[index] = null -- constant pool index
+    DELETE_INDEXC {
+        object: usize,
+        index_constant: usize,
+    },
     /// Creates an empty object at the target register
     NEW_OBJECT(usize),
     /// Creates an empty array at the target register
@@ -244,6 +373,8 @@ pub enum Instruction {
     ARRAY_PUSH { target: usize, source: usize },
     /// target.push(value)
     ARRAY_PUSHK { target: usize, value: VmValue },
+    /// target.push(constant)
+    ARRAY_PUSHC { target: usize, constant: usize },
     /// target = source.length
     LEN { target: usize, source: usize },
 
@@ -266,31 +397,111 @@ pub enum Instruction {
     /// Jump to instruction at `address` if values of `a` and `b` are not equal
     JNEQ { a: usize, b: usize, address: usize },
 
-    /// Store the value of register `target` into variable `name`
+    /// Store the value of register `source` into the nearest variable `name`, declaring it if none exists
     STORE { source: usize, name: String },
     /// Store `value` into variable `name`
     STOREK { name: String, value: VmValue },
+    /// Store the constant at `constant` into variable `name`
+    STOREC { name: String, constant: usize },
     /// Load the value of variable `name` into register `target`
     LOAD { target: usize, name: String },
+    /// Declare variable `name` in the innermost scope with the value of register `source`
+    DECLARE { source: usize, name: String },
+    /// Store the value of register `source` into global variable `name`
+    STORE_GLOBAL { source: usize, name: String },
+    /// Load the value of global variable `name` into register `target`
+    LOAD_GLOBAL { target: usize, name: String },
+    /// Open a new local scope
+    PUSH_SCOPE,
+    /// Close the innermost local scope
+    POP_SCOPE,
     /// Call a subroutine at the specified instruction
     CALL(usize),
-    /// Return from a subroutine
+    /// Call `function` from the function table with arguments in registers `args..args + arg_count`,
+    /// storing up to `result_count` return values into registers `target..target + result_count`
+    CALLF {
+        function: usize,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+        result_count: usize,
+    },
+    /// Call the closure in register `callee` with arguments in registers `args..args + arg_count`,
+    /// storing up to `result_count` return values into registers `target..target + result_count`
+    CALLR {
+        callee: usize,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+        result_count: usize,
+    },
+    /// Create a closure over `function` in register `target`, capturing `captures` as its upvalues
+    CLOSURE {
+        target: usize,
+        function: usize,
+        captures: Vec<Capture>,
+    },
+    /// Load upvalue `upvalue` of the running closure into register `target`
+    GETUPVAL { target: usize, upvalue: usize },
+    /// Store the value of register `source` into upvalue `upvalue` of the running closure
+    SETUPVAL { source: usize, upvalue: usize },
+    /// Call the native function `name` with arguments in registers `args..args + arg_count`,
+    /// storing its return value in register `target`
+    CALL_NATIVE {
+        name: String,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+    },
+    /// Call the native function named by the string constant at `name_constant`
+    CALL_NATIVEC {
+        name_constant: usize,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+    },
+    /// Return from a subroutine or function
     RETURN,
+    /// Return from a function, passing registers `source..source + count` back to the caller
+    RETURNV { source: usize, count: usize },
 
     /// Prints the value of the specified register into stdout
     PRINT(usize),
     /// Prints the value into stdout
     PRINTK(VmValue),
+    /// Prints the constant at the specified constant pool index into stdout
+    PRINTC(usize),
     /// Stops execution
     HALT,
+
+    /// Raise the value of register `source` as an exception
+    THROW(usize),
+    /// Install an exception handler: an exception raised before the matching
+    /// `POP_HANDLER` unwinds to the current frame, stores the exception in
+    /// register `target` and jumps to `address`
+    PUSH_HANDLER { target: usize, address: usize },
+    /// Remove the innermost exception handler
+    POP_HANDLER,
+
+    /// Suspend the running coroutine, or the program itself outside of one,
+    /// passing the value of register `source` to whoever resumed it. The
+    /// value it is resumed with is stored in register `target`.
+    YIELD { target: usize, source: usize },
+    /// Create a suspended coroutine in register `target` running the closure
+    /// in register `callee`, which takes a single argument
+    COROUTINE { target: usize, callee: usize },
+    /// Resume the coroutine in register `coroutine` with the value of register
+    /// `value`, storing the value it yields or returns in register `target`.
+    /// The first resume passes the value as the closure's argument.
+    RESUME {
+        target: usize,
+        coroutine: usize,
+        value: usize,
+    },
 }
 
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            format!("{:?}", self).split(" ").next().unwrap().trim()
-        )
+        write!(f, "{}", self.opcode())
     }
 }
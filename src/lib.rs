@@ -1,8 +1,18 @@
 pub mod aot;
 pub mod array;
+pub mod asm;
+pub mod cli;
+pub mod coroutine;
+pub mod debug_info;
+pub mod debugger;
 pub mod error;
+pub mod function;
 pub mod instruction;
+pub mod limits;
 pub mod object;
+pub mod opcode;
+pub mod output;
 pub mod serde;
 pub mod value;
+pub mod verifier;
 pub mod vm;
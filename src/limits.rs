@@ -0,0 +1,39 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Resource limits for running untrusted bytecode with `Vm::run_with_limits`
+/// or `Vm::resume_with_limits`.
+/// `None` leaves a resource unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// Number of instructions the run may execute
+    pub fuel: Option<u64>,
+    /// Number of frames the call stack may hold
+    pub max_call_depth: Option<usize>,
+    /// Number of elements an array or entries an object may grow to
+    pub max_collection_size: Option<usize>,
+    /// Number of bytes a string built by the program may have
+    pub max_string_length: Option<usize>,
+    pub cancellation: Option<CancellationToken>,
+}
+
+/// Flag that stops a running VM before its next instruction. Clones share
+/// the flag, so one can be handed to another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
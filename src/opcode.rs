@@ -0,0 +1,295 @@
+use std::fmt;
+
+use crate::{function::Capture, instruction::Instruction, value::VmValue};
+
+/// Kind of an instruction operand, used by tools that handle every opcode generically
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OperandKind {
+    Register,
+    OptionalRegister,
+    Address,
+    Constant,
+    Function,
+    Upvalue,
+    Number,
+    Boolean,
+    Name,
+    Value,
+    Captures,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Operand {
+    Register(usize),
+    OptionalRegister(Option<usize>),
+    Address(usize),
+    Constant(usize),
+    Function(usize),
+    Upvalue(usize),
+    Number(usize),
+    Boolean(bool),
+    Name(String),
+    Value(VmValue),
+    Captures(Vec<Capture>),
+}
+
+impl Operand {
+    pub fn kind(&self) -> OperandKind {
+        match self {
+            Operand::Register(_) => OperandKind::Register,
+            Operand::OptionalRegister(_) => OperandKind::OptionalRegister,
+            Operand::Address(_) => OperandKind::Address,
+            Operand::Constant(_) => OperandKind::Constant,
+            Operand::Function(_) => OperandKind::Function,
+            Operand::Upvalue(_) => OperandKind::Upvalue,
+            Operand::Number(_) => OperandKind::Number,
+            Operand::Boolean(_) => OperandKind::Boolean,
+            Operand::Name(_) => OperandKind::Name,
+            Operand::Value(_) => OperandKind::Value,
+            Operand::Captures(_) => OperandKind::Captures,
+        }
+    }
+}
+
+macro_rules! variant {
+    ($name:ident { $($field:ident: $kind:ident),* }) => {
+        Instruction::$name { $($field),* }
+    };
+    ($name:ident ($field:ident: $kind:ident)) => {
+        Instruction::$name($field)
+    };
+    ($name:ident) => {
+        Instruction::$name
+    };
+}
+
+macro_rules! take_operand {
+    ($operands:ident, $kind:ident) => {
+        match $operands.next() {
+            Some(Operand::$kind(value)) => value,
+            _ => return None,
+        }
+    };
+}
+
+/// Declares the opcode table. Each entry lists the fields of the matching
+/// `Instruction` variant in declaration order, along with their operand kinds.
+/// Opcode numbers are part of the binary format and must never be reused.
+macro_rules! opcodes {
+    ($(
+        $name:ident = $code:literal
+            $({ $($field:ident: $kind:ident),* })?
+            $(($tuple_field:ident: $tuple_kind:ident))?
+    ),* $(,)?) => {
+        #[allow(non_camel_case_types)]
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        #[repr(u8)]
+        pub enum Opcode {
+            $($name = $code),*
+        }
+
+        impl Opcode {
+            pub const ALL: &[Opcode] = &[$(Opcode::$name),*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$name => stringify!($name)),*
+                }
+            }
+
+            pub fn operand_kinds(self) -> &'static [OperandKind] {
+                match self {
+                    $(Opcode::$name => &[
+                        $($(OperandKind::$kind,)*)?
+                        $(OperandKind::$tuple_kind)?
+                    ]),*
+                }
+            }
+
+            pub fn from_byte(byte: u8) -> Option<Opcode> {
+                match byte {
+                    $($code => Some(Opcode::$name),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl Instruction {
+            pub fn opcode(&self) -> Opcode {
+                match self {
+                    $(Instruction::$name { .. } => Opcode::$name),*
+                }
+            }
+
+            /// Operands of this instruction, in the order given by `Opcode::operand_kinds`
+            pub fn operands(&self) -> Vec<Operand> {
+                match self {
+                    $(variant!($name $({ $($field: $kind),* })? $(($tuple_field: $tuple_kind))?) => vec![
+                        $($(Operand::$kind($field.clone()),)*)?
+                        $(Operand::$tuple_kind($tuple_field.clone()))?
+                    ]),*
+                }
+            }
+
+            /// Builds an instruction from operands matching `opcode.operand_kinds()`,
+            /// returning `None` if they do not match
+            pub fn from_operands(opcode: Opcode, operands: Vec<Operand>) -> Option<Instruction> {
+                let mut operands = operands.into_iter();
+                let instruction = match opcode {
+                    $(Opcode::$name => {
+                        $($(let $field = take_operand!(operands, $kind);)*)?
+                        $(let $tuple_field = take_operand!(operands, $tuple_kind);)?
+                        variant!($name $({ $($field: $kind),* })? $(($tuple_field: $tuple_kind))?)
+                    }),*
+                };
+
+                if operands.next().is_some() {
+                    None
+                } else {
+                    Some(instruction)
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    LOADC = 0 { target: Register, constant_index: Constant },
+    LOADV = 1 { target: Register, value: Value },
+    ADD = 2 { target: Register, a: Register, b: Register },
+    ADDK = 3 { target: Register, a_value: Value, b: Register },
+    ADDC = 4 { target: Register, a_constant: Constant, b: Register },
+    SUB = 5 { target: Register, a: Register, b: Register },
+    SUBK = 6 { target: Register, a_value: Value, b: Register },
+    SUBC = 7 { target: Register, a_constant: Constant, b: Register },
+    MUL = 8 { target: Register, a: Register, b: Register },
+    MULK = 9 { target: Register, a_value: Value, b: Register },
+    MULC = 10 { target: Register, a_constant: Constant, b: Register },
+    DIV = 11 { target: Register, a: Register, b: Register },
+    DIVK = 12 { target: Register, a_value: Value, b: Register },
+    DIVC = 13 { target: Register, a_constant: Constant, b: Register },
+    IDIV = 14 { target: Register, a: Register, b: Register },
+    IDIVK = 15 { target: Register, a_value: Value, b: Register },
+    IDIVC = 16 { target: Register, a_constant: Constant, b: Register },
+    POW = 17 { target: Register, a: Register, b: Register },
+    POWK = 18 { target: Register, a_value: Value, b: Register },
+    POWC = 19 { target: Register, a_constant: Constant, b: Register },
+    MOD = 20 { target: Register, a: Register, b: Register },
+    MODK = 21 { target: Register, a_value: Value, b: Register },
+    MODC = 22 { target: Register, a_constant: Constant, b: Register },
+    BXOR = 23 { target: Register, a: Register, b: Register },
+    BXORK = 24 { target: Register, a_value: Value, b: Register },
+    BXORC = 25 { target: Register, a_constant: Constant, b: Register },
+    BAND = 26 { target: Register, a: Register, b: Register },
+    BANDK = 27 { target: Register, a_value: Value, b: Register },
+    BANDC = 28 { target: Register, a_constant: Constant, b: Register },
+    BOR = 29 { target: Register, a: Register, b: Register },
+    BORK = 30 { target: Register, a_value: Value, b: Register },
+    BORC = 31 { target: Register, a_constant: Constant, b: Register },
+    BLSH = 32 { target: Register, a: Register, b: Register },
+    BLSHK = 33 { target: Register, a_value: Value, b: Register },
+    BLSHC = 34 { target: Register, a_constant: Constant, b: Register },
+    BRSH = 35 { target: Register, a: Register, b: Register },
+    BRSHK = 36 { target: Register, a_value: Value, b: Register },
+    BRSHC = 37 { target: Register, a_constant: Constant, b: Register },
+    BARSH = 38 { target: Register, a: Register, b: Register },
+    BARSHK = 39 { target: Register, a_value: Value, b: Register },
+    BARSHC = 40 { target: Register, a_constant: Constant, b: Register },
+    BNOT = 41 { target: Register, operand: Register },
+    BNOTK = 42 { target: Register, operand_value: Value },
+    BNOTC = 43 { target: Register, operand_constant: Constant },
+    NEGATE = 44 { target: Register, operand: Register },
+    NEGATEK = 45 { target: Register, operand_value: Value },
+    NEGATEC = 46 { target: Register, operand_constant: Constant },
+    AND = 47 { target: Register, a: Register, b: Register },
+    ANDK = 48 { target: Register, a_value: Value, b: Register },
+    ANDC = 49 { target: Register, a_constant: Constant, b: Register },
+    OR = 50 { target: Register, a: Register, b: Register },
+    ORK = 51 { target: Register, a_value: Value, b: Register },
+    ORC = 52 { target: Register, a_constant: Constant, b: Register },
+    NULL_COALESCE = 53 { target: Register, a: Register, b: Register },
+    NULL_COALESCEK = 54 { target: Register, a_value: Value, b: Register },
+    NULL_COALESCEC = 55 { target: Register, a_constant: Constant, b: Register },
+    EQ = 56 { target: Register, a: Register, b: Register },
+    NEQ = 57 { target: Register, a: Register, b: Register },
+    LT = 58 { target: Register, a: Register, b: Register },
+    LTE = 59 { target: Register, a: Register, b: Register },
+    GT = 60 { target: Register, a: Register, b: Register },
+    GTE = 61 { target: Register, a: Register, b: Register },
+    NOT = 62 { target: Register, operand: Register },
+    NOTK = 63 { target: Register, operand_value: Value },
+    NOTC = 64 { target: Register, operand_constant: Constant },
+    INC = 65 { target: OptionalRegister, name: Name, returns_old: Boolean },
+    DEC = 66 { target: OptionalRegister, name: Name, returns_old: Boolean },
+    INDEX = 67 { target: Register, object: Register, index: Register },
+    INDEXN = 68 { target: Register, object: Register, index: Number },
+    INDEXK = 69 { target: Register, object: Register, index: Value },
+    INDEXC = 70 { target: Register, object: Register, index_constant: Constant },
+    STORE_INDEX = 71 { source: Register, object: Register, index: Register },
+    STORE_INDEXN = 72 { source: Register, object: Register, index: Number },
+    STORE_INDEXK = 73 { source: Register, object: Register, index: Value },
+    STORE_INDEXC = 74 { source: Register, object: Register, index_constant: Constant },
+    DELETE_INDEX = 75 { object: Register, index: Register },
+    DELETE_INDEXN = 76 { object: Register, index: Number },
+    DELETE_INDEXK = 77 { object: Register, index: Value },
+    DELETE_INDEXC = 78 { object: Register, index_constant: Constant },
+    NEW_OBJECT = 79 (target: Register),
+    NEW_ARRAY = 80 (target: Register),
+    ARRAY_PUSH = 81 { target: Register, source: Register },
+    ARRAY_PUSHK = 82 { target: Register, value: Value },
+    ARRAY_PUSHC = 83 { target: Register, constant: Constant },
+    LEN = 84 { target: Register, source: Register },
+    JMP = 85 (address: Address),
+    JZ = 86 { source: Register, address: Address },
+    JNZ = 87 { source: Register, address: Address },
+    JLT = 88 { a: Register, b: Register, address: Address },
+    JLTE = 89 { a: Register, b: Register, address: Address },
+    JGT = 90 { a: Register, b: Register, address: Address },
+    JGTE = 91 { a: Register, b: Register, address: Address },
+    JEQ = 92 { a: Register, b: Register, address: Address },
+    JNEQ = 93 { a: Register, b: Register, address: Address },
+    STORE = 94 { source: Register, name: Name },
+    STOREK = 95 { name: Name, value: Value },
+    STOREC = 96 { name: Name, constant: Constant },
+    LOAD = 97 { target: Register, name: Name },
+    DECLARE = 98 { source: Register, name: Name },
+    STORE_GLOBAL = 99 { source: Register, name: Name },
+    LOAD_GLOBAL = 100 { target: Register, name: Name },
+    PUSH_SCOPE = 101,
+    POP_SCOPE = 102,
+    CALL = 103 (address: Address),
+    CALLF = 104 { function: Function, args: Register, arg_count: Number, target: Register, result_count: Number },
+    CALLR = 105 { callee: Register, args: Register, arg_count: Number, target: Register, result_count: Number },
+    CLOSURE = 106 { target: Register, function: Function, captures: Captures },
+    GETUPVAL = 107 { target: Register, upvalue: Upvalue },
+    SETUPVAL = 108 { source: Register, upvalue: Upvalue },
+    CALL_NATIVE = 109 { name: Name, args: Register, arg_count: Number, target: Register },
+    CALL_NATIVEC = 110 { name_constant: Constant, args: Register, arg_count: Number, target: Register },
+    RETURN = 111,
+    RETURNV = 112 { source: Register, count: Number },
+    PRINT = 113 (target: Register),
+    PRINTK = 114 (value: Value),
+    PRINTC = 115 (constant: Constant),
+    HALT = 116,
+    THROW = 117 (source: Register),
+    PUSH_HANDLER = 118 { target: Register, address: Address },
+    POP_HANDLER = 119,
+    YIELD = 120 { target: Register, source: Register },
+    COROUTINE = 121 { target: Register, callee: Register },
+    RESUME = 122 { target: Register, coroutine: Register, value: Register },
+}
+
+impl Opcode {
+    pub fn from_name(name: &str) -> Option<Opcode> {
+        Opcode::ALL
+            .iter()
+            .copied()
+            .find(|opcode| opcode.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
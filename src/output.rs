@@ -0,0 +1,60 @@
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+/// Destination of `PRINT` output
+pub enum Output {
+    Stdout,
+    Writer(Box<dyn Write>),
+    /// Called with each printed line, without the trailing newline
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+impl Output {
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout => writeln!(io::stdout().lock(), "{}", line),
+            Output::Writer(writer) => writeln!(writer, "{}", line),
+            Output::Callback(callback) => {
+                callback(line);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().flush(),
+            Output::Writer(writer) => writer.flush(),
+            Output::Callback(_) => Ok(()),
+        }
+    }
+}
+
+/// In-memory writer whose clones share the same buffer, so output given to
+/// a `Vm` can still be read afterwards
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
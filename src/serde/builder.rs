@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use crate::{
+    debug_info::DebugInfo, function::FunctionProto, instruction::Instruction, value::VmValue,
+};
+
+use super::Program;
+
+/// Identity of a constant for interning purposes. Arrays and objects are
+/// mutable reference types, so they are never merged with each other.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Float(u64),
+    Int(i32),
+    String(String),
+    Boolean(bool),
+    Null,
+}
+
+fn constant_key(value: &VmValue) -> Option<ConstantKey> {
+    match value {
+        VmValue::Float(v) => Some(ConstantKey::Float(v.to_bits())),
+        VmValue::Int(v) => Some(ConstantKey::Int(*v)),
+        VmValue::String(v) => Some(ConstantKey::String(v.clone())),
+        VmValue::Boolean(v) => Some(ConstantKey::Boolean(*v)),
+        VmValue::Null => Some(ConstantKey::Null),
+        _ => None,
+    }
+}
+
+/// Incrementally builds a `Program`, interning constants into the constant pool.
+///
+/// When the program is built, every instruction carrying an inline value
+/// (`LOADV` and the `*K` forms) is rewritten to its constant pool form, so
+/// each distinct constant is stored exactly once.
+#[derive(Default)]
+pub struct ProgramBuilder {
+    instructions: Vec<Instruction>,
+    constant_pool: Vec<VmValue>,
+    functions: Vec<FunctionProto>,
+    interned: HashMap<ConstantKey, usize>,
+    debug_info: Option<DebugInfo>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an instruction and returns its address
+    pub fn push(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    /// Adds an entry to the function table and returns its index
+    pub fn function(&mut self, function: FunctionProto) -> usize {
+        self.functions.push(function);
+        self.functions.len() - 1
+    }
+
+    /// Sets the source position of the next pushed instruction and of those
+    /// after it, until the next call
+    pub fn location(&mut self, file: &str, line: usize, column: usize) {
+        let address = self.next_address();
+        self.debug_info
+            .get_or_insert_default()
+            .add(address, file, line, column);
+    }
+
+    /// Returns the address the next pushed instruction will have
+    pub fn next_address(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Adds `value` to the constant pool, returning the index of an equal constant if one exists
+    pub fn constant(&mut self, value: VmValue) -> usize {
+        let key = constant_key(&value);
+        if let Some(index) = key.as_ref().and_then(|key| self.interned.get(key)) {
+            return *index;
+        }
+
+        let index = self.constant_pool.len();
+        self.constant_pool.push(value);
+        if let Some(key) = key {
+            self.interned.insert(key, index);
+        }
+        index
+    }
+
+    pub fn build(mut self) -> Program {
+        let instructions = std::mem::take(&mut self.instructions)
+            .into_iter()
+            .map(|instruction| self.intern_instruction(instruction))
+            .collect();
+
+        Program::new(instructions, self.constant_pool)
+            .with_functions(self.functions)
+            .with_debug_info(self.debug_info)
+    }
+
+    fn intern_instruction(&mut self, instruction: Instruction) -> Instruction {
+        use Instruction::*;
+        match instruction {
+            LOADV { target, value } => LOADC {
+                target,
+                constant_index: self.constant(value),
+            },
+            ADDK { target, a_value, b } => ADDC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            SUBK { target, a_value, b } => SUBC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            MULK { target, a_value, b } => MULC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            DIVK { target, a_value, b } => DIVC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            IDIVK { target, a_value, b } => IDIVC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            POWK { target, a_value, b } => POWC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            MODK { target, a_value, b } => MODC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            BXORK { target, a_value, b } => BXORC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            BANDK { target, a_value, b } => BANDC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            BORK { target, a_value, b } => BORC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            BLSHK { target, a_value, b } => BLSHC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            BRSHK { target, a_value, b } => BRSHC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            BARSHK { target, a_value, b } => BARSHC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            BNOTK {
+                target,
+                operand_value,
+            } => BNOTC {
+                target,
+                operand_constant: self.constant(operand_value),
+            },
+            NEGATEK {
+                target,
+                operand_value,
+            } => NEGATEC {
+                target,
+                operand_constant: self.constant(operand_value),
+            },
+            ANDK { target, a_value, b } => ANDC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            ORK { target, a_value, b } => ORC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            NULL_COALESCEK { target, a_value, b } => NULL_COALESCEC {
+                target,
+                a_constant: self.constant(a_value),
+                b,
+            },
+            NOTK {
+                target,
+                operand_value,
+            } => NOTC {
+                target,
+                operand_constant: self.constant(operand_value),
+            },
+            INDEXK {
+                target,
+                object,
+                index,
+            } => INDEXC {
+                target,
+                object,
+                index_constant: self.constant(index),
+            },
+            STORE_INDEXK {
+                source,
+                object,
+                index,
+            } => STORE_INDEXC {
+                source,
+                object,
+                index_constant: self.constant(index),
+            },
+            DELETE_INDEXK { object, index } => DELETE_INDEXC {
+                object,
+                index_constant: self.constant(index),
+            },
+            ARRAY_PUSHK { target, value } => ARRAY_PUSHC {
+                target,
+                constant: self.constant(value),
+            },
+            STOREK { name, value } => STOREC {
+                name,
+                constant: self.constant(value),
+            },
+            PRINTK(value) => PRINTC(self.constant(value)),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Container around an encoded program:
+//!
+//! | bytes | content                                        |
+//! |-------|------------------------------------------------|
+//! | 4     | magic, `RYDE`                                  |
+//! | 1     | format version                                 |
+//! | 4     | payload length, little endian                  |
+//! | 4     | FNV-1a checksum of the payload, little endian  |
+//! | ...   | payload                                        |
+use super::ProgramError;
+
+pub const MAGIC: [u8; 4] = *b"RYDE";
+pub const HEADER_LEN: usize = 13;
+
+/// Wraps `payload` in a header for the given format version, failing if its
+/// length does not fit the header
+pub fn wrap(version: u8, payload: &[u8]) -> Result<Vec<u8>, ProgramError> {
+    let length =
+        u32::try_from(payload.len()).map_err(|_| ProgramError::PayloadTooLarge(payload.len()))?;
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(&MAGIC);
+    out.push(version);
+    out.extend_from_slice(&length.to_le_bytes());
+    out.extend_from_slice(&checksum(payload).to_le_bytes());
+    out.extend_from_slice(payload);
+    Ok(out)
+}
+
+/// Checks the header of `buf`, returning the format version and the payload
+pub fn unwrap(buf: &[u8]) -> Result<(u8, &[u8]), ProgramError> {
+    if buf.len() < HEADER_LEN || buf[0..4] != MAGIC {
+        return Err(ProgramError::InvalidMagic);
+    }
+    let version = buf[4];
+    let length = u32::from_le_bytes(buf[5..9].try_into().unwrap()) as usize;
+    let expected_checksum = u32::from_le_bytes(buf[9..13].try_into().unwrap());
+
+    let payload = &buf[HEADER_LEN..];
+    if payload.len() != length {
+        return Err(ProgramError::LengthMismatch {
+            expected: length,
+            actual: payload.len(),
+        });
+    }
+    let actual_checksum = checksum(payload);
+    if actual_checksum != expected_checksum {
+        return Err(ProgramError::ChecksumMismatch {
+            expected: expected_checksum,
+            actual: actual_checksum,
+        });
+    }
+    Ok((version, payload))
+}
+
+/// 32-bit FNV-1a
+pub fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
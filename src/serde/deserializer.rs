@@ -1,5 +1,24 @@
-use bincode::error::DecodeError;
+use super::{
+    ProgramError, container, encoding, legacy,
+    migration::{self, MIGRATIONS, Migration},
+};
 
-pub fn deserialize(buf: Vec<u8>) -> Result<super::Program, DecodeError> {
-    bincode::decode_from_slice(buf.as_slice(), super::CONFIG).map(|v| v.0)
+pub fn deserialize(buf: Vec<u8>) -> Result<super::Program, ProgramError> {
+    deserialize_with_migrations(&buf, MIGRATIONS)
+}
+
+/// Deserializes `buf`, upgrading older format versions with `migrations`.
+/// Headerless version 0 files are decoded directly instead.
+pub fn deserialize_with_migrations(
+    buf: &[u8],
+    migrations: &[Migration],
+) -> Result<super::Program, ProgramError> {
+    // version 0 predates the container: the file is a bare bincode `Program`,
+    // starting with its version byte where the magic would be
+    if buf.first() == Some(&0) {
+        return legacy::decode_program(buf, &legacy::V0);
+    }
+    let (version, payload) = container::unwrap(buf)?;
+    let payload = migration::migrate(version, payload, migrations)?;
+    encoding::decode_program(&payload)
 }
@@ -0,0 +1,531 @@
+//! Compact bytecode encoding used as the container payload.
+//!
+//! Counts, indices and addresses are LEB128 varints and integers are zigzag
+//! varints. Every instruction is a one-byte opcode (its `Opcode` code)
+//! followed by its operands in table order:
+//!
+//! - registers are a single byte; `0xff` encodes an absent optional register
+//! - variable and native names are indices into the name pool
+//! - inline values use the same tagged encoding as the constant pool
+//!
+//! The payload is laid out as the name pool, the constant pool, the function
+//! table and the instruction stream, so `ProgramReader` can decode the pools
+//! up front and then stream instructions straight out of a borrowed slice,
+//! such as a memory-mapped file. The optional debug info comes last: a
+//! presence byte, then the file names and the line table entries.
+use std::collections::HashMap;
+
+use crate::{
+    array::DynamicArray,
+    debug_info::{DebugInfo, LineEntry},
+    function::{Capture, FunctionProto},
+    instruction::Instruction,
+    object::Object,
+    opcode::{Opcode, Operand, OperandKind},
+    value::VmValue,
+};
+
+use super::{Program, ProgramError};
+
+const NO_REGISTER: u8 = 0xff;
+
+const TAG_NULL: u8 = 0;
+const TAG_INT: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FALSE: u8 = 4;
+const TAG_TRUE: u8 = 5;
+const TAG_ARRAY: u8 = 6;
+const TAG_OBJECT: u8 = 7;
+
+/// Deepest array or object nesting accepted in inline values and constants
+const MAX_VALUE_DEPTH: usize = 64;
+
+const CAPTURE_VARIABLE: u8 = 0;
+const CAPTURE_REGISTER: u8 = 1;
+const CAPTURE_UPVALUE: u8 = 2;
+
+pub fn encode_program(program: &Program) -> Result<Vec<u8>, ProgramError> {
+    let mut out = encode_without_debug_info(program)?;
+    write_debug_info(&mut out, program.debug_info.as_ref());
+    Ok(out)
+}
+
+/// Encodes all sections but the debug info, which is the version 2 payload
+pub(super) fn encode_without_debug_info(program: &Program) -> Result<Vec<u8>, ProgramError> {
+    let mut encoder = Encoder::default();
+    for function in program.functions.iter() {
+        encoder.intern(&function.name);
+    }
+    let mut instructions = Vec::new();
+    for instruction in program.instructions.iter() {
+        encoder.instruction(&mut instructions, instruction)?;
+    }
+
+    let mut out = Vec::new();
+    write_varint(&mut out, encoder.names.len());
+    for name in encoder.names.iter() {
+        write_str(&mut out, name);
+    }
+    write_varint(&mut out, program.constant_pool.len());
+    for constant in program.constant_pool.iter() {
+        write_value(&mut out, constant)?;
+    }
+    write_varint(&mut out, program.functions.len());
+    for function in program.functions.iter() {
+        write_varint(&mut out, encoder.intern(&function.name));
+        write_varint(&mut out, function.address);
+        write_varint(&mut out, function.arity);
+        write_varint(&mut out, function.register_count);
+    }
+    write_varint(&mut out, program.instructions.len());
+    out.extend_from_slice(&instructions);
+    Ok(out)
+}
+
+pub fn decode_program(bytes: &[u8]) -> Result<Program, ProgramError> {
+    let reader = ProgramReader::new(bytes)?;
+    // The count comes from the file, so it is not trusted for preallocation
+    let mut instructions = Vec::new();
+    let mut decoder = reader.instructions();
+    for instruction in decoder.by_ref() {
+        instructions.push(instruction?);
+    }
+    let debug_info = decoder.finish()?;
+
+    Ok(Program::new(instructions, reader.constant_pool)
+        .with_functions(reader.functions)
+        .with_debug_info(debug_info))
+}
+
+#[derive(Default)]
+struct Encoder {
+    names: Vec<String>,
+    name_indices: HashMap<String, usize>,
+}
+
+impl Encoder {
+    fn intern(&mut self, name: &str) -> usize {
+        if let Some(index) = self.name_indices.get(name) {
+            return *index;
+        }
+        let index = self.names.len();
+        self.names.push(name.to_string());
+        self.name_indices.insert(name.to_string(), index);
+        index
+    }
+
+    fn instruction(
+        &mut self,
+        out: &mut Vec<u8>,
+        instruction: &Instruction,
+    ) -> Result<(), ProgramError> {
+        out.push(instruction.opcode() as u8);
+        for operand in instruction.operands() {
+            match operand {
+                Operand::Register(register) => write_register(out, register)?,
+                Operand::OptionalRegister(register) => match register {
+                    Some(register) => write_register(out, register)?,
+                    None => out.push(NO_REGISTER),
+                },
+                Operand::Address(n)
+                | Operand::Constant(n)
+                | Operand::Function(n)
+                | Operand::Upvalue(n)
+                | Operand::Number(n) => write_varint(out, n),
+                Operand::Boolean(value) => out.push(value as u8),
+                Operand::Name(name) => write_varint(out, self.intern(&name)),
+                Operand::Value(value) => write_value(out, &value)?,
+                Operand::Captures(captures) => {
+                    write_varint(out, captures.len());
+                    for capture in captures.iter() {
+                        match capture {
+                            Capture::Variable(name) => {
+                                out.push(CAPTURE_VARIABLE);
+                                write_varint(out, self.intern(name));
+                            }
+                            Capture::Register(register) => {
+                                out.push(CAPTURE_REGISTER);
+                                write_register(out, *register)?;
+                            }
+                            Capture::Upvalue(index) => {
+                                out.push(CAPTURE_UPVALUE);
+                                write_varint(out, *index);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, value: usize) {
+    let mut value = value as u64;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_debug_info(out: &mut Vec<u8>, debug_info: Option<&DebugInfo>) {
+    let Some(debug_info) = debug_info else {
+        out.push(0);
+        return;
+    };
+    out.push(1);
+    write_varint(out, debug_info.files.len());
+    for file in debug_info.files.iter() {
+        write_str(out, file);
+    }
+    write_varint(out, debug_info.entries.len());
+    for entry in debug_info.entries.iter() {
+        write_varint(out, entry.instruction);
+        write_varint(out, entry.file);
+        write_varint(out, entry.line);
+        write_varint(out, entry.column);
+    }
+}
+
+fn write_register(out: &mut Vec<u8>, register: usize) -> Result<(), ProgramError> {
+    match u8::try_from(register) {
+        Ok(byte) if byte != NO_REGISTER => {
+            out.push(byte);
+            Ok(())
+        }
+        _ => Err(ProgramError::RegisterTooLarge(register)),
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &VmValue) -> Result<(), ProgramError> {
+    match value {
+        VmValue::Null => out.push(TAG_NULL),
+        VmValue::Int(v) => {
+            out.push(TAG_INT);
+            let zigzag = ((*v << 1) ^ (*v >> 31)) as u32;
+            write_varint(out, zigzag as usize);
+        }
+        VmValue::Float(v) => {
+            out.push(TAG_FLOAT);
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        VmValue::String(s) => {
+            out.push(TAG_STRING);
+            write_str(out, s);
+        }
+        VmValue::Boolean(false) => out.push(TAG_FALSE),
+        VmValue::Boolean(true) => out.push(TAG_TRUE),
+        VmValue::DynamicArray(array) => {
+            out.push(TAG_ARRAY);
+            let elements = array.0.borrow();
+            write_varint(out, elements.len());
+            for element in elements.iter() {
+                write_value(out, element)?;
+            }
+        }
+        VmValue::Object(object) => {
+            out.push(TAG_OBJECT);
+            let entries = object.0.borrow();
+            // sorted by encoded key, as the map's iteration order differs
+            // between maps holding the same entries
+            let mut sorted = Vec::new();
+            for (key, value) in entries.iter() {
+                let mut encoded_key = Vec::new();
+                write_value(&mut encoded_key, key)?;
+                sorted.push((encoded_key, value));
+            }
+            sorted.sort_by(|a, b| a.0.cmp(&b.0));
+            write_varint(out, sorted.len());
+            for (encoded_key, value) in sorted {
+                out.extend_from_slice(&encoded_key);
+                write_value(out, value)?;
+            }
+        }
+        VmValue::Closure(_) | VmValue::Coroutine(_) => {
+            return Err(ProgramError::UnencodableValue(value.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Reads an encoded payload. The pools and function table are decoded when
+/// the reader is created; instructions are decoded on demand.
+pub struct ProgramReader<'a> {
+    pub names: Vec<&'a str>,
+    pub constant_pool: Vec<VmValue>,
+    pub functions: Vec<FunctionProto>,
+    instruction_count: usize,
+    instructions: Cursor<'a>,
+}
+
+impl<'a> ProgramReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ProgramError> {
+        let mut cursor = Cursor { bytes, position: 0 };
+
+        let name_count = cursor.varint()?;
+        let names = (0..name_count)
+            .map(|_| cursor.str())
+            .collect::<Result<Vec<_>, _>>()?;
+        let constant_count = cursor.varint()?;
+        let constant_pool = (0..constant_count)
+            .map(|_| cursor.value())
+            .collect::<Result<Vec<_>, _>>()?;
+        let function_count = cursor.varint()?;
+        let mut functions = Vec::new();
+        for _ in 0..function_count {
+            let name = cursor.name(&names)?;
+            functions.push(FunctionProto::new(
+                name,
+                cursor.varint()?,
+                cursor.varint()?,
+                cursor.varint()?,
+            ));
+        }
+        let instruction_count = cursor.varint()?;
+
+        Ok(Self {
+            names,
+            constant_pool,
+            functions,
+            instruction_count,
+            instructions: cursor,
+        })
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    /// Streams the instructions in order
+    pub fn instructions(&self) -> InstructionDecoder<'a, '_> {
+        InstructionDecoder {
+            names: &self.names,
+            cursor: self.instructions.clone(),
+            remaining: self.instruction_count,
+        }
+    }
+}
+
+pub struct InstructionDecoder<'a, 'r> {
+    names: &'r [&'a str],
+    cursor: Cursor<'a>,
+    remaining: usize,
+}
+
+impl InstructionDecoder<'_, '_> {
+    /// Reads the debug info following the instructions once every
+    /// instruction has been read, checking that it ends the payload
+    pub fn finish(&self) -> Result<Option<DebugInfo>, ProgramError> {
+        if self.remaining > 0 {
+            return Ok(None);
+        }
+        let mut cursor = self.cursor.clone();
+        let debug_info = cursor.debug_info()?;
+        if cursor.position < cursor.bytes.len() {
+            return Err(cursor.error("trailing bytes after the debug info"));
+        }
+        Ok(debug_info)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, ProgramError> {
+        let offset = self.cursor.position;
+        let byte = self.cursor.byte()?;
+        let opcode = Opcode::from_byte(byte)
+            .ok_or_else(|| self.cursor.error(format!("unknown opcode {:#04x}", byte)))?;
+
+        let mut operands = Vec::new();
+        for kind in opcode.operand_kinds() {
+            let cursor = &mut self.cursor;
+            let operand = match kind {
+                OperandKind::Register => Operand::Register(cursor.byte()? as usize),
+                OperandKind::OptionalRegister => match cursor.byte()? {
+                    NO_REGISTER => Operand::OptionalRegister(None),
+                    register => Operand::OptionalRegister(Some(register as usize)),
+                },
+                OperandKind::Address => Operand::Address(cursor.varint()?),
+                OperandKind::Constant => Operand::Constant(cursor.varint()?),
+                OperandKind::Function => Operand::Function(cursor.varint()?),
+                OperandKind::Upvalue => Operand::Upvalue(cursor.varint()?),
+                OperandKind::Number => Operand::Number(cursor.varint()?),
+                OperandKind::Boolean => Operand::Boolean(cursor.byte()? != 0),
+                OperandKind::Name => Operand::Name(cursor.name(self.names)?.to_string()),
+                OperandKind::Value => Operand::Value(cursor.value()?),
+                OperandKind::Captures => {
+                    let count = cursor.varint()?;
+                    let mut captures = Vec::new();
+                    for _ in 0..count {
+                        captures.push(match cursor.byte()? {
+                            CAPTURE_VARIABLE => {
+                                Capture::Variable(cursor.name(self.names)?.to_string())
+                            }
+                            CAPTURE_REGISTER => Capture::Register(cursor.byte()? as usize),
+                            CAPTURE_UPVALUE => Capture::Upvalue(cursor.varint()?),
+                            tag => {
+                                return Err(cursor.error(format!("unknown capture tag {}", tag)));
+                            }
+                        });
+                    }
+                    Operand::Captures(captures)
+                }
+            };
+            operands.push(operand);
+        }
+
+        Instruction::from_operands(opcode, operands).ok_or(ProgramError::MalformedBytecode {
+            offset,
+            message: format!("invalid operands for {}", opcode),
+        })
+    }
+}
+
+impl Iterator for InstructionDecoder<'_, '_> {
+    type Item = Result<Instruction, ProgramError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let result = self.instruction();
+        // Stop after the first error, the rest of the stream cannot be located
+        self.remaining = if result.is_ok() {
+            self.remaining - 1
+        } else {
+            0
+        };
+        Some(result)
+    }
+}
+
+#[derive(Clone)]
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn error(&self, message: impl Into<String>) -> ProgramError {
+        ProgramError::MalformedBytecode {
+            offset: self.position,
+            message: message.into(),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProgramError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| self.error("unexpected end of bytecode"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, ProgramError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<usize, ProgramError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(value).map_err(|_| self.error("varint is too large"));
+            }
+        }
+        Err(self.error("varint is too long"))
+    }
+
+    fn str(&mut self) -> Result<&'a str, ProgramError> {
+        let len = self.varint()?;
+        let bytes = self.take(len)?;
+        std::str::from_utf8(bytes).map_err(|_| self.error("string is not valid UTF-8"))
+    }
+
+    fn name<'n>(&mut self, names: &[&'n str]) -> Result<&'n str, ProgramError> {
+        let index = self.varint()?;
+        names
+            .get(index)
+            .copied()
+            .ok_or_else(|| self.error(format!("invalid name index {}", index)))
+    }
+
+    fn debug_info(&mut self) -> Result<Option<DebugInfo>, ProgramError> {
+        match self.byte()? {
+            0 => return Ok(None),
+            1 => {}
+            flag => return Err(self.error(format!("invalid debug info flag {}", flag))),
+        }
+        let file_count = self.varint()?;
+        let files = (0..file_count)
+            .map(|_| Ok(self.str()?.to_string()))
+            .collect::<Result<Vec<_>, ProgramError>>()?;
+        let entry_count = self.varint()?;
+        let mut entries = Vec::new();
+        for _ in 0..entry_count {
+            let entry = LineEntry {
+                instruction: self.varint()?,
+                file: self.varint()?,
+                line: self.varint()?,
+                column: self.varint()?,
+            };
+            if entry.file >= files.len() {
+                return Err(self.error(format!("invalid file index {}", entry.file)));
+            }
+            entries.push(entry);
+        }
+        Ok(Some(DebugInfo { files, entries }))
+    }
+
+    fn value(&mut self) -> Result<VmValue, ProgramError> {
+        self.nested_value(0)
+    }
+
+    fn nested_value(&mut self, depth: usize) -> Result<VmValue, ProgramError> {
+        if depth > MAX_VALUE_DEPTH {
+            return Err(self.error("values are nested too deeply"));
+        }
+        let value = match self.byte()? {
+            TAG_NULL => VmValue::Null,
+            TAG_INT => {
+                let zigzag = u32::try_from(self.varint()?)
+                    .map_err(|_| self.error("integer is out of range"))?;
+                VmValue::Int(((zigzag >> 1) as i32) ^ -((zigzag & 1) as i32))
+            }
+            TAG_FLOAT => VmValue::Float(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            TAG_STRING => VmValue::String(self.str()?.to_string()),
+            TAG_FALSE => VmValue::Boolean(false),
+            TAG_TRUE => VmValue::Boolean(true),
+            TAG_ARRAY => {
+                let array = DynamicArray::new();
+                for _ in 0..self.varint()? {
+                    array.0.borrow_mut().push(self.nested_value(depth + 1)?);
+                }
+                VmValue::DynamicArray(array)
+            }
+            TAG_OBJECT => {
+                let object = Object::new();
+                for _ in 0..self.varint()? {
+                    let key = self.nested_value(depth + 1)?;
+                    let value = self.nested_value(depth + 1)?;
+                    object.0.borrow_mut().insert(key, value);
+                }
+                VmValue::Object(object)
+            }
+            tag => return Err(self.error(format!("unknown value tag {}", tag))),
+        };
+        Ok(value)
+    }
+}
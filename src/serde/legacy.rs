@@ -0,0 +1,435 @@
+//! Frozen copies of the bincode layouts of programs stored before the
+//! compact encoding.
+//!
+//! Headerless version 0 files and version 1 payloads are the bincode
+//! encoding of `Program` as it was then. Bincode identifies enum variants by
+//! their position in the declaration, so decoding them with today's
+//! `Instruction` and `VmValue` breaks as soon as a variant is inserted, as
+//! the constant pool opcodes were in version 1. The tables below list the
+//! variants in their original order and are never to be edited.
+use bincode::{Decode, de::Decoder, error::DecodeError};
+
+use super::{CONFIG, Program, ProgramError};
+use crate::{
+    array::DynamicArray,
+    function::{Capture, FunctionProto},
+    instruction::Instruction,
+    object::Object,
+    opcode::{Opcode, Operand, OperandKind},
+    value::VmValue,
+};
+
+use OperandKind::*;
+
+/// Deepest array or object nesting accepted in values
+const MAX_VALUE_DEPTH: usize = 64;
+
+/// Bincode layout of the programs of one format version
+pub struct Layout {
+    /// `Instruction` variants in declaration order, with their fields
+    instructions: &'static [(Opcode, &'static [OperandKind])],
+    /// `VmValue` variants in declaration order
+    values: &'static [ValueVariant],
+    /// Whether `Program` ends with the function table, added in version 1
+    functions: bool,
+}
+
+#[derive(Clone, Copy)]
+enum ValueVariant {
+    Float,
+    Int,
+    String,
+    Boolean,
+    DynamicArray,
+    Object,
+    Closure,
+    Null,
+}
+
+pub const V0: Layout = Layout {
+    instructions: V0_INSTRUCTIONS,
+    values: &[
+        ValueVariant::Float,
+        ValueVariant::Int,
+        ValueVariant::String,
+        ValueVariant::Boolean,
+        ValueVariant::DynamicArray,
+        ValueVariant::Object,
+        ValueVariant::Null,
+    ],
+    functions: false,
+};
+
+pub const V1: Layout = Layout {
+    instructions: V1_INSTRUCTIONS,
+    values: &[
+        ValueVariant::Float,
+        ValueVariant::Int,
+        ValueVariant::String,
+        ValueVariant::Boolean,
+        ValueVariant::DynamicArray,
+        ValueVariant::Object,
+        ValueVariant::Closure,
+        ValueVariant::Null,
+    ],
+    functions: true,
+};
+
+/// `Instruction` as of version 0
+const V0_INSTRUCTIONS: &[(Opcode, &[OperandKind])] = &[
+    (Opcode::LOADV, &[Register, Value]),
+    (Opcode::ADD, &[Register, Register, Register]),
+    (Opcode::ADDK, &[Register, Value, Register]),
+    (Opcode::SUB, &[Register, Register, Register]),
+    (Opcode::SUBK, &[Register, Value, Register]),
+    (Opcode::MUL, &[Register, Register, Register]),
+    (Opcode::MULK, &[Register, Value, Register]),
+    (Opcode::DIV, &[Register, Register, Register]),
+    (Opcode::DIVK, &[Register, Value, Register]),
+    (Opcode::IDIV, &[Register, Register, Register]),
+    (Opcode::IDIVK, &[Register, Value, Register]),
+    (Opcode::POW, &[Register, Register, Register]),
+    (Opcode::POWK, &[Register, Value, Register]),
+    (Opcode::MOD, &[Register, Register, Register]),
+    (Opcode::MODK, &[Register, Value, Register]),
+    (Opcode::BXOR, &[Register, Register, Register]),
+    (Opcode::BXORK, &[Register, Value, Register]),
+    (Opcode::BAND, &[Register, Register, Register]),
+    (Opcode::BANDK, &[Register, Value, Register]),
+    (Opcode::BOR, &[Register, Register, Register]),
+    (Opcode::BORK, &[Register, Value, Register]),
+    (Opcode::BLSH, &[Register, Register, Register]),
+    (Opcode::BLSHK, &[Register, Value, Register]),
+    (Opcode::BRSH, &[Register, Register, Register]),
+    (Opcode::BRSHK, &[Register, Value, Register]),
+    (Opcode::BARSH, &[Register, Register, Register]),
+    (Opcode::BARSHK, &[Register, Value, Register]),
+    (Opcode::BNOT, &[Register, Register]),
+    (Opcode::BNOTK, &[Register, Value]),
+    (Opcode::NEGATE, &[Register, Register]),
+    (Opcode::NEGATEK, &[Register, Value]),
+    (Opcode::AND, &[Register, Register, Register]),
+    (Opcode::ANDK, &[Register, Value, Register]),
+    (Opcode::OR, &[Register, Register, Register]),
+    (Opcode::ORK, &[Register, Value, Register]),
+    (Opcode::NULL_COALESCE, &[Register, Register, Register]),
+    (Opcode::NULL_COALESCEK, &[Register, Value, Register]),
+    (Opcode::EQ, &[Register, Register, Register]),
+    (Opcode::NEQ, &[Register, Register, Register]),
+    (Opcode::LT, &[Register, Register, Register]),
+    (Opcode::LTE, &[Register, Register, Register]),
+    (Opcode::GT, &[Register, Register, Register]),
+    (Opcode::GTE, &[Register, Register, Register]),
+    (Opcode::NOT, &[Register, Register]),
+    (Opcode::NOTK, &[Register, Value]),
+    (Opcode::INC, &[OptionalRegister, Name, Boolean]),
+    (Opcode::DEC, &[OptionalRegister, Name, Boolean]),
+    (Opcode::INDEX, &[Register, Register, Register]),
+    (Opcode::INDEXN, &[Register, Register, Number]),
+    (Opcode::INDEXK, &[Register, Register, Value]),
+    (Opcode::STORE_INDEX, &[Register, Register, Register]),
+    (Opcode::STORE_INDEXN, &[Register, Register, Number]),
+    (Opcode::STORE_INDEXK, &[Register, Register, Value]),
+    (Opcode::DELETE_INDEX, &[Register, Register]),
+    (Opcode::DELETE_INDEXN, &[Register, Number]),
+    (Opcode::DELETE_INDEXK, &[Register, Value]),
+    (Opcode::NEW_OBJECT, &[Register]),
+    (Opcode::NEW_ARRAY, &[Register]),
+    (Opcode::ARRAY_PUSH, &[Register, Register]),
+    (Opcode::ARRAY_PUSHK, &[Register, Value]),
+    (Opcode::LEN, &[Register, Register]),
+    (Opcode::JMP, &[Address]),
+    (Opcode::JZ, &[Register, Address]),
+    (Opcode::JNZ, &[Register, Address]),
+    (Opcode::JLT, &[Register, Register, Address]),
+    (Opcode::JLTE, &[Register, Register, Address]),
+    (Opcode::JGT, &[Register, Register, Address]),
+    (Opcode::JGTE, &[Register, Register, Address]),
+    (Opcode::JEQ, &[Register, Register, Address]),
+    (Opcode::JNEQ, &[Register, Register, Address]),
+    (Opcode::STORE, &[Register, Name]),
+    (Opcode::STOREK, &[Name, Value]),
+    (Opcode::LOAD, &[Register, Name]),
+    (Opcode::CALL, &[Address]),
+    (Opcode::RETURN, &[]),
+    (Opcode::PRINT, &[Register]),
+    (Opcode::PRINTK, &[Value]),
+    (Opcode::HALT, &[]),
+];
+
+/// `Instruction` as of version 1
+const V1_INSTRUCTIONS: &[(Opcode, &[OperandKind])] = &[
+    (Opcode::LOADC, &[Register, Constant]),
+    (Opcode::LOADV, &[Register, Value]),
+    (Opcode::ADD, &[Register, Register, Register]),
+    (Opcode::ADDK, &[Register, Value, Register]),
+    (Opcode::ADDC, &[Register, Constant, Register]),
+    (Opcode::SUB, &[Register, Register, Register]),
+    (Opcode::SUBK, &[Register, Value, Register]),
+    (Opcode::SUBC, &[Register, Constant, Register]),
+    (Opcode::MUL, &[Register, Register, Register]),
+    (Opcode::MULK, &[Register, Value, Register]),
+    (Opcode::MULC, &[Register, Constant, Register]),
+    (Opcode::DIV, &[Register, Register, Register]),
+    (Opcode::DIVK, &[Register, Value, Register]),
+    (Opcode::DIVC, &[Register, Constant, Register]),
+    (Opcode::IDIV, &[Register, Register, Register]),
+    (Opcode::IDIVK, &[Register, Value, Register]),
+    (Opcode::IDIVC, &[Register, Constant, Register]),
+    (Opcode::POW, &[Register, Register, Register]),
+    (Opcode::POWK, &[Register, Value, Register]),
+    (Opcode::POWC, &[Register, Constant, Register]),
+    (Opcode::MOD, &[Register, Register, Register]),
+    (Opcode::MODK, &[Register, Value, Register]),
+    (Opcode::MODC, &[Register, Constant, Register]),
+    (Opcode::BXOR, &[Register, Register, Register]),
+    (Opcode::BXORK, &[Register, Value, Register]),
+    (Opcode::BXORC, &[Register, Constant, Register]),
+    (Opcode::BAND, &[Register, Register, Register]),
+    (Opcode::BANDK, &[Register, Value, Register]),
+    (Opcode::BANDC, &[Register, Constant, Register]),
+    (Opcode::BOR, &[Register, Register, Register]),
+    (Opcode::BORK, &[Register, Value, Register]),
+    (Opcode::BORC, &[Register, Constant, Register]),
+    (Opcode::BLSH, &[Register, Register, Register]),
+    (Opcode::BLSHK, &[Register, Value, Register]),
+    (Opcode::BLSHC, &[Register, Constant, Register]),
+    (Opcode::BRSH, &[Register, Register, Register]),
+    (Opcode::BRSHK, &[Register, Value, Register]),
+    (Opcode::BRSHC, &[Register, Constant, Register]),
+    (Opcode::BARSH, &[Register, Register, Register]),
+    (Opcode::BARSHK, &[Register, Value, Register]),
+    (Opcode::BARSHC, &[Register, Constant, Register]),
+    (Opcode::BNOT, &[Register, Register]),
+    (Opcode::BNOTK, &[Register, Value]),
+    (Opcode::BNOTC, &[Register, Constant]),
+    (Opcode::NEGATE, &[Register, Register]),
+    (Opcode::NEGATEK, &[Register, Value]),
+    (Opcode::NEGATEC, &[Register, Constant]),
+    (Opcode::AND, &[Register, Register, Register]),
+    (Opcode::ANDK, &[Register, Value, Register]),
+    (Opcode::ANDC, &[Register, Constant, Register]),
+    (Opcode::OR, &[Register, Register, Register]),
+    (Opcode::ORK, &[Register, Value, Register]),
+    (Opcode::ORC, &[Register, Constant, Register]),
+    (Opcode::NULL_COALESCE, &[Register, Register, Register]),
+    (Opcode::NULL_COALESCEK, &[Register, Value, Register]),
+    (Opcode::NULL_COALESCEC, &[Register, Constant, Register]),
+    (Opcode::EQ, &[Register, Register, Register]),
+    (Opcode::NEQ, &[Register, Register, Register]),
+    (Opcode::LT, &[Register, Register, Register]),
+    (Opcode::LTE, &[Register, Register, Register]),
+    (Opcode::GT, &[Register, Register, Register]),
+    (Opcode::GTE, &[Register, Register, Register]),
+    (Opcode::NOT, &[Register, Register]),
+    (Opcode::NOTK, &[Register, Value]),
+    (Opcode::NOTC, &[Register, Constant]),
+    (Opcode::INC, &[OptionalRegister, Name, Boolean]),
+    (Opcode::DEC, &[OptionalRegister, Name, Boolean]),
+    (Opcode::INDEX, &[Register, Register, Register]),
+    (Opcode::INDEXN, &[Register, Register, Number]),
+    (Opcode::INDEXK, &[Register, Register, Value]),
+    (Opcode::INDEXC, &[Register, Register, Constant]),
+    (Opcode::STORE_INDEX, &[Register, Register, Register]),
+    (Opcode::STORE_INDEXN, &[Register, Register, Number]),
+    (Opcode::STORE_INDEXK, &[Register, Register, Value]),
+    (Opcode::STORE_INDEXC, &[Register, Register, Constant]),
+    (Opcode::DELETE_INDEX, &[Register, Register]),
+    (Opcode::DELETE_INDEXN, &[Register, Number]),
+    (Opcode::DELETE_INDEXK, &[Register, Value]),
+    (Opcode::DELETE_INDEXC, &[Register, Constant]),
+    (Opcode::NEW_OBJECT, &[Register]),
+    (Opcode::NEW_ARRAY, &[Register]),
+    (Opcode::ARRAY_PUSH, &[Register, Register]),
+    (Opcode::ARRAY_PUSHK, &[Register, Value]),
+    (Opcode::ARRAY_PUSHC, &[Register, Constant]),
+    (Opcode::LEN, &[Register, Register]),
+    (Opcode::JMP, &[Address]),
+    (Opcode::JZ, &[Register, Address]),
+    (Opcode::JNZ, &[Register, Address]),
+    (Opcode::JLT, &[Register, Register, Address]),
+    (Opcode::JLTE, &[Register, Register, Address]),
+    (Opcode::JGT, &[Register, Register, Address]),
+    (Opcode::JGTE, &[Register, Register, Address]),
+    (Opcode::JEQ, &[Register, Register, Address]),
+    (Opcode::JNEQ, &[Register, Register, Address]),
+    (Opcode::STORE, &[Register, Name]),
+    (Opcode::STOREK, &[Name, Value]),
+    (Opcode::STOREC, &[Name, Constant]),
+    (Opcode::LOAD, &[Register, Name]),
+    (Opcode::DECLARE, &[Register, Name]),
+    (Opcode::STORE_GLOBAL, &[Register, Name]),
+    (Opcode::LOAD_GLOBAL, &[Register, Name]),
+    (Opcode::PUSH_SCOPE, &[]),
+    (Opcode::POP_SCOPE, &[]),
+    (Opcode::CALL, &[Address]),
+    (
+        Opcode::CALLF,
+        &[Function, Register, Number, Register, Number],
+    ),
+    (
+        Opcode::CALLR,
+        &[Register, Register, Number, Register, Number],
+    ),
+    (Opcode::CLOSURE, &[Register, Function, Captures]),
+    (Opcode::GETUPVAL, &[Register, Upvalue]),
+    (Opcode::SETUPVAL, &[Register, Upvalue]),
+    (Opcode::CALL_NATIVE, &[Name, Register, Number, Register]),
+    (
+        Opcode::CALL_NATIVEC,
+        &[Constant, Register, Number, Register],
+    ),
+    (Opcode::RETURN, &[]),
+    (Opcode::RETURNV, &[Register, Number]),
+    (Opcode::PRINT, &[Register]),
+    (Opcode::PRINTK, &[Value]),
+    (Opcode::PRINTC, &[Constant]),
+    (Opcode::HALT, &[]),
+];
+
+/// Decodes a payload laid out as `layout`, rejecting trailing bytes
+pub fn decode_program(payload: &[u8], layout: &'static Layout) -> Result<Program, ProgramError> {
+    let (LegacyProgram(program), read) =
+        bincode::decode_from_slice_with_context(payload, CONFIG, layout)
+            .map_err(ProgramError::DecodeError)?;
+    if read != payload.len() {
+        return Err(ProgramError::LengthMismatch {
+            expected: read,
+            actual: payload.len(),
+        });
+    }
+    Ok(program)
+}
+
+struct LegacyProgram(Program);
+
+impl Decode<&'static Layout> for LegacyProgram {
+    fn decode<D: Decoder<Context = &'static Layout>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let layout = *decoder.context();
+        let _version = u8::decode(decoder)?;
+        let constant_pool = sequence(decoder, |decoder| value(decoder, layout, 0))?;
+        let instructions = sequence(decoder, |decoder| instruction(decoder, layout))?;
+        let functions = if layout.functions {
+            sequence(decoder, function)?
+        } else {
+            Vec::new()
+        };
+        Ok(Self(
+            Program::new(instructions, constant_pool).with_functions(functions),
+        ))
+    }
+}
+
+/// Decodes a length-prefixed sequence. The length comes from the file, so it
+/// is not trusted for preallocation.
+fn sequence<D: Decoder, T>(
+    decoder: &mut D,
+    mut element: impl FnMut(&mut D) -> Result<T, DecodeError>,
+) -> Result<Vec<T>, DecodeError> {
+    let mut elements = Vec::new();
+    for _ in 0..usize::decode(decoder)? {
+        elements.push(element(decoder)?);
+    }
+    Ok(elements)
+}
+
+fn instruction<D: Decoder>(decoder: &mut D, layout: &Layout) -> Result<Instruction, DecodeError> {
+    let variant = u32::decode(decoder)?;
+    let (opcode, fields) = layout
+        .instructions
+        .get(variant as usize)
+        .ok_or_else(|| DecodeError::OtherString(format!("unknown instruction {}", variant)))?;
+    let mut operands = Vec::new();
+    for kind in fields.iter() {
+        operands.push(operand(decoder, layout, *kind)?);
+    }
+    Instruction::from_operands(*opcode, operands).ok_or_else(|| {
+        DecodeError::OtherString(format!("operands of {} do not match its opcode", opcode))
+    })
+}
+
+fn operand<D: Decoder>(
+    decoder: &mut D,
+    layout: &Layout,
+    kind: OperandKind,
+) -> Result<Operand, DecodeError> {
+    let operand = match kind {
+        Register => Operand::Register(usize::decode(decoder)?),
+        OptionalRegister => Operand::OptionalRegister(Option::<usize>::decode(decoder)?),
+        Address => Operand::Address(usize::decode(decoder)?),
+        Constant => Operand::Constant(usize::decode(decoder)?),
+        Function => Operand::Function(usize::decode(decoder)?),
+        Upvalue => Operand::Upvalue(usize::decode(decoder)?),
+        Number => Operand::Number(usize::decode(decoder)?),
+        Boolean => Operand::Boolean(bool::decode(decoder)?),
+        Name => Operand::Name(String::decode(decoder)?),
+        Value => Operand::Value(value(decoder, layout, 0)?),
+        Captures => Operand::Captures(sequence(decoder, capture)?),
+    };
+    Ok(operand)
+}
+
+fn capture<D: Decoder>(decoder: &mut D) -> Result<Capture, DecodeError> {
+    match u32::decode(decoder)? {
+        0 => Ok(Capture::Variable(String::decode(decoder)?)),
+        1 => Ok(Capture::Register(usize::decode(decoder)?)),
+        2 => Ok(Capture::Upvalue(usize::decode(decoder)?)),
+        variant => Err(DecodeError::OtherString(format!(
+            "unknown capture {}",
+            variant
+        ))),
+    }
+}
+
+fn value<D: Decoder>(
+    decoder: &mut D,
+    layout: &Layout,
+    depth: usize,
+) -> Result<VmValue, DecodeError> {
+    if depth > MAX_VALUE_DEPTH {
+        return Err(DecodeError::Other("values are nested too deeply"));
+    }
+    let variant = u32::decode(decoder)?;
+    let variant = layout
+        .values
+        .get(variant as usize)
+        .ok_or_else(|| DecodeError::OtherString(format!("unknown value {}", variant)))?;
+    let value = match variant {
+        ValueVariant::Float => VmValue::Float(f64::decode(decoder)?),
+        ValueVariant::Int => VmValue::Int(i32::decode(decoder)?),
+        ValueVariant::String => VmValue::String(String::decode(decoder)?),
+        ValueVariant::Boolean => VmValue::Boolean(bool::decode(decoder)?),
+        ValueVariant::DynamicArray => {
+            let array = DynamicArray::new();
+            for _ in 0..usize::decode(decoder)? {
+                array
+                    .0
+                    .borrow_mut()
+                    .push(value(decoder, layout, depth + 1)?);
+            }
+            VmValue::DynamicArray(array)
+        }
+        ValueVariant::Object => {
+            let object = Object::new();
+            for _ in 0..usize::decode(decoder)? {
+                let key = value(decoder, layout, depth + 1)?;
+                let value = value(decoder, layout, depth + 1)?;
+                object.0.borrow_mut().insert(key, value);
+            }
+            VmValue::Object(object)
+        }
+        ValueVariant::Closure => {
+            return Err(DecodeError::Other("closures cannot be stored in a program"));
+        }
+        ValueVariant::Null => VmValue::Null,
+    };
+    Ok(value)
+}
+
+fn function<D: Decoder>(decoder: &mut D) -> Result<FunctionProto, DecodeError> {
+    let name = String::decode(decoder)?;
+    let address = usize::decode(decoder)?;
+    let arity = usize::decode(decoder)?;
+    let register_count = usize::decode(decoder)?;
+    Ok(FunctionProto::new(&name, address, arity, register_count))
+}
@@ -0,0 +1,64 @@
+//! Upgrades payloads written by older format versions.
+//!
+//! When the encoding of a program changes (for example when opcodes are
+//! renumbered), bump `CURRENT_VERSION` and append a `Migration` from the
+//! previous version that rewrites an old payload into the new encoding.
+//! Loading applies migrations in sequence until the payload is current.
+use super::{CURRENT_VERSION, ProgramError, encoding, legacy};
+
+pub struct Migration {
+    /// Version of the payloads this migration accepts. It produces `from + 1`.
+    pub from: u8,
+    pub migrate: fn(&[u8]) -> Result<Vec<u8>, ProgramError>,
+}
+
+/// Migrations applied when loading programs. Version 1 is the first
+/// container format; headerless version 0 files have no payload to migrate
+/// and are decoded with their frozen layout by the deserializer.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        migrate: bincode_to_compact,
+    },
+    Migration {
+        from: 2,
+        migrate: add_debug_info,
+    },
+];
+
+/// Version 1 payloads are the bincode encoding of `Program`. The compact
+/// encoding produced is that of version 2, without debug info.
+fn bincode_to_compact(payload: &[u8]) -> Result<Vec<u8>, ProgramError> {
+    let program = legacy::decode_program(payload, &legacy::V1)?;
+    encoding::encode_without_debug_info(&program)
+}
+
+/// Version 3 appends the optional debug info to the payload
+fn add_debug_info(payload: &[u8]) -> Result<Vec<u8>, ProgramError> {
+    let mut payload = payload.to_vec();
+    payload.push(0);
+    Ok(payload)
+}
+
+/// Brings `payload` from `version` up to `CURRENT_VERSION`
+pub fn migrate(
+    version: u8,
+    payload: &[u8],
+    migrations: &[Migration],
+) -> Result<Vec<u8>, ProgramError> {
+    if version > CURRENT_VERSION {
+        return Err(ProgramError::UnsupportedVersion(version));
+    }
+
+    let mut version = version;
+    let mut payload = payload.to_vec();
+    while version < CURRENT_VERSION {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(ProgramError::UnsupportedVersion(version))?;
+        payload = (migration.migrate)(&payload)?;
+        version += 1;
+    }
+    Ok(payload)
+}
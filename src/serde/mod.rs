@@ -1,15 +1,26 @@
-use crate::{instruction::Instruction, value::VmValue};
+use std::{error::Error, fmt};
+
+use crate::{
+    debug_info::DebugInfo, function::FunctionProto, instruction::Instruction, value::VmValue,
+};
 use bincode::{
     Decode, Encode,
     config::{self, Configuration},
     error::DecodeError,
 };
 
+pub mod builder;
+pub mod container;
 pub mod deserializer;
+pub mod encoding;
+mod legacy;
+pub mod migration;
 pub mod serializer;
 
 const CONFIG: Configuration = config::standard();
-const CURRENT_VERSION: u8 = 0;
+/// Format version written by `serializer::serialize`. Bump it, and add a
+/// migration, whenever the encoding of a program changes.
+pub const CURRENT_VERSION: u8 = 3;
 
 #[repr(C)]
 #[derive(Encode, Decode, PartialEq, Debug)]
@@ -17,12 +28,91 @@ pub struct Program {
     pub version: u8,
     pub constant_pool: Vec<VmValue>,
     pub instructions: Vec<Instruction>,
+    pub functions: Vec<FunctionProto>,
+    pub debug_info: Option<DebugInfo>,
 }
 
 #[derive(Debug)]
 pub enum ProgramError {
     FileError(std::io::Error),
     DecodeError(DecodeError),
+    /// The data neither starts with the container header nor is a headerless
+    /// version 0 program
+    InvalidMagic,
+    /// The format version is newer than this build, or no migration exists for it
+    UnsupportedVersion(u8),
+    /// The payload is longer than the 32-bit length in the container header
+    PayloadTooLarge(usize),
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// Registers are encoded as a single byte, `0xff` being reserved
+    RegisterTooLarge(usize),
+    /// Only plain data can be stored in a program, not runtime values such as closures
+    UnencodableValue(String),
+    MalformedBytecode {
+        offset: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::FileError(error) => write!(f, "File error: {}", error),
+            ProgramError::DecodeError(error) => write!(f, "Decode error: {}", error),
+            ProgramError::InvalidMagic => write!(f, "Not a Ryde program: invalid magic bytes"),
+            ProgramError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported format version {} (current version is {})",
+                version, CURRENT_VERSION
+            ),
+            ProgramError::PayloadTooLarge(length) => write!(
+                f,
+                "Payload of {} bytes is too large, the limit is {} bytes",
+                length,
+                u32::MAX
+            ),
+            ProgramError::LengthMismatch { expected, actual } => write!(
+                f,
+                "Payload length mismatch: expected {} bytes, got {}",
+                expected, actual
+            ),
+            ProgramError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            ProgramError::RegisterTooLarge(register) => {
+                write!(
+                    f,
+                    "Register r{} cannot be encoded, the limit is r254",
+                    register
+                )
+            }
+            ProgramError::UnencodableValue(value) => {
+                write!(f, "Value '{}' cannot be encoded", value)
+            }
+            ProgramError::MalformedBytecode { offset, message } => {
+                write!(f, "Malformed bytecode at offset {}: {}", offset, message)
+            }
+        }
+    }
+}
+
+impl Error for ProgramError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProgramError::FileError(error) => Some(error),
+            ProgramError::DecodeError(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl Program {
@@ -31,6 +121,8 @@ impl Program {
             version: CURRENT_VERSION,
             instructions,
             constant_pool,
+            functions: Vec::new(),
+            debug_info: None,
         }
     }
 
@@ -39,11 +131,23 @@ impl Program {
             version: CURRENT_VERSION,
             instructions,
             constant_pool: Vec::new(),
+            functions: Vec::new(),
+            debug_info: None,
         }
     }
 
+    pub fn with_functions(mut self, functions: Vec<FunctionProto>) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    pub fn with_debug_info(mut self, debug_info: Option<DebugInfo>) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+
     pub fn from_file(path: &str) -> Result<Program, ProgramError> {
         let binary = std::fs::read(path).map_err(ProgramError::FileError)?;
-        deserializer::deserialize(binary).map_err(ProgramError::DecodeError)
+        deserializer::deserialize(binary)
     }
 }
@@ -1,5 +1,6 @@
-use bincode::error::EncodeError;
+use super::{CURRENT_VERSION, ProgramError, container, encoding};
 
-pub fn serialize(program: &super::Program) -> Result<Vec<u8>, EncodeError> {
-    bincode::encode_to_vec(program, super::CONFIG)
+pub fn serialize(program: &super::Program) -> Result<Vec<u8>, ProgramError> {
+    let payload = encoding::encode_program(program)?;
+    container::wrap(CURRENT_VERSION, &payload)
 }
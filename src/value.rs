@@ -8,7 +8,10 @@ use std::{
     rc::Rc,
 };
 
-use crate::{array::DynamicArray, error::vm::VmError, object::Object};
+use crate::{
+    array::DynamicArray, coroutine::Coroutine, error::vm::VmError, function::Closure,
+    object::Object,
+};
 
 pub type SharedValue = Rc<RefCell<VmValue>>;
 
@@ -20,6 +23,8 @@ pub enum VmValue {
     Boolean(bool),
     DynamicArray(DynamicArray),
     Object(Object),
+    Closure(Closure),
+    Coroutine(Coroutine),
     Null,
 }
 
@@ -132,6 +137,12 @@ impl VmValue {
                 }
                 write!(f, "}}")
             }
+            VmValue::Closure(closure) => write!(f, "<function #{}>", closure.function),
+            VmValue::Coroutine(coroutine) => write!(
+                f,
+                "<coroutine of function #{}>",
+                coroutine.0.borrow().closure.function
+            ),
             VmValue::Null => write!(f, "null"),
         }
     }
@@ -146,6 +157,8 @@ impl Hash for VmValue {
             VmValue::Boolean(b) => b.hash(state),
             VmValue::DynamicArray(arr) => arr.hash(state),
             VmValue::Object(obj) => obj.hash(state),
+            VmValue::Closure(closure) => closure.hash(state),
+            VmValue::Coroutine(coroutine) => coroutine.hash(state),
             VmValue::Null => ().hash(state),
         }
     }
@@ -185,6 +198,8 @@ impl PartialEq for VmValue {
             (VmValue::Float(a), VmValue::Int(b)) => *a == (*b as f64),
             (VmValue::Boolean(a), VmValue::Boolean(b)) => *a == *b,
             (VmValue::String(a), VmValue::String(b)) => *a == *b,
+            (VmValue::Closure(a), VmValue::Closure(b)) => a == b,
+            (VmValue::Coroutine(a), VmValue::Coroutine(b)) => a == b,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -0,0 +1,267 @@
+//! Static checks over a `Program`, run ahead of execution.
+//!
+//! The verifier follows control flow from the entry point and from every
+//! function in the function table, tracking the size of the register window
+//! each instruction runs in: the register count given to `Vm::new` at the top
+//! level, the function's own register count inside a function, and the
+//! caller's window inside a `CALL` subroutine. Instructions that are never
+//! reached are not checked.
+use std::collections::HashSet;
+
+use crate::{
+    error::verifier::{Diagnostic, DiagnosticKind},
+    function::Capture,
+    instruction::Instruction,
+    opcode::Operand,
+    serde::Program,
+};
+
+/// Where an instruction runs: the size of its register window, and whether a
+/// frame is on the call stack for `RETURN` to pop
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Context {
+    register_count: usize,
+    in_call: bool,
+}
+
+/// Verifies `program` for a VM created with `register_count` registers,
+/// returning diagnostics sorted by instruction index
+pub fn verify(program: &Program, register_count: usize) -> Vec<Diagnostic> {
+    let mut verifier = Verifier {
+        program,
+        visited: HashSet::new(),
+        worklist: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+
+    verifier.enqueue(
+        0,
+        Context {
+            register_count,
+            in_call: false,
+        },
+    );
+    for function in program.functions.iter() {
+        verifier.enqueue(
+            function.address,
+            Context {
+                register_count: function.register_count,
+                in_call: true,
+            },
+        );
+    }
+    while let Some((index, context)) = verifier.worklist.pop() {
+        verifier.visit(index, context);
+    }
+
+    let mut diagnostics = verifier.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| diagnostic.index);
+    diagnostics
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    visited: HashSet<(usize, Context)>,
+    worklist: Vec<(usize, Context)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Verifier<'_> {
+    fn enqueue(&mut self, index: usize, context: Context) {
+        if index < self.program.instructions.len() && self.visited.insert((index, context)) {
+            self.worklist.push((index, context));
+        }
+    }
+
+    /// Records a diagnostic once, even if the instruction is reached in several contexts
+    fn report(&mut self, index: usize, kind: DiagnosticKind) {
+        let diagnostic = Diagnostic::new(index, kind);
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn visit(&mut self, index: usize, context: Context) {
+        use Instruction::*;
+        let instruction = &self.program.instructions[index];
+
+        let register_count = context.register_count;
+        for registers in registers(instruction) {
+            match registers {
+                Registers::Single(register) if register >= register_count => self.report(
+                    index,
+                    DiagnosticKind::RegisterOutOfBounds {
+                        register,
+                        register_count,
+                    },
+                ),
+                Registers::Range { start, count }
+                    if count > 0
+                        && start
+                            .checked_add(count)
+                            .is_none_or(|end| end > register_count) =>
+                {
+                    self.report(
+                        index,
+                        DiagnosticKind::RegisterRangeOutOfBounds {
+                            start,
+                            count,
+                            register_count,
+                        },
+                    )
+                }
+                _ => {}
+            }
+        }
+        for operand in instruction.operands() {
+            match operand {
+                Operand::Address(address) => self.check_address(index, address),
+                Operand::Constant(constant) if constant >= self.program.constant_pool.len() => {
+                    self.report(index, DiagnosticKind::ConstantOutOfBounds(constant))
+                }
+                Operand::Function(function) => self.check_function(index, function),
+                _ => {}
+            }
+        }
+
+        let next = index + 1;
+        match instruction {
+            JMP(address) => self.enqueue(*address, context),
+            JZ { address, .. }
+            | JNZ { address, .. }
+            | JLT { address, .. }
+            | JLTE { address, .. }
+            | JGT { address, .. }
+            | JGTE { address, .. }
+            | JEQ { address, .. }
+            | JNEQ { address, .. } => {
+                self.enqueue(*address, context);
+                self.enqueue(next, context);
+            }
+            CALL(address) => {
+                self.enqueue(
+                    *address,
+                    Context {
+                        in_call: true,
+                        ..context
+                    },
+                );
+                self.enqueue(next, context);
+            }
+            CALLF {
+                function,
+                arg_count,
+                ..
+            } => {
+                if let Some(proto) = self.program.functions.get(*function)
+                    && proto.arity != *arg_count
+                {
+                    self.report(
+                        index,
+                        DiagnosticKind::ArityMismatch {
+                            name: proto.name.clone(),
+                            expected: proto.arity,
+                            actual: *arg_count,
+                        },
+                    );
+                }
+                self.enqueue(next, context);
+            }
+            RETURN | RETURNV { .. } => {
+                if !context.in_call {
+                    self.report(index, DiagnosticKind::ReturnOutsideCall);
+                }
+            }
+            PUSH_HANDLER { address, .. } => {
+                self.enqueue(*address, context);
+                self.enqueue(next, context);
+            }
+            HALT | THROW(_) => {}
+            _ => self.enqueue(next, context),
+        }
+    }
+
+    fn check_address(&mut self, index: usize, address: usize) {
+        if address >= self.program.instructions.len() {
+            self.report(index, DiagnosticKind::AddressOutOfBounds(address));
+        }
+    }
+
+    fn check_function(&mut self, index: usize, function: usize) {
+        match self.program.functions.get(function) {
+            Some(proto) => self.check_address(index, proto.address),
+            None => self.report(index, DiagnosticKind::FunctionOutOfBounds(function)),
+        }
+    }
+}
+
+/// Registers of the current window that an instruction reads or writes
+enum Registers {
+    Single(usize),
+    /// `count` consecutive registers from `start`, as used by calls and `RETURNV`
+    Range {
+        start: usize,
+        count: usize,
+    },
+}
+
+/// Registers of the current window that `instruction` reads or writes.
+/// Argument and result ranges only count the registers actually used.
+fn registers(instruction: &Instruction) -> Vec<Registers> {
+    use Instruction::*;
+    let range = |start: &usize, count: &usize| Registers::Range {
+        start: *start,
+        count: *count,
+    };
+    match instruction {
+        CALLF {
+            args,
+            arg_count,
+            target,
+            result_count,
+            ..
+        } => vec![range(args, arg_count), range(target, result_count)],
+        CALLR {
+            callee,
+            args,
+            arg_count,
+            target,
+            result_count,
+        } => vec![
+            Registers::Single(*callee),
+            range(args, arg_count),
+            range(target, result_count),
+        ],
+        CALL_NATIVE {
+            args,
+            arg_count,
+            target,
+            ..
+        }
+        | CALL_NATIVEC {
+            args,
+            arg_count,
+            target,
+            ..
+        } => vec![range(args, arg_count), Registers::Single(*target)],
+        RETURNV { source, count } => vec![range(source, count)],
+        _ => instruction
+            .operands()
+            .into_iter()
+            .flat_map(|operand| match operand {
+                Operand::Register(register) | Operand::OptionalRegister(Some(register)) => {
+                    vec![register]
+                }
+                Operand::Captures(captures) => captures
+                    .iter()
+                    .filter_map(|capture| match capture {
+                        Capture::Register(register) => Some(*register),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            })
+            .map(Registers::Single)
+            .collect(),
+    }
+}
@@ -1,30 +1,155 @@
 use std::cell::{RefCell, RefMut};
 use std::collections::HashMap;
+use std::io::Write;
 use std::rc::Rc;
 
 use crate::array::DynamicArray;
-use crate::error::vm::{VmError, invalid_index_err};
+use crate::coroutine::{Coroutine, CoroutineStatus};
+use crate::debug_info::SourceLocation;
+use crate::error::vm::{TraceFrame, VariableScope, VmError, VmTrace, invalid_index_err};
+use crate::function::{Capture, Closure};
 use crate::instruction::Instruction;
+use crate::limits::{CancellationToken, Limits};
 use crate::object::Object;
+use crate::opcode::Opcode;
+use crate::output::Output;
 use crate::serde::Program;
 use crate::value::{SharedValue, VmValue};
+use crate::verifier;
 
 pub struct Vm<'a> {
     pub pc: usize,
+    /// Register stack; each function call owns a window at the top of it
     pub registers: Vec<SharedValue>,
     pub program: &'a Program,
-    pub variables: HashMap<String, SharedValue>, // TODO: scoping
+    pub globals: Scope,
+    /// Local scopes opened by top-level code, innermost last
+    pub scopes: Vec<Scope>,
     pub call_stack: Vec<Frame>,
+    /// Exception handlers installed by `PUSH_HANDLER`, innermost last
+    handlers: Vec<Handler>,
+    /// Threads waiting on the coroutines they resumed, innermost last
+    resumers: Vec<Resumer>,
+    /// Value passed to the host by the last instruction, if it was a `YIELD`
+    /// outside of any coroutine
+    yielded: Option<VmValue>,
+    /// Register of that `YIELD` receiving the value of the next `Vm::resume`
+    yield_target: Option<usize>,
+    /// Limits of the current `run_with_limits` or `resume_with_limits` call
+    limits: Limits,
+    register_count: usize,
+    natives: HashMap<String, NativeFunction>,
+    output: Output,
+}
+
+pub type Scope = HashMap<String, SharedValue>;
+
+/// Host function callable from bytecode through `CALL_NATIVE`
+pub type NativeFunction = Rc<dyn Fn(&mut Vm<'_>, &[VmValue]) -> Result<VmValue, VmError>>;
+
+/// How far `Vm::resume` got
+#[derive(Debug, PartialEq)]
+pub enum VmState {
+    /// The program yielded a value and can be resumed
+    Yielded(VmValue),
+    Finished,
+}
+
+/// Registers, call stack and exception handlers of one line of execution:
+/// the program itself, or a coroutine
+#[derive(Debug, Default)]
+pub(crate) struct Thread {
+    pc: usize,
+    registers: Vec<SharedValue>,
+    call_stack: Vec<Frame>,
+    handlers: Vec<Handler>,
+}
+
+/// A thread suspended by `RESUME` until the coroutine it resumed yields,
+/// returns or fails
+#[derive(Debug)]
+struct Resumer {
+    coroutine: Coroutine,
+    thread: Thread,
+    /// Register receiving the coroutine's yielded or returned value
+    target: usize,
+}
+
+/// Where an exception raised inside a `PUSH_HANDLER` block resumes
+#[derive(Debug)]
+struct Handler {
+    address: usize,
+    target: usize,
+    /// Length of the call stack and of the frame's scope stack when the
+    /// handler was installed
+    call_depth: usize,
+    scope_depth: usize,
 }
 
 #[derive(Debug)]
 pub struct Frame {
     return_address: usize,
+    /// Index into the function table, or `None` for a plain subroutine
+    function: Option<usize>,
+    register_base: usize,
+    register_count: usize,
+    /// Caller registers receiving the return values
+    result_target: usize,
+    result_count: usize,
+    /// Local scopes of this frame, innermost last
+    scopes: Vec<Scope>,
+    /// Upvalues of the running closure
+    upvalues: Rc<Vec<SharedValue>>,
 }
 
 impl Frame {
-    pub fn new(return_address: usize) -> Self {
-        Self { return_address }
+    /// Frame for a subroutine, which shares the register window and upvalues of its caller
+    pub fn new(
+        return_address: usize,
+        register_base: usize,
+        register_count: usize,
+        upvalues: Rc<Vec<SharedValue>>,
+    ) -> Self {
+        Self {
+            return_address,
+            function: None,
+            register_base,
+            register_count,
+            result_target: 0,
+            result_count: 0,
+            scopes: Vec::new(),
+            upvalues,
+        }
+    }
+
+    pub fn function(
+        return_address: usize,
+        function: usize,
+        register_base: usize,
+        register_count: usize,
+        result_target: usize,
+        result_count: usize,
+        upvalues: Rc<Vec<SharedValue>>,
+    ) -> Self {
+        Self {
+            return_address,
+            function: Some(function),
+            register_base,
+            register_count,
+            result_target,
+            result_count,
+            scopes: vec![Scope::new()],
+            upvalues,
+        }
+    }
+
+    pub fn return_address(&self) -> usize {
+        self.return_address
+    }
+
+    /// Base and size of the frame's register window
+    pub fn window(&self) -> (usize, usize) {
+        (self.register_base, self.register_count)
     }
 }
 
@@ -35,6 +160,34 @@ fn index_string(s: String, index: usize) -> VmValue {
         .unwrap_or(VmValue::Null)
 }
 
+/// Fails with `error(limit)` if `size` exceeds `limit`
+fn check_limit(
+    size: usize,
+    limit: Option<usize>,
+    error: fn(usize) -> VmError,
+) -> Result<(), VmError> {
+    match limit {
+        Some(limit) if size > limit => Err(error(limit)),
+        _ => Ok(()),
+    }
+}
+
+/// Number of elements or entries `value` holds once `index` is stored into
+/// it. Arrays only take non-negative integer indices.
+fn size_after_store(value: &VmValue, index: &VmValue) -> Result<usize, VmError> {
+    match (value, index) {
+        (VmValue::DynamicArray(arr), VmValue::Int(i)) if *i >= 0 => {
+            Ok(arr.len().max((*i as usize).saturating_add(1)))
+        }
+        (VmValue::DynamicArray(_), _) => Err(invalid_index_err(index.clone())),
+        (VmValue::Object(obj), _) => {
+            let entries = obj.0.borrow();
+            Ok(entries.len() + usize::from(!entries.contains_key(index)))
+        }
+        _ => Ok(0),
+    }
+}
+
 fn idiv(a: f64, b: f64) -> f64 {
     f64::floor(a / b)
 }
@@ -47,8 +200,18 @@ fn pow(a: f64, b: f64) -> f64 {
     }
 }
 
+/// Shift counts are masked to 5 bits, as in the AOT runtime, so any count
+/// is valid
+fn lsh(a: i32, b: i32) -> i32 {
+    a.wrapping_shl((b & 31) as u32)
+}
+
+fn arithmetic_rsh(a: i32, b: i32) -> i32 {
+    a.wrapping_shr((b & 31) as u32)
+}
+
 fn logical_rsh(a: i32, b: i32) -> i32 {
-    ((a as u32) >> b) as i32
+    (a as u32).wrapping_shr((b & 31) as u32) as i32
 }
 
 fn null_coalesce(a_value: &VmValue, b_value: &VmValue) -> VmValue {
@@ -65,20 +228,254 @@ impl<'a> Vm<'a> {
             pc: 0,
             registers: vec![Rc::new(RefCell::new(VmValue::Null)); register_count],
             program,
-            variables: HashMap::new(),
+            globals: Scope::new(),
+            scopes: Vec::new(),
             call_stack: Vec::new(),
+            handlers: Vec::new(),
+            resumers: Vec::new(),
+            yielded: None,
+            yield_target: None,
+            limits: Limits::default(),
+            register_count,
+            natives: HashMap::new(),
+            output: Output::Stdout,
         }
     }
 
+    /// Redirects `PRINT` output to `writer` instead of stdout
+    pub fn set_output<W: Write + 'static>(&mut self, writer: W) {
+        self.output = Output::Writer(Box::new(writer));
+    }
+
+    /// Redirects `PRINT` output to `callback`, which receives each printed line
+    pub fn set_output_callback<F: FnMut(&str) + 'static>(&mut self, callback: F) {
+        self.output = Output::Callback(Box::new(callback));
+    }
+
+    /// Registers a host function that bytecode can call by `name`, replacing any previous one
+    pub fn register_native<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&mut Vm<'_>, &[VmValue]) -> Result<VmValue, VmError> + 'static,
+    {
+        self.natives.insert(name.to_string(), Rc::new(function));
+    }
+
+    /// Checks the program statically against this VM's register count
+    pub fn verify(&self) -> Result<(), VmError> {
+        let diagnostics = verifier::verify(self.program, self.register_count);
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(VmError::VerificationFailed(diagnostics))
+        }
+    }
+
+    /// Verifies the program, then runs it
+    pub fn run_verified(&mut self) -> Result<(), VmError> {
+        self.verify()?;
+        self.run()
+    }
+
     pub fn run(&mut self) -> Result<(), VmError> {
-        while self.pc < self.instruction_count() {
-            let instruction = self.current_instruction().clone();
-            self.pc += 1;
-            self.execute_instruction(instruction)?;
+        while !self.is_finished() {
+            self.step().or_else(|error| self.fail(error))?;
+        }
+        self.output.flush().map_err(VmError::IoError)
+    }
+
+    /// Flushes the output before returning `error`, so what the program
+    /// printed before failing is not lost. `error` is kept even if the flush
+    /// fails as well.
+    fn fail<T>(&mut self, error: VmError) -> Result<T, VmError> {
+        let _ = self.output.flush();
+        Err(error)
+    }
+
+    /// Runs the program within `limits`, failing with an error naming the
+    /// limit that was hit.
+    ///
+    /// Running out of fuel or being cancelled stops the VM before the next
+    /// instruction, so calling this again continues where it left off.
+    pub fn run_with_limits(&mut self, limits: Limits) -> Result<(), VmError> {
+        self.with_limits(limits, false).map(|_| ())
+    }
+
+    /// Runs `run_metered` with `limits` in place of the VM's own
+    fn with_limits(&mut self, limits: Limits, until_yield: bool) -> Result<VmState, VmError> {
+        let fuel = limits.fuel;
+        let cancellation = limits.cancellation.clone();
+        let previous = std::mem::replace(&mut self.limits, limits);
+        let result = self.run_metered(fuel, cancellation.as_ref(), until_yield);
+        self.limits = previous;
+        result
+    }
+
+    /// Runs until the program finishes, `fuel` instructions have run or
+    /// `cancellation` is set, and with `until_yield`, until it yields
+    fn run_metered(
+        &mut self,
+        mut fuel: Option<u64>,
+        cancellation: Option<&CancellationToken>,
+        until_yield: bool,
+    ) -> Result<VmState, VmError> {
+        while !self.is_finished() {
+            if cancellation.is_some_and(CancellationToken::is_cancelled) {
+                return self.fail(VmError::Cancelled);
+            }
+            if let Some(remaining) = fuel.as_mut() {
+                if *remaining == 0 {
+                    return self.fail(VmError::FuelExhausted);
+                }
+                *remaining -= 1;
+            }
+            self.step().or_else(|error| self.fail(error))?;
+            if until_yield && let Some(value) = self.yielded.take() {
+                return Ok(VmState::Yielded(value));
+            }
+        }
+        Ok(VmState::Finished)
+    }
+
+    /// Like [`Vm::run`], but failures carry the failing instruction and the
+    /// unwound call stack, resolved through the program's debug info
+    pub fn run_traced(&mut self) -> Result<(), Box<VmTrace>> {
+        while !self.is_finished() {
+            let pc = self.pc;
+            self.step()
+                .or_else(|error| self.fail(error))
+                .map_err(|error| Box::new(self.trace(error, pc)))?;
+        }
+        Ok(())
+    }
+
+    /// Wraps `error`, raised by the instruction at `pc`, with the current
+    /// call stack
+    pub fn trace(&self, error: VmError, pc: usize) -> VmTrace {
+        let frames = self
+            .call_stack
+            .iter()
+            .rev()
+            .map(|frame| {
+                let call_site = frame.return_address.saturating_sub(1);
+                TraceFrame {
+                    function: self.frame_name(frame).map(str::to_string),
+                    call_site,
+                    location: self.location(call_site),
+                }
+            })
+            .collect();
+        VmTrace {
+            error,
+            pc,
+            opcode: self.program.instructions[pc].opcode(),
+            location: self.location(pc),
+            frames,
+        }
+    }
+
+    /// Source position of the instruction at `index`, if the program has
+    /// debug info covering it
+    pub fn location(&self, index: usize) -> Option<SourceLocation> {
+        self.program.debug_info.as_ref()?.location(index)
+    }
+
+    /// Runs the program until it yields to the host or finishes. `value`
+    /// becomes the result of the `YIELD` the program is suspended at, if any.
+    ///
+    /// `Vm::run` and `Vm::step` resume yields with `null` instead.
+    pub fn resume(&mut self, value: VmValue) -> Result<VmState, VmError> {
+        self.resume_with_limits(value, Limits::default())
+    }
+
+    /// Like [`Vm::resume`], within `limits` as with [`Vm::run_with_limits`].
+    /// After running out of fuel or being cancelled, calling this again
+    /// continues where it left off; `value` is then ignored, as the yield has
+    /// already received its value.
+    pub fn resume_with_limits(
+        &mut self,
+        value: VmValue,
+        limits: Limits,
+    ) -> Result<VmState, VmError> {
+        if let Some(target) = self.yield_target.take() {
+            self.set_register(target, value)?;
+        }
+        self.with_limits(limits, true)
+    }
+
+    /// Executes the instruction at `pc`, flushing the output once the
+    /// program has finished. Errors caught by an exception handler resume
+    /// execution at the handler instead of being returned.
+    pub fn step(&mut self) -> Result<(), VmError> {
+        self.yielded = None;
+        self.yield_target = None;
+        let instruction = self.current_instruction().clone();
+        self.pc += 1;
+        if let Err(error) = self.execute_instruction(instruction) {
+            self.catch(error)?;
+        }
+        if self.is_finished() {
+            self.output.flush().map_err(VmError::IoError)?;
         }
         Ok(())
     }
 
+    /// Whether `pc` has run past the last instruction
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.instruction_count()
+    }
+
+    /// Value of a register in the current frame's window
+    pub fn register(&self, index: usize) -> Result<VmValue, VmError> {
+        Ok(self.get_register(index)?.borrow().clone())
+    }
+
+    /// Values of the current frame's register window
+    pub fn window(&self) -> Vec<VmValue> {
+        let (base, count) = self.register_window();
+        self.registers[base..base + count]
+            .iter()
+            .map(|register| register.borrow().clone())
+            .collect()
+    }
+
+    /// Index in the register stack of a register of the current window
+    pub fn register_slot(&self, index: usize) -> Result<usize, VmError> {
+        let (base, count) = self.register_window();
+        if index < count {
+            Ok(base + index)
+        } else {
+            Err(VmError::RegisterOutOfBounds(index))
+        }
+    }
+
+    /// Value of the variable `name` as seen by the current instruction
+    pub fn variable(&self, name: &str) -> Result<VmValue, VmError> {
+        Ok(self.lookup_variable(name)?.borrow().clone())
+    }
+
+    /// Variables visible to the current instruction, sorted by name, with
+    /// shadowed bindings left out
+    pub fn variables(&self) -> Vec<(String, VmValue)> {
+        let mut variables: Vec<(String, VmValue)> = Vec::new();
+        for scope in self.visible_scopes().into_iter().chain([&self.globals]) {
+            for (name, value) in scope.iter() {
+                if !variables.iter().any(|(seen, _)| seen == name) {
+                    variables.push((name.clone(), value.borrow().clone()));
+                }
+            }
+        }
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+        variables
+    }
+
+    /// Name of a frame's function, or `None` for a subroutine
+    pub fn frame_name(&self, frame: &Frame) -> Option<&str> {
+        frame
+            .function
+            .and_then(|function| self.program.functions.get(function))
+            .map(|function| function.name.as_str())
+    }
+
     #[cfg(debug_assertions)]
     pub fn visualize_callstack(&self) -> String {
         if self.call_stack.is_empty() {
@@ -86,9 +483,10 @@ impl<'a> Vm<'a> {
         } else {
             let mut s = String::from("call stack:\n");
             for (i, frame) in self.call_stack.iter().rev().enumerate() {
+                let name = self.frame_name(frame).unwrap_or("<subroutine>");
                 s.push_str(&format!(
-                    "  frame {}: return address -> {}\n",
-                    i, frame.return_address
+                    "  frame {}: {}, return address -> {}\n",
+                    i, name, frame.return_address
                 ));
             }
             s
@@ -97,61 +495,116 @@ impl<'a> Vm<'a> {
 
     fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), VmError> {
         use Instruction::*;
-        let opcode_name = format!("{}", instruction);
+        let opcode = instruction.opcode();
+        let opcode_name = opcode.to_string();
         match instruction {
             LOADV { target, value } => self.set_register(target, value)?,
+            LOADC {
+                target,
+                constant_index,
+            } => {
+                let value = self.get_constant(constant_index)?;
+                self.set_register(target, value)?
+            }
             ADD { target, a, b } => {
-                self.add_reg(target, a, b, opcode_name)?;
+                self.add_reg(target, a, b, opcode)?;
             }
             ADDK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.add(target, &a_value, &b_value.borrow(), opcode_name)?;
+                self.add(target, &a_value, &b_value.borrow(), opcode)?;
             }
-            SUB { target, a, b } => {
-                self.float_binop_reg(target, a, b, opcode_name, |a, b| a - b)?
+            ADDC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.add(target, &a_value, &b_value.borrow(), opcode)?;
             }
+            SUB { target, a, b } => self.float_binop_reg(target, a, b, opcode, |a, b| a - b)?,
             SUBK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.float_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
-                    a - b
-                })?
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a - b)?
             }
-            MUL { target, a, b } => {
-                self.float_binop_reg(target, a, b, opcode_name, |a, b| a * b)?
+            SUBC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a - b)?
             }
+            MUL { target, a, b } => self.float_binop_reg(target, a, b, opcode, |a, b| a * b)?,
             MULK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.float_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
-                    a * b
-                })?
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a * b)?
             }
-            DIV { target, a, b } => {
-                self.float_binop_reg(target, a, b, opcode_name, |a, b| a / b)?
+            MULC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a * b)?
             }
+            DIV { target, a, b } => self.float_binop_reg(target, a, b, opcode, |a, b| a / b)?,
             DIVK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.float_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
-                    a / b
-                })?
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a / b)?
             }
-            IDIV { target, a, b } => self.float_binop_reg(target, a, b, opcode_name, idiv)?,
+            DIVC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a / b)?
+            }
+            IDIV { target, a, b } => self.float_binop_reg(target, a, b, opcode, idiv)?,
             IDIVK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.float_binop(target, &a_value, &b_value.borrow(), opcode_name, idiv)?
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, idiv)?
+            }
+            IDIVC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, idiv)?
             }
-            POW { target, a, b } => self.float_binop_reg(target, a, b, opcode_name, pow)?,
+            POW { target, a, b } => self.float_binop_reg(target, a, b, opcode, pow)?,
             POWK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.float_binop(target, &a_value, &b_value.borrow(), opcode_name, pow)?
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, pow)?
             }
-            MOD { target, a, b } => {
-                self.float_binop_reg(target, a, b, opcode_name, |a, b| a % b)?
+            POWC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, pow)?
             }
+            MOD { target, a, b } => self.float_binop_reg(target, a, b, opcode, |a, b| a % b)?,
             MODK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.float_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
-                    a % b
-                })?
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a % b)?
+            }
+            MODC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.float_binop(target, &a_value, &b_value.borrow(), opcode, |a, b| a % b)?
             }
             BXOR { target, a, b } => self.int_binop_reg(target, a, b, opcode_name, |a, b| a ^ b)?,
             BXORK { target, a_value, b } => {
@@ -160,6 +613,17 @@ impl<'a> Vm<'a> {
                     a ^ b
                 })?
             }
+            BXORC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.int_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
+                    a ^ b
+                })?
+            }
             BAND { target, a, b } => self.int_binop_reg(target, a, b, opcode_name, |a, b| a & b)?,
             BANDK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
@@ -167,6 +631,17 @@ impl<'a> Vm<'a> {
                     a & b
                 })?
             }
+            BANDC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.int_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
+                    a & b
+                })?
+            }
             BOR { target, a, b } => self.int_binop_reg(target, a, b, opcode_name, |a, b| a | b)?,
             BORK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
@@ -174,15 +649,31 @@ impl<'a> Vm<'a> {
                     a | b
                 })?
             }
-            BLSH { target, a, b } => {
-                self.int_binop_reg(target, a, b, opcode_name, |a, b| a << b)?
-            }
-            BLSHK { target, a_value, b } => {
+            BORC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
                 let b_value = self.get_register(b)?;
                 self.int_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
-                    a << b
+                    a | b
                 })?
             }
+            BLSH { target, a, b } => self.int_binop_reg(target, a, b, opcode_name, lsh)?,
+            BLSHK { target, a_value, b } => {
+                let b_value = self.get_register(b)?;
+                self.int_binop(target, &a_value, &b_value.borrow(), opcode_name, lsh)?
+            }
+            BLSHC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.int_binop(target, &a_value, &b_value.borrow(), opcode_name, lsh)?
+            }
             BRSH { target, a, b } => self.int_binop_reg(target, a, b, opcode_name, logical_rsh)?,
             BRSHK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
@@ -194,36 +685,102 @@ impl<'a> Vm<'a> {
                     logical_rsh,
                 )?
             }
+            BRSHC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.int_binop(
+                    target,
+                    &a_value,
+                    &b_value.borrow(),
+                    opcode_name,
+                    logical_rsh,
+                )?
+            }
             BARSH { target, a, b } => {
-                self.int_binop_reg(target, a, b, opcode_name, |a, b| a >> b)?
+                self.int_binop_reg(target, a, b, opcode_name, arithmetic_rsh)?
             }
             BARSHK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
-                self.int_binop(target, &a_value, &b_value.borrow(), opcode_name, |a, b| {
-                    a >> b
-                })?
+                self.int_binop(
+                    target,
+                    &a_value,
+                    &b_value.borrow(),
+                    opcode_name,
+                    arithmetic_rsh,
+                )?
+            }
+            BARSHC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.int_binop(
+                    target,
+                    &a_value,
+                    &b_value.borrow(),
+                    opcode_name,
+                    arithmetic_rsh,
+                )?
             }
             BNOT { target, operand } => self.int_unop_reg(target, operand, |v| !v)?,
             BNOTK {
                 target,
                 operand_value,
             } => self.int_unop(target, &operand_value, |v| !v)?,
+            BNOTC {
+                target,
+                operand_constant,
+            } => {
+                let operand_value = self.get_constant(operand_constant)?;
+                self.int_unop(target, &operand_value, |v| !v)?
+            }
             NEGATE { target, operand } => self.float_unop_reg(target, operand, |v| -v)?,
             NEGATEK {
                 target,
                 operand_value,
             } => self.float_unop(target, &operand_value, |v| -v)?,
+            NEGATEC {
+                target,
+                operand_constant,
+            } => {
+                let operand_value = self.get_constant(operand_constant)?;
+                self.float_unop(target, &operand_value, |v| -v)?
+            }
 
             AND { target, a, b } => self.logical_binop_reg(target, a, b, |a, b| a && b)?,
             ANDK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
                 self.logical_binop(target, &a_value, &b_value.borrow(), |a, b| a && b)?
             }
+            ANDC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.logical_binop(target, &a_value, &b_value.borrow(), |a, b| a && b)?
+            }
             OR { target, a, b } => self.logical_binop_reg(target, a, b, |a, b| a || b)?,
             ORK { target, a_value, b } => {
                 let b_value = self.get_register(b)?;
                 self.logical_binop(target, &a_value, &b_value.borrow(), |a, b| a || b)?
             }
+            ORC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.logical_binop(target, &a_value, &b_value.borrow(), |a, b| a || b)?
+            }
             NULL_COALESCE { target, a, b } => {
                 let a_value = self.get_register(a)?;
                 let b_value = self.get_register(b)?;
@@ -233,6 +790,15 @@ impl<'a> Vm<'a> {
                 let b_value = self.get_register(b)?;
                 self.set_register(target, null_coalesce(&a_value, &b_value.borrow()))?
             }
+            NULL_COALESCEC {
+                target,
+                a_constant,
+                b,
+            } => {
+                let a_value = self.get_constant(a_constant)?;
+                let b_value = self.get_register(b)?;
+                self.set_register(target, null_coalesce(&a_value, &b_value.borrow()))?
+            }
             EQ { target, a, b } => self.comparison_binop(target, a, b, |a, b| a == b)?,
             NEQ { target, a, b } => self.comparison_binop(target, a, b, |a, b| a != b)?,
             LT { target, a, b } => self.comparison_binop(target, a, b, |a, b| a < b)?,
@@ -244,6 +810,13 @@ impl<'a> Vm<'a> {
                 target,
                 operand_value,
             } => self.logical_unop(target, &operand_value, |v| !v)?,
+            NOTC {
+                target,
+                operand_constant,
+            } => {
+                let operand_value = self.get_constant(operand_constant)?;
+                self.logical_unop(target, &operand_value, |v| !v)?
+            }
             INC {
                 target,
                 name,
@@ -283,6 +856,15 @@ impl<'a> Vm<'a> {
                 let value = self.index(object, &index)?;
                 self.set_register(target, value)?;
             }
+            INDEXC {
+                target,
+                object,
+                index_constant,
+            } => {
+                let index = self.get_constant(index_constant)?;
+                let value = self.index(object, &index)?;
+                self.set_register(target, value)?;
+            }
             STORE_INDEX {
                 source,
                 object,
@@ -305,6 +887,14 @@ impl<'a> Vm<'a> {
             } => {
                 self.new_index(object, &index, source)?;
             }
+            STORE_INDEXC {
+                source,
+                object,
+                index_constant,
+            } => {
+                let index = self.get_constant(index_constant)?;
+                self.new_index(object, &index, source)?;
+            }
             DELETE_INDEX { object, index } => {
                 let index_value = self.get_register(index)?;
                 self.delete_index_rc(object, index_value)?;
@@ -315,6 +905,13 @@ impl<'a> Vm<'a> {
             DELETE_INDEXK { object, index } => {
                 self.delete_index(object, &index)?;
             }
+            DELETE_INDEXC {
+                object,
+                index_constant,
+            } => {
+                let index = self.get_constant(index_constant)?;
+                self.delete_index(object, &index)?;
+            }
             NEW_OBJECT(target) => self.set_register(target, Object::new_vm_value())?,
             NEW_ARRAY(target) => self.set_register(target, DynamicArray::new_vm_value())?,
             ARRAY_PUSH { target, source } => {
@@ -324,6 +921,10 @@ impl<'a> Vm<'a> {
             ARRAY_PUSHK { target, value } => {
                 self.array_push(target, value)?;
             }
+            ARRAY_PUSHC { target, constant } => {
+                let value = self.get_constant(constant)?;
+                self.array_push(target, value)?;
+            }
             LEN { target, source } => {
                 let object_value = self.get_register(source)?;
                 let object_ref = object_value.borrow();
@@ -396,32 +997,360 @@ impl<'a> Vm<'a> {
                 }
             }
             STORE { source, name } => {
-                let value = self.get_register(source)?;
-                self.set_variable_rc(name, value);
+                let value = self.get_register(source)?.borrow().clone();
+                self.set_variable(name, value);
             }
             STOREK { name, value } => self.set_variable(name, value),
+            STOREC { name, constant } => {
+                let value = self.get_constant(constant)?;
+                self.set_variable(name, value)
+            }
             LOAD { target, name } => {
-                let value = self.lookup_variable(&name)?;
-                self.set_register_rc(target, value)?
+                let value = self.lookup_variable(&name)?.borrow().clone();
+                self.set_register(target, value)?
+            }
+            DECLARE { source, name } => {
+                let value = self.get_register(source)?.borrow().clone();
+                self.declare_variable(name, value);
+            }
+            STORE_GLOBAL { source, name } => {
+                let value = self.get_register(source)?.borrow().clone();
+                self.set_global(name, value);
+            }
+            LOAD_GLOBAL { target, name } => {
+                let value = self.lookup_global(&name)?.borrow().clone();
+                self.set_register(target, value)?
+            }
+            PUSH_SCOPE => self.current_scopes_mut().push(Scope::new()),
+            POP_SCOPE => {
+                self.current_scopes_mut()
+                    .pop()
+                    .ok_or(VmError::ScopeStackEmpty)?;
             }
             CALL(address) => self.call(address)?,
-            RETURN => self.call_return()?,
+            CALLF {
+                function,
+                args,
+                arg_count,
+                target,
+                result_count,
+            } => self.call_function(
+                function,
+                Rc::default(),
+                args,
+                arg_count,
+                target,
+                result_count,
+            )?,
+            CALLR {
+                callee,
+                args,
+                arg_count,
+                target,
+                result_count,
+            } => {
+                let callee_value = self.get_register(callee)?.borrow().clone();
+                let VmValue::Closure(closure) = callee_value else {
+                    return Err(VmError::OperandTypeMismatch {
+                        expected: "function".to_string(),
+                        actual: format!("{:?}", callee_value),
+                    });
+                };
+                self.call_function(
+                    closure.function,
+                    closure.upvalues,
+                    args,
+                    arg_count,
+                    target,
+                    result_count,
+                )?
+            }
+            CALL_NATIVE {
+                name,
+                args,
+                arg_count,
+                target,
+            } => self.call_native(&name, args, arg_count, target)?,
+            CALL_NATIVEC {
+                name_constant,
+                args,
+                arg_count,
+                target,
+            } => {
+                let name = match self.get_constant(name_constant)? {
+                    VmValue::String(name) => name,
+                    other => {
+                        return Err(VmError::OperandTypeMismatch {
+                            expected: "string".to_string(),
+                            actual: format!("{:?}", other),
+                        });
+                    }
+                };
+                self.call_native(&name, args, arg_count, target)?
+            }
+            CLOSURE {
+                target,
+                function,
+                captures,
+            } => {
+                if function >= self.program.functions.len() {
+                    return Err(VmError::FunctionOutOfBounds(function));
+                }
+                let upvalues = captures
+                    .iter()
+                    .map(|capture| self.capture(capture))
+                    .collect::<Result<Vec<_>, VmError>>()?;
+                self.set_register(target, Closure::new_vm_value(function, upvalues))?
+            }
+            GETUPVAL { target, upvalue } => {
+                let value = self.get_upvalue(upvalue)?.borrow().clone();
+                self.set_register(target, value)?
+            }
+            SETUPVAL { source, upvalue } => {
+                let value = self.get_register(source)?.borrow().clone();
+                *self.get_upvalue(upvalue)?.borrow_mut() = value;
+            }
+            RETURN => self.call_return(Vec::new())?,
+            RETURNV { source, count } => {
+                let values = self.register_values(source, count)?;
+                self.call_return(values)?
+            }
 
-            PRINT(target) => self.print_value(&self.get_register(target)?.borrow()),
-            PRINTK(value) => self.print_value(&value),
+            PRINT(target) => {
+                let value = self.get_register(target)?.borrow().clone();
+                self.print_value(&value)?
+            }
+            PRINTK(value) => self.print_value(&value)?,
+            PRINTC(constant) => {
+                let value = self.get_constant(constant)?;
+                self.print_value(&value)?
+            }
             HALT => self.pc = self.instruction_count(),
+
+            THROW(source) => {
+                let value = self.get_register(source)?.borrow().clone();
+                return Err(VmError::Thrown(value));
+            }
+            PUSH_HANDLER { target, address } => {
+                if address >= self.instruction_count() {
+                    return Err(VmError::ProgramCounterOutOfBounds);
+                }
+                let scope_depth = self.current_scopes_mut().len();
+                self.handlers.push(Handler {
+                    address,
+                    target,
+                    call_depth: self.call_stack.len(),
+                    scope_depth,
+                });
+            }
+            POP_HANDLER => {
+                self.handlers.pop().ok_or(VmError::HandlerStackEmpty)?;
+            }
+
+            YIELD { target, source } => {
+                let value = self.get_register(source)?.borrow().clone();
+                self.set_register(target, VmValue::Null)?;
+                match self.resumers.pop() {
+                    Some(resumer) => {
+                        let coroutine = resumer.coroutine.clone();
+                        let thread = self.restore_resumer(resumer, value)?;
+                        let mut state = coroutine.0.borrow_mut();
+                        state.status = CoroutineStatus::Suspended;
+                        state.thread = thread;
+                        state.yield_target = Some(target);
+                    }
+                    None => {
+                        self.yielded = Some(value);
+                        self.yield_target = Some(target);
+                    }
+                }
+            }
+            COROUTINE { target, callee } => {
+                let callee_value = self.get_register(callee)?.borrow().clone();
+                let VmValue::Closure(closure) = callee_value else {
+                    return Err(VmError::OperandTypeMismatch {
+                        expected: "function".to_string(),
+                        actual: format!("{:?}", callee_value),
+                    });
+                };
+                self.set_register(target, VmValue::Coroutine(Coroutine::new(closure)))?
+            }
+            RESUME {
+                target,
+                coroutine,
+                value,
+            } => {
+                let coroutine_value = self.get_register(coroutine)?.borrow().clone();
+                let VmValue::Coroutine(coroutine) = coroutine_value else {
+                    return Err(VmError::OperandTypeMismatch {
+                        expected: "coroutine".to_string(),
+                        actual: format!("{:?}", coroutine_value),
+                    });
+                };
+                let value = self.get_register(value)?.borrow().clone();
+                self.resume_coroutine(coroutine, value, target)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches to `coroutine`, starting it if it has not run yet
+    fn resume_coroutine(
+        &mut self,
+        coroutine: Coroutine,
+        value: VmValue,
+        target: usize,
+    ) -> Result<(), VmError> {
+        let mut state = coroutine.0.borrow_mut();
+        match state.status {
+            CoroutineStatus::Suspended => {}
+            CoroutineStatus::Running => return Err(VmError::CoroutineRunning),
+            CoroutineStatus::Dead => return Err(VmError::CoroutineDead),
+        }
+        let starting = state.thread.call_stack.is_empty();
+        let proto = self
+            .program
+            .functions
+            .get(state.closure.function)
+            .ok_or(VmError::FunctionOutOfBounds(state.closure.function))?;
+        if starting {
+            if proto.arity != 1 {
+                return Err(VmError::ArityMismatch {
+                    name: proto.name.clone(),
+                    expected: proto.arity,
+                    actual: 1,
+                });
+            }
+            if proto.address >= self.instruction_count() {
+                return Err(VmError::ProgramCounterOutOfBounds);
+            }
+            if proto.register_count == 0 {
+                return Err(VmError::RegisterOutOfBounds(0));
+            }
+        }
+        let frames = state.thread.call_stack.len().max(1);
+        check_limit(
+            self.call_depth() + frames,
+            self.limits.max_call_depth,
+            VmError::CallDepthExceeded,
+        )?;
+
+        let mut thread = std::mem::take(&mut state.thread);
+        if starting {
+            let register_count = proto.register_count;
+            thread.pc = proto.address;
+            thread.registers = (0..register_count)
+                .map(|_| Rc::new(RefCell::new(VmValue::Null)))
+                .collect();
+            thread.call_stack.push(Frame::function(
+                0,
+                state.closure.function,
+                0,
+                register_count,
+                0,
+                0,
+                state.closure.upvalues.clone(),
+            ));
+        }
+        state.status = CoroutineStatus::Running;
+        let yield_target = state.yield_target.take();
+        drop(state);
+
+        self.swap_thread(&mut thread);
+        self.resumers.push(Resumer {
+            coroutine,
+            thread,
+            target,
+        });
+        match yield_target {
+            Some(yield_target) => self.set_register(yield_target, value),
+            None if starting => self.set_register(0, value),
+            None => Ok(()),
         }
+    }
+
+    /// Switches back to the thread that resumed the running coroutine,
+    /// storing `value` as the result of its `RESUME`. Returns the thread of
+    /// the coroutine.
+    fn restore_resumer(&mut self, resumer: Resumer, value: VmValue) -> Result<Thread, VmError> {
+        let mut thread = resumer.thread;
+        self.swap_thread(&mut thread);
+        self.set_register(resumer.target, value)?;
+        Ok(thread)
+    }
+
+    /// Ends the running coroutine, passing `value` to its resumer
+    fn finish_coroutine(&mut self, value: VmValue) -> Result<(), VmError> {
+        let resumer = self.resumers.pop().ok_or(VmError::CallStackEmpty)?;
+        let coroutine = resumer.coroutine.clone();
+        self.restore_resumer(resumer, value)?;
+        coroutine.0.borrow_mut().status = CoroutineStatus::Dead;
         Ok(())
     }
 
+    fn swap_thread(&mut self, thread: &mut Thread) {
+        std::mem::swap(&mut self.pc, &mut thread.pc);
+        std::mem::swap(&mut self.registers, &mut thread.registers);
+        std::mem::swap(&mut self.call_stack, &mut thread.call_stack);
+        std::mem::swap(&mut self.handlers, &mut thread.handlers);
+    }
+
+    /// Frames on the call stacks of the running thread and of every thread
+    /// waiting on a coroutine
+    fn call_depth(&self) -> usize {
+        self.call_stack.len()
+            + self
+                .resumers
+                .iter()
+                .map(|resumer| resumer.thread.call_stack.len())
+                .sum::<usize>()
+    }
+
+    /// Unwinds to the innermost exception handler, passing it the value of
+    /// `error`. Returns `error` if it cannot be caught or no handler is installed.
+    ///
+    /// Coroutines without a handler of their own end, and the exception is
+    /// raised again at the `RESUME` in their resumer.
+    fn catch(&mut self, error: VmError) -> Result<(), VmError> {
+        let Some(value) = error.exception_value() else {
+            return Err(error);
+        };
+        let handled = !self.handlers.is_empty()
+            || self
+                .resumers
+                .iter()
+                .any(|resumer| !resumer.thread.handlers.is_empty());
+        if !handled {
+            return Err(error);
+        }
+        while self.handlers.is_empty() {
+            let resumer = self.resumers.pop().ok_or(VmError::HandlerStackEmpty)?;
+            let coroutine = resumer.coroutine.clone();
+            let mut thread = resumer.thread;
+            self.swap_thread(&mut thread);
+            coroutine.0.borrow_mut().status = CoroutineStatus::Dead;
+        }
+        let handler = self.handlers.pop().ok_or(VmError::HandlerStackEmpty)?;
+
+        while self.call_stack.len() > handler.call_depth {
+            let frame = self.call_stack.pop().ok_or(VmError::CallStackEmpty)?;
+            if frame.function.is_some() {
+                self.registers.truncate(frame.register_base);
+            }
+        }
+        self.current_scopes_mut().truncate(handler.scope_depth);
+        self.pc = handler.address;
+        self.set_register(handler.target, value)
+    }
+
     fn add_reg(
         &mut self,
         target: usize,
         a: usize,
         b: usize,
-        opcode_name: String,
+        opcode: Opcode,
     ) -> Result<(), VmError> {
-        if let Err(_) = self.float_binop_reg(target, a, b, opcode_name, |a, b| a + b) {
+        if let Err(_) = self.float_binop_reg(target, a, b, opcode, |a, b| a + b) {
             let a_value = self.get_register(a)?.clone();
             let b_value = self.get_register(b)?.clone();
             self.string_concat(target, &a_value.borrow(), &b_value.borrow())?;
@@ -434,9 +1363,9 @@ impl<'a> Vm<'a> {
         target: usize,
         a_value: &VmValue,
         b_value: &VmValue,
-        opcode_name: String,
+        opcode: Opcode,
     ) -> Result<(), VmError> {
-        if let Err(_) = self.float_binop(target, a_value, b_value, opcode_name, |a, b| a + b) {
+        if let Err(_) = self.float_binop(target, a_value, b_value, opcode, |a, b| a + b) {
             self.string_concat(target, a_value, b_value)?;
         }
         Ok(())
@@ -451,24 +1380,132 @@ impl<'a> Vm<'a> {
         if let VmValue::String(a) = a_value
             && let VmValue::String(b) = b_value
         {
+            check_limit(
+                a.len() + b.len(),
+                self.limits.max_string_length,
+                VmError::StringTooLong,
+            )?;
             self.set_register(target, VmValue::String(a.clone() + b))?;
         }
         Ok(())
     }
 
-    fn set_variable_rc(&mut self, name: String, value: SharedValue) -> () {
-        self.variables.insert(name, value);
+    /// Scopes of the current frame. Subroutines have no scopes of their own
+    /// until they push one, so they share the scopes of their caller.
+    fn current_scopes_mut(&mut self) -> &mut Vec<Scope> {
+        match self.call_stack.last_mut() {
+            Some(frame) => &mut frame.scopes,
+            None => &mut self.scopes,
+        }
+    }
+
+    /// Local scopes visible from the current frame, innermost first. The
+    /// search passes through subroutine frames but stops at the nearest
+    /// function frame, since functions cannot see their caller's locals.
+    fn visible_scopes(&self) -> Vec<&Scope> {
+        let mut scopes = Vec::new();
+        for frame in self.call_stack.iter().rev() {
+            scopes.extend(frame.scopes.iter().rev());
+            if frame.function.is_some() {
+                return scopes;
+            }
+        }
+        scopes.extend(self.scopes.iter().rev());
+        scopes
+    }
+
+    /// Assigns to the nearest visible binding of `name`, declaring it in the
+    /// innermost scope (or globally, if no scope is open) when there is none
+    fn set_variable(&mut self, name: String, value: VmValue) {
+        if let Ok(variable) = self.lookup_variable(&name) {
+            *variable.borrow_mut() = value;
+        } else {
+            self.declare_variable(name, value);
+        }
+    }
+
+    /// Binds `name` in the innermost visible scope, shadowing outer bindings
+    fn declare_variable(&mut self, name: String, value: VmValue) {
+        let value = Rc::new(RefCell::new(value));
+        let frame_index = self
+            .call_stack
+            .iter()
+            .rposition(|frame| !frame.scopes.is_empty() || frame.function.is_some());
+        let scope = match frame_index {
+            Some(index) => self.call_stack[index].scopes.last_mut(),
+            None => self.scopes.last_mut(),
+        };
+
+        match scope {
+            Some(scope) => scope.insert(name, value),
+            None => self.globals.insert(name, value),
+        };
     }
 
-    fn set_variable(&mut self, name: String, value: VmValue) -> () {
-        self.set_variable_rc(name, Rc::new(RefCell::new(value)));
+    fn set_global(&mut self, name: String, value: VmValue) {
+        if let Some(variable) = self.globals.get(&name) {
+            *variable.borrow_mut() = value;
+        } else {
+            self.globals.insert(name, Rc::new(RefCell::new(value)));
+        }
     }
 
     fn lookup_variable(&self, name: &str) -> Result<SharedValue, VmError> {
-        self.variables
+        let scopes = self.visible_scopes();
+        if let Some(variable) = scopes.iter().find_map(|scope| scope.get(name)) {
+            return Ok(variable.clone());
+        }
+
+        self.globals.get(name).cloned().ok_or_else(|| {
+            let function = self
+                .call_stack
+                .iter()
+                .rev()
+                .find_map(|frame| frame.function)
+                .map(|function| self.program.functions[function].name.clone());
+            let scope = if scopes.is_empty() && function.is_none() {
+                VariableScope::Global
+            } else {
+                VariableScope::Local {
+                    function,
+                    depth: scopes.len(),
+                }
+            };
+
+            VmError::VariableNotFound {
+                name: name.to_string(),
+                scope,
+            }
+        })
+    }
+
+    fn lookup_global(&self, name: &str) -> Result<SharedValue, VmError> {
+        self.globals
             .get(name)
             .cloned()
-            .ok_or(VmError::VariableNotFound(name.to_string()))
+            .ok_or_else(|| VmError::VariableNotFound {
+                name: name.to_string(),
+                scope: VariableScope::Global,
+            })
+    }
+
+    fn capture(&self, capture: &Capture) -> Result<SharedValue, VmError> {
+        match capture {
+            Capture::Variable(name) => self.lookup_variable(name),
+            Capture::Register(index) => {
+                let value = self.get_register(*index)?.borrow().clone();
+                Ok(Rc::new(RefCell::new(value)))
+            }
+            Capture::Upvalue(index) => self.get_upvalue(*index),
+        }
+    }
+
+    fn get_upvalue(&self, index: usize) -> Result<SharedValue, VmError> {
+        self.call_stack
+            .last()
+            .and_then(|frame| frame.upvalues.get(index))
+            .cloned()
+            .ok_or(VmError::UpvalueOutOfBounds(index))
     }
 
     fn index_rc(&self, object: usize, index: SharedValue) -> Result<VmValue, VmError> {
@@ -517,6 +1554,7 @@ impl<'a> Vm<'a> {
     }
 
     fn new_index(&mut self, object: usize, index: &VmValue, source: usize) -> Result<(), VmError> {
+        self.check_store(object, index)?;
         let source_value = self.get_register(source)?;
         let mut object_value = self.get_register_mut(object)?;
         if let Ok(arr) = object_value.as_array_mut() {
@@ -537,6 +1575,7 @@ impl<'a> Vm<'a> {
         index: usize,
         source: usize,
     ) -> Result<(), VmError> {
+        self.check_store(object, &VmValue::Int(index as i32))?;
         let source_value = self.get_register(source)?;
         let mut object_value = self.get_register_mut(object)?;
         if let Ok(arr) = object_value.as_array_mut() {
@@ -547,11 +1586,23 @@ impl<'a> Vm<'a> {
         Ok(())
     }
 
+    /// Fails if storing `index` into the collection in register `object`
+    /// would grow it past the collection size limit
+    fn check_store(&self, object: usize, index: &VmValue) -> Result<(), VmError> {
+        let size = size_after_store(&self.get_register(object)?.borrow(), index)?;
+        check_limit(
+            size,
+            self.limits.max_collection_size,
+            VmError::CollectionTooLarge,
+        )
+    }
+
     fn delete_index_rc(&mut self, object: usize, index: SharedValue) -> Result<(), VmError> {
         self.delete_index(object, &index.borrow())
     }
 
     fn delete_index(&mut self, object: usize, index: &VmValue) -> Result<(), VmError> {
+        self.check_store(object, index)?;
         let mut object_value = self.get_register_mut(object)?;
         if let Ok(arr) = object_value.as_array_mut() {
             if let VmValue::Int(i) = index {
@@ -566,6 +1617,7 @@ impl<'a> Vm<'a> {
     }
 
     fn delete_index_known(&mut self, object: usize, index: usize) -> Result<(), VmError> {
+        self.check_store(object, &VmValue::Int(index as i32))?;
         let mut object_value = self.get_register_mut(object)?;
         if let Ok(arr) = object_value.as_array_mut() {
             arr.new_index(index, VmValue::Null);
@@ -576,8 +1628,14 @@ impl<'a> Vm<'a> {
     }
 
     fn array_push(&mut self, target: usize, value: VmValue) -> Result<(), VmError> {
+        let max_collection_size = self.limits.max_collection_size;
         let mut arr_value = self.get_register_mut(target)?;
         if let Ok(arr) = arr_value.as_array_mut() {
+            check_limit(
+                arr.len() + 1,
+                max_collection_size,
+                VmError::CollectionTooLarge,
+            )?;
             arr.0.borrow_mut().push(value);
             Ok(())
         } else {
@@ -595,10 +1653,11 @@ impl<'a> Vm<'a> {
         returns_old: bool,
         amount: i8,
     ) -> Result<(), VmError> {
-        let value = self.lookup_variable(&name)?;
-        Ok(if let VmValue::Int(n) = *value.borrow() {
+        let variable = self.lookup_variable(&name)?;
+        let value = variable.borrow().clone();
+        Ok(if let VmValue::Int(n) = value {
             let new_value = VmValue::Int(n + amount as i32);
-            self.set_variable(name, new_value.clone());
+            *variable.borrow_mut() = new_value.clone();
             if let Some(target) = target {
                 self.set_register(
                     target,
@@ -629,7 +1688,7 @@ impl<'a> Vm<'a> {
     {
         let a_value = self.get_register(a)?;
         let b_value = self.get_register(b)?;
-        let result = f(&*a_value.borrow(), &*b_value.borrow());
+        let result = f(&a_value.borrow(), &b_value.borrow());
         self.set_register(target, VmValue::Boolean(result))
     }
 
@@ -778,7 +1837,7 @@ impl<'a> Vm<'a> {
         target: usize,
         a: usize,
         b: usize,
-        opcode_name: String,
+        opcode: Opcode,
         f: F,
     ) -> Result<(), VmError>
     where
@@ -786,7 +1845,7 @@ impl<'a> Vm<'a> {
     {
         let a_value = self.get_register(a)?;
         let b_value = self.get_register(b)?;
-        self.float_binop(target, &a_value.borrow(), &b_value.borrow(), opcode_name, f)
+        self.float_binop(target, &a_value.borrow(), &b_value.borrow(), opcode, f)
     }
 
     fn float_binop<F>(
@@ -794,7 +1853,7 @@ impl<'a> Vm<'a> {
         target: usize,
         a_value: &VmValue,
         b_value: &VmValue,
-        opcode_name: String,
+        opcode: Opcode,
         f: F,
     ) -> Result<(), VmError>
     where
@@ -807,7 +1866,7 @@ impl<'a> Vm<'a> {
             (VmValue::Float(af), VmValue::Float(bf)) => (*af, *bf, false),
             (a_other, b_other) => {
                 return Err(VmError::BinaryTypeMismatch {
-                    opcode_name,
+                    opcode_name: opcode.to_string(),
                     expected: "number".to_string(),
                     a_actual: format!("{:?}", a_other),
                     b_actual: format!("{:?}", b_other),
@@ -816,46 +1875,59 @@ impl<'a> Vm<'a> {
         };
 
         let result = f(a_number, b_number);
-        if opcode_name == "IDIV" || (both_int && result.fract() == 0.0) {
+        // integer division always results in an Int, even from Floats
+        let integer = matches!(opcode, Opcode::IDIV | Opcode::IDIVC);
+        if integer || (both_int && result.fract() == 0.0) {
             self.set_register(target, VmValue::Int(result as i32))
         } else {
             self.set_register(target, VmValue::Float(result))
         }
     }
 
-    fn get_register(&self, index: usize) -> Result<SharedValue, VmError> {
-        self.registers
+    fn get_constant(&self, index: usize) -> Result<VmValue, VmError> {
+        self.program
+            .constant_pool
             .get(index)
             .cloned()
-            .ok_or(VmError::RegisterOutOfBounds(index))
+            .ok_or(VmError::ConstantOutOfBounds(index))
+    }
+
+    /// Base and size of the register window of the current frame
+    fn register_window(&self) -> (usize, usize) {
+        self.call_stack
+            .last()
+            .map_or((0, self.register_count), |frame| {
+                (frame.register_base, frame.register_count)
+            })
+    }
+
+    fn get_register(&self, index: usize) -> Result<SharedValue, VmError> {
+        let slot = self.register_slot(index)?;
+        Ok(self.registers[slot].clone())
     }
 
     fn get_register_mut(&mut self, index: usize) -> Result<RefMut<'_, VmValue>, VmError> {
-        self.registers
-            .get(index)
-            .ok_or(VmError::RegisterOutOfBounds(index))
-            .map(|rc| rc.borrow_mut())
+        let slot = self.register_slot(index)?;
+        Ok(self.registers[slot].borrow_mut())
     }
 
     fn set_register_rc(&mut self, index: usize, value: SharedValue) -> Result<(), VmError> {
-        if let Some(reg) = self.registers.get_mut(index) {
-            *reg = value;
-            Ok(())
-        } else {
-            Err(VmError::RegisterOutOfBounds(index))
-        }
+        let slot = self.register_slot(index)?;
+        self.registers[slot] = value;
+        Ok(())
     }
 
     fn set_register(&mut self, index: usize, value: VmValue) -> Result<(), VmError> {
         self.set_register_rc(index, Rc::new(RefCell::new(value)))
     }
 
-    fn print_value(&self, value: &VmValue) -> () {
-        if let VmValue::String(s) = value {
-            println!("{}", s);
+    fn print_value(&mut self, value: &VmValue) -> Result<(), VmError> {
+        let line = if let VmValue::String(s) = value {
+            s.clone()
         } else {
-            println!("{}", value);
-        }
+            value.to_string()
+        };
+        self.output.write_line(&line).map_err(VmError::IoError)
     }
 
     fn jump(&mut self, address: usize) -> Result<(), VmError> {
@@ -871,15 +1943,134 @@ impl<'a> Vm<'a> {
         if address >= self.instruction_count() {
             return Err(VmError::ProgramCounterOutOfBounds);
         }
+        self.check_call_depth()?;
 
-        self.call_stack.push(Frame::new(self.pc));
+        let (base, count) = self.register_window();
+        let upvalues = self
+            .call_stack
+            .last()
+            .map(|frame| frame.upvalues.clone())
+            .unwrap_or_default();
+        self.call_stack
+            .push(Frame::new(self.pc, base, count, upvalues));
         self.pc = address;
         Ok(())
     }
 
-    fn call_return(&mut self) -> Result<(), VmError> {
+    /// Fails if pushing another frame would exceed the call depth limit
+    fn check_call_depth(&self) -> Result<(), VmError> {
+        check_limit(
+            self.call_depth() + 1,
+            self.limits.max_call_depth,
+            VmError::CallDepthExceeded,
+        )
+    }
+
+    fn call_function(
+        &mut self,
+        function: usize,
+        upvalues: Rc<Vec<SharedValue>>,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+        result_count: usize,
+    ) -> Result<(), VmError> {
+        let proto = self
+            .program
+            .functions
+            .get(function)
+            .ok_or(VmError::FunctionOutOfBounds(function))?;
+        if arg_count != proto.arity {
+            return Err(VmError::ArityMismatch {
+                name: proto.name.clone(),
+                expected: proto.arity,
+                actual: arg_count,
+            });
+        }
+        if proto.address >= self.instruction_count() {
+            return Err(VmError::ProgramCounterOutOfBounds);
+        }
+        if arg_count > proto.register_count {
+            return Err(VmError::RegisterOutOfBounds(arg_count - 1));
+        }
+        self.check_call_depth()?;
+
+        let arguments = self.register_values(args, arg_count)?;
+
+        let base = self.registers.len();
+        self.registers
+            .extend((0..proto.register_count).map(|_| Rc::new(RefCell::new(VmValue::Null))));
+        for (i, argument) in arguments.into_iter().enumerate() {
+            self.registers[base + i] = Rc::new(RefCell::new(argument));
+        }
+
+        self.call_stack.push(Frame::function(
+            self.pc,
+            function,
+            base,
+            proto.register_count,
+            target,
+            result_count,
+            upvalues,
+        ));
+        self.pc = proto.address;
+        Ok(())
+    }
+
+    fn call_native(
+        &mut self,
+        name: &str,
+        args: usize,
+        arg_count: usize,
+        target: usize,
+    ) -> Result<(), VmError> {
+        let function = self
+            .natives
+            .get(name)
+            .cloned()
+            .ok_or_else(|| VmError::NativeFunctionNotFound(name.to_string()))?;
+        let arguments = self.register_values(args, arg_count)?;
+
+        let value = function(self, &arguments).map_err(|error| VmError::NativeFunctionError {
+            name: name.to_string(),
+            error: Box::new(error),
+        })?;
+        self.set_register(target, value)
+    }
+
+    /// Values of the `count` registers from `start`, as passed to a call or returned
+    fn register_values(&self, start: usize, count: usize) -> Result<Vec<VmValue>, VmError> {
+        // the range runs past the largest register index
+        let end = start
+            .checked_add(count)
+            .ok_or(VmError::RegisterOutOfBounds(usize::MAX))?;
+        (start..end)
+            .map(|index| Ok(self.get_register(index)?.borrow().clone()))
+            .collect()
+    }
+
+    fn call_return(&mut self, values: Vec<VmValue>) -> Result<(), VmError> {
         let frame = self.call_stack.pop().ok_or(VmError::CallStackEmpty)?;
+        // a coroutine's first frame returns to whoever resumed it
+        if self.call_stack.is_empty() && !self.resumers.is_empty() {
+            let value = values.into_iter().next().unwrap_or(VmValue::Null);
+            return self.finish_coroutine(value);
+        }
         self.pc = frame.return_address;
+        // handlers installed by the frame are not left behind for its caller
+        let call_depth = self.call_stack.len();
+        self.handlers
+            .retain(|handler| handler.call_depth <= call_depth);
+        if frame.function.is_none() {
+            return Ok(());
+        }
+
+        self.registers.truncate(frame.register_base);
+        let mut values = values.into_iter();
+        for i in 0..frame.result_count {
+            let value = values.next().unwrap_or(VmValue::Null);
+            self.set_register(frame.result_target + i, value)?;
+        }
         Ok(())
     }
 
@@ -0,0 +1,243 @@
+//! Differential tests running every program in `tests/programs` in the VM
+//! and as native binaries built by the AOT backends, comparing what they
+//! print and the error they stop with.
+//!
+//! The assembly backend needs `nasm` and a C compiler, the C backend only a
+//! C compiler (`$CC`, or `cc`). The tests are ignored by default and fail if
+//! their tools are missing, so run them with `cargo test -- --ignored`.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use pretty_assertions::assert_eq;
+use ryde::{
+    aot::{self, runtime, state::AotTarget},
+    asm::assemble,
+    error::aot::{AotError, AotErrorKind},
+    instruction::Instruction,
+    output::SharedBuffer,
+    serde::Program,
+    vm::Vm,
+};
+
+/// Registers of the top-level window, enough for every program in the corpus
+const REGISTER_COUNT: usize = 64;
+
+/// What a program printed, followed by the error it stopped with
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    stdout: String,
+    error: String,
+}
+
+fn corpus() -> Vec<(String, Program)> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "ryasm")
+        })
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let source = fs::read_to_string(&path).unwrap();
+            let program =
+                assemble(&source).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+            (name, program)
+        })
+        .collect()
+}
+
+fn run_vm(program: &Program) -> Outcome {
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(program, REGISTER_COUNT);
+    vm.set_output(output.clone());
+    let error = match vm.run() {
+        Ok(()) => String::new(),
+        Err(error) => format!("VM error: {}\n", error),
+    };
+    Outcome {
+        stdout: output.contents(),
+        error,
+    }
+}
+
+fn c_compiler() -> String {
+    env::var("CC").unwrap_or_else(|_| "cc".to_string())
+}
+
+fn tool_available(tool: &str, version_flag: &str) -> bool {
+    Command::new(tool).arg(version_flag).output().is_ok()
+}
+
+/// Fresh directory for building one program, holding the runtime library
+fn build_directory(backend: &str, name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!(
+        "ryde_aot_diff_{}_{}_{}",
+        std::process::id(),
+        backend,
+        name
+    ));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("ryde_runtime.c"), runtime::LIBRARY_SOURCE).unwrap();
+    fs::write(directory.join("ryde_runtime.h"), runtime::HEADER_SOURCE).unwrap();
+    directory
+}
+
+fn run_tool(tool: &str, arguments: &[&str], directory: &Path) {
+    let output = Command::new(tool)
+        .args(arguments)
+        .current_dir(directory)
+        .output()
+        .unwrap_or_else(|error| panic!("failed to run {}: {}", tool, error));
+    assert!(
+        output.status.success(),
+        "{} {} failed in {}:\n{}",
+        tool,
+        arguments.join(" "),
+        directory.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Runs the binary built in `directory` and removes the directory
+fn run_binary(directory: &Path) -> Outcome {
+    let binary = directory.join(if cfg!(windows) { "out.exe" } else { "out" });
+    let output = Command::new(&binary).output().unwrap();
+    let outcome = Outcome {
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        error: String::from_utf8_lossy(&output.stderr).to_string(),
+    };
+    // a crash has no error message, unlike a runtime error
+    assert!(
+        output.status.code().is_some(),
+        "{} crashed: {}",
+        binary.display(),
+        output.status
+    );
+    fs::remove_dir_all(directory).unwrap();
+    outcome
+}
+
+fn run_asm(name: &str, program: &Program) -> Outcome {
+    let target = AotTarget::host();
+    let directory = build_directory("asm", name);
+    fs::write(
+        directory.join("out.asm"),
+        aot::compile(program, target).unwrap(),
+    )
+    .unwrap();
+    run_tool(
+        "nasm",
+        &["-f", target.nasm_format(), "out.asm", "-o", "out.o"],
+        &directory,
+    );
+    run_tool(
+        &c_compiler(),
+        &["out.o", "ryde_runtime.c", "-o", "out", "-lm"],
+        &directory,
+    );
+    run_binary(&directory)
+}
+
+fn run_c(name: &str, program: &Program) -> Outcome {
+    let directory = build_directory("c", name);
+    fs::write(
+        directory.join("out.c"),
+        aot::c::compile(program, REGISTER_COUNT).unwrap(),
+    )
+    .unwrap();
+    run_tool(
+        &c_compiler(),
+        &["out.c", "ryde_runtime.c", "-o", "out", "-lm"],
+        &directory,
+    );
+    run_binary(&directory)
+}
+
+/// Exception handlers and coroutines have no translation in either backend,
+/// which reject programs using them with an error
+fn aot_supports(program: &Program) -> bool {
+    !program.instructions.iter().any(|instruction| {
+        matches!(
+            instruction,
+            Instruction::THROW(_)
+                | Instruction::PUSH_HANDLER { .. }
+                | Instruction::POP_HANDLER
+                | Instruction::YIELD { .. }
+                | Instruction::COROUTINE { .. }
+                | Instruction::RESUME { .. }
+        )
+    })
+}
+
+fn is_unsupported_opcode<T>(result: Result<T, AotError>) -> bool {
+    matches!(
+        result,
+        Err(AotError {
+            kind: AotErrorKind::UnsupportedOpcode,
+            ..
+        })
+    )
+}
+
+/// The assembly backend keeps registers in machine registers and has no
+/// register windows, so it compiles no function calls or closures
+fn asm_supports(program: &Program) -> bool {
+    !program.instructions.iter().any(|instruction| {
+        matches!(
+            instruction,
+            Instruction::CALLF { .. }
+                | Instruction::CALLR { .. }
+                | Instruction::CLOSURE { .. }
+                | Instruction::GETUPVAL { .. }
+                | Instruction::SETUPVAL { .. }
+                | Instruction::CALL_NATIVE { .. }
+                | Instruction::CALL_NATIVEC { .. }
+                | Instruction::RETURNV { .. }
+        )
+    })
+}
+
+#[test]
+#[ignore = "needs nasm and a C compiler"]
+fn test_asm_matches_vm() {
+    assert!(tool_available("nasm", "-v"), "nasm is not installed");
+    assert!(
+        tool_available(&c_compiler(), "--version"),
+        "no C compiler is installed"
+    );
+    for (name, program) in corpus() {
+        if !aot_supports(&program) {
+            let result = aot::compile(&program, AotTarget::host());
+            assert!(is_unsupported_opcode(result), "{}", name);
+        } else if asm_supports(&program) {
+            assert_eq!(run_asm(&name, &program), run_vm(&program), "{}", name);
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a C compiler"]
+fn test_c_matches_vm() {
+    assert!(
+        tool_available(&c_compiler(), "--version"),
+        "no C compiler is installed"
+    );
+    for (name, program) in corpus() {
+        if aot_supports(&program) {
+            assert_eq!(run_c(&name, &program), run_vm(&program), "{}", name);
+        } else {
+            let result = aot::c::compile(&program, REGISTER_COUNT);
+            assert!(is_unsupported_opcode(result), "{}", name);
+        }
+    }
+}
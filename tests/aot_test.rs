@@ -0,0 +1,551 @@
+use ryde::aot::regalloc::{self, LiveInterval, Location};
+use ryde::aot::runtime::{CANONICAL_NAN, INT_TAG, NULL, box_value};
+use ryde::aot::types::{self, ValueType};
+use ryde::aot::{self, state::AotTarget};
+use ryde::asm::assemble;
+use ryde::error::aot::{AotError, AotErrorKind};
+use ryde::instruction::Instruction;
+use ryde::opcode::Opcode;
+use ryde::serde::Program;
+use ryde::value::VmValue;
+
+fn print_sum_program() -> Program {
+    Program::from_instructions(vec![
+        Instruction::LOADV {
+            target: 0,
+            value: VmValue::Int(5),
+        },
+        Instruction::LOADV {
+            target: 1,
+            value: VmValue::Int(10),
+        },
+        Instruction::ADD {
+            target: 0,
+            a: 0,
+            b: 1,
+        },
+        Instruction::PRINT(0),
+        Instruction::HALT,
+    ])
+}
+
+/// Instruction lines of the generated assembly, without comments
+fn instructions(asm: &str) -> Vec<&str> {
+    asm.lines()
+        .map(|line| line.split(';').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+#[test]
+fn test_compile_sysv64() {
+    let asm = aot::compile(&print_sum_program(), AotTarget::SysV64).unwrap();
+    let lines = instructions(&asm);
+
+    assert!(lines.contains(&"section .note.GNU-stack noalloc noexec nowrite progbits"));
+    let call = lines
+        .iter()
+        .position(|line| *line == "call ry_print wrt ..plt")
+        .unwrap();
+    assert_eq!(lines[call - 1], "mov rdi, rbx");
+    assert!(lines.contains(&"extern ry_print"));
+    assert!(!asm.contains("rsp, 32"));
+
+    let epilogue = lines.iter().position(|line| *line == "pop rbp").unwrap();
+    assert_eq!(
+        lines[epilogue - 4..=epilogue + 1],
+        [
+            "xor eax, eax",
+            "lea rsp, [rbp - 16]",
+            "pop r12",
+            "pop rbx",
+            "pop rbp",
+            "ret"
+        ]
+    );
+}
+
+#[test]
+fn test_compile_win64() {
+    let asm = aot::compile(&print_sum_program(), AotTarget::Win64).unwrap();
+    let lines = instructions(&asm);
+
+    let call = lines
+        .iter()
+        .position(|line| *line == "call ry_print")
+        .unwrap();
+    assert_eq!(
+        lines[call - 2..=call + 1],
+        [
+            "mov rcx, rbx",
+            "sub rsp, 32",
+            "call ry_print",
+            "add rsp, 32"
+        ]
+    );
+    assert!(lines.contains(&"extern ry_print"));
+    assert!(!asm.contains("GNU-stack"));
+}
+
+#[test]
+fn test_live_intervals_cover_loops() {
+    let program = assemble(
+        r#"
+            LOADV r0, 0
+            LOADV r1, 10
+            LOADV r2, 1
+        loop:
+            ADD r0, r0, r2
+            LOADV r3, 7
+            PRINT r3
+            JLT r0, r1, loop
+            PRINT r0
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        regalloc::live_intervals(&program),
+        vec![
+            LiveInterval {
+                register: 0,
+                start: 0,
+                end: 7
+            },
+            LiveInterval {
+                register: 1,
+                start: 1,
+                end: 6
+            },
+            LiveInterval {
+                register: 2,
+                start: 2,
+                end: 6
+            },
+            LiveInterval {
+                register: 3,
+                start: 4,
+                end: 5
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_allocation_spills_and_saves() {
+    // Twenty values live at once is more than the machine has registers for
+    let mut program = Vec::new();
+    for i in 0..20 {
+        program.push(Instruction::LOADV {
+            target: i,
+            value: VmValue::Int(i as i32),
+        });
+    }
+    for i in 0..20 {
+        program.push(Instruction::PRINT(i));
+    }
+    let program = Program::from_instructions(program);
+
+    let allocation = regalloc::allocate(&program, AotTarget::SysV64);
+    assert_eq!(allocation.spill_slots, 8);
+    assert_eq!(allocation.callee_saved, ["rbx", "r12", "r13", "r14", "r15"]);
+    assert_eq!(allocation.location(0), Some(Location::Register("rbx")));
+    assert_eq!(allocation.location(19), Some(Location::Stack(7)));
+    // After the first PRINT, r1..r19 are still needed
+    assert_eq!(
+        allocation.live_across(20),
+        [
+            "r10", "r12", "r13", "r14", "r15", "r8", "r9", "rcx", "rdi", "rdx", "rsi"
+        ]
+    );
+
+    let asm = aot::compile(&program, AotTarget::SysV64).unwrap();
+    let lines = instructions(&asm);
+    let load = lines
+        .iter()
+        .rposition(|line| *line == "mov qword [rbp - 48], rax")
+        .unwrap();
+    assert_eq!(lines[load - 1], "mov rax, 0xfff900000000000c");
+    let call = lines
+        .iter()
+        .position(|line| *line == "call ry_print wrt ..plt")
+        .unwrap();
+    assert!(lines[..call].contains(&"mov qword [rbp - 112], rcx"));
+    assert!(lines[call..].contains(&"mov rcx, qword [rbp - 112]"));
+}
+
+#[test]
+fn test_compile_control_flow() {
+    let program = assemble(
+        r#"
+            LOADV r0, 0
+            LOADV r1, 3.5
+        loop:
+            CALL step
+            JLT r0, r1, loop
+            JNZ r0, done
+            PRINT r1
+        done:
+            HALT
+        step:
+            ADD r0, r0, r1
+            RETURN
+        "#,
+    )
+    .unwrap();
+
+    let asm = aot::compile(&program, AotTarget::SysV64).unwrap();
+    let lines = instructions(&asm);
+    for index in 0..program.instructions.len() {
+        assert!(lines.contains(&format!(".L{}:", index).as_str()));
+    }
+
+    let call = lines.iter().position(|line| *line == "call .L7").unwrap();
+    assert_eq!(lines[call - 1], "sub rsp, 8");
+    assert_eq!(lines[call + 1], "add rsp, 8");
+    let compare = lines
+        .iter()
+        .position(|line| *line == "call ry_compare wrt ..plt")
+        .unwrap();
+    assert_eq!(lines[compare + 1..=compare + 2], ["cmp eax, -1", "je .L2"]);
+    // JNZ falls through on false and null, and jumps on everything else
+    let truth = lines.iter().position(|line| *line == ".L4:").unwrap();
+    assert_eq!(
+        lines[truth + 1..=truth + 6],
+        [
+            "mov rax, 0xfffa000000000000",
+            "cmp rbx, rax",
+            "je .L5",
+            "mov rax, 0xfffb000000000000",
+            "cmp rbx, rax",
+            "jne .L6"
+        ]
+    );
+    let step = lines.iter().position(|line| *line == ".L8:").unwrap();
+    assert_eq!(lines[step + 1], "ret");
+}
+
+#[test]
+fn test_box_values() {
+    assert_eq!(box_value(&VmValue::Int(-1)), Some(INT_TAG | 0xffff_ffff));
+    assert_eq!(box_value(&VmValue::Float(1.5)), Some(1.5f64.to_bits()));
+    assert_eq!(box_value(&VmValue::Float(-f64::NAN)), Some(CANONICAL_NAN));
+    assert_eq!(box_value(&VmValue::Null), Some(NULL));
+    assert_eq!(box_value(&VmValue::String("a".to_string())), None);
+}
+
+#[test]
+fn test_compile_arithmetic() {
+    let program = assemble(
+        r#"
+            LOADV r0, 7.5
+            LOADV r1, 2.5
+            SUBK r2, 1, r0
+            IDIV r2, r2, r1
+            IDIVK r2, 3, r2
+            BNOT r3, r0
+            MUL r1, r0, r1
+            PRINT r1
+        "#,
+    )
+    .unwrap();
+
+    let asm = aot::compile(&program, AotTarget::SysV64).unwrap();
+    let lines = instructions(&asm);
+    assert_eq!(
+        lines[lines.iter().position(|line| *line == ".L1:").unwrap() + 1],
+        "mov r12, 0x4004000000000000"
+    );
+
+    // immediates are staged with the other operand before the call
+    let subtract = lines
+        .iter()
+        .position(|line| *line == "call ry_sub")
+        .unwrap();
+    assert_eq!(
+        lines[subtract - 4..=subtract + 1],
+        [
+            "mov rax, 0xfff9000000000001",
+            "mov r11, rbx",
+            "mov rdi, rax",
+            "mov rsi, r11",
+            "call ry_sub",
+            "mov r13, rax"
+        ]
+    );
+    for routine in [
+        "ry_idiv:",
+        "ry_idivk:",
+        "ry_bnot:",
+        "ry_mul:",
+        "ry_box_number:",
+    ] {
+        assert!(lines.contains(&routine), "missing {}", routine);
+    }
+    assert!(!lines.contains(&"ry_add:"));
+    assert!(lines.contains(&"extern floor"));
+    assert!(lines.contains(&"call floor wrt ..plt"));
+}
+
+#[test]
+fn test_compile_dynamic_values() {
+    let program = assemble(
+        r#"
+            LOADV r0, "hi"
+            NEW_ARRAY r1
+            ARRAY_PUSH r1, r0
+            STORE_INDEXN r0, r1, 1
+            LEN r3, r1
+            PRINT r3
+        "#,
+    )
+    .unwrap();
+
+    let asm = aot::compile(&program, AotTarget::SysV64).unwrap();
+    let lines = instructions(&asm);
+    assert!(lines.contains(&"mov rbx, qword [rel ry_constant_0]"));
+    assert_eq!(
+        lines[lines.iter().position(|line| *line == "align 8").unwrap() + 1..],
+        [
+            "ry_string_0 dq 2",
+            "ry_string_0_bytes db 104, 105, 0",
+            "ry_constant_0 dq ry_string_0 + 0xfffc000000000000"
+        ]
+    );
+    for routine in [
+        "ry_new_array",
+        "ry_array_push",
+        "ry_store_index",
+        "ry_len",
+        "ry_print",
+    ] {
+        assert!(lines.contains(&format!("extern {}", routine).as_str()));
+    }
+
+    // STORE_INDEX passes the object, the key and then the value
+    let store = lines
+        .iter()
+        .position(|line| *line == "call ry_store_index wrt ..plt")
+        .unwrap();
+    assert_eq!(
+        lines[store - 5..store],
+        [
+            "mov rax, r13",
+            "mov r11, 0xfff9000000000001",
+            "mov rdx, rbx",
+            "mov rdi, rax",
+            "mov rsi, r11"
+        ]
+    );
+}
+
+#[test]
+fn test_infer_types() {
+    let program = assemble(
+        r#"
+            LOADV r0, 0
+            LOADV r1, 1.5
+            LOADV r2, true
+        loop:
+            ADD r0, r0, r0
+            MUL r3, r0, r1
+            IDIV r4, r1, r1
+            DIV r5, r0, r0
+            LOADV r2, "a"
+            JLT r0, r0, loop
+            PRINT r0
+        "#,
+    )
+    .unwrap();
+
+    let types = types::infer(&program);
+    assert_eq!(types.register_type(3, 0), ValueType::Int);
+    assert_eq!(types.register_type(3, 1), ValueType::Float);
+    assert_eq!(types.register_type(4, 0), ValueType::Int);
+    assert_eq!(types.register_type(5, 3), ValueType::Float);
+    assert_eq!(types.register_type(6, 4), ValueType::Int);
+    // Ints divide into Floats too
+    assert_eq!(types.register_type(7, 5), ValueType::Unknown);
+    // a Bool on entry to the loop and a String coming back around
+    assert_eq!(types.register_type(3, 2), ValueType::Unknown);
+    assert_eq!(types.register_type(9, 0), ValueType::Int);
+}
+
+#[test]
+fn test_compile_int_fast_paths() {
+    let program = assemble(
+        r#"
+            LOADV r0, 0
+            LOADV r1, 10
+        loop:
+            ADDK r0, 1, r0
+            JLT r0, r1, loop
+            JNZ r0, done
+            LOADV r1, "a"
+            ADD r0, r0, r1
+        done:
+            PRINT r0
+        "#,
+    )
+    .unwrap();
+
+    let asm = aot::compile(&program, AotTarget::SysV64).unwrap();
+    let lines = instructions(&asm);
+    let loop_start = lines.iter().position(|line| *line == ".L2:").unwrap();
+    assert_eq!(
+        lines[loop_start + 1..=loop_start + 17],
+        [
+            "mov rax, 1",
+            "movsxd r11, ebx",
+            "add rax, r11",
+            "mov r11, 2147483647",
+            "cmp rax, r11",
+            "cmovg rax, r11",
+            "mov r11, -2147483648",
+            "cmp rax, r11",
+            "cmovl rax, r11",
+            "mov eax, eax",
+            "mov r11, 0xfff9000000000000",
+            "or rax, r11",
+            "mov rbx, rax",
+            ".L3:",
+            "mov eax, ebx",
+            "cmp eax, r12d",
+            "jl .L2"
+        ]
+    );
+    // an Int is always truthy
+    assert_eq!(lines[loop_start + 19], "jmp .L7");
+    // adding a String goes through the runtime
+    assert!(lines.contains(&"call ry_add"));
+    assert!(!lines.contains(&"call ry_compare wrt ..plt"));
+}
+
+/// Statements of the generated C, without comments
+fn statements(source: &str) -> Vec<&str> {
+    source
+        .lines()
+        .map(|line| line.split("//").next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+#[test]
+fn test_compile_c() {
+    let source = aot::c::compile(&print_sum_program(), 4).unwrap();
+    let lines = statements(&source);
+    assert_eq!(
+        lines[1..],
+        [
+            "#include \"ryde_runtime.h\"",
+            "int main(void) {",
+            "ry_start(4, NULL, 0, 5);",
+            "R(0) = 0xfff9000000000005ULL;",
+            "R(1) = 0xfff900000000000aULL;",
+            "R(0) = ry_box_int(ry_saturate_int((int64_t)ry_int(R(0)) + ry_int(R(1))));",
+            "ry_print(R(0));",
+            "return 0;",
+            "}"
+        ]
+    );
+}
+
+#[test]
+fn test_compile_c_functions() {
+    let program = assemble(
+        r#"
+            .function fib 1 8 fib
+                LOADV r0, 15
+                CALLF fib, r0, 1, r1, 1
+                PRINT r1
+                HALT
+            fib:
+                LOADV r1, 2
+                JLT r0, r1, base
+                LOADV r2, "a\"?"
+                RETURNV r0, 1
+            base:
+                RETURNV r0, 1
+        "#,
+    )
+    .unwrap();
+
+    let source = aot::c::compile(&program, 4).unwrap();
+    let lines = statements(&source);
+    assert!(lines.contains(&"{\"fib\", 4, 1, 8},"));
+    assert!(lines.contains(&"ry_start(4, ry_functions, 1, 9);"));
+    assert!(lines.contains(&"ry_strings[0] = ry_string(\"a\\\"\\077\", 3);"));
+    assert!(lines.contains(&"if (ry_compare(R(0), R(1)) == -1) goto L8;"));
+    assert!(lines.contains(&"ry_address = ry_call_function(2, 0, 0, 1, 1, 1);"));
+    assert!(lines.contains(&"ry_address = ry_return(0, 1);"));
+
+    // only instructions jumped or returned to keep their label
+    let labels: Vec<&str> = lines
+        .iter()
+        .copied()
+        .filter(|line| line.starts_with('L') && line.ends_with(':'))
+        .collect();
+    assert_eq!(labels, ["L2:", "L4:", "L8:"]);
+    let dispatch = lines
+        .iter()
+        .position(|line| *line == "ry_dispatch:")
+        .unwrap();
+    assert_eq!(
+        lines[dispatch + 1..dispatch + 6],
+        [
+            "switch (ry_address) {",
+            "case 2:",
+            "goto L2;",
+            "case 4:",
+            "goto L4;"
+        ]
+    );
+}
+
+#[test]
+fn test_compile_unsupported_instruction() {
+    let program = assemble("LOADV r0, 1\nTHROW r0\n").unwrap();
+    let expected = AotError::new(1, Opcode::THROW, AotErrorKind::UnsupportedOpcode);
+
+    assert_eq!(
+        aot::compile(&program, AotTarget::SysV64),
+        Err(expected.clone())
+    );
+    assert_eq!(aot::c::compile(&program, 4), Err(expected));
+}
+
+#[test]
+fn test_compile_unsupported_operands() {
+    // arrays and objects have no immediate form in either backend
+    let program = assemble("LOADV r0, 1\nPRINTK [1, 2]\n").unwrap();
+    let Instruction::PRINTK(array) = program.instructions[1].clone() else {
+        panic!("expected PRINTK");
+    };
+    let expected = AotError::new(1, Opcode::PRINTK, AotErrorKind::UnsupportedImmediate(array));
+    assert_eq!(
+        aot::compile(&program, AotTarget::SysV64),
+        Err(expected.clone())
+    );
+    assert_eq!(aot::c::compile(&program, 4), Err(expected));
+
+    // the assembly backend has no register windows for functions
+    let program = assemble(".function f 0 1 f\nCALLF f, r0, 0, r0, 0\nHALT\nf: RETURN\n").unwrap();
+    assert_eq!(
+        aot::compile(&program, AotTarget::SysV64),
+        Err(AotError::new(
+            0,
+            Opcode::CALLF,
+            AotErrorKind::UnsupportedOpcode
+        ))
+    );
+
+    let program = Program::from_instructions(vec![Instruction::PRINTC(3)]);
+    assert_eq!(
+        aot::compile(&program, AotTarget::SysV64),
+        Err(AotError::new(
+            0,
+            Opcode::PRINTC,
+            AotErrorKind::ConstantOutOfBounds(3)
+        ))
+    );
+}
@@ -0,0 +1,278 @@
+use ryde::asm::{assemble, disassemble};
+use ryde::function::{Capture, FunctionProto};
+use ryde::instruction::Instruction;
+use ryde::output::SharedBuffer;
+use ryde::serde::Program;
+use ryde::serde::builder::ProgramBuilder;
+use ryde::value::VmValue;
+use ryde::vm::Vm;
+
+#[test]
+fn test_assemble_fib() {
+    let program = assemble(
+        r#"
+        .function fib 1 4 fib
+
+        ; print fib(10)
+            LOADV r0, 10
+            CALLF fib, r0, 1, r1, 1
+            PRINT r1
+            HALT
+
+        fib:
+            LOADV r1, 2
+            JLT r0, r1, done
+            SUBK r1, 1, r0     ; r1 = 1 - n
+            NEGATE r1, r1
+            CALLF fib, r1, 1, r2, 1
+            LOADV r1, 2
+            SUB r1, r0, r1
+            CALLF fib, r1, 1, r3, 1
+            ADD r0, r2, r3
+        done:
+            RETURNV r0, 1
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(program.functions, vec![FunctionProto::new("fib", 4, 1, 4)]);
+    assert_eq!(
+        program.instructions[5],
+        Instruction::JLT {
+            a: 0,
+            b: 1,
+            address: 13,
+        }
+    );
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 4);
+    vm.set_output(output.clone());
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "55\n");
+}
+
+#[test]
+fn test_assemble_literals() {
+    let program = assemble(
+        r#"
+        .const "a \"quoted\" string\n"
+        .const -2.5e3
+            LOADV r0, [1, -2, 3.5, "four", [true, false], null]
+            LOADV r1, { "x": 1, 2: "y" }
+            LOADV r2, -inf
+            LOADC r3, #1
+            INC _, counter, true
+            INC r2, "odd name", false
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        program.constant_pool,
+        vec![
+            VmValue::String("a \"quoted\" string\n".to_string()),
+            VmValue::Float(-2500.0)
+        ]
+    );
+    let Instruction::LOADV {
+        value: VmValue::DynamicArray(array),
+        ..
+    } = &program.instructions[0]
+    else {
+        panic!("expected array literal");
+    };
+    assert_eq!(array.len(), 6);
+    assert_eq!(array.index(1), VmValue::Int(-2));
+    let Instruction::LOADV {
+        value: VmValue::Object(object),
+        ..
+    } = &program.instructions[1]
+    else {
+        panic!("expected object literal");
+    };
+    assert_eq!(
+        object.index(&VmValue::Int(2)),
+        VmValue::String("y".to_string())
+    );
+    assert_eq!(
+        program.instructions[4],
+        Instruction::INC {
+            target: None,
+            name: "counter".to_string(),
+            returns_old: true,
+        }
+    );
+    assert_eq!(
+        program.instructions[5],
+        Instruction::INC {
+            target: Some(2),
+            name: "odd name".to_string(),
+            returns_old: false,
+        }
+    );
+}
+
+#[test]
+fn test_assemble_closure_captures() {
+    let program = assemble(
+        r#"
+        .function f 0 1 f
+            CLOSURE r0, f, x, r1, ^2
+            CLOSURE r1, 0
+        f:  RETURN
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        program.instructions[0],
+        Instruction::CLOSURE {
+            target: 0,
+            function: 0,
+            captures: vec![
+                Capture::Variable("x".to_string()),
+                Capture::Register(1),
+                Capture::Upvalue(2),
+            ],
+        }
+    );
+    assert_eq!(
+        program.instructions[1],
+        Instruction::CLOSURE {
+            target: 1,
+            function: 0,
+            captures: Vec::new(),
+        }
+    );
+}
+
+#[test]
+fn test_assemble_errors() {
+    let error = assemble("LOADV r0, 1\n  FOO r1").unwrap_err();
+    assert_eq!((error.line, error.column), (2, 3));
+    assert!(error.message.contains("unknown opcode"));
+
+    let error = assemble("JMP nowhere").unwrap_err();
+    assert_eq!((error.line, error.column), (1, 5));
+    assert!(error.message.contains("undefined label"));
+
+    let error = assemble("ADD r0, r1").unwrap_err();
+    assert_eq!((error.line, error.column), (1, 11));
+
+    let error = assemble("LOADV r0, 1 2").unwrap_err();
+    assert!(error.message.contains("end of line"));
+
+    let error = assemble("PRINTK \"open").unwrap_err();
+    assert!(error.message.contains("unterminated"));
+}
+
+/// Disassembles `program`, reassembles the text and checks that nothing changed
+fn assert_round_trip(program: &Program) -> String {
+    let text = disassemble(program);
+    let reassembled = assemble(&text).unwrap_or_else(|error| panic!("{}\n{}", error, text));
+    assert_eq!(&reassembled, program);
+    assert_eq!(disassemble(&reassembled), text);
+    text
+}
+
+#[test]
+fn test_disassemble_round_trip() {
+    let program = assemble(
+        r#"
+        .const "a \"quoted\" string\n"
+        .const -2.5e3
+        .const 1.0
+        .const -2147483648
+        .function fib 1 4 fib
+        .function "two words" 0 1 anon
+            LOADV r0, [1, -2, 3.5, "four", [true, false], null]
+            LOADV r1, { "x": 1, 2: "y", "n": { } }
+            LOADV r2, -inf
+            LOADC r3, #3
+            INC _, counter, true
+            INC r2, "r5", false
+            STORE r1, "_"
+            CLOSURE r0, fib, x, r1, ^2
+            CLOSURE r1, 1
+            CALLF fib, r0, 1, r1, 1
+            JMP end
+        fib:
+            RETURNV r0, 1
+        anon:
+            RETURN
+        end:
+        "#,
+    )
+    .unwrap();
+
+    let text = assert_round_trip(&program);
+    assert!(text.contains(".function fib 1 4 fib\n"));
+    assert!(text.contains(".function \"two words\" 0 1 L12\n"));
+    assert!(text.contains("CLOSURE r1, 1 "));
+    assert!(text.contains("    JMP L13"));
+    assert!(text.ends_with("L13:\n"));
+    assert!(text.contains("INC r2, \"r5\", false"));
+    assert!(text.contains("STORE r1, \"_\""));
+}
+
+#[test]
+fn test_debug_locations_round_trip() {
+    let program = assemble(
+        r#"
+            .loc "main.ry" 1 1
+            LOADV r0, 1
+            JMP end
+            .loc "main.ry" 3 5
+            .loc "other file.ry" 2 1
+        end:
+            PRINT r0
+            .loc main 4 1
+        "#,
+    )
+    .unwrap();
+
+    let debug_info = program.debug_info.as_ref().unwrap();
+    assert_eq!(debug_info.files, ["main.ry", "other file.ry", "main"]);
+    assert_eq!(debug_info.entries.len(), 3);
+    assert_eq!(
+        debug_info.location(2).unwrap().to_string(),
+        "other file.ry:2:1"
+    );
+
+    let text = assert_round_trip(&program);
+    assert!(text.contains("L2:\n    .loc \"other file.ry\" 2 1\n    PRINT r0"));
+    assert!(text.ends_with(".loc main 4 1\n"));
+}
+
+#[test]
+fn test_disassemble_built_program() {
+    let mut builder = ProgramBuilder::new();
+    builder.function(FunctionProto::new("L0", 3, 0, 1));
+    builder.function(FunctionProto::new("dup", 3, 0, 1));
+    builder.function(FunctionProto::new("dup", 3, 0, 1));
+    builder.push(Instruction::LOADV {
+        target: 0,
+        value: VmValue::Float(3.0),
+    });
+    builder.push(Instruction::ADDK {
+        target: 0,
+        a_value: VmValue::Int(3),
+        b: 0,
+    });
+    builder.push(Instruction::CALLF {
+        function: 2,
+        args: 0,
+        arg_count: 0,
+        target: 0,
+        result_count: 0,
+    });
+    builder.push(Instruction::RETURN);
+    let program = builder.build();
+
+    let text = assert_round_trip(&program);
+    assert!(text.contains(".const 3.0"));
+    assert!(text.contains(".const 3 "));
+    assert!(text.contains("CALLF 2, r0, 0, r0, 0"));
+}
@@ -0,0 +1,253 @@
+use std::{env, fs, path::PathBuf, process::Command as Process};
+
+use ryde::aot::state::AotTarget;
+use ryde::cli::{self, Command, DEFAULT_REGISTERS, Target};
+use ryde::error::cli::CliError;
+
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(str::to_string).collect()
+}
+
+/// Fresh directory for the files of one test
+fn scratch_directory(name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!("ryde_cli_{}_{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn ryde(args: &[&str]) -> std::process::Output {
+    Process::new(env!("CARGO_BIN_EXE_ryde"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_parse_commands() {
+    assert_eq!(
+        cli::parse(&args("run prog.bin")).unwrap(),
+        Command::Run {
+            file: "prog.bin".to_string(),
+            registers: DEFAULT_REGISTERS,
+        }
+    );
+    assert_eq!(
+        cli::parse(&args("run --registers 8 prog.bin")).unwrap(),
+        Command::Run {
+            file: "prog.bin".to_string(),
+            registers: 8,
+        }
+    );
+    assert_eq!(
+        cli::parse(&args("compile prog.bin -o prog.c --target c")).unwrap(),
+        Command::Compile {
+            file: "prog.bin".to_string(),
+            output: "prog.c".to_string(),
+            target: Target::C,
+            registers: DEFAULT_REGISTERS,
+        }
+    );
+    assert_eq!(
+        cli::parse(&args("compile prog.bin --target win64")).unwrap(),
+        Command::Compile {
+            file: "prog.bin".to_string(),
+            output: "out.asm".to_string(),
+            target: Target::Asm(AotTarget::Win64),
+            registers: DEFAULT_REGISTERS,
+        }
+    );
+    assert_eq!(
+        cli::parse(&args("asm prog.ryasm -o prog.bin")).unwrap(),
+        Command::Asm {
+            source: "prog.ryasm".to_string(),
+            output: "prog.bin".to_string(),
+        }
+    );
+    assert_eq!(
+        cli::parse(&args("disasm prog.bin")).unwrap(),
+        Command::Disasm {
+            file: "prog.bin".to_string(),
+        }
+    );
+    assert_eq!(
+        cli::parse(&args("debug prog.ryasm")).unwrap(),
+        Command::Debug {
+            file: "prog.ryasm".to_string(),
+            registers: DEFAULT_REGISTERS,
+        }
+    );
+    assert_eq!(
+        cli::parse(&args("verify prog.bin --registers 2")).unwrap(),
+        Command::Verify {
+            file: "prog.bin".to_string(),
+            registers: 2,
+        }
+    );
+}
+
+#[test]
+fn test_parse_errors() {
+    for line in [
+        "",
+        "launch prog.bin",
+        "run",
+        "run a.bin b.bin",
+        "run prog.bin --registers",
+        "run prog.bin --registers many",
+        "disasm prog.bin -o out",
+        "compile prog.bin --target arm",
+    ] {
+        let error = cli::parse(&args(line)).unwrap_err();
+        assert!(matches!(error, CliError::Usage(_)), "{}", line);
+        assert_eq!(error.exit_code(), 2);
+    }
+}
+
+#[test]
+fn test_asm_then_load() {
+    let directory = scratch_directory("asm");
+    let source = directory.join("prog.ryasm");
+    let binary = directory.join("prog.bin");
+    fs::write(&source, "LOADV r0, 5\nPRINT r0\nHALT\n").unwrap();
+
+    cli::execute(Command::Asm {
+        source: source.to_string_lossy().to_string(),
+        output: binary.to_string_lossy().to_string(),
+    })
+    .unwrap();
+
+    let assembled = cli::load_program(&source.to_string_lossy()).unwrap();
+    let loaded = cli::load_program(&binary.to_string_lossy()).unwrap();
+    assert_eq!(loaded, assembled);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_compile_writes_runtime() {
+    let directory = scratch_directory("compile");
+    let source = directory.join("prog.ryasm");
+    let output = directory.join("prog.c");
+    fs::write(&source, "LOADV r0, 5\nPRINT r0\n").unwrap();
+
+    cli::execute(Command::Compile {
+        file: source.to_string_lossy().to_string(),
+        output: output.to_string_lossy().to_string(),
+        target: Target::C,
+        registers: 4,
+    })
+    .unwrap();
+
+    assert!(
+        fs::read_to_string(&output)
+            .unwrap()
+            .contains("ry_start(4, NULL, 0, 2);")
+    );
+    assert!(directory.join("ryde_runtime.c").exists());
+    assert!(directory.join("ryde_runtime.h").exists());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_exit_codes() {
+    let directory = scratch_directory("exit_codes");
+    let good = directory.join("good.ryasm");
+    let failing = directory.join("failing.ryasm");
+    fs::write(&good, "LOADV r0, 5\nPRINT r0\n").unwrap();
+    fs::write(&failing, "LOADV r0, 5\nPRINT r0\nLOAD r1, missing\n").unwrap();
+    let (good, failing) = (good.to_string_lossy(), failing.to_string_lossy());
+
+    let output = ryde(&["run", &good]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+
+    let output = ryde(&["run", &failing]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("VM error: "));
+
+    let output = ryde(&["verify", &good, "--registers", "1"]);
+    assert_eq!(output.status.code(), Some(0));
+    let output = ryde(&["verify", &failing, "--registers", "1"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Program failed verification:\n  0002: Register r1 is out of bounds for a window of 1 register(s)\n"
+    );
+
+    let output = ryde(&["run", &directory.join("missing.bin").to_string_lossy()]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = ryde(&["frobnicate"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Usage:"));
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_compile_unsupported_instruction() {
+    let directory = scratch_directory("compile_unsupported");
+    let source = directory.join("prog.ryasm");
+    fs::write(&source, "LOADV r0, 1\nTHROW r0\n").unwrap();
+
+    let output = ryde(&[
+        "compile",
+        &source.to_string_lossy(),
+        "-o",
+        &directory.join("out.c").to_string_lossy(),
+        "--target",
+        "c",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr).trim_end(),
+        "Compile error: Cannot compile THROW at pc 1: the opcode is not supported by this backend"
+    );
+    assert!(!directory.join("out.c").exists());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_compile_coroutines() {
+    let directory = scratch_directory("compile_coroutines");
+    let source = directory.join("prog.ryasm");
+    let output_file = directory.join("out");
+
+    // the assembly backends reject CLOSURE before they get to COROUTINE
+    for (program, targets, rejected) in [
+        (
+            "LOADV r0, 1\nYIELD r0, r0\n",
+            &["c", "sysv64", "win64"][..],
+            "YIELD at pc 1",
+        ),
+        (
+            ".function f 1 1 f\nCLOSURE r0, f\nCOROUTINE r0, r0\nRESUME r0, r0, r0\nHALT\nf: RETURN\n",
+            &["c"][..],
+            "COROUTINE at pc 1",
+        ),
+    ] {
+        fs::write(&source, program).unwrap();
+        for target in targets {
+            let output = ryde(&[
+                "compile",
+                &source.to_string_lossy(),
+                "-o",
+                &output_file.to_string_lossy(),
+                "--target",
+                target,
+            ]);
+            assert_eq!(output.status.code(), Some(1), "{}", target);
+            assert_eq!(
+                String::from_utf8_lossy(&output.stderr).trim_end(),
+                format!(
+                    "Compile error: Cannot compile {}: the opcode is not supported by this backend",
+                    rejected
+                ),
+                "{}",
+                target
+            );
+            assert!(!output_file.exists());
+        }
+    }
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,199 @@
+use ryde::asm::assemble;
+use ryde::coroutine::CoroutineStatus;
+use ryde::error::vm::VmError;
+use ryde::limits::Limits;
+use ryde::output::SharedBuffer;
+use ryde::value::VmValue;
+use ryde::vm::{Vm, VmState};
+
+#[test]
+fn test_resume_from_host() {
+    let program = assemble(
+        r#"
+            LOADV r0, 1
+            YIELD r1, r0
+            ADDK r1, 10, r1
+            YIELD r1, r1
+            PRINT r1
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 2);
+    vm.set_output(output.clone());
+
+    assert_eq!(
+        vm.resume(VmValue::Null).unwrap(),
+        VmState::Yielded(VmValue::Int(1))
+    );
+    assert_eq!(
+        vm.resume(VmValue::Int(5)).unwrap(),
+        VmState::Yielded(VmValue::Int(15))
+    );
+    assert_eq!(output.contents(), "");
+    assert_eq!(vm.resume(VmValue::Int(7)).unwrap(), VmState::Finished);
+    assert_eq!(output.contents(), "7\n");
+    assert_eq!(vm.resume(VmValue::Null).unwrap(), VmState::Finished);
+
+    // run does not stop at yields, which resume with null
+    let mut vm = Vm::new(&program, 2);
+    vm.set_output(output.clone());
+    vm.run().unwrap();
+    assert_eq!(output.contents(), "7\nnull\n");
+}
+
+#[test]
+fn test_coroutines_exchange_values() {
+    let program = assemble(
+        r#"
+            .function accumulate 1 2 accumulate
+            CLOSURE r0, accumulate
+            COROUTINE r1, r0
+            LOADV r2, 1
+            RESUME r3, r1, r2
+            PRINT r3
+            LOADV r2, 2
+            RESUME r3, r1, r2
+            PRINT r3
+            LOADV r2, 3
+            RESUME r3, r1, r2
+            PRINT r3
+            HALT
+        accumulate:
+            ; the first resumed value is the argument, later ones are yield results
+            YIELD r1, r0
+            ADD r0, r0, r1
+            YIELD r1, r0
+            ADD r0, r0, r1
+            RETURNV r0, 1
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 4);
+    vm.set_output(output.clone());
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "1\n3\n6\n");
+    let VmValue::Coroutine(coroutine) = vm.register(1).unwrap() else {
+        panic!("expected a coroutine");
+    };
+    assert_eq!(coroutine.status(), CoroutineStatus::Dead);
+    // the coroutine's registers and frames are gone with it
+    assert_eq!(vm.registers.len(), 4);
+    assert!(vm.call_stack.is_empty());
+}
+
+#[test]
+fn test_nested_coroutines() {
+    let program = assemble(
+        r#"
+            .function outer 1 3 outer
+            .function inner 1 1 inner
+            CLOSURE r0, outer
+            COROUTINE r0, r0
+            RESUME r1, r0, r0
+            PRINT r1
+            RESUME r1, r0, r0
+            PRINT r1
+            HALT
+        outer:
+            CLOSURE r1, inner
+            COROUTINE r1, r1
+            RESUME r2, r1, r1
+            ADDK r2, 1, r2
+            YIELD r2, r2
+            ; resuming itself is an error
+            PUSH_HANDLER r2, caught
+            RESUME r2, r0, r0
+        caught:
+            INDEXK r2, r2, "kind"
+            RETURNV r2, 1
+        inner:
+            LOADV r0, 41
+            YIELD r0, r0
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 2);
+    vm.set_output(output.clone());
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "42\nCoroutineRunning\n");
+}
+
+#[test]
+fn test_coroutine_errors() {
+    // uncaught exceptions end the coroutine and reach its resumer
+    let program = assemble(
+        r#"
+            .function fail 1 1 fail
+            CLOSURE r0, fail
+            COROUTINE r0, r0
+            PUSH_HANDLER r1, caught
+            RESUME r1, r0, r0
+            HALT
+        caught:
+            PRINT r1
+            PUSH_HANDLER r1, dead
+            RESUME r1, r0, r0
+            HALT
+        dead:
+            INDEXK r1, r1, "kind"
+            PRINT r1
+            HALT
+        fail:
+            LOADV r0, "boom"
+            THROW r0
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 2);
+    vm.set_output(output.clone());
+    vm.run().unwrap();
+    assert_eq!(output.contents(), "boom\nCoroutineDead\n");
+
+    let program = assemble("LOADV r0, 1\nRESUME r0, r0, r0\n").unwrap();
+    let result = Vm::new(&program, 1).run();
+    assert!(matches!(result, Err(VmError::OperandTypeMismatch { .. })));
+
+    // the first resume passes a single argument
+    let program = assemble(
+        ".function f 0 1 f\nCLOSURE r0, f\nCOROUTINE r0, r0\nRESUME r0, r0, r0\nHALT\nf: RETURN\n",
+    )
+    .unwrap();
+    let result = Vm::new(&program, 1).run();
+    assert!(matches!(result, Err(VmError::ArityMismatch { .. })));
+}
+
+#[test]
+fn test_coroutine_frames_count_towards_call_depth() {
+    let program = assemble(
+        r#"
+            .function nest 1 2 nest
+            CLOSURE r0, nest
+            COROUTINE r1, r0
+            RESUME r1, r1, r0
+            HALT
+        nest:
+            COROUTINE r1, r0
+            RESUME r1, r1, r0
+            RETURN
+        "#,
+    )
+    .unwrap();
+
+    let mut vm = Vm::new(&program, 2);
+    let result = vm.run_with_limits(Limits {
+        max_call_depth: Some(20),
+        ..Limits::default()
+    });
+
+    assert!(matches!(result, Err(VmError::CallDepthExceeded(20))));
+}
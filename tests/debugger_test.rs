@@ -0,0 +1,151 @@
+use ryde::asm::assemble;
+use ryde::debugger::{self, Debugger, Stop, Watch};
+use ryde::output::SharedBuffer;
+use ryde::serde::Program;
+use ryde::value::VmValue;
+use ryde::vm::Vm;
+
+fn program() -> Program {
+    assemble(
+        r#"
+            .function double 1 2 double
+                LOADV r0, 1
+                STORE r0, x
+                LOADV r1, 2
+                CALLF double, r1, 1, r2, 1
+                PRINT r2
+                HALT
+            double:
+                ADD r1, r0, r0
+                RETURNV r1, 1
+        "#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_step_and_inspect() {
+    let program = program();
+    let mut vm = Vm::new(&program, 3);
+    vm.set_output(SharedBuffer::new());
+    let mut debugger = Debugger::new(&mut vm);
+
+    for _ in 0..3 {
+        assert!(matches!(debugger.step(), Stop::Step));
+    }
+    assert_eq!(debugger.vm.pc, 3);
+    assert_eq!(
+        debugger.vm.window(),
+        [VmValue::Int(1), VmValue::Int(2), VmValue::Null]
+    );
+    assert_eq!(
+        debugger.vm.variables(),
+        [("x".to_string(), VmValue::Int(1))]
+    );
+
+    // into the function, with a window of its own
+    assert!(matches!(debugger.step(), Stop::Step));
+    assert_eq!(debugger.vm.pc, 6);
+    assert_eq!(debugger.vm.window(), [VmValue::Int(2), VmValue::Null]);
+    assert_eq!(debugger.vm.call_stack.len(), 1);
+    let frame = &debugger.vm.call_stack[0];
+    assert_eq!(debugger.vm.frame_name(frame), Some("double"));
+    assert_eq!(frame.return_address(), 4);
+    assert_eq!(frame.window(), (3, 2));
+    assert_eq!(debugger.vm.variable("x").unwrap(), VmValue::Int(1));
+}
+
+#[test]
+fn test_breakpoints() {
+    let program = program();
+    let mut vm = Vm::new(&program, 3);
+    let output = SharedBuffer::new();
+    vm.set_output(output.clone());
+    let mut debugger = Debugger::new(&mut vm);
+
+    assert!(debugger.add_breakpoint(6));
+    assert!(debugger.add_breakpoint(4));
+    assert!(!debugger.add_breakpoint(4));
+    assert!(matches!(debugger.resume(), Stop::Breakpoint(6)));
+    assert!(matches!(debugger.resume(), Stop::Breakpoint(4)));
+    assert_eq!(output.contents(), "");
+    assert!(debugger.remove_breakpoint(6));
+    assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [4]);
+    assert!(matches!(debugger.resume(), Stop::Finished));
+    assert_eq!(output.contents(), "4\n");
+    assert!(matches!(debugger.step(), Stop::Finished));
+}
+
+#[test]
+fn test_watchpoints() {
+    let program = program();
+    let mut vm = Vm::new(&program, 3);
+    vm.set_output(SharedBuffer::new());
+    let mut debugger = Debugger::new(&mut vm);
+
+    debugger.watch(Watch::Variable("x".to_string()));
+    debugger.watch_register(2).unwrap();
+    assert!(debugger.watch_register(3).is_err());
+
+    match debugger.resume() {
+        Stop::Watchpoint { watch, old, new } => {
+            assert_eq!(watch, Watch::Variable("x".to_string()));
+            assert_eq!(old, None);
+            assert_eq!(new.as_deref(), Some("1"));
+        }
+        stop => panic!("unexpected stop: {:?}", stop),
+    }
+    assert_eq!(debugger.vm.pc, 2);
+
+    // r2 of the top level changes when the function returns into it
+    match debugger.resume() {
+        Stop::Watchpoint { watch, old, new } => {
+            assert_eq!(watch, Watch::Slot(2));
+            assert_eq!(old.as_deref(), Some("null"));
+            assert_eq!(new.as_deref(), Some("4"));
+        }
+        stop => panic!("unexpected stop: {:?}", stop),
+    }
+    assert_eq!(debugger.vm.pc, 4);
+
+    assert_eq!(debugger.unwatch(0), Some(Watch::Variable("x".to_string())));
+    assert_eq!(debugger.unwatch(5), None);
+    assert!(matches!(debugger.resume(), Stop::Finished));
+}
+
+#[test]
+fn test_error_stops_on_instruction() {
+    let program = assemble("LOADV r0, 1\nLOAD r1, missing\nPRINT r0\n").unwrap();
+    let mut vm = Vm::new(&program, 2);
+    let mut debugger = Debugger::new(&mut vm);
+
+    assert!(matches!(debugger.resume(), Stop::Error(_)));
+    assert_eq!(debugger.vm.pc, 1);
+    assert!(matches!(debugger.step(), Stop::Error(_)));
+}
+
+#[test]
+fn test_repl() {
+    let program = program();
+    let mut vm = Vm::new(&program, 3);
+    vm.set_output(SharedBuffer::new());
+    let mut debugger = Debugger::new(&mut vm);
+    let input = "break 6\ncontinue\nbt\np r0\np x\nstep 2\nquit\n";
+    let mut output = Vec::new();
+
+    debugger::repl(&mut debugger, input.as_bytes(), &mut output).unwrap();
+
+    assert_eq!(
+        String::from_utf8(output).unwrap().replace("(ryde) ", ""),
+        "=> 0000: LOADV r0, 1
+Breakpoint at 0006
+Breakpoint at 0006
+=> 0006: ADD r1, r0, r0
+#0 0006 in double, registers 3..5
+#1 0003 in <top level>
+r0 = 2
+x = 1
+=> 0004: PRINT r2
+"
+    );
+}
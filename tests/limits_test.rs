@@ -0,0 +1,240 @@
+use std::{thread, time::Duration};
+
+use ryde::asm::assemble;
+use ryde::error::vm::VmError;
+use ryde::limits::{CancellationToken, Limits};
+use ryde::output::SharedBuffer;
+use ryde::value::VmValue;
+use ryde::vm::{Vm, VmState};
+
+#[test]
+fn test_fuel_exhaustion_is_resumable() {
+    let program = assemble(
+        r#"
+            LOADV r0, 0
+            LOADV r1, 10
+        loop:
+            ADDK r0, 1, r0
+            PRINT r0
+            JLT r0, r1, loop
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 2);
+    vm.set_output(output.clone());
+    let limits = Limits {
+        fuel: Some(7),
+        ..Limits::default()
+    };
+
+    let mut runs = 1;
+    while let Err(error) = vm.run_with_limits(limits.clone()) {
+        assert!(matches!(error, VmError::FuelExhausted));
+        runs += 1;
+    }
+    // 2 + 3 * 10 instructions
+    assert_eq!(runs, 5);
+    assert_eq!(output.contents(), "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+
+    let program = assemble("loop:\nJMP loop\n").unwrap();
+    let mut vm = Vm::new(&program, 1);
+    assert!(matches!(
+        vm.run_with_limits(limits),
+        Err(VmError::FuelExhausted)
+    ));
+    assert_eq!(vm.pc, 0);
+}
+
+#[test]
+fn test_call_depth_limit() {
+    let program = assemble(
+        r#"
+            .function recurse 0 1 recurse
+            PUSH_HANDLER r0, caught
+            CALLF recurse, r0, 0, r0, 0
+        caught:
+            HALT
+        recurse:
+            CALLF recurse, r0, 0, r0, 0
+            RETURN
+        "#,
+    )
+    .unwrap();
+
+    let mut vm = Vm::new(&program, 1);
+    let result = vm.run_with_limits(Limits {
+        max_call_depth: Some(50),
+        ..Limits::default()
+    });
+
+    // limits cannot be caught by the program
+    assert!(matches!(result, Err(VmError::CallDepthExceeded(50))));
+    assert_eq!(vm.call_stack.len(), 50);
+}
+
+#[test]
+fn test_collection_size_limit() {
+    let limits = Limits {
+        max_collection_size: Some(8),
+        ..Limits::default()
+    };
+    for source in [
+        "NEW_ARRAY r0\nloop:\nARRAY_PUSHK r0, 1\nJMP loop\n",
+        "NEW_ARRAY r0\nSTORE_INDEXN r1, r0, 7\nSTORE_INDEXN r1, r0, 8\n",
+        "NEW_ARRAY r0\nDELETE_INDEXN r0, 1000000000\n",
+        "LOADV r0, 0\nNEW_OBJECT r1\nloop:\nSTORE_INDEX r0, r1, r0\nADDK r0, 1, r0\nJMP loop\n",
+    ] {
+        let program = assemble(source).unwrap();
+        let mut vm = Vm::new(&program, 2);
+        let result = vm.run_with_limits(limits.clone());
+        assert!(
+            matches!(result, Err(VmError::CollectionTooLarge(8))),
+            "{}",
+            source
+        );
+    }
+
+    // negative indices are rejected before they are taken as a size
+    for source in [
+        "NEW_ARRAY r0\nLOADV r1, -1\nSTORE_INDEX r1, r0, r1\n",
+        "NEW_ARRAY r0\nSTORE_INDEXK r0, r0, -1\n",
+        "NEW_ARRAY r0\nDELETE_INDEXK r0, -2147483648\n",
+    ] {
+        let program = assemble(source).unwrap();
+        let mut vm = Vm::new(&program, 2);
+        let result = vm.run_with_limits(limits.clone());
+        assert!(
+            matches!(result, Err(VmError::InvalidIndexType(_))),
+            "{}",
+            source
+        );
+        let result = Vm::new(&program, 2).run();
+        assert!(
+            matches!(result, Err(VmError::InvalidIndexType(_))),
+            "{}",
+            source
+        );
+    }
+
+    // overwriting existing entries does not grow the collection
+    let program = assemble(
+        "LOADV r0, { \"a\": 1 }\nLOADV r1, 2\nSTORE_INDEXK r1, r0, \"a\"\nDELETE_INDEXK r0, \"a\"\n",
+    )
+    .unwrap();
+    let mut vm = Vm::new(&program, 2);
+    vm.run_with_limits(Limits {
+        max_collection_size: Some(1),
+        ..Limits::default()
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_string_length_limit() {
+    let program = assemble("LOADV r0, \"ab\"\nloop:\nADD r0, r0, r0\nJMP loop\n").unwrap();
+    let mut vm = Vm::new(&program, 1);
+    let result = vm.run_with_limits(Limits {
+        max_string_length: Some(100),
+        ..Limits::default()
+    });
+
+    assert!(matches!(result, Err(VmError::StringTooLong(100))));
+    assert_eq!(vm.register(0).unwrap().to_string().len(), 64 + 2);
+}
+
+#[test]
+fn test_cancellation() {
+    let program = assemble("loop:\nJMP loop\n").unwrap();
+    let token = CancellationToken::new();
+    let limits = Limits {
+        cancellation: Some(token.clone()),
+        ..Limits::default()
+    };
+
+    let canceller = thread::spawn({
+        let token = token.clone();
+        move || {
+            thread::sleep(Duration::from_millis(20));
+            token.cancel();
+        }
+    });
+    let mut vm = Vm::new(&program, 1);
+    let result = vm.run_with_limits(limits.clone());
+    canceller.join().unwrap();
+
+    assert!(matches!(result, Err(VmError::Cancelled)));
+    assert!(token.is_cancelled());
+    assert!(matches!(
+        vm.run_with_limits(limits),
+        Err(VmError::Cancelled)
+    ));
+}
+
+#[test]
+fn test_resume_with_limits() {
+    let program = assemble("LOADV r0, 1\nYIELD r1, r0\nPRINT r1\n").unwrap();
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 2);
+    vm.set_output(output.clone());
+    let limits = Limits {
+        fuel: Some(1),
+        ..Limits::default()
+    };
+
+    assert!(matches!(
+        vm.resume_with_limits(VmValue::Null, limits.clone()),
+        Err(VmError::FuelExhausted)
+    ));
+    assert_eq!(
+        vm.resume_with_limits(VmValue::Null, limits.clone())
+            .unwrap(),
+        VmState::Yielded(VmValue::Int(1))
+    );
+
+    // the yield receives its value even if the VM is stopped right after
+    let token = CancellationToken::new();
+    token.cancel();
+    let cancelled = Limits {
+        cancellation: Some(token),
+        ..Limits::default()
+    };
+    assert!(matches!(
+        vm.resume_with_limits(VmValue::Int(7), cancelled),
+        Err(VmError::Cancelled)
+    ));
+    assert_eq!(
+        vm.resume_with_limits(VmValue::Null, limits).unwrap(),
+        VmState::Finished
+    );
+    assert_eq!(output.contents(), "7\n");
+}
+
+#[test]
+fn test_shift_counts_cannot_crash_the_host() {
+    // counts are masked to 5 bits instead of overflowing
+    let program = assemble(
+        r#"
+            LOADV r0, 32
+            BLSHK r1, 1, r0
+            PRINT r1
+            LOADV r0, -1
+            BLSHK r1, 1, r0
+            PRINT r1
+            LOADV r2, -8
+            BARSH r1, r2, r0
+            PRINT r1
+            BRSH r1, r2, r0
+            PRINT r1
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 3);
+    vm.set_output(output.clone());
+    vm.run_with_limits(Limits::default()).unwrap();
+
+    assert_eq!(output.contents(), "1\n-2147483648\n-1\n1\n");
+}
@@ -0,0 +1,430 @@
+use bincode::config;
+use ryde::array::DynamicArray;
+use ryde::asm::assemble;
+use ryde::debug_info::DebugInfo;
+use ryde::function::{Capture, Closure, FunctionProto};
+use ryde::instruction::Instruction;
+use ryde::object::Object;
+use ryde::serde::builder::ProgramBuilder;
+use ryde::serde::container::{self, HEADER_LEN, MAGIC};
+use ryde::serde::deserializer::{deserialize, deserialize_with_migrations};
+use ryde::serde::encoding::{ProgramReader, decode_program, encode_program};
+use ryde::serde::migration::Migration;
+use ryde::serde::serializer::serialize;
+use ryde::serde::{CURRENT_VERSION, Program, ProgramError};
+use ryde::value::VmValue;
+
+fn sample_program() -> Program {
+    assemble(
+        r#"
+        .const "hello"
+        .function f 0 1 f
+            PRINTC #0
+            CALLF f, r0, 0, r0, 0
+            HALT
+        f:  RETURN
+        "#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_serialize_round_trip() {
+    let program = sample_program();
+    let binary = serialize(&program).unwrap();
+
+    assert_eq!(binary[0..4], MAGIC);
+    assert_eq!(binary[4], CURRENT_VERSION);
+    assert_eq!(deserialize(binary).unwrap(), program);
+}
+
+#[test]
+fn test_deserialize_rejects_invalid_data() {
+    let binary = serialize(&sample_program()).unwrap();
+
+    let mut bad_magic = binary.clone();
+    bad_magic[0] = b'X';
+    assert!(matches!(
+        deserialize(bad_magic),
+        Err(ProgramError::InvalidMagic)
+    ));
+    assert!(matches!(
+        deserialize(b"RY".to_vec()),
+        Err(ProgramError::InvalidMagic)
+    ));
+
+    let mut newer = binary.clone();
+    newer[4] = CURRENT_VERSION + 1;
+    assert!(matches!(
+        deserialize(newer),
+        Err(ProgramError::UnsupportedVersion(v)) if v == CURRENT_VERSION + 1
+    ));
+
+    let mut truncated = binary.clone();
+    truncated.pop();
+    assert!(matches!(
+        deserialize(truncated),
+        Err(ProgramError::LengthMismatch { .. })
+    ));
+
+    let mut corrupted = binary.clone();
+    corrupted[HEADER_LEN] ^= 0xff;
+    assert!(matches!(
+        deserialize(corrupted),
+        Err(ProgramError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_deserialize_runs_migrations() {
+    let binary = serialize(&sample_program()).unwrap();
+    let (_, payload) = container::unwrap(&binary).unwrap();
+
+    // An "old" format that stored the payload reversed
+    let reversed: Vec<u8> = payload.iter().rev().copied().collect();
+    let old = container::wrap(CURRENT_VERSION - 1, &reversed).unwrap();
+
+    assert!(matches!(
+        deserialize_with_migrations(&old, &[]),
+        Err(ProgramError::UnsupportedVersion(v)) if v == CURRENT_VERSION - 1
+    ));
+
+    let migrations = [Migration {
+        from: CURRENT_VERSION - 1,
+        migrate: |payload| Ok(payload.iter().rev().copied().collect()),
+    }];
+    assert_eq!(
+        deserialize_with_migrations(&old, &migrations).unwrap(),
+        sample_program()
+    );
+}
+
+/// The program stored in `fixtures/version_0.bin`, written before programs
+/// had a container
+fn version_0_program() -> Program {
+    let array = DynamicArray::new();
+    array.0.borrow_mut().push(VmValue::Int(1));
+    array
+        .0
+        .borrow_mut()
+        .push(VmValue::String("two".to_string()));
+    array.0.borrow_mut().push(VmValue::Null);
+    let object = Object::new();
+    object
+        .0
+        .borrow_mut()
+        .insert(VmValue::String("k".to_string()), VmValue::Null);
+
+    Program::new(
+        vec![
+            Instruction::LOADV {
+                target: 0,
+                value: VmValue::Int(5),
+            },
+            Instruction::LOADV {
+                target: 1,
+                value: VmValue::DynamicArray(array),
+            },
+            Instruction::ADDK {
+                target: 0,
+                a_value: VmValue::Float(0.5),
+                b: 0,
+            },
+            Instruction::INC {
+                target: Some(2),
+                name: "x".to_string(),
+                returns_old: true,
+            },
+            Instruction::STOREK {
+                name: "y".to_string(),
+                value: VmValue::Boolean(false),
+            },
+            Instruction::INDEXK {
+                target: 2,
+                object: 1,
+                index: VmValue::Int(1),
+            },
+            Instruction::JLT {
+                a: 0,
+                b: 2,
+                address: 8,
+            },
+            Instruction::PRINTK(VmValue::Object(object)),
+            Instruction::NEW_OBJECT(3),
+            Instruction::CALL(11),
+            Instruction::HALT,
+            Instruction::RETURN,
+        ],
+        vec![VmValue::String("unused".to_string()), VmValue::Float(2.0)],
+    )
+}
+
+#[test]
+fn test_version_0_files_still_load() {
+    // headerless files, such as the out.bin written before the container
+    let mut old = include_bytes!("fixtures/version_0.bin").to_vec();
+    assert_eq!(deserialize(old.clone()).unwrap(), version_0_program());
+
+    old.push(0);
+    assert!(matches!(
+        deserialize(old.clone()),
+        Err(ProgramError::LengthMismatch { expected, actual })
+            if expected == old.len() - 1 && actual == old.len()
+    ));
+}
+
+/// The program stored in `fixtures/version_1.bin`, written by the version 1
+/// serializer
+fn version_1_program() -> Program {
+    let array = DynamicArray::new();
+    array.0.borrow_mut().push(VmValue::Int(1));
+    array.0.borrow_mut().push(VmValue::Float(2.5));
+    let object = Object::new();
+    object.0.borrow_mut().insert(
+        VmValue::String("a".to_string()),
+        VmValue::DynamicArray(array),
+    );
+    let constant = DynamicArray::new();
+    constant.0.borrow_mut().push(VmValue::Int(-7));
+    constant.0.borrow_mut().push(VmValue::Null);
+
+    Program::new(
+        vec![
+            Instruction::LOADC {
+                target: 0,
+                constant_index: 0,
+            },
+            Instruction::LOADV {
+                target: 1,
+                value: VmValue::Object(object),
+            },
+            Instruction::INC {
+                target: Some(2),
+                name: "x".to_string(),
+                returns_old: true,
+            },
+            Instruction::DEC {
+                target: None,
+                name: "x".to_string(),
+                returns_old: false,
+            },
+            Instruction::CLOSURE {
+                target: 2,
+                function: 0,
+                captures: vec![
+                    Capture::Variable("x".to_string()),
+                    Capture::Register(1),
+                    Capture::Upvalue(0),
+                ],
+            },
+            Instruction::CALLF {
+                function: 0,
+                args: 0,
+                arg_count: 1,
+                target: 1,
+                result_count: 1,
+            },
+            Instruction::CALL_NATIVEC {
+                name_constant: 1,
+                args: 1,
+                arg_count: 1,
+                target: 2,
+            },
+            Instruction::STORE_INDEXK {
+                source: 0,
+                object: 1,
+                index: VmValue::String("a".to_string()),
+            },
+            Instruction::PRINTK(VmValue::Float(-0.5)),
+            Instruction::JNZ {
+                source: 0,
+                address: 11,
+            },
+            Instruction::HALT,
+            Instruction::RETURNV {
+                source: 0,
+                count: 1,
+            },
+        ],
+        vec![
+            VmValue::String("hello".to_string()),
+            VmValue::String("len".to_string()),
+            VmValue::DynamicArray(constant),
+        ],
+    )
+    .with_functions(vec![FunctionProto::new("f", 11, 1, 2)])
+}
+
+#[test]
+fn test_version_1_files_still_load() {
+    let old = include_bytes!("fixtures/version_1.bin").to_vec();
+    assert_eq!(deserialize(old.clone()).unwrap(), version_1_program());
+
+    // the bincode payload must fill the container
+    let (version, payload) = container::unwrap(&old).unwrap();
+    let mut payload = payload.to_vec();
+    payload.push(0);
+    assert!(matches!(
+        deserialize(container::wrap(version, &payload).unwrap()),
+        Err(ProgramError::LengthMismatch { expected, actual })
+            if expected == payload.len() - 1 && actual == payload.len()
+    ));
+}
+
+#[test]
+fn test_serialization_is_deterministic() {
+    let source = format!(
+        "LOADV r0, {{ {} }}\nPRINT r0\n",
+        (0..32)
+            .map(|i| format!("\"key{}\": {}", i, i))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    // each assembly builds its objects with a differently seeded map
+    let first = serialize(&assemble(&source).unwrap()).unwrap();
+    let second = serialize(&assemble(&source).unwrap()).unwrap();
+
+    assert_eq!(first, second);
+}
+
+#[test]
+fn test_version_2_files_still_load() {
+    // version 2 payloads end where the debug info section now starts
+    let program = sample_program();
+    let mut payload = encode_program(&program).unwrap();
+    assert_eq!(payload.pop(), Some(0));
+    let old = container::wrap(2, &payload).unwrap();
+
+    assert_eq!(deserialize(old).unwrap(), program);
+}
+
+#[test]
+fn test_debug_info_round_trip() {
+    let mut builder = ProgramBuilder::new();
+    builder.location("main.ry", 1, 1);
+    builder.push(Instruction::LOADV {
+        target: 0,
+        value: VmValue::Int(1),
+    });
+    builder.push(Instruction::PRINT(0));
+    builder.location("lib.ry", 4, 2);
+    builder.location("lib.ry", 5, 3);
+    builder.push(Instruction::HALT);
+    let program = builder.build();
+
+    let debug_info = program.debug_info.as_ref().unwrap();
+    assert_eq!(debug_info.files, ["main.ry", "lib.ry"]);
+    assert_eq!(debug_info.entries.len(), 2);
+    assert_eq!(debug_info.location(1).unwrap().to_string(), "main.ry:1:1");
+    assert_eq!(debug_info.location(2).unwrap().to_string(), "lib.ry:5:3");
+    assert_eq!(DebugInfo::new().location(0), None);
+
+    let binary = serialize(&program).unwrap();
+    assert_eq!(deserialize(binary).unwrap(), program);
+
+    let mut bytes = encode_program(&program).unwrap();
+    bytes.push(0);
+    assert!(matches!(
+        decode_program(&bytes),
+        Err(ProgramError::MalformedBytecode { .. })
+    ));
+}
+
+#[test]
+fn test_compact_encoding_is_smaller_than_bincode() {
+    // Variable names are stored once in the name pool instead of in every instruction
+    let mut source = String::new();
+    for i in 0..20 {
+        source.push_str(&format!(
+            "LOAD r{0}, accumulator\nADDK r{0}, {0}, r{0}\nSTORE r{0}, accumulator\nINC _, iterations, false\n",
+            i % 4
+        ));
+    }
+    let program = assemble(&source).unwrap();
+
+    let compact = encode_program(&program).unwrap();
+    let bincode = bincode::encode_to_vec(&program, config::standard()).unwrap();
+    assert!(compact.len() * 2 < bincode.len());
+    assert_eq!(decode_program(&compact).unwrap(), program);
+}
+
+#[test]
+fn test_stream_instructions_from_slice() {
+    let program = sample_program();
+    let bytes = encode_program(&program).unwrap();
+
+    let reader = ProgramReader::new(&bytes).unwrap();
+    assert_eq!(reader.names, vec!["f"]);
+    assert_eq!(reader.constant_pool, program.constant_pool);
+    assert_eq!(reader.instruction_count(), 4);
+    let mut instructions = reader.instructions();
+    assert_eq!(
+        instructions.next().unwrap().unwrap(),
+        Instruction::PRINTC(0)
+    );
+    assert_eq!(instructions.count(), 3);
+}
+
+#[test]
+fn test_encoding_errors() {
+    let program = Program::from_instructions(vec![Instruction::PRINT(255)]);
+    assert!(matches!(
+        serialize(&program),
+        Err(ProgramError::RegisterTooLarge(255))
+    ));
+
+    let program = Program::from_instructions(vec![Instruction::PRINTK(Closure::new_vm_value(
+        0,
+        Vec::new(),
+    ))]);
+    assert!(matches!(
+        serialize(&program),
+        Err(ProgramError::UnencodableValue(_))
+    ));
+
+    let mut bytes = encode_program(&sample_program()).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] = 0xfe;
+    assert!(matches!(
+        decode_program(&bytes),
+        Err(ProgramError::MalformedBytecode { offset, .. }) if offset == last + 1
+    ));
+    bytes.pop();
+    assert!(matches!(
+        decode_program(&bytes),
+        Err(ProgramError::MalformedBytecode { .. })
+    ));
+}
+
+#[test]
+fn test_decoding_untrusted_counts() {
+    // oversized name, constant, function and instruction counts fail once
+    // the payload runs out instead of allocating
+    let huge_count = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+    for position in 0..4 {
+        let mut bytes = vec![0; position];
+        bytes.extend_from_slice(&huge_count);
+        assert!(matches!(
+            decode_program(&bytes),
+            Err(ProgramError::MalformedBytecode { message, .. }) if message == "unexpected end of bytecode"
+        ));
+    }
+
+    let bytes = encode_program(&sample_program()).unwrap();
+    for len in 0..bytes.len() {
+        assert!(
+            decode_program(&bytes[..len]).is_err(),
+            "truncated to {} bytes",
+            len
+        );
+    }
+
+    // one constant made of arrays nested past the depth cap
+    let mut nested = vec![0, 1];
+    nested.extend(std::iter::repeat_n([6, 1], 100_000).flatten());
+    nested.extend_from_slice(&[0, 0, 0, 0]);
+    assert!(matches!(
+        decode_program(&nested),
+        Err(ProgramError::MalformedBytecode { message, .. }) if message == "values are nested too deeply"
+    ));
+}
@@ -0,0 +1,245 @@
+use ryde::asm::assemble;
+use ryde::error::verifier::{Diagnostic, DiagnosticKind};
+use ryde::error::vm::VmError;
+use ryde::function::FunctionProto;
+use ryde::instruction::Instruction;
+use ryde::output::SharedBuffer;
+use ryde::serde::Program;
+use ryde::verifier::verify;
+use ryde::vm::Vm;
+
+fn register_out_of_bounds(index: usize, register: usize, register_count: usize) -> Diagnostic {
+    Diagnostic::new(
+        index,
+        DiagnosticKind::RegisterOutOfBounds {
+            register,
+            register_count,
+        },
+    )
+}
+
+fn register_range_out_of_bounds(
+    index: usize,
+    start: usize,
+    count: usize,
+    register_count: usize,
+) -> Diagnostic {
+    Diagnostic::new(
+        index,
+        DiagnosticKind::RegisterRangeOutOfBounds {
+            start,
+            count,
+            register_count,
+        },
+    )
+}
+
+#[test]
+fn test_verify_valid_program() {
+    let program = assemble(
+        r#"
+        .function fib 1 4 fib
+            LOADV r0, 10
+            CALLF fib, r0, 1, r1, 1
+            PRINT r1
+            HALT
+        fib:
+            LOADV r1, 2
+            JLT r0, r1, done
+            LOADV r1, 1
+            SUB r1, r0, r1
+            CALLF fib, r1, 1, r2, 1
+            LOADV r1, 2
+            SUB r1, r0, r1
+            CALLF fib, r1, 1, r3, 1
+            ADD r0, r2, r3
+        done:
+            RETURNV r0, 1
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(verify(&program, 2), Vec::new());
+}
+
+#[test]
+fn test_verify_register_windows() {
+    let program = assemble(
+        r#"
+        .function f 0 2 f
+            LOADV r1, 1
+            LOADV r2, 2
+            CALL sub
+            CALLF f, r0, 0, r1, 3
+            HALT
+        sub:
+            LOADV r1, 1
+            RETURN
+        f:
+            LOADV r1, 1
+            RETURNV r0, 3
+        "#,
+    )
+    .unwrap();
+
+    // `sub` shares the top-level window, while `f` has its own two registers.
+    // The result range of CALLF is checked in the caller's window.
+    assert_eq!(
+        verify(&program, 2),
+        vec![
+            register_out_of_bounds(1, 2, 2),
+            register_range_out_of_bounds(3, 1, 3, 2),
+            register_range_out_of_bounds(8, 0, 3, 2),
+        ]
+    );
+    assert_eq!(
+        verify(&program, 3),
+        vec![
+            register_range_out_of_bounds(3, 1, 3, 3),
+            register_range_out_of_bounds(8, 0, 3, 2),
+        ]
+    );
+}
+
+#[test]
+fn test_verify_register_range_overflow() {
+    let program = Program::from_instructions(vec![
+        Instruction::CALLF {
+            function: 0,
+            args: 1,
+            arg_count: usize::MAX,
+            target: 0,
+            result_count: 1,
+        },
+        Instruction::CALL_NATIVE {
+            name: "print".to_string(),
+            args: 0,
+            arg_count: 1 << 40,
+            target: 0,
+        },
+        Instruction::HALT,
+        Instruction::RETURNV {
+            source: usize::MAX,
+            count: 2,
+        },
+    ])
+    .with_functions(vec![FunctionProto::new("f", 3, 1, 1)]);
+
+    assert_eq!(
+        verify(&program, 1),
+        vec![
+            register_range_out_of_bounds(0, 1, usize::MAX, 1),
+            Diagnostic::new(
+                0,
+                DiagnosticKind::ArityMismatch {
+                    name: "f".to_string(),
+                    expected: 1,
+                    actual: usize::MAX,
+                }
+            ),
+            register_range_out_of_bounds(1, 0, 1 << 40, 1),
+            register_range_out_of_bounds(3, usize::MAX, 2, 1),
+        ]
+    );
+}
+
+#[test]
+fn test_verify_operands() {
+    let program = assemble(
+        r#"
+        .const 1
+        .function f 1 1 f
+        .function g 0 1 99
+            LOADC r0, #1
+            JZ r0, 99
+            CALLF f, r0, 0, r0, 1
+            CLOSURE r0, 5
+            CLOSURE r0, g
+            CALL_NATIVE print, r0, 0, r0
+            HALT
+        f:
+            RETURN
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        verify(&program, 1),
+        vec![
+            Diagnostic::new(0, DiagnosticKind::ConstantOutOfBounds(1)),
+            Diagnostic::new(1, DiagnosticKind::AddressOutOfBounds(99)),
+            Diagnostic::new(
+                2,
+                DiagnosticKind::ArityMismatch {
+                    name: "f".to_string(),
+                    expected: 1,
+                    actual: 0,
+                }
+            ),
+            Diagnostic::new(3, DiagnosticKind::FunctionOutOfBounds(5)),
+            Diagnostic::new(4, DiagnosticKind::AddressOutOfBounds(99)),
+        ]
+    );
+}
+
+#[test]
+fn test_verify_return_outside_call() {
+    let program = assemble(
+        r#"
+            LOADV r0, true
+            JZ r0, skip
+            RETURN
+        skip:
+            CALL sub
+            HALT
+            RETURNV r7, 1     ; unreachable, so not checked
+        sub:
+            RETURN
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        verify(&program, 1),
+        vec![Diagnostic::new(2, DiagnosticKind::ReturnOutsideCall)]
+    );
+}
+
+#[test]
+fn test_verify_exception_handlers() {
+    let program = assemble(
+        r#"
+            PUSH_HANDLER r1, caught
+            THROW r0
+            LOADV r5, 1       ; unreachable, so not checked
+        caught:
+            PRINT r2
+            POP_HANDLER
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(verify(&program, 2), vec![register_out_of_bounds(3, 2, 2)]);
+}
+
+#[test]
+fn test_run_verified() {
+    let program = assemble(
+        r#"
+            PRINTK "never printed"
+            LOADV r4, 1
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 4);
+    vm.set_output(output.clone());
+    match vm.run_verified() {
+        Err(VmError::VerificationFailed(diagnostics)) => {
+            assert_eq!(diagnostics, vec![register_out_of_bounds(1, 4, 4)]);
+        }
+        other => panic!("expected verification failure, got {:?}", other),
+    }
+    assert_eq!(output.contents(), "");
+}
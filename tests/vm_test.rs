@@ -1,6 +1,18 @@
+use ryde::asm::assemble;
+use ryde::function::{Capture, FunctionProto};
 use ryde::instruction::Instruction;
+use ryde::opcode::Opcode;
+use ryde::output::SharedBuffer;
 use ryde::serde::Program;
-use ryde::{error::vm::VmError, value::VmValue, vm::Vm};
+use ryde::serde::builder::ProgramBuilder;
+use std::{cell::RefCell, io, rc::Rc};
+
+use ryde::{
+    error::vm::{VariableScope, VmError},
+    limits::Limits,
+    value::VmValue,
+    vm::Vm,
+};
 
 #[test]
 fn test_loadv() -> () {
@@ -18,6 +30,44 @@ fn test_loadv() -> () {
     assert_eq!(*vm.registers[0].borrow(), VmValue::Int(420));
 }
 
+#[test]
+fn test_loadc() {
+    let program = Program::new(
+        vec![
+            Instruction::LOADC {
+                target: 0,
+                constant_index: 1,
+            },
+            Instruction::HALT,
+        ],
+        vec![VmValue::Int(1), VmValue::String("hello".to_string())],
+    );
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(
+        *vm.registers[0].borrow(),
+        VmValue::String("hello".to_string())
+    );
+}
+
+#[test]
+fn test_loadc_out_of_bounds() {
+    let program = Program::from_instructions(vec![
+        Instruction::LOADC {
+            target: 0,
+            constant_index: 3,
+        },
+        Instruction::HALT,
+    ]);
+
+    let mut vm = Vm::new(&program, 4);
+    let result = vm.run();
+
+    assert!(matches!(result, Err(VmError::ConstantOutOfBounds(3))));
+}
+
 #[test]
 fn test_add() -> () {
     let program = Program::from_instructions(vec![
@@ -64,6 +114,30 @@ fn test_addk() -> () {
     assert_eq!(*vm.registers[0].borrow(), VmValue::Int(69));
 }
 
+#[test]
+fn test_addc() {
+    let program = Program::new(
+        vec![
+            Instruction::LOADV {
+                target: 0,
+                value: VmValue::Int(60),
+            },
+            Instruction::ADDC {
+                target: 0,
+                a_constant: 0,
+                b: 0,
+            },
+            Instruction::HALT,
+        ],
+        vec![VmValue::Int(9)],
+    );
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(*vm.registers[0].borrow(), VmValue::Int(69));
+}
+
 #[test]
 fn test_sub() -> () {
     let program = Program::from_instructions(vec![
@@ -248,6 +322,64 @@ fn test_idivk() -> () {
     assert_eq!(*vm.registers[0].borrow(), VmValue::Int(6));
 }
 
+#[test]
+fn test_idivc() {
+    let program = Program::new(
+        vec![
+            Instruction::LOADV {
+                target: 0,
+                value: VmValue::Int(2),
+            },
+            Instruction::IDIVC {
+                target: 1,
+                a_constant: 0,
+                b: 0,
+            },
+            Instruction::LOADV {
+                target: 0,
+                value: VmValue::Int(0),
+            },
+            Instruction::IDIVC {
+                target: 2,
+                a_constant: 1,
+                b: 0,
+            },
+            Instruction::HALT,
+        ],
+        vec![VmValue::Float(7.5), VmValue::Int(1)],
+    );
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    // like IDIV, the result is an Int even from a Float or a division by zero
+    assert_eq!(*vm.registers[1].borrow(), VmValue::Int(3));
+    assert_eq!(*vm.registers[2].borrow(), VmValue::Int(i32::MAX));
+}
+
+#[test]
+fn test_shift_constants() {
+    // the constant forms mask their counts like the register forms
+    let program = assemble(
+        r#"
+        .const -8
+            LOADV r0, 33
+            BLSHC r1, #0, r0
+            LOADV r0, -1
+            BARSHC r2, #0, r0
+            BRSHC r3, #0, r0
+        "#,
+    )
+    .unwrap();
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(vm.register(1).unwrap(), VmValue::Int(-16));
+    assert_eq!(vm.register(2).unwrap(), VmValue::Int(-1));
+    assert_eq!(vm.register(3).unwrap(), VmValue::Int(1));
+}
+
 #[test]
 fn test_pow() -> () {
     let program = Program::from_instructions(vec![
@@ -645,7 +777,130 @@ fn test_store_and_load() -> () {
     vm.run().unwrap();
 
     assert_eq!(*vm.registers[1].borrow(), VmValue::Int(123));
-    assert_eq!(*vm.variables.get("x").unwrap().borrow(), VmValue::Int(123));
+    assert_eq!(*vm.globals.get("x").unwrap().borrow(), VmValue::Int(123));
+}
+
+#[test]
+fn test_scope_shadowing() {
+    let program = Program::from_instructions(vec![
+        Instruction::PUSH_SCOPE,
+        Instruction::STOREK {
+            name: "x".to_string(),
+            value: VmValue::Int(1),
+        },
+        Instruction::PUSH_SCOPE,
+        Instruction::LOADV {
+            target: 0,
+            value: VmValue::Int(2),
+        },
+        Instruction::DECLARE {
+            source: 0,
+            name: "x".to_string(),
+        },
+        Instruction::INC {
+            target: Some(1),
+            name: "x".to_string(),
+            returns_old: false,
+        },
+        Instruction::POP_SCOPE,
+        Instruction::LOAD {
+            target: 2,
+            name: "x".to_string(),
+        },
+        Instruction::POP_SCOPE,
+        Instruction::HALT,
+    ]);
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(*vm.registers[0].borrow(), VmValue::Int(2));
+    assert_eq!(*vm.registers[1].borrow(), VmValue::Int(3));
+    assert_eq!(*vm.registers[2].borrow(), VmValue::Int(1));
+    assert!(vm.globals.is_empty());
+}
+
+#[test]
+fn test_function_locals_do_not_clobber_globals() {
+    let program = Program::from_instructions(vec![
+        Instruction::STOREK {
+            name: "x".to_string(),
+            value: VmValue::Int(1),
+        },
+        Instruction::CALLF {
+            function: 0,
+            args: 0,
+            arg_count: 0,
+            target: 0,
+            result_count: 0,
+        },
+        Instruction::LOAD {
+            target: 0,
+            name: "x".to_string(),
+        },
+        Instruction::HALT,
+        // f()
+        Instruction::LOADV {
+            target: 0,
+            value: VmValue::Int(100),
+        },
+        Instruction::DECLARE {
+            source: 0,
+            name: "x".to_string(),
+        },
+        Instruction::STORE_GLOBAL {
+            source: 0,
+            name: "y".to_string(),
+        },
+        Instruction::RETURN,
+    ])
+    .with_functions(vec![FunctionProto::new("f", 4, 0, 1)]);
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(*vm.registers[0].borrow(), VmValue::Int(1));
+    assert_eq!(*vm.globals.get("y").unwrap().borrow(), VmValue::Int(100));
+}
+
+#[test]
+fn test_variable_not_found_in_function() {
+    let program = Program::from_instructions(vec![
+        Instruction::CALLF {
+            function: 0,
+            args: 0,
+            arg_count: 0,
+            target: 0,
+            result_count: 0,
+        },
+        Instruction::HALT,
+        Instruction::LOAD {
+            target: 0,
+            name: "missing".to_string(),
+        },
+        Instruction::RETURN,
+    ])
+    .with_functions(vec![FunctionProto::new("f", 2, 0, 1)]);
+
+    let mut vm = Vm::new(&program, 4);
+    let result = vm.run();
+
+    assert!(matches!(
+        result,
+        Err(VmError::VariableNotFound {
+            scope: VariableScope::Local { depth: 1, .. },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_pop_scope_without_push() {
+    let program = Program::from_instructions(vec![Instruction::POP_SCOPE, Instruction::HALT]);
+    let mut vm = Vm::new(&program, 4);
+    let result = vm.run();
+
+    assert!(matches!(result, Err(VmError::ScopeStackEmpty)));
 }
 
 #[test]
@@ -677,6 +932,441 @@ fn test_call_and_return() {
     assert_eq!(vm.call_stack.len(), 0);
 }
 
+fn fib_program(n: i32) -> Program {
+    Program::from_instructions(vec![
+        Instruction::LOADV {
+            target: 0,
+            value: VmValue::Int(n),
+        },
+        Instruction::CALLF {
+            function: 0,
+            args: 0,
+            arg_count: 1,
+            target: 1,
+            result_count: 1,
+        },
+        Instruction::HALT,
+        // fib(n)
+        Instruction::LOADV {
+            target: 1,
+            value: VmValue::Int(2),
+        },
+        Instruction::JLT {
+            a: 0,
+            b: 1,
+            address: 13,
+        },
+        Instruction::LOADV {
+            target: 1,
+            value: VmValue::Int(1),
+        },
+        Instruction::SUB {
+            target: 1,
+            a: 0,
+            b: 1,
+        },
+        Instruction::CALLF {
+            function: 0,
+            args: 1,
+            arg_count: 1,
+            target: 2,
+            result_count: 1,
+        },
+        Instruction::LOADV {
+            target: 1,
+            value: VmValue::Int(2),
+        },
+        Instruction::SUB {
+            target: 1,
+            a: 0,
+            b: 1,
+        },
+        Instruction::CALLF {
+            function: 0,
+            args: 1,
+            arg_count: 1,
+            target: 3,
+            result_count: 1,
+        },
+        Instruction::ADD {
+            target: 0,
+            a: 2,
+            b: 3,
+        },
+        Instruction::RETURNV {
+            source: 0,
+            count: 1,
+        },
+        Instruction::RETURNV {
+            source: 0,
+            count: 1,
+        },
+    ])
+    .with_functions(vec![FunctionProto::new("fib", 3, 1, 4)])
+}
+
+#[test]
+fn test_recursive_function() {
+    let program = fib_program(10);
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(*vm.registers[0].borrow(), VmValue::Int(10));
+    assert_eq!(*vm.registers[1].borrow(), VmValue::Int(55));
+    assert_eq!(vm.registers.len(), 4);
+    assert_eq!(vm.call_stack.len(), 0);
+}
+
+#[test]
+fn test_function_multiple_results() {
+    let program = Program::from_instructions(vec![
+        Instruction::LOADV {
+            target: 0,
+            value: VmValue::Int(7),
+        },
+        Instruction::LOADV {
+            target: 1,
+            value: VmValue::Int(8),
+        },
+        Instruction::CALLF {
+            function: 0,
+            args: 0,
+            arg_count: 2,
+            target: 2,
+            result_count: 2,
+        },
+        Instruction::HALT,
+        // swap(a, b)
+        Instruction::LOADV {
+            target: 2,
+            value: VmValue::Int(999),
+        },
+        Instruction::RETURNV {
+            source: 1,
+            count: 1,
+        },
+    ])
+    .with_functions(vec![FunctionProto::new("swap", 4, 2, 3)]);
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(*vm.registers[2].borrow(), VmValue::Int(8));
+    assert_eq!(*vm.registers[3].borrow(), VmValue::Null);
+}
+
+#[test]
+fn test_function_arity_mismatch() {
+    let program = Program::from_instructions(vec![
+        Instruction::CALLF {
+            function: 0,
+            args: 0,
+            arg_count: 2,
+            target: 0,
+            result_count: 0,
+        },
+        Instruction::RETURN,
+    ])
+    .with_functions(vec![FunctionProto::new("f", 1, 1, 1)]);
+
+    let mut vm = Vm::new(&program, 4);
+    let result = vm.run();
+
+    assert!(matches!(
+        result,
+        Err(VmError::ArityMismatch {
+            expected: 1,
+            actual: 2,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_run_traced() {
+    let program = assemble(
+        r#"
+            .function outer 0 1 outer
+            .function inner 0 1 inner
+            .loc "main.ry" 1 1
+            CALLF outer, r0, 0, r0, 0
+            HALT
+        outer:
+            .loc "lib.ry" 2 5
+            CALLF inner, r0, 0, r0, 0
+            RETURN
+        inner:
+            .loc "lib.ry" 7 9
+            LOADV r0, 1
+            LOAD r0, missing
+            RETURN
+        "#,
+    )
+    .unwrap();
+
+    let mut vm = Vm::new(&program, 4);
+    let trace = vm.run_traced().unwrap_err();
+
+    assert!(matches!(trace.error, VmError::VariableNotFound { .. }));
+    assert_eq!(trace.pc, 5);
+    assert_eq!(trace.opcode, Opcode::LOAD);
+    assert_eq!(trace.location.as_ref().unwrap().line, 7);
+    assert_eq!(
+        trace
+            .frames
+            .iter()
+            .map(|frame| frame.call_site)
+            .collect::<Vec<_>>(),
+        [2, 0]
+    );
+    assert_eq!(
+        trace.to_string(),
+        "Variable 'missing' not found in local scope of function 'inner' (depth 1) or global scope
+  at 0005 LOAD in inner (lib.ry:7:9)
+  at 0002 in outer (lib.ry:2:5)
+  at 0000 in <top level> (main.ry:1:1)"
+    );
+
+    // without debug info only the instruction indices are known
+    let program = Program::from_instructions(vec![Instruction::RETURN]);
+    let trace = Vm::new(&program, 1).run_traced().unwrap_err();
+    assert_eq!(
+        trace.to_string(),
+        "Call stack is empty, cannot return\n  at 0000 RETURN in <top level>"
+    );
+}
+
+#[test]
+fn test_closures_share_captured_variable() {
+    let call = |callee, target, result_count| Instruction::CALLR {
+        callee,
+        args: 0,
+        arg_count: 0,
+        target,
+        result_count,
+    };
+    let program = Program::from_instructions(vec![
+        Instruction::CALLF {
+            function: 0,
+            args: 0,
+            arg_count: 0,
+            target: 0,
+            result_count: 2,
+        },
+        call(0, 0, 0),
+        call(0, 0, 0),
+        call(1, 2, 1),
+        Instruction::HALT,
+        // make_counter()
+        Instruction::LOADV {
+            target: 0,
+            value: VmValue::Int(0),
+        },
+        Instruction::DECLARE {
+            source: 0,
+            name: "count".to_string(),
+        },
+        Instruction::CLOSURE {
+            target: 0,
+            function: 1,
+            captures: vec![Capture::Variable("count".to_string())],
+        },
+        Instruction::CLOSURE {
+            target: 1,
+            function: 2,
+            captures: vec![Capture::Variable("count".to_string())],
+        },
+        Instruction::RETURNV {
+            source: 0,
+            count: 2,
+        },
+        // increment()
+        Instruction::GETUPVAL {
+            target: 0,
+            upvalue: 0,
+        },
+        Instruction::ADDK {
+            target: 0,
+            a_value: VmValue::Int(1),
+            b: 0,
+        },
+        Instruction::SETUPVAL {
+            source: 0,
+            upvalue: 0,
+        },
+        Instruction::RETURN,
+        // get()
+        Instruction::GETUPVAL {
+            target: 0,
+            upvalue: 0,
+        },
+        Instruction::RETURNV {
+            source: 0,
+            count: 1,
+        },
+    ])
+    .with_functions(vec![
+        FunctionProto::new("make_counter", 5, 0, 2),
+        FunctionProto::new("increment", 10, 0, 1),
+        FunctionProto::new("get", 14, 0, 1),
+    ]);
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert!(matches!(*vm.registers[0].borrow(), VmValue::Closure(_)));
+    assert_eq!(*vm.registers[2].borrow(), VmValue::Int(2));
+}
+
+#[test]
+fn test_upvalue_outside_closure() {
+    let program = Program::from_instructions(vec![
+        Instruction::GETUPVAL {
+            target: 0,
+            upvalue: 0,
+        },
+        Instruction::HALT,
+    ]);
+
+    let mut vm = Vm::new(&program, 4);
+    let result = vm.run();
+
+    assert!(matches!(result, Err(VmError::UpvalueOutOfBounds(0))));
+}
+
+#[test]
+fn test_call_native() {
+    let program = Program::new(
+        vec![
+            Instruction::LOADV {
+                target: 0,
+                value: VmValue::Int(40),
+            },
+            Instruction::LOADV {
+                target: 1,
+                value: VmValue::Int(2),
+            },
+            Instruction::CALL_NATIVE {
+                name: "sum".to_string(),
+                args: 0,
+                arg_count: 2,
+                target: 2,
+            },
+            Instruction::CALL_NATIVEC {
+                name_constant: 0,
+                args: 0,
+                arg_count: 1,
+                target: 3,
+            },
+            Instruction::HALT,
+        ],
+        vec![VmValue::String("sum".to_string())],
+    );
+
+    let mut vm = Vm::new(&program, 4);
+    vm.register_native("sum", |_, args| {
+        let mut total = 0;
+        for arg in args {
+            if let VmValue::Int(n) = arg {
+                total += n;
+            }
+        }
+        Ok(VmValue::Int(total))
+    });
+    vm.run().unwrap();
+
+    assert_eq!(*vm.registers[2].borrow(), VmValue::Int(42));
+    assert_eq!(*vm.registers[3].borrow(), VmValue::Int(40));
+}
+
+#[test]
+fn test_call_native_error() {
+    let program = Program::from_instructions(vec![
+        Instruction::CALL_NATIVE {
+            name: "fail".to_string(),
+            args: 0,
+            arg_count: 0,
+            target: 0,
+        },
+        Instruction::HALT,
+    ]);
+
+    let mut vm = Vm::new(&program, 4);
+    vm.register_native("fail", |_, _| Err(VmError::CallStackEmpty));
+    let result = vm.run();
+
+    assert!(matches!(
+        result,
+        Err(VmError::NativeFunctionError { ref name, ref error })
+            if name == "fail" && matches!(**error, VmError::CallStackEmpty)
+    ));
+}
+
+#[test]
+fn test_call_native_not_found() {
+    let program = Program::from_instructions(vec![
+        Instruction::CALL_NATIVE {
+            name: "missing".to_string(),
+            args: 0,
+            arg_count: 0,
+            target: 0,
+        },
+        Instruction::HALT,
+    ]);
+
+    let mut vm = Vm::new(&program, 4);
+    let result = vm.run();
+
+    assert!(matches!(result, Err(VmError::NativeFunctionNotFound(_))));
+}
+
+#[test]
+fn test_call_argument_range_overflow() {
+    let program = Program::from_instructions(vec![
+        Instruction::CALL_NATIVE {
+            name: "count".to_string(),
+            args: 2,
+            arg_count: usize::MAX,
+            target: 0,
+        },
+        Instruction::HALT,
+    ]);
+    let mut vm = Vm::new(&program, 4);
+    vm.register_native("count", |_, args| Ok(VmValue::Int(args.len() as i32)));
+    let result = vm.run();
+    assert!(matches!(result, Err(VmError::RegisterOutOfBounds(_))));
+
+    let program = Program::from_instructions(vec![
+        Instruction::CALLF {
+            function: 0,
+            args: 1,
+            arg_count: usize::MAX,
+            target: 0,
+            result_count: 0,
+        },
+        Instruction::HALT,
+        Instruction::RETURN,
+    ])
+    .with_functions(vec![FunctionProto::new("f", 2, usize::MAX, usize::MAX)]);
+    let result = Vm::new(&program, 4).run();
+    assert!(matches!(result, Err(VmError::RegisterOutOfBounds(_))));
+}
+
+#[test]
+fn test_return_range_overflow() {
+    let program = Program::from_instructions(vec![
+        Instruction::CALL(2),
+        Instruction::HALT,
+        Instruction::RETURNV {
+            source: 3,
+            count: usize::MAX,
+        },
+    ]);
+    let result = Vm::new(&program, 4).run();
+
+    assert!(matches!(result, Err(VmError::RegisterOutOfBounds(_))));
+}
+
 #[test]
 fn test_return_without_call() {
     let program = Program::from_instructions(vec![Instruction::RETURN, Instruction::HALT]);
@@ -740,3 +1430,244 @@ fn test_invalid_register() -> () {
 
     assert!(matches!(result, Err(VmError::RegisterOutOfBounds(_))));
 }
+
+#[test]
+fn test_builder_interns_constants() {
+    let mut builder = ProgramBuilder::new();
+    builder.push(Instruction::LOADV {
+        target: 0,
+        value: VmValue::String("abc".to_string()),
+    });
+    builder.push(Instruction::ADDK {
+        target: 0,
+        a_value: VmValue::String("abc".to_string()),
+        b: 0,
+    });
+    builder.push(Instruction::LOADV {
+        target: 1,
+        value: VmValue::Float(1.0),
+    });
+    builder.push(Instruction::LOADV {
+        target: 2,
+        value: VmValue::Int(1),
+    });
+    builder.push(Instruction::HALT);
+    let program = builder.build();
+
+    assert_eq!(program.constant_pool.len(), 3);
+    assert_eq!(
+        program.instructions[1],
+        Instruction::ADDC {
+            target: 0,
+            a_constant: 0,
+            b: 0,
+        }
+    );
+
+    let mut vm = Vm::new(&program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(
+        *vm.registers[0].borrow(),
+        VmValue::String("abcabc".to_string())
+    );
+    assert!(matches!(*vm.registers[1].borrow(), VmValue::Float(_)));
+    assert!(matches!(*vm.registers[2].borrow(), VmValue::Int(1)));
+}
+
+#[test]
+fn test_print_to_writer() {
+    let program = Program::new(
+        vec![
+            Instruction::LOADV {
+                target: 0,
+                value: VmValue::Int(69),
+            },
+            Instruction::PRINT(0),
+            Instruction::PRINTK(VmValue::String("hello".to_string())),
+            Instruction::PRINTC(0),
+            Instruction::HALT,
+        ],
+        vec![VmValue::Boolean(true)],
+    );
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 4);
+    vm.set_output(output.clone());
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "69\nhello\ntrue\n");
+}
+
+#[test]
+fn test_print_to_callback() {
+    let program = Program::from_instructions(vec![
+        Instruction::PRINTK(VmValue::Float(4.5)),
+        Instruction::PRINTK(VmValue::Null),
+        Instruction::HALT,
+    ]);
+
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new(&program, 4);
+    let sink = lines.clone();
+    vm.set_output_callback(move |line| sink.borrow_mut().push(line.to_string()));
+    vm.run().unwrap();
+
+    assert_eq!(*lines.borrow(), vec!["4.5", "null"]);
+}
+
+#[test]
+fn test_output_is_flushed_on_error() {
+    let program = assemble("PRINTK \"before\"\nLOAD r0, missing\n").unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 1);
+    vm.set_output(io::BufWriter::new(output.clone()));
+    assert!(vm.run().is_err());
+    assert_eq!(output.contents(), "before\n");
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 1);
+    vm.set_output(io::BufWriter::new(output.clone()));
+    assert!(vm.run_with_limits(Limits::default()).is_err());
+    assert_eq!(output.contents(), "before\n");
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 1);
+    vm.set_output(io::BufWriter::new(output.clone()));
+    assert!(vm.run_traced().is_err());
+    assert_eq!(output.contents(), "before\n");
+}
+
+struct FailingWriter;
+
+impl io::Write for FailingWriter {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("closed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_print_io_error() {
+    let program = Program::from_instructions(vec![Instruction::PRINTK(VmValue::Int(1))]);
+    let mut vm = Vm::new(&program, 4);
+    vm.set_output(FailingWriter);
+    let result = vm.run();
+
+    assert!(matches!(result, Err(VmError::IoError(_))));
+}
+
+#[test]
+fn test_throw_unwinds_to_handler() {
+    let program = assemble(
+        r#"
+            .function thrower 1 2 thrower
+            PUSH_SCOPE
+            PUSH_HANDLER r1, caught
+            PUSH_SCOPE
+            LOADV r0, "boom"
+            CALLF thrower, r0, 1, r2, 1
+            HALT
+        caught:
+            PRINT r1
+            HALT
+        thrower:
+            PUSH_SCOPE
+            STORE r0, local
+            THROW r0
+        "#,
+    )
+    .unwrap();
+
+    let output = SharedBuffer::new();
+    let mut vm = Vm::new(&program, 3);
+    vm.set_output(output.clone());
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "boom\n");
+    assert!(vm.call_stack.is_empty());
+    assert_eq!(vm.registers.len(), 3);
+    assert_eq!(vm.scopes.len(), 1);
+}
+
+#[test]
+fn test_runtime_errors_are_caught_as_values() {
+    let program = assemble(
+        r#"
+            PUSH_HANDLER r2, caught
+            LOADV r0, [1]
+            INDEXK r1, r0, "one"
+            HALT
+        caught:
+            INDEXK r0, r2, "kind"
+            INDEXK r1, r2, "message"
+        "#,
+    )
+    .unwrap();
+
+    let mut vm = Vm::new(&program, 3);
+    vm.run().unwrap();
+
+    assert_eq!(
+        *vm.registers[0].borrow(),
+        VmValue::String("InvalidIndexType".to_string())
+    );
+    assert_eq!(
+        *vm.registers[1].borrow(),
+        VmValue::String("Invalid index type, got 'String(\"one\")'".to_string())
+    );
+}
+
+#[test]
+fn test_uncaught_exceptions() {
+    // the handler is gone once the function that installed it returns
+    let program = assemble(
+        r#"
+            .function guarded 0 1 guarded
+            CALLF guarded, r0, 0, r0, 0
+            LOADV r0, 7
+            THROW r0
+        guarded:
+            PUSH_HANDLER r0, caught
+            RETURN
+        caught:
+            HALT
+        "#,
+    )
+    .unwrap();
+    let result = Vm::new(&program, 1).run();
+    assert!(matches!(result, Err(VmError::Thrown(VmValue::Int(7)))));
+
+    // malformed bytecode is not catchable
+    let program = assemble("PUSH_HANDLER r0, caught\nPOP_SCOPE\ncaught:\nHALT\n").unwrap();
+    let result = Vm::new(&program, 1).run();
+    assert!(matches!(result, Err(VmError::ScopeStackEmpty)));
+
+    let program = Program::from_instructions(vec![Instruction::POP_HANDLER]);
+    let result = Vm::new(&program, 1).run();
+    assert!(matches!(result, Err(VmError::HandlerStackEmpty)));
+}
+
+#[test]
+fn test_native_functions_throw_values() {
+    let program = assemble(
+        r#"
+            PUSH_HANDLER r0, caught
+            CALL_NATIVE fail, r0, 0, r0
+            POP_HANDLER
+        caught:
+            HALT
+        "#,
+    )
+    .unwrap();
+
+    let mut vm = Vm::new(&program, 1);
+    vm.register_native("fail", |_, _| Err(VmError::Thrown(VmValue::Int(3))));
+    vm.run().unwrap();
+
+    assert_eq!(*vm.registers[0].borrow(), VmValue::Int(3));
+}